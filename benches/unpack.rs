@@ -0,0 +1,195 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use oci_bundle::{unpack_with_options, UnpackOptions};
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::Dir;
+use ocidir::oci_spec::image::{ImageManifest, Platform};
+use ocidir::OciDir;
+use std::fs;
+use std::path::Path;
+
+const LAYERS: usize = 20;
+const FILES_PER_LAYER: usize = 500;
+
+/// Creates an image with `layers` layers, calling `fill` with each layer's index and a function
+/// that appends a regular file to it
+fn create_image(
+    path: &Path,
+    layers: usize,
+    mut fill: impl FnMut(usize, &mut dyn FnMut(String, &[u8])),
+) -> (OciDir, ImageManifest) {
+    fs::create_dir_all(path).unwrap();
+    let oci_dir =
+        OciDir::ensure(&Dir::open_ambient_dir(path, ambient_authority()).unwrap()).unwrap();
+    let mut manifest = ocidir::new_empty_manifest().build().unwrap();
+    let mut config = ocidir::oci_spec::image::ImageConfigurationBuilder::default()
+        .build()
+        .unwrap();
+
+    for layer in 0..layers {
+        let mut tar = oci_dir.create_layer(None).unwrap();
+        let mut append = |path: String, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_cksum();
+            tar.append_data(&mut header, path, data).unwrap();
+        };
+        fill(layer, &mut append);
+        let layer = tar.into_inner().unwrap().complete().unwrap();
+        oci_dir.push_layer(&mut manifest, &mut config, layer, "layer", None);
+    }
+
+    let descriptor = oci_dir
+        .insert_manifest_and_config(manifest, config, None, Platform::default())
+        .unwrap();
+    let manifest = ImageManifest::from_reader(oci_dir.read_blob(&descriptor).unwrap()).unwrap();
+    (oci_dir, manifest)
+}
+
+fn parallel_layers(c: &mut Criterion) {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    // Each layer also overwrites and whites out files from the layer below
+    let (oci_dir, manifest) =
+        create_image(&temp_dir.path().join("oci"), LAYERS, |layer, append| {
+            for file in 0..FILES_PER_LAYER {
+                let data = format!("layer {layer} file {file}").repeat(64);
+                append(format!("layer{layer}/file{file}"), data.as_bytes());
+                append(format!("shared/file{file}"), data.as_bytes());
+            }
+            if layer > 0 {
+                append(format!("layer{}/.wh.file0", layer - 1), b"");
+            }
+        });
+    let bundle = temp_dir.path().join("bundle");
+
+    let mut group = c.benchmark_group("parallel_layers");
+    group.sample_size(10);
+    for jobs in [1, 2, 4, 8] {
+        let options = UnpackOptions::new().parallel_layers(jobs);
+        group.bench_with_input(BenchmarkId::from_parameter(jobs), &options, |b, options| {
+            b.iter(|| unpack_with_options(&manifest, &oci_dir, &bundle, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn buffer_sizes(c: &mut Criterion) {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let bundle = temp_dir.path().join("bundle");
+    let images = [
+        (
+            "many_small_files",
+            create_image(&temp_dir.path().join("small"), 1, |_, append| {
+                for file in 0..20_000 {
+                    append(format!("dir{}/file{file}", file % 100), &[b'x'; 64]);
+                }
+            }),
+        ),
+        (
+            "huge_file",
+            create_image(&temp_dir.path().join("huge"), 1, |_, append| {
+                let data: Vec<u8> = (0..256 * 1024 * 1024u32)
+                    .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+                    .collect();
+                append("huge".to_string(), &data);
+            }),
+        ),
+    ];
+
+    for (name, (oci_dir, manifest)) in &images {
+        let mut group = c.benchmark_group(*name);
+        group.sample_size(10);
+        for (id, options) in [
+            // The 8 KiB buffers std::io uses by default, as there's no way to read unbuffered
+            (
+                "small_buffers",
+                UnpackOptions::new()
+                    .read_buffer_size(8 * 1024)
+                    .write_buffer_size(8 * 1024),
+            ),
+            ("read_buffered", UnpackOptions::new()),
+            (
+                "read_write_buffered",
+                UnpackOptions::new().write_buffer_size(1024 * 1024),
+            ),
+            ("mmap", UnpackOptions::new().mmap_blobs(true)),
+        ] {
+            group.bench_with_input(id, &options, |b, options| {
+                b.iter(|| unpack_with_options(manifest, oci_dir, &bundle, options).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+fn tiny_files(c: &mut Criterion) {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    // Mostly metadata, so the cost is dominated by syscalls per entry rather than data
+    let (oci_dir, manifest) = create_image(&temp_dir.path().join("oci"), 1, |_, append| {
+        for file in 0..50_000 {
+            append(format!("dir{}/file{file}", file % 500), b"x");
+        }
+    });
+    let bundle = temp_dir.path().join("bundle");
+
+    let mut group = c.benchmark_group("tiny_files");
+    group.sample_size(10);
+    for (id, options) in [
+        ("preserve_ownership", UnpackOptions::new()),
+        (
+            "ignore_ownership",
+            UnpackOptions::new().preserve_ownership(false),
+        ),
+    ] {
+        group.bench_with_input(id, &options, |b, options| {
+            b.iter(|| unpack_with_options(&manifest, &oci_dir, &bundle, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn tiny_layers(c: &mut Criterion) {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    // Like a config image built a step at a time: most of what each layer writes is replaced or
+    // whited out by the next
+    let (oci_dir, manifest) = create_image(&temp_dir.path().join("oci"), 100, |layer, append| {
+        for file in 0..20 {
+            append(
+                format!("etc/conf{file}"),
+                format!("layer {layer}").as_bytes(),
+            );
+            append(format!("tmp/layer{layer}/file{file}"), b"scratch");
+        }
+        if layer > 0 {
+            append(format!("tmp/.wh.layer{}", layer - 1), b"");
+        }
+    });
+    let bundle = temp_dir.path().join("bundle");
+
+    let mut group = c.benchmark_group("tiny_layers");
+    group.sample_size(10);
+    for (id, options) in [
+        ("streaming", UnpackOptions::new()),
+        (
+            "in_memory",
+            UnpackOptions::new().in_memory_max_bytes(16 * 1024 * 1024),
+        ),
+    ] {
+        group.bench_with_input(id, &options, |b, options| {
+            b.iter(|| unpack_with_options(&manifest, &oci_dir, &bundle, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    parallel_layers,
+    buffer_sizes,
+    tiny_files,
+    tiny_layers
+);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use oci_bundle::{Strictness, UnpackOptions};
+
+// Extracting must fail cleanly, never panic or hang, and never write outside the root
+fuzz_target!(|data: &[u8]| {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path().join("rootfs");
+    std::fs::create_dir(&root).unwrap();
+    for strictness in [Strictness::Permissive, Strictness::Strict] {
+        let options = UnpackOptions::new()
+            .preserve_ownership(false)
+            .strictness(strictness);
+        let _ = oci_bundle::fuzz_extract_layer(data, &root, &options);
+    }
+    let outside: Vec<_> = std::fs::read_dir(temp.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(outside, ["rootfs"]);
+});
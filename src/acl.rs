@@ -0,0 +1,144 @@
+//! POSIX ACLs, kept in the `system.posix_acl_access` and `system.posix_acl_default` extended
+//! attributes, which change a file's effective permissions, so files that lose them are reported
+//! with [`crate::WarningKind::AclDropped`].
+
+use std::ffi::OsStr;
+use std::fs::{File, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use xattr::FileExt;
+
+/// The extended attribute holding a file's access ACL
+pub(crate) const ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// The extended attribute holding the default ACL that a directory's new entries inherit
+pub(crate) const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// The version of the extended attributes' format
+const VERSION: u32 = 2;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+/// Returns whether `name` is one of the extended attributes holding an ACL
+pub(crate) fn is_acl(name: &OsStr) -> bool {
+    [ACCESS_XATTR, DEFAULT_XATTR]
+        .iter()
+        .any(|acl| name.as_bytes() == acl.as_bytes())
+}
+
+/// Returns whether failing to set the ACL `name` with `error` means it's dropped, rather than the
+/// layer failing, which is so for ACLs the unpack isn't privileged to set or the filesystem
+/// doesn't support
+fn is_dropped(name: &OsStr, error: &io::Error) -> bool {
+    is_acl(name)
+        && matches!(
+            error.raw_os_error(),
+            Some(libc::EPERM | libc::ENOTSUP | libc::EACCES)
+        )
+}
+
+/// Returns the permission bits that the access ACL `value` grants, if it has no entries for
+/// named users or groups, so that mode bits can represent it without changing anyone's
+/// effective permissions. The group bits are those of the owning group, limited by the mask.
+pub(crate) fn mode_bits(value: &[u8]) -> Option<u32> {
+    let (version, entries) = value.split_first_chunk::<4>()?;
+    if u32::from_le_bytes(*version) != VERSION || entries.len() % 8 != 0 {
+        return None;
+    }
+    let (mut user, mut group, mut mask, mut other) = (None, None, 0o7, None);
+    for entry in entries.chunks_exact(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u32::from(u16::from_le_bytes([entry[2], entry[3]]) & 0o7);
+        match tag {
+            TAG_USER_OBJ => user = Some(perm),
+            TAG_GROUP_OBJ => group = Some(perm),
+            TAG_MASK => mask = perm,
+            TAG_OTHER => other = Some(perm),
+            // Named users and groups have no mode bits
+            _ => return None,
+        }
+    }
+    Some((user? << 6) | ((group? & mask) << 3) | other?)
+}
+
+/// Sets the ACL `name` of `file` to `value`, after its `mode` is set, returning whether the ACL
+/// was dropped. With `translate`, a dropped access ACL that [`mode_bits`] can represent is
+/// applied as the mode's permission bits instead, without those in `mask`, and isn't dropped.
+pub(crate) fn set(
+    file: &File,
+    name: &OsStr,
+    value: &[u8],
+    (mode, mask): (u32, u32),
+    translate: bool,
+) -> io::Result<bool> {
+    match file.set_xattr(name, value) {
+        Err(e) if is_dropped(name, &e) => {
+            let bits = mode_bits(value).filter(|_| translate && name == ACCESS_XATTR);
+            match bits {
+                Some(bits) => {
+                    let mode = (mode & !0o777) | (bits & !mask);
+                    file.set_permissions(Permissions::from_mode(mode))?;
+                    Ok(false)
+                }
+                None => Ok(true),
+            }
+        }
+        result => result.map(|()| false),
+    }
+}
+
+/// Copies the ACLs of the directory at `src` to that at `dst`, for directories merged into ones
+/// already in the rootfs
+pub(crate) fn copy(src: &Path, dst: &Path) -> io::Result<()> {
+    for name in [ACCESS_XATTR, DEFAULT_XATTR] {
+        if let Some(value) = xattr::get(src, name)? {
+            xattr::set(dst, name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(entries: &[(u16, u16)]) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for (tag, perm) in entries {
+            value.extend(tag.to_le_bytes());
+            value.extend(perm.to_le_bytes());
+            value.extend(u32::MAX.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn test_mode_bits() {
+        let minimal = acl(&[(TAG_USER_OBJ, 7), (TAG_GROUP_OBJ, 5), (TAG_OTHER, 4)]);
+        assert_eq!(mode_bits(&minimal), Some(0o754));
+        let masked = acl(&[
+            (TAG_USER_OBJ, 6),
+            (TAG_GROUP_OBJ, 6),
+            (TAG_MASK, 4),
+            (TAG_OTHER, 0),
+        ]);
+        assert_eq!(mode_bits(&masked), Some(0o640));
+        // A named group, such as from `setfacl -m g:adm:r-x`
+        let named = acl(&[
+            (TAG_USER_OBJ, 7),
+            (TAG_GROUP_OBJ, 5),
+            (0x08, 5),
+            (TAG_MASK, 5),
+            (TAG_OTHER, 0),
+        ]);
+        assert_eq!(mode_bits(&named), None);
+        assert_eq!(mode_bits(&acl(&[(TAG_USER_OBJ, 7), (TAG_OTHER, 0)])), None);
+        assert_eq!(mode_bits(&minimal[..10]), None);
+        assert_eq!(mode_bits(b""), None);
+    }
+}
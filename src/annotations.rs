@@ -0,0 +1,187 @@
+//! Normalizing the annotations of the generated runtime config, which come in part from the
+//! image's `Config.Labels` and so may hold anything, before they're written.
+
+use crate::options::{ControlCharacters, RuntimeConfigOptions};
+use crate::spec::{SpecIssue, SpecIssueCode};
+use std::collections::{BTreeMap, HashMap};
+
+/// The prefix of the keys reserved by the image spec, which are de-duplicated ignoring case
+const RESERVED_PREFIX: &str = "org.opencontainers.";
+
+/// Normalizes `annotations`, of which those in `labels` came from `Config.Labels`, returning the
+/// issues describing what was changed
+pub(crate) fn normalize(
+    annotations: &mut HashMap<String, String>,
+    labels: &HashMap<String, String>,
+    options: &RuntimeConfigOptions,
+) -> Vec<SpecIssue> {
+    let mut issues = Vec::new();
+    deduplicate(annotations, labels, &mut issues);
+
+    let mut keys: Vec<_> = annotations.keys().cloned().collect();
+    keys.sort_unstable();
+    for key in &keys {
+        let value = annotations.get_mut(key).expect("key is in the annotations");
+        match options.annotation_control_characters {
+            ControlCharacters::Keep => {}
+            ControlCharacters::Strip => value.retain(|c| !c.is_control()),
+            ControlCharacters::Escape if value.contains(char::is_control) => *value = escape(value),
+            ControlCharacters::Escape => {}
+        }
+        if value.len() > options.max_annotation_value_len {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::AnnotationTooLong,
+                field(key),
+                format!(
+                    "Value of {} bytes truncated to {}",
+                    value.len(),
+                    options.max_annotation_value_len
+                ),
+            ));
+            let mut len = options.max_annotation_value_len;
+            while !value.is_char_boundary(len) {
+                len -= 1;
+            }
+            value.truncate(len);
+        }
+    }
+
+    // Drop the largest annotations until the rest fit
+    let size = |(key, value): (&String, &String)| key.len() + value.len();
+    let mut total: usize = annotations.iter().map(size).sum();
+    if total > options.max_annotations_size {
+        let mut by_size: Vec<_> = annotations
+            .iter()
+            .map(|annotation| (size(annotation), annotation.0.clone()))
+            .collect();
+        by_size.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        for (annotation_size, key) in by_size {
+            if total <= options.max_annotations_size {
+                break;
+            }
+            annotations.remove(&key);
+            total -= annotation_size;
+            issues.push(SpecIssue::new(
+                SpecIssueCode::AnnotationsTooLarge,
+                field(&key),
+                format!(
+                    "Dropped, as the annotations exceed {} bytes",
+                    options.max_annotations_size
+                ),
+            ));
+        }
+    }
+    issues
+}
+
+/// Keeps one of each set of keys under [`RESERVED_PREFIX`] that differ only in case: the one
+/// this crate generated if any, or else the first, with the value of the first from `labels`, as
+/// labels take precedence
+fn deduplicate(
+    annotations: &mut HashMap<String, String>,
+    labels: &HashMap<String, String>,
+    issues: &mut Vec<SpecIssue>,
+) {
+    let mut variants: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for key in annotations.keys() {
+        let is_reserved = key
+            .get(..RESERVED_PREFIX.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(RESERVED_PREFIX));
+        if is_reserved {
+            variants
+                .entry(key.to_ascii_lowercase())
+                .or_default()
+                .push(key.clone());
+        }
+    }
+    for mut keys in variants.into_values().filter(|keys| keys.len() > 1) {
+        keys.sort_unstable();
+        let kept = keys
+            .iter()
+            .find(|key| !labels.contains_key(*key))
+            .unwrap_or(&keys[0])
+            .clone();
+        let value = keys
+            .iter()
+            .find(|key| labels.contains_key(*key))
+            .map(|key| annotations[key].clone());
+        for key in keys.iter().filter(|key| **key != kept) {
+            annotations.remove(key);
+            issues.push(SpecIssue::new(
+                SpecIssueCode::DuplicateAnnotationKey,
+                field(key),
+                format!("Differs from {kept} only in case"),
+            ));
+        }
+        if let Some(value) = value {
+            annotations.insert(kept, value);
+        }
+    }
+}
+
+/// Replaces each control character in `value` with an escape sequence: `\n`, `\r` and `\t`, or
+/// `\u` and four hex digits
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `key` follows the reverse domain notation the runtime spec asks annotation keys to
+/// use: a domain, in lowercase, and a name, separated by dots, without whitespace or control
+/// characters
+pub(crate) fn is_reverse_domain(key: &str) -> bool {
+    let segments: Vec<_> = key.split('.').collect();
+    segments.len() >= 3
+        && segments.iter().all(|segment| !segment.is_empty())
+        && segments[..2].iter().all(|segment| {
+            segment
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+        && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn field(key: &str) -> String {
+    format!("annotations[{key:?}]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_reverse_domain() {
+        for key in [
+            "org.opencontainers.image.stopSignal",
+            "com.example.key",
+            "io.k8s.display-name",
+            "oci-bundle.config.OnBuild",
+        ] {
+            assert!(is_reverse_domain(key), "{key}");
+        }
+        for key in [
+            "maintainer",
+            "com.example",
+            "Com.Example.key",
+            "com.example.my key",
+            "com..key",
+            "com.example.",
+        ] {
+            assert!(!is_reverse_domain(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\nb\tc\r\u{1b}é"), "a\\nb\\tc\\r\\u001bé");
+    }
+}
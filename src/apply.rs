@@ -0,0 +1,703 @@
+//! The semantics of applying a layer's entries to a tree: whiteouts, opaque whiteouts, and
+//! entries replacing those already in place.
+//!
+//! [`LayerApplier`] holds those rules and [`FsTarget`] the operations they need, so that they
+//! apply in the same way to the rootfs on disk and to a [`MemoryTree`], which models a tree
+//! without touching disk.
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::ApplyMode;
+use crate::{link_target, normalize, or_dot, write};
+use ocidir::cap_std::fs::{Dir, OpenOptions, OpenOptionsExt};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
+use xattr::FileExt;
+
+/// The type of an entry in a tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, FIFOs and sockets
+    Other,
+}
+
+/// The operations on a tree that applying layers needs. Paths are relative to the root of the
+/// tree, and normalized, so the root itself is the empty path.
+pub trait FsTarget {
+    /// Writes a regular file at `path`, where there is nothing, creating its missing parents
+    fn write_file(&mut self, path: &Path, content: &mut dyn Read) -> Result<()>;
+
+    /// Creates a directory at `path`, with its missing parents, unless there is one already
+    fn mkdir(&mut self, path: &Path) -> Result<()>;
+
+    /// Creates a symlink at `path` to `target`, where there is nothing, creating its missing
+    /// parents
+    fn symlink(&mut self, path: &Path, target: &Path) -> Result<()>;
+
+    /// Creates a hard link at `path` to the entry at `target`, where there is nothing, creating
+    /// its missing parents
+    fn hard_link(&mut self, path: &Path, target: &Path) -> Result<()>;
+
+    /// Removes the entry at `path`, which is either not a directory, or an empty one
+    fn remove(&mut self, path: &Path) -> Result<()>;
+
+    /// Removes the entry at `path`, along with everything beneath it if it's a directory
+    fn remove_all(&mut self, path: &Path) -> Result<()>;
+
+    /// Returns the type of the entry at `path`, without following a symlink there, or `None` if
+    /// there is nothing
+    fn kind(&self, path: &Path) -> Result<Option<FileKind>>;
+
+    /// Returns the names of the entries in the directory at `path`
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>>;
+
+    /// Returns whether there is an entry at `path`
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.kind(path)?.is_some())
+    }
+
+    /// Returns whether `path` is a directory. Targets that can follow symlinks do so here.
+    fn is_dir(&self, path: &Path) -> Result<bool> {
+        Ok(self.kind(path)? == Some(FileKind::Dir))
+    }
+
+    /// Creates an overlayfs whiteout, a character device with device number 0/0, at `path`, where
+    /// there is nothing, creating its missing parents. Only needed for
+    /// [`ApplyMode::OverlayUpper`], so defaults to failing.
+    fn overlay_whiteout(&mut self, path: &Path) -> Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported)).with_path(path)
+    }
+
+    /// Marks the directory at `dir` as an overlayfs opaque directory, setting the extended
+    /// attribute `name` to `y`. Only needed for [`ApplyMode::OverlayUpper`], so defaults to
+    /// failing.
+    fn mark_opaque(&mut self, dir: &Path, name: &str) -> Result<()> {
+        let _ = name;
+        Err(io::Error::from(io::ErrorKind::Unsupported)).with_path(dir)
+    }
+}
+
+/// Applies layers' entries to a tree, following the OCI image spec's rules for whiteouts and
+/// changes. An entry replaces whatever is at its path, except that a directory entry merges with
+/// a directory or symlink already there. A whiteout removes its path, and an opaque whiteout the
+/// contents of its directory other than those added by the same layer.
+///
+/// ```
+/// use oci_bundle::testing::{EntrySpec, LayerBuilder};
+/// use oci_bundle::{LayerApplier, MemoryNode, MemoryTree};
+/// use std::path::Path;
+///
+/// # fn main() -> oci_bundle::Result<()> {
+/// let mut applier = LayerApplier::new(MemoryTree::new());
+/// let lower = LayerBuilder::new().entry(EntrySpec::file("etc/motd", "hello"));
+/// let upper = LayerBuilder::new().entry(EntrySpec::opaque_whiteout("etc"));
+/// applier.apply(&mut tar::Archive::new(lower.archive()?.as_slice()))?;
+/// applier.apply(&mut tar::Archive::new(upper.archive()?.as_slice()))?;
+/// assert_eq!(applier.target().get(Path::new("etc")), Some(&MemoryNode::Dir));
+/// assert_eq!(applier.target().get(Path::new("etc/motd")), None);
+/// # Ok(())
+/// # }
+/// ```
+pub struct LayerApplier<T> {
+    target: T,
+    mode: ApplyMode,
+    /// Paths added by the layer being applied, which its opaque whiteouts mustn't remove
+    added: LayerPaths,
+}
+
+impl<T: FsTarget> LayerApplier<T> {
+    pub fn new(target: T) -> Self {
+        Self {
+            target,
+            mode: ApplyMode::Rootfs,
+            added: LayerPaths::default(),
+        }
+    }
+
+    /// How whiteouts are applied. With [`ApplyMode::OverlayUpper`], the target must support
+    /// [`FsTarget::overlay_whiteout`] and [`FsTarget::mark_opaque`]. Defaults to
+    /// [`ApplyMode::Rootfs`].
+    pub fn apply_mode(mut self, mode: ApplyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    pub(crate) fn target_mut(&mut self) -> &mut T {
+        &mut self.target
+    }
+
+    pub fn into_target(self) -> T {
+        self.target
+    }
+
+    /// Applies the layer `archive` to the target. Directories, regular files, symlinks and hard
+    /// links are applied, and other entries and their metadata are skipped, as are entries that
+    /// would be warned about when unpacking.
+    pub fn apply<R: Read>(&mut self, archive: &mut Archive<R>) -> Result<()> {
+        self.added = LayerPaths::default();
+        let mut dirs = Vec::new();
+        for entry in archive.entries().map_err(Error::Archive)? {
+            let mut entry = entry.map_err(Error::Archive)?;
+            let path = crate::entry_path(&entry)?;
+            if path.components().any(|c| c == Component::ParentDir) {
+                continue;
+            }
+            let path = normalize(&path);
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                self.replace_with_dir(&path)?;
+                dirs.push(path);
+                continue;
+            }
+            match Whiteout::parse(&path) {
+                Some(Whiteout::Opaque(dir)) => {
+                    self.opaque_whiteout(&dir)?;
+                }
+                Some(Whiteout::Entry(path)) => {
+                    self.whiteout(&path)?;
+                }
+                Some(Whiteout::Invalid) => {}
+                None if entry_type.is_symlink() => {
+                    let target = entry.link_name().map_err(Error::Archive)?;
+                    self.replace(&path)?;
+                    self.target.symlink(&path, &target.unwrap_or_default())?;
+                }
+                None if entry_type.is_hard_link() => {
+                    let target = link_target(&entry, &path)?;
+                    self.replace(&path)?;
+                    self.target.hard_link(&path, &target)?;
+                }
+                None if write::is_regular_file(entry_type) => {
+                    self.replace(&path)?;
+                    self.target.write_file(&path, &mut entry)?;
+                }
+                None => {}
+            }
+        }
+        // As when unpacking, directories are applied last
+        dirs.sort_by(|a, b| b.cmp(a));
+        for dir in dirs {
+            self.target.mkdir(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Clears the way for a non-directory entry at `path`, recording it as added by the layer
+    pub(crate) fn replace(&mut self, path: &Path) -> Result<()> {
+        let path = normalize(path);
+        self.added.insert(&path);
+        match self.target.kind(&path)? {
+            Some(FileKind::Dir) => self.target.remove_all(&path),
+            Some(_) => self.target.remove(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Clears the way for a directory entry at `path`, removing what's there unless it's a
+    /// directory, or a symlink, through which the directory's metadata is applied
+    pub(crate) fn replace_with_dir(&mut self, path: &Path) -> Result<()> {
+        let path = normalize(path);
+        match self.target.kind(&path)? {
+            Some(FileKind::File | FileKind::Other) => self.target.remove(&path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the entry at `path`, returning whether there was one. With
+    /// [`ApplyMode::OverlayUpper`], an overlayfs whiteout takes its place, and there always is.
+    pub(crate) fn whiteout(&mut self, path: &Path) -> Result<bool> {
+        let path = normalize(path);
+        let exists = self.target.exists(&path)?;
+        if exists {
+            log::trace!("Removing {}", path.display());
+            self.target.remove_all(&path)?;
+        }
+        if let ApplyMode::OverlayUpper { .. } = self.mode {
+            self.target.overlay_whiteout(&path)?;
+            return Ok(true);
+        }
+        Ok(exists)
+    }
+
+    /// Removes the entries in `dir`, except those added by the layer and the directories
+    /// containing them, returning whether the directory exists.
+    ///
+    /// `dir` is a path in the layer, so an empty `dir` is the root.
+    pub(crate) fn opaque_whiteout(&mut self, dir: &Path) -> Result<bool> {
+        let dir = normalize(dir);
+        if let ApplyMode::OverlayUpper { userxattr } = self.mode {
+            // The directory is marked even if it's only in the lowerdir
+            if !self.target.is_dir(&dir)? {
+                self.replace_with_dir(&dir)?;
+                self.target.mkdir(&dir)?;
+            }
+            self.clear_dir(&dir)?;
+            let name = if userxattr {
+                "user.overlay.opaque"
+            } else {
+                "trusted.overlay.opaque"
+            };
+            self.target.mark_opaque(&dir, name)?;
+            return Ok(true);
+        }
+        if !self.target.is_dir(&dir)? {
+            return Ok(false);
+        }
+        self.clear_dir(&dir)?;
+        Ok(true)
+    }
+
+    /// Deletes the entries in `dir` that weren't added by the layer, depth first, so that each
+    /// directory is walked once however many of its entries are kept
+    fn clear_dir(&mut self, dir: &Path) -> Result<()> {
+        for name in self.target.read_dir(dir)? {
+            let path = dir.join(name);
+            if self.added.is_entry(&path) {
+                continue;
+            }
+            if self.target.kind(&path)? == Some(FileKind::Dir) {
+                self.clear_dir(&path)?;
+                if !self.added.is_ancestor(&path) {
+                    log::trace!("Removing directory {}", path.display());
+                    self.target.remove(&path)?;
+                }
+            } else {
+                log::trace!("Removing file {}", path.display());
+                self.target.remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a whiteout entry removes
+pub(crate) enum Whiteout {
+    /// The contents of a directory, from `.wh..wh..opq`
+    Opaque(PathBuf),
+    /// A path, from `.wh.` followed by its file name
+    Entry(PathBuf),
+    /// Nothing, from a bare `.wh.`, which mustn't be taken as a whiteout of its directory
+    Invalid,
+}
+
+impl Whiteout {
+    /// Returns what the entry at `path` removes, if it's a whiteout.
+    ///
+    /// The name of the target is the raw bytes of the whiteout's name after `.wh.`, so that it
+    /// names the same file as the layer that added it whatever its encoding.
+    pub(crate) fn parse(path: &Path) -> Option<Self> {
+        let target = path.file_name()?.as_bytes().strip_prefix(b".wh.")?;
+        // Paths with a file name have a parent, which is empty at the top level
+        let parent = path.parent().unwrap_or(Path::new(""));
+        Some(match target {
+            b"" => Whiteout::Invalid,
+            b".wh..opq" => Whiteout::Opaque(parent.to_path_buf()),
+            _ => Whiteout::Entry(parent.join(OsStr::from_bytes(target))),
+        })
+    }
+}
+
+/// The paths of entries added by the current layer, which its opaque whiteouts mustn't remove.
+///
+/// Paths are stored as a tree of their components, so the name of a directory with many entries
+/// is kept once rather than in the path of each of them, as are the directories containing the
+/// entries, which must be kept for them.
+struct LayerPaths {
+    /// The root and each component added beneath it
+    nodes: Vec<PathNode>,
+}
+
+#[derive(Default)]
+struct PathNode {
+    /// The indices of the components beneath this one, by name
+    children: HashMap<Box<OsStr>, usize>,
+    /// Whether this is an added entry, rather than only a directory containing one
+    entry: bool,
+}
+
+impl Default for LayerPaths {
+    fn default() -> Self {
+        Self {
+            nodes: vec![PathNode::default()],
+        }
+    }
+}
+
+impl LayerPaths {
+    fn insert(&mut self, path: &Path) {
+        let mut node = 0;
+        for name in path.iter() {
+            node = match self.nodes[node].children.get(name) {
+                Some(child) => *child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(PathNode::default());
+                    self.nodes[node].children.insert(name.into(), child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].entry = true;
+    }
+
+    fn get(&self, path: &Path) -> Option<&PathNode> {
+        let mut node = &self.nodes[0];
+        for name in path.iter() {
+            node = &self.nodes[*node.children.get(name)?];
+        }
+        Some(node)
+    }
+
+    fn is_entry(&self, path: &Path) -> bool {
+        self.get(path).is_some_and(|node| node.entry)
+    }
+
+    fn is_ancestor(&self, path: &Path) -> bool {
+        self.get(path).is_some_and(|node| !node.children.is_empty())
+    }
+}
+
+/// A tree on disk, accessed through cap-std, so that nothing outside it can be changed
+pub(crate) struct DirTarget<'a> {
+    root_dir: &'a Dir,
+}
+
+impl<'a> DirTarget<'a> {
+    pub(crate) fn new(root_dir: &'a Dir) -> Self {
+        Self { root_dir }
+    }
+
+    fn create_parent(&self, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                self.root_dir.create_dir_all(parent).with_path(parent)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FsTarget for DirTarget<'_> {
+    fn write_file(&mut self, path: &Path, content: &mut dyn Read) -> Result<()> {
+        self.create_parent(path)?;
+        // Creating it exclusively never opens something else there, such as a FIFO
+        let mut file = self
+            .root_dir
+            .open_with(path, OpenOptions::new().write(true).create_new(true))
+            .with_path(path)?;
+        io::copy(content, &mut file).with_path(path)?;
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<()> {
+        self.root_dir.create_dir_all(path).with_path(path)
+    }
+
+    fn symlink(&mut self, path: &Path, target: &Path) -> Result<()> {
+        self.create_parent(path)?;
+        self.root_dir.symlink(target, path).with_path(path)
+    }
+
+    fn hard_link(&mut self, path: &Path, target: &Path) -> Result<()> {
+        self.create_parent(path)?;
+        self.root_dir
+            .hard_link(target, self.root_dir, path)
+            .with_path(path)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.kind(path)? == Some(FileKind::Dir) {
+            self.root_dir.remove_dir(path)
+        } else {
+            self.root_dir.remove_file(path)
+        }
+        .with_path(path)
+    }
+
+    fn remove_all(&mut self, path: &Path) -> Result<()> {
+        if self.kind(path)? == Some(FileKind::Dir) {
+            self.root_dir.remove_dir_all(path)
+        } else {
+            self.root_dir.remove_file(path)
+        }
+        .with_path(path)
+    }
+
+    fn kind(&self, path: &Path) -> Result<Option<FileKind>> {
+        let file_type = match self.root_dir.symlink_metadata(or_dot(path)) {
+            Ok(metadata) => metadata.file_type(),
+            // Nothing can be beneath a file
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e).with_path(path),
+        };
+        Ok(Some(if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        }))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        self.root_dir
+            .read_dir(or_dot(path))
+            .with_path(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()).with_path(path))
+            .collect()
+    }
+
+    /// Follows symlinks within the tree, as cap-std does
+    fn is_dir(&self, path: &Path) -> Result<bool> {
+        Ok(self.root_dir.is_dir(or_dot(path)))
+    }
+
+    fn overlay_whiteout(&mut self, path: &Path) -> Result<()> {
+        self.create_parent(path)?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let parent_dir = self.root_dir.open_dir(or_dot(parent)).with_path(parent)?;
+        let name = path.file_name().expect("whiteouts name a file");
+        let name = CString::new(name.as_bytes())
+            .map_err(io::Error::from)
+            .with_path(path)?;
+        let ret = unsafe {
+            libc::mknodat(
+                parent_dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::S_IFCHR,
+                libc::makedev(0, 0),
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error()).with_path(path);
+        }
+        Ok(())
+    }
+
+    fn mark_opaque(&mut self, dir: &Path, name: &str) -> Result<()> {
+        let path = or_dot(dir);
+        self.root_dir
+            .open_with(
+                path,
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECTORY),
+            )
+            .with_path(path)?
+            .into_std()
+            .set_xattr(name, b"y")
+            .with_path(path)
+    }
+}
+
+/// An entry in a [`MemoryTree`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryNode {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// A tree held in memory, for applying layers without touching disk. Only the type and content
+/// of entries are modelled: hard links are copies of their targets, and symlinks are never
+/// followed, so entries can't be written beneath them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryTree {
+    /// The entries, other than the root, which is always a directory
+    entries: BTreeMap<PathBuf, MemoryNode>,
+}
+
+impl MemoryTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the entry at `path`
+    pub fn get(&self, path: &Path) -> Option<&MemoryNode> {
+        self.entries.get(&normalize(path))
+    }
+
+    /// Returns the entries in the tree, other than the root, with their paths in order
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, &MemoryNode)> {
+        self.entries
+            .iter()
+            .map(|(path, node)| (path.as_path(), node))
+    }
+
+    /// Inserts `node` at `path`, where there must be nothing, creating the missing parents
+    fn insert(&mut self, path: &Path, node: MemoryNode) -> Result<()> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        self.mkdir(parent)?;
+        if self.entries.contains_key(path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists)).with_path(path);
+        }
+        self.entries.insert(path.to_path_buf(), node);
+        Ok(())
+    }
+}
+
+impl FsTarget for MemoryTree {
+    fn write_file(&mut self, path: &Path, content: &mut dyn Read) -> Result<()> {
+        let mut data = Vec::new();
+        content.read_to_end(&mut data).with_path(path)?;
+        self.insert(path, MemoryNode::File(data))
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<()> {
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            match self.entries.get(&current) {
+                Some(MemoryNode::Dir) => {}
+                Some(_) => {
+                    return Err(io::Error::from(io::ErrorKind::NotADirectory)).with_path(&current)
+                }
+                None => {
+                    self.entries.insert(current.clone(), MemoryNode::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn symlink(&mut self, path: &Path, target: &Path) -> Result<()> {
+        self.insert(path, MemoryNode::Symlink(target.to_path_buf()))
+    }
+
+    fn hard_link(&mut self, path: &Path, target: &Path) -> Result<()> {
+        match self.entries.get(target) {
+            Some(MemoryNode::Dir) => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            Some(node) => return self.insert(path, node.clone()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+        .with_path(target)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.read_dir(path).is_ok_and(|names| !names.is_empty()) {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty)).with_path(path);
+        }
+        match self.entries.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)).with_path(path),
+        }
+    }
+
+    fn remove_all(&mut self, path: &Path) -> Result<()> {
+        if self.entries.remove(path).is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound)).with_path(path);
+        }
+        self.entries.retain(|entry, _| !entry.starts_with(path));
+        Ok(())
+    }
+
+    fn kind(&self, path: &Path) -> Result<Option<FileKind>> {
+        if path.as_os_str().is_empty() {
+            return Ok(Some(FileKind::Dir));
+        }
+        Ok(self.entries.get(path).map(|node| match node {
+            MemoryNode::File(_) => FileKind::File,
+            MemoryNode::Dir => FileKind::Dir,
+            MemoryNode::Symlink(_) => FileKind::Symlink,
+        }))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        if self.kind(path)? != Some(FileKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory)).with_path(path);
+        }
+        Ok(self
+            .entries
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(OsStr::to_os_string))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_paths() {
+        let mut paths = LayerPaths::default();
+        paths.insert(Path::new("a/b/c"));
+        paths.insert(Path::new("a/d"));
+        paths.insert(Path::new("a/b"));
+        assert!(paths.is_entry(Path::new("a/b/c")));
+        assert!(paths.is_entry(Path::new("a/b")));
+        assert!(!paths.is_entry(Path::new("a")));
+        assert!(!paths.is_entry(Path::new("a/b/c/e")));
+        assert!(paths.is_ancestor(Path::new("a")));
+        assert!(paths.is_ancestor(Path::new("a/b")));
+        assert!(!paths.is_ancestor(Path::new("a/d")));
+        assert!(!paths.is_ancestor(Path::new("e")));
+    }
+
+    #[test]
+    fn test_memory_tree() {
+        let mut tree = MemoryTree::new();
+        tree.write_file(Path::new("a/b/file"), &mut &b"content"[..])
+            .unwrap();
+        tree.symlink(Path::new("a/link"), Path::new("b/file"))
+            .unwrap();
+        assert_eq!(tree.kind(Path::new("a/b")).unwrap(), Some(FileKind::Dir));
+        assert_eq!(tree.read_dir(Path::new("a")).unwrap(), ["b", "link"]);
+        assert!(tree.remove(Path::new("a/b")).is_err());
+        assert!(tree
+            .write_file(Path::new("a/link/x"), &mut io::empty())
+            .is_err());
+
+        tree.remove_all(Path::new("a/b")).unwrap();
+        let paths: Vec<_> = tree.entries().map(|(path, _)| path).collect();
+        assert_eq!(paths, [Path::new("a"), Path::new("a/link")]);
+    }
+
+    #[test]
+    fn test_parse_whiteout() {
+        assert!(Whiteout::parse(Path::new("etc/motd")).is_none());
+        assert!(matches!(
+            Whiteout::parse(Path::new("etc/.wh.motd")),
+            Some(Whiteout::Entry(path)) if path == Path::new("etc/motd")
+        ));
+        assert!(matches!(
+            Whiteout::parse(Path::new(".wh..wh..opq")),
+            Some(Whiteout::Opaque(path)) if path.as_os_str().is_empty()
+        ));
+        assert!(matches!(
+            Whiteout::parse(Path::new("etc/.wh.")),
+            Some(Whiteout::Invalid)
+        ));
+        // Latin-1, which isn't UTF-8
+        assert!(matches!(
+            Whiteout::parse(Path::new(OsStr::from_bytes(b"etc/.wh.caf\xe9"))),
+            Some(Whiteout::Entry(path)) if path.as_os_str().as_bytes() == b"etc/caf\xe9"
+        ));
+        assert!(Whiteout::parse(Path::new(OsStr::from_bytes(b"etc/.WH.motd"))).is_none());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::options::RuntimeConfigOptions;
+
+/// Composes the process's arguments from the image config's `Entrypoint` and `Cmd` and the
+/// overrides in `options`, as `docker run` does: an overridden entrypoint discards the image's
+/// `Cmd` unless `Cmd` is overridden too. [`RuntimeConfigOptions::args_prepend`] goes before
+/// everything else.
+///
+/// Returns `None`, leaving the default arguments, if there's nothing to run from either.
+pub(crate) fn compose(
+    entrypoint: Option<&Vec<String>>,
+    cmd: Option<&Vec<String>>,
+    options: &RuntimeConfigOptions,
+) -> Option<Vec<String>> {
+    let (entrypoint, cmd) = match (&options.entrypoint_override, &options.cmd_override) {
+        (None, None) => (entrypoint, cmd),
+        (None, Some(cmd)) => (entrypoint, Some(cmd)),
+        (Some(entrypoint), cmd) => (Some(entrypoint), cmd.as_ref()),
+    };
+    if entrypoint.is_none() && cmd.is_none() && options.args_prepend.is_empty() {
+        return None;
+    }
+    Some(
+        options
+            .args_prepend
+            .iter()
+            .chain(entrypoint.into_iter().flatten())
+            .chain(cmd.into_iter().flatten())
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Returns whether the image config's `Cmd` or `Entrypoint` is replaced, so that `ArgsEscaped`,
+/// which describes how the image's `Cmd` was written, no longer applies
+pub(crate) fn overridden(options: &RuntimeConfigOptions) -> bool {
+    options.entrypoint_override.is_some() || options.cmd_override.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_compose() {
+        let image_entrypoint = args(&["/entrypoint.sh"]);
+        let image_cmd = args(&["serve"]);
+        let images = [
+            (None, None),
+            (Some(&image_entrypoint), None),
+            (None, Some(&image_cmd)),
+            (Some(&image_entrypoint), Some(&image_cmd)),
+        ];
+        let overrides = [
+            (None, None),
+            (Some(args(&["/bin/sh"])), None),
+            (None, Some(args(&["debug"]))),
+            (Some(args(&["/bin/sh"])), Some(args(&["-c", "ls"]))),
+            // An empty entrypoint, like `docker run --entrypoint ""`, resets it
+            (Some(args(&[])), None),
+        ];
+        #[rustfmt::skip]
+        let expected: [[Option<&[&str]>; 5]; 4] = [
+            [None, Some(&["/bin/sh"]), Some(&["debug"]), Some(&["/bin/sh", "-c", "ls"]), Some(&[])],
+            [
+                Some(&["/entrypoint.sh"]), Some(&["/bin/sh"]), Some(&["/entrypoint.sh", "debug"]),
+                Some(&["/bin/sh", "-c", "ls"]), Some(&[]),
+            ],
+            [Some(&["serve"]), Some(&["/bin/sh"]), Some(&["debug"]), Some(&["/bin/sh", "-c", "ls"]), Some(&[])],
+            [
+                Some(&["/entrypoint.sh", "serve"]), Some(&["/bin/sh"]),
+                Some(&["/entrypoint.sh", "debug"]), Some(&["/bin/sh", "-c", "ls"]), Some(&[]),
+            ],
+        ];
+        for (image, expected) in images.iter().zip(expected) {
+            for ((entrypoint, cmd), expected) in overrides.iter().zip(expected) {
+                let mut options = RuntimeConfigOptions::new();
+                options.entrypoint_override = entrypoint.clone();
+                options.cmd_override = cmd.clone();
+                let composed = compose(image.0, image.1, &options);
+                assert_eq!(composed, expected.map(args), "{image:?} {options:?}");
+
+                // Prepended arguments always come first, even with nothing else to run
+                let options = options.args_prepend(args(&["/init", "--"]));
+                let mut prepended = args(&["/init", "--"]);
+                prepended.extend(expected.map(args).unwrap_or_default());
+                assert_eq!(compose(image.0, image.1, &options), Some(prepended));
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! The base image an image was built on, as recorded by the `org.opencontainers.image.base.*`
+//! annotations, which may be on its manifest or among its config's labels.
+
+use crate::error::{Error, Result};
+use crate::options::Strictness;
+use crate::report::{Warning, WarningKind};
+use ocidir::oci_spec::image::{ImageConfiguration, ImageManifest};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The annotation naming the base image, e.g. `docker.io/library/alpine:3.20`
+pub const BASE_NAME_ANNOTATION: &str = "org.opencontainers.image.base.name";
+
+/// The annotation giving the digest of the base image's manifest
+pub const BASE_DIGEST_ANNOTATION: &str = "org.opencontainers.image.base.digest";
+
+/// The base image an image was built on, from its [`BASE_NAME_ANNOTATION`] and
+/// [`BASE_DIGEST_ANNOTATION`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct BaseImageRef {
+    /// The base image's name, usually a reference with a tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The digest of the base image's manifest, e.g. `sha256:...`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+impl BaseImageRef {
+    /// Returns the annotations recording the base image
+    pub(crate) fn annotations(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        [
+            (BASE_NAME_ANNOTATION, &self.name),
+            (BASE_DIGEST_ANNOTATION, &self.digest),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+    }
+
+    fn from_annotations(annotations: Option<&HashMap<String, String>>) -> Option<Self> {
+        let annotations = annotations?;
+        let base = Self {
+            name: annotations.get(BASE_NAME_ANNOTATION).cloned(),
+            digest: annotations.get(BASE_DIGEST_ANNOTATION).cloned(),
+        };
+        (base.name.is_some() || base.digest.is_some()).then_some(base)
+    }
+
+    /// Returns whether `self` and `other` give different values for the same annotation
+    fn disagrees_with(&self, other: &Self) -> bool {
+        let differ =
+            |a: &Option<String>, b: &Option<String>| matches!((a, b), (Some(a), Some(b)) if a != b);
+        differ(&self.name, &other.name) || differ(&self.digest, &other.digest)
+    }
+}
+
+/// Returns the base image that the image with `manifest` and `config` was built on, if its
+/// manifest's annotations or its config's labels record one.
+///
+/// Where both record it, their annotations are combined, unless they give different values for
+/// the same one, in which case only the manifest's are used. Unpacking warns about that with
+/// [`WarningKind::BaseImageMismatch`]. Nothing checks that the base image's layers are the first
+/// of the image's.
+///
+/// ```
+/// # fn check(manifest: &ocidir::oci_spec::image::ImageManifest, config: &ocidir::oci_spec::image::ImageConfiguration) -> Result<(), String> {
+/// let approved = ["sha256:4bcff63911fcb4448bd4fdacec207030997caf25e9bea4045fa6c8c44de311d1"];
+/// let base = oci_bundle::base_image_of(manifest, config);
+/// match base.and_then(|base| base.digest) {
+///     Some(digest) if approved.contains(&digest.as_str()) => Ok(()),
+///     _ => Err("not built on an approved base image".to_string()),
+/// }
+/// # }
+/// ```
+pub fn base_image_of(
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+) -> Option<BaseImageRef> {
+    combine(manifest, config).0
+}
+
+/// Returns the base image as [`base_image_of`] does, with whether the manifest and config
+/// disagree about it
+fn combine(manifest: &ImageManifest, config: &ImageConfiguration) -> (Option<BaseImageRef>, bool) {
+    let from_manifest = BaseImageRef::from_annotations(manifest.annotations().as_ref());
+    let labels = config
+        .config()
+        .as_ref()
+        .and_then(|config| config.labels().as_ref());
+    let from_config = BaseImageRef::from_annotations(labels);
+    match (from_manifest, from_config) {
+        (Some(manifest), Some(config)) if manifest.disagrees_with(&config) => {
+            (Some(manifest), true)
+        }
+        (Some(manifest), Some(config)) => {
+            let combined = BaseImageRef {
+                name: manifest.name.or(config.name),
+                digest: manifest.digest.or(config.digest),
+            };
+            (Some(combined), false)
+        }
+        (manifest, config) => (manifest.or(config), false),
+    }
+}
+
+/// Returns the base image as [`base_image_of`] does, along with a warning if the manifest and
+/// config disagree about it. Fails instead of warning with [`Strictness::Strict`].
+pub(crate) fn resolve(
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+    strictness: Strictness,
+) -> Result<(Option<BaseImageRef>, Option<Warning>)> {
+    let (base, disagree) = combine(manifest, config);
+    if !disagree {
+        return Ok((base, None));
+    }
+    let warning = Warning {
+        layer_index: 0,
+        path: PathBuf::new(),
+        kind: WarningKind::BaseImageMismatch,
+    };
+    if strictness == Strictness::Strict {
+        return Err(Error::Warning(warning));
+    }
+    log::warn!("{warning}: using the manifest's {base:?}");
+    Ok((base, Some(warning)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::image::{ConfigBuilder, ImageConfigurationBuilder};
+
+    fn image(
+        manifest_annotations: &[(&str, &str)],
+        labels: &[(&str, &str)],
+    ) -> (ImageManifest, ImageConfiguration) {
+        let to_map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let mut manifest = ocidir::new_empty_manifest().build().unwrap();
+        manifest.set_annotations(Some(to_map(manifest_annotations)));
+        let config = ImageConfigurationBuilder::default()
+            .config(
+                ConfigBuilder::default()
+                    .labels(to_map(labels))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        (manifest, config)
+    }
+
+    #[test]
+    fn test_base_image_of() {
+        let (manifest, config) = image(&[], &[]);
+        assert_eq!(base_image_of(&manifest, &config), None);
+
+        let (manifest, config) = image(
+            &[(BASE_NAME_ANNOTATION, "alpine:3.20")],
+            &[(BASE_DIGEST_ANNOTATION, "sha256:abc")],
+        );
+        assert_eq!(
+            resolve(&manifest, &config, Strictness::Strict).unwrap(),
+            (
+                Some(BaseImageRef {
+                    name: Some("alpine:3.20".to_string()),
+                    digest: Some("sha256:abc".to_string()),
+                }),
+                None
+            )
+        );
+
+        // The manifest's are preferred, without mixing in the config's
+        let (manifest, config) = image(
+            &[(BASE_NAME_ANNOTATION, "alpine:3.20")],
+            &[
+                (BASE_NAME_ANNOTATION, "alpine:3.19"),
+                (BASE_DIGEST_ANNOTATION, "sha256:abc"),
+            ],
+        );
+        let (base, warning) = resolve(&manifest, &config, Strictness::Permissive).unwrap();
+        assert_eq!(
+            base,
+            Some(BaseImageRef {
+                name: Some("alpine:3.20".to_string()),
+                digest: None,
+            })
+        );
+        assert_eq!(warning.unwrap().kind, WarningKind::BaseImageMismatch);
+        assert!(resolve(&manifest, &config, Strictness::Strict).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! A cache of decompressed layer archives by diff ID, so that unpacking an image again reads each
+//! layer's archive from `<dir>/<diff ID>.tar` rather than decompressing its blob.
+//!
+//! Entries are written to a temporary file as a blob is decompressed, and renamed into place once
+//! all of the layer's digests are verified, so only complete entries are ever found. Entries are
+//! verified against their diff ID again each time they're read, and removed if they don't match.
+//! Reading an entry updates its mtime, so pruning oldest first removes the least recently used.
+//!
+//! The cache may be shared by concurrent unpacks, in this process and others. An unpack that
+//! misses takes an advisory lock on the entry, with `flock`, until it's committed or abandoned,
+//! so that others unpacking the same layer wait and then read it from the cache rather than all
+//! decompressing it. The locks are taken on empty `.<diff ID>.lock` files, which are left in
+//! place, as removing them would let a waiter lock a file that's no longer the entry's lock.
+
+use crate::deadline::Deadline;
+use crate::error::{IoResultExt, Result};
+use filetime::FileTime;
+use ocidir::oci_spec::image::{Digest, DigestAlgorithm};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Returns the path of the entry for `diff_id`. Only SHA-256 diff IDs, which are the only ones
+/// that can be verified, have entries.
+fn entry_path(dir: &Path, diff_id: &str) -> Option<PathBuf> {
+    let digest: Digest = diff_id.parse().ok()?;
+    (*digest.algorithm() == DigestAlgorithm::Sha256).then(|| dir.join(format!("{digest}.tar")))
+}
+
+/// What the cache has for a layer
+pub(crate) enum Lookup {
+    /// The entry, opened and marked as used
+    Hit(File),
+    /// No entry, and a new one to populate, unless it can't be cached
+    Miss(Option<NewEntry>),
+}
+
+/// Looks up the entry for `diff_id`. On a miss, a new entry is started if `populate` is set, once
+/// any other unpack populating it has finished, in which case that unpack's entry is returned.
+/// Waiting stops at the `deadline`, leaving the layer uncached.
+pub(crate) fn lookup(
+    dir: &Path,
+    diff_id: &str,
+    populate: bool,
+    deadline: Option<&Deadline>,
+) -> Lookup {
+    if let Some(file) = open(dir, diff_id) {
+        return Lookup::Hit(file);
+    }
+    if !populate || entry_path(dir, diff_id).is_none() {
+        return Lookup::Miss(None);
+    }
+    let lock = match EntryLock::acquire(dir, diff_id, deadline) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => return Lookup::Miss(None),
+        Err(e) => {
+            log::warn!("Not caching layer {diff_id}: {e}");
+            return Lookup::Miss(None);
+        }
+    };
+    // Cached by the unpack that held the lock
+    if let Some(file) = open(dir, diff_id) {
+        return Lookup::Hit(file);
+    }
+    Lookup::Miss(NewEntry::create(dir, diff_id, lock))
+}
+
+/// Returns whether there's an entry for `diff_id`, without marking it as used
+pub(crate) fn contains(dir: &Path, diff_id: &str) -> bool {
+    entry_path(dir, diff_id).is_some_and(|path| path.is_file())
+}
+
+/// Opens the entry for `diff_id`, marking it as used, if there is one
+fn open(dir: &Path, diff_id: &str) -> Option<File> {
+    let path = entry_path(dir, diff_id)?;
+    match File::open(&path) {
+        Ok(file) => {
+            if let Err(e) = filetime::set_file_mtime(&path, FileTime::now()) {
+                log::debug!("Failed to mark {} as used: {e}", path.display());
+            }
+            Some(file)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            log::warn!("Failed to open cached layer {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Removes the entry for `diff_id`, whose content, read from `file`, doesn't match it. Nothing is
+/// removed if the entry has since been replaced.
+pub(crate) fn remove(dir: &Path, diff_id: &str, file: &fs::Metadata) {
+    let Some(path) = entry_path(dir, diff_id) else {
+        return;
+    };
+    let replaced = fs::metadata(&path).map_or(true, |entry| {
+        (entry.dev(), entry.ino()) != (file.dev(), file.ino())
+    });
+    if replaced {
+        return;
+    }
+    log::warn!(
+        "Removing cached layer {}, which doesn't match its diff ID",
+        path.display()
+    );
+    if let Err(e) = fs::remove_file(&path) {
+        log::warn!("Failed to remove {}: {e}", path.display());
+    }
+}
+
+/// An exclusive lock on populating the entry for a diff ID, released when dropped
+struct EntryLock {
+    _file: File,
+}
+
+impl EntryLock {
+    /// Waits for the lock on the entry for `diff_id`, or returns `None` if the `deadline` passes
+    /// first
+    fn acquire(dir: &Path, diff_id: &str, deadline: Option<&Deadline>) -> io::Result<Option<Self>> {
+        fs::create_dir_all(dir)?;
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(format!(".{diff_id}.lock")))?;
+        // Polled with a deadline, as a blocking flock can't be abandoned
+        let operation = match deadline {
+            Some(_) => libc::LOCK_EX | libc::LOCK_NB,
+            None => libc::LOCK_EX,
+        };
+        loop {
+            // SAFETY: the file descriptor is open for the duration of the call
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(Some(Self { _file: file }));
+            }
+            let e = io::Error::last_os_error();
+            match (e.kind(), deadline) {
+                (io::ErrorKind::Interrupted, _) => {}
+                (io::ErrorKind::WouldBlock, Some(deadline)) => {
+                    if deadline.check().is_err() {
+                        return Ok(None);
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+/// An entry being written as a layer is decompressed, which is removed unless committed. Others
+/// wait to populate the entry until it's dropped.
+pub(crate) struct NewEntry {
+    file: BufWriter<File>,
+    temp: PathBuf,
+    path: PathBuf,
+    _lock: EntryLock,
+}
+
+impl NewEntry {
+    /// Starts an entry for `diff_id`, holding its `lock`, unless it can't be cached
+    fn create(dir: &Path, diff_id: &str, lock: EntryLock) -> Option<Self> {
+        static ENTRIES: AtomicUsize = AtomicUsize::new(0);
+        let path = entry_path(dir, diff_id)?;
+        // Unique to this entry, as other threads and processes may be caching the same layer
+        let temp = dir.join(format!(
+            ".{diff_id}.{}-{}.tmp",
+            std::process::id(),
+            ENTRIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        match File::options().write(true).create_new(true).open(&temp) {
+            Ok(file) => Some(Self {
+                file: BufWriter::new(file),
+                temp,
+                path,
+                _lock: lock,
+            }),
+            Err(e) => {
+                log::warn!("Not caching layer {diff_id}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Moves the entry into place, once its layer is verified, then prunes the cache to
+    /// `max_bytes`. Failures are logged, as the unpack doesn't depend on the cache.
+    pub(crate) fn commit(mut self, max_bytes: Option<u64>) {
+        let result = self
+            .file
+            .flush()
+            .and_then(|()| self.file.get_ref().sync_all())
+            .and_then(|()| fs::rename(&self.temp, &self.path));
+        if let Err(e) = result {
+            log::warn!("Failed to cache layer at {}: {e}", self.path.display());
+            return;
+        }
+        log::debug!("Cached layer at {}", self.path.display());
+        let dir = self
+            .path
+            .parent()
+            .expect("entries are in the cache directory");
+        if let Some(max_bytes) = max_bytes {
+            if let Err(e) = prune_decompressed_blob_cache(dir, max_bytes) {
+                log::warn!("Failed to prune {}: {e}", dir.display());
+            }
+        }
+    }
+}
+
+impl Drop for NewEntry {
+    fn drop(&mut self) {
+        // Nothing is left to remove once the entry is committed
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+/// Copies what's read from a reader into a new entry, if there is one. The entry is abandoned if
+/// writing to it fails.
+pub(crate) struct Tee<R> {
+    inner: R,
+    entry: Option<NewEntry>,
+}
+
+impl<R: Read> Tee<R> {
+    pub(crate) fn new(inner: R, entry: Option<NewEntry>) -> Self {
+        Self { inner, entry }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the reader, and the entry if nothing failed to be written to it
+    pub(crate) fn into_parts(self) -> (R, Option<NewEntry>) {
+        (self.inner, self.entry)
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(entry) = &mut self.entry {
+            if let Err(e) = entry.file.write_all(&buf[..len]) {
+                log::warn!("Not caching layer at {}: {e}", entry.path.display());
+                self.entry = None;
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Removes the least recently used entries from the decompressed blob cache in `dir`, as set by
+/// [`crate::UnpackOptions::decompressed_blob_cache`], until the rest take no more than
+/// `max_bytes`. Returns the number of bytes removed.
+///
+/// Entries are used when they're written and each time a layer is read from them, as recorded
+/// by their mtime. Entries still being written are left alone.
+pub fn prune_decompressed_blob_cache(dir: &Path, max_bytes: u64) -> Result<u64> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_path(dir),
+    };
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        let entry = entry.with_path(dir)?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.as_encoded_bytes();
+        if name.starts_with(b".") || !name.ends_with(b".tar") {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Removed by another prune
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_path(path),
+        };
+        let used = FileTime::from_last_modification_time(&metadata);
+        entries.push((used, metadata.len(), path));
+    }
+    let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    entries.sort();
+    let mut removed = 0;
+    for (_, size, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        log::debug!("Pruning cached layer {}", path.display());
+        match fs::remove_file(&path) {
+            Ok(()) => removed += size,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_path(path),
+        }
+        total -= size;
+    }
+    Ok(removed)
+}
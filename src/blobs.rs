@@ -0,0 +1,147 @@
+//! Opening an image's blobs. Unpacking opens every blob it needs before it changes anything and
+//! reads them through those handles, so that a blob removed from the layout midway, such as by
+//! another process's garbage collection, can still be read.
+
+use crate::blob_cache;
+use crate::error::{BlobRole, Error, IoResultExt, Result};
+use crate::{blob_path, open_blob};
+use ocidir::oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use ocidir::OciDir;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::Path;
+
+/// Where the config and layers of an image are read from: the layout, or the handles opened by
+/// [`BlobSource::pin`]
+pub(crate) struct BlobSource<'a> {
+    oci_dir: &'a OciDir,
+    config: Option<File>,
+    /// The handles of the layers, by index, or `None` for those that weren't pinned
+    layers: Vec<Option<File>>,
+}
+
+impl<'a> BlobSource<'a> {
+    /// Reads each blob from the layout when it's needed
+    pub(crate) fn layout(oci_dir: &'a OciDir) -> Self {
+        Self {
+            oci_dir,
+            config: None,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Opens the config blob and each layer blob that isn't skipped, checking their sizes, so
+    /// that blobs that are missing, unreadable or truncated are found before the bundle is
+    /// replaced. Returns the error for the only blob that couldn't be opened, or
+    /// [`Error::InaccessibleBlobs`] listing all of them.
+    ///
+    /// The blobs are then read through the handles, so one file descriptor is held for each
+    /// until the source is dropped.
+    ///
+    /// Layers whose archives are in the decompressed blob `cache` are read from there, so their
+    /// blobs needn't be in the layout, as when they weren't pulled because
+    /// [`crate::missing_layers`] didn't list them.
+    pub(crate) fn pin(
+        oci_dir: &'a OciDir,
+        manifest: &ImageManifest,
+        skipped: &[bool],
+        cache: Option<&Path>,
+    ) -> Result<Self> {
+        let layers = manifest.layers();
+        let mut errors = Vec::new();
+        let config = match open_blob(oci_dir, manifest.config(), BlobRole::Config) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                errors.push(e);
+                None
+            }
+        };
+        let cached = match (&config, cache) {
+            (Some(config), Some(cache)) => cached_layers(config, cache),
+            _ => Vec::new(),
+        };
+        let mut pinned = Vec::with_capacity(layers.len());
+        for (index, descriptor) in layers.iter().enumerate() {
+            if skipped[index] {
+                pinned.push(None);
+                continue;
+            }
+            let role = BlobRole::Layer {
+                index,
+                count: layers.len(),
+            };
+            match open_blob(oci_dir, descriptor, role) {
+                Ok(file) => pinned.push(Some(file)),
+                Err(_) if cached.get(index).copied().unwrap_or(false) => {
+                    log::debug!("Layer {index} isn't in the layout, so is read from the cache");
+                    pinned.push(None);
+                }
+                Err(e) => errors.push(e.in_layer(index, descriptor)),
+            }
+        }
+        match errors.len() {
+            0 => Ok(Self {
+                oci_dir,
+                config,
+                layers: pinned,
+            }),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::InaccessibleBlobs(errors)),
+        }
+    }
+
+    /// Opens the config blob with `descriptor`
+    pub(crate) fn config(&self, descriptor: &Descriptor) -> Result<File> {
+        match &self.config {
+            Some(file) => reopen(file, descriptor),
+            None => open_blob(self.oci_dir, descriptor, BlobRole::Config),
+        }
+    }
+
+    /// Opens the blob of the layer at `index` in `layers`
+    pub(crate) fn layer(&self, layers: &[Descriptor], index: usize) -> Result<File> {
+        let descriptor = &layers[index];
+        match self.layers.get(index).and_then(Option::as_ref) {
+            Some(file) => reopen(file, descriptor),
+            None => {
+                let role = BlobRole::Layer {
+                    index,
+                    count: layers.len(),
+                };
+                open_blob(self.oci_dir, descriptor, role)
+            }
+        }
+    }
+}
+
+/// Returns whether the archive of each layer is in the decompressed blob `cache`, by the diff IDs
+/// in `config`. The config isn't verified yet, but the archives are verified against the diff IDs
+/// in the verified config when they're read.
+fn cached_layers(config: &File, cache: &Path) -> Vec<bool> {
+    let read = || -> std::io::Result<ImageConfiguration> {
+        let mut file = config.try_clone()?;
+        let image_config = serde_json::from_reader(BufReader::new(&file))?;
+        file.rewind()?;
+        Ok(image_config)
+    };
+    match read() {
+        Ok(image_config) => image_config
+            .rootfs()
+            .diff_ids()
+            .iter()
+            .map(|diff_id| blob_cache::contains(cache, diff_id))
+            .collect(),
+        Err(e) => {
+            log::debug!("Can't read the diff IDs of cached layers: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Returns a handle to a pinned blob, read from its start. The handle shares its offset with the
+/// pinned one, which is fine as each blob is only read by one reader at a time.
+fn reopen(file: &File, descriptor: &Descriptor) -> Result<File> {
+    let mut file = file.try_clone().with_path(blob_path(descriptor))?;
+    file.rewind().with_path(blob_path(descriptor))?;
+    Ok(file)
+}
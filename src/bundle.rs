@@ -0,0 +1,173 @@
+//! Handles on unpacked bundles, from [`Bundle::open`].
+
+use crate::bundle_archive::{self, ExportOptions};
+use crate::effective::EffectiveOptions;
+use crate::error::{BundleError, Error, IoResultExt, Result};
+use crate::metadata::{self, BundleMetadata};
+use crate::options::UnpackOptions;
+use crate::remap::{self, IdMapping, RemapReport};
+use crate::update::UpdateReport;
+use crate::verify::{self, VerifyBundleOptions, VerifyBundleReport};
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::Dir;
+use ocidir::oci_spec::image::ImageManifest;
+use ocidir::oci_spec::runtime::Spec;
+use ocidir::OciDir;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A complete bundle, opened by [`Bundle::open`], with handles on its directory and rootfs and
+/// its parsed runtime config and metadata.
+///
+/// The handles stay on the directories that were opened, even if the bundle is later moved or
+/// replaced at its path. The runtime config and metadata are those read when it was opened.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    path: PathBuf,
+    dir: Arc<Dir>,
+    rootfs: Arc<Dir>,
+    spec: Spec,
+    metadata: Option<BundleMetadata>,
+}
+
+impl Bundle {
+    /// Opens the bundle at `path`, checking that it's a directory with a `rootfs` directory and
+    /// a `config.json` that parses as a runtime config, and that its metadata, if it has any,
+    /// parses. Bundles unpacked by versions of this crate that didn't record metadata have none.
+    ///
+    /// Fails with [`Error::NotABundle`], saying why, if the directory isn't laid out as a bundle,
+    /// or with [`Error::IncompleteBundle`] if it contains the [`crate::INCOMPLETE_SENTINEL`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let not_a_bundle = |source| Error::NotABundle {
+            path: path.to_path_buf(),
+            source,
+        };
+        let dir = match Dir::open_ambient_dir(path, ambient_authority()) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(not_a_bundle(BundleError::Missing(e)))
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+                return Err(not_a_bundle(BundleError::NotADirectory))
+            }
+            Err(e) => return Err(e).with_path(path),
+        };
+        if metadata::is_incomplete(path) {
+            return Err(Error::IncompleteBundle(path.to_path_buf()));
+        }
+        let rootfs = match dir.open_dir("rootfs") {
+            Ok(rootfs) => rootfs,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.raw_os_error() == Some(libc::ENOTDIR) =>
+            {
+                return Err(not_a_bundle(BundleError::MissingRootfs))
+            }
+            Err(e) => return Err(e).with_path(path.join("rootfs")),
+        };
+        let spec = load_spec(&dir, path)?;
+        let metadata = match dir.read(metadata::BUNDLE_METADATA) {
+            Ok(json) => Some(metadata::parse(path, &json)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_path(path.join(metadata::BUNDLE_METADATA)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            dir: Arc::new(dir),
+            rootfs: Arc::new(rootfs),
+            spec,
+            metadata,
+        })
+    }
+
+    /// The path the bundle was opened at
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A handle on the bundle's directory
+    pub fn dir(&self) -> &Dir {
+        &self.dir
+    }
+
+    /// A handle on the bundle's rootfs
+    pub fn rootfs_dir(&self) -> &Dir {
+        &self.rootfs
+    }
+
+    /// The bundle's runtime config, from its `config.json`
+    pub fn spec(&self) -> &Spec {
+        &self.spec
+    }
+
+    /// What the bundle records about the image it was unpacked from, if anything
+    pub fn metadata(&self) -> Option<&BundleMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Returns whether the bundle is current for the image with `manifest`, as
+    /// [`crate::is_bundle_current`] says
+    pub fn is_current(&self, manifest: &ImageManifest) -> Result<bool> {
+        metadata::is_current(&self.path, manifest)
+    }
+
+    /// Returns whether the bundle is current for the image with `manifest` and `options`, as
+    /// [`crate::is_bundle_current_with_options`] says
+    pub fn is_current_with_options(
+        &self,
+        manifest: &ImageManifest,
+        options: &UnpackOptions,
+    ) -> Result<bool> {
+        metadata::is_current_with_options(&self.path, manifest, &EffectiveOptions::new(options))
+    }
+
+    /// Checks that the rootfs hasn't changed since the bundle was unpacked, as
+    /// [`crate::verify_bundle_with_options`] does
+    pub fn verify(&self, options: &VerifyBundleOptions) -> Result<VerifyBundleReport> {
+        verify::verify_bundle(&self.path, options)
+    }
+
+    /// Writes an archive of the bundle to `out`, as [`crate::export_bundle`] does
+    pub fn export(&self, out: impl Write, options: &ExportOptions) -> Result<()> {
+        bundle_archive::export(&self.path, out, options)
+    }
+
+    /// Changes the owners of the paths in the rootfs and records `mappings` in the runtime
+    /// config, as [`crate::remap_bundle_ownership`] does. [`Self::spec`] is then the changed
+    /// runtime config.
+    pub fn remap_ownership(&mut self, mappings: &[IdMapping]) -> Result<RemapReport> {
+        let report = remap::remap(&self.path, mappings)?;
+        self.spec = load_spec(&self.dir, &self.path)?;
+        Ok(report)
+    }
+
+    /// Updates the bundle to the image with `manifest`, as [`crate::update_bundle`] does. The
+    /// bundle may be unpacked again from scratch, so this handle is consumed, and the updated
+    /// bundle is in the report's [`crate::UnpackReport::bundle`].
+    pub fn update(
+        self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        options: &UnpackOptions,
+    ) -> Result<UpdateReport> {
+        crate::update_bundle(&self.path, manifest, oci_dir, options)
+    }
+}
+
+/// Reads and parses the runtime config of the bundle in `dir`, at `path`
+fn load_spec(dir: &Dir, path: &Path) -> Result<Spec> {
+    let not_a_bundle = |source| Error::NotABundle {
+        path: path.to_path_buf(),
+        source,
+    };
+    let json = match dir.read("config.json") {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(not_a_bundle(BundleError::MissingConfig))
+        }
+        Err(e) => return Err(e).with_path(path.join("config.json")),
+    };
+    serde_json::from_slice(&json).map_err(|e| not_a_bundle(BundleError::InvalidConfig(e)))
+}
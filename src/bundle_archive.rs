@@ -0,0 +1,563 @@
+//! Archiving a bundle with [`crate::export_bundle`] and restoring it with
+//! [`crate::import_bundle`], to copy it to another host without unpacking its image there.
+//!
+//! An archive is two tar streams, one after the other. The first has the files at the top of
+//! the bundle, such as `config.json` and the bundle metadata, and the second the rootfs, with
+//! its owners, extended attributes, hard links and, as old GNU sparse entries, the holes in
+//! sparse files. The second is extracted as a layer would be.
+
+use crate::bundle::Bundle;
+use crate::effective::EffectiveOptions;
+use crate::error::{Error, IoResultExt, Result};
+use crate::metadata::{self, INCOMPLETE_SENTINEL};
+use crate::options::{OwnershipMode, UnpackOptions};
+use crate::report::UnpackReport;
+use crate::verify::{self, Difference, VerifyBundleOptions};
+use crate::{capabilities, extract_layer, rootless, LayerChanges};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Instant;
+use tar::{Archive, EntryType, Header};
+
+/// The first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+const BLOCK_SIZE: u64 = 512;
+
+/// The data regions an old GNU sparse header, and each of its extension blocks, can list
+const SPARSE_HEADER_REGIONS: usize = 4;
+const SPARSE_EXTENSION_REGIONS: usize = 21;
+
+/// How [`crate::export_bundle`] compresses the archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ArchiveCompression {
+    #[default]
+    None,
+    /// Compress with the `zstd` program, which must be on the `PATH`
+    Zstd,
+}
+
+/// Options controlling how [`crate::export_bundle`] archives a bundle
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub(crate) compression: ArchiveCompression,
+}
+
+impl ExportOptions {
+    /// Creates the default options, which write an uncompressed archive
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How to compress the archive. Defaults to [`ArchiveCompression::None`].
+    pub fn compression(mut self, compression: ArchiveCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
+/// Writes an archive of `bundle` to `out`
+pub(crate) fn export(bundle: &Path, mut out: impl Write, options: &ExportOptions) -> Result<()> {
+    if metadata::is_incomplete(bundle) {
+        return Err(Error::IncompleteBundle(bundle.to_path_buf()));
+    }
+    match options.compression {
+        ArchiveCompression::None => write_archive(bundle, &mut out),
+        ArchiveCompression::Zstd => {
+            let mut child = spawn_zstd(&["-q", "-c"])?;
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            let mut stdout = child.stdout.take().expect("stdout is piped");
+            // The archive is written from another thread, as `out` needn't be `Send`
+            let (written, copied) = thread::scope(|scope| {
+                let writer = scope.spawn(move || write_archive(bundle, &mut stdin));
+                let copied = io::copy(&mut stdout, &mut out);
+                // Closing the pipe stops zstd, and so the writer, if `out` failed
+                drop(stdout);
+                let written = writer.join().expect("the archive writer doesn't panic");
+                (written, copied)
+            });
+            let status = wait_zstd(child);
+            copied.with_path(bundle)?;
+            status?;
+            written
+        }
+    }
+}
+
+/// Writes the bundle's top-level files, then its rootfs, as two tar streams
+fn write_archive(bundle: &Path, out: &mut impl Write) -> Result<()> {
+    let mut files = tar::Builder::new(&mut *out);
+    let mut entries: Vec<_> = fs::read_dir(bundle)
+        .with_path(bundle)?
+        .collect::<io::Result<_>>()
+        .with_path(bundle)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let path = entry.path();
+        if name == "rootfs" || name == INCOMPLETE_SENTINEL {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(&path).with_path(&path)?;
+        if !metadata.is_file() {
+            log::warn!("Not archiving {}, which isn't a file", path.display());
+            continue;
+        }
+        let mut header = header(&metadata, EntryType::Regular);
+        header.set_size(metadata.len());
+        let file = File::open(&path).with_path(&path)?;
+        files
+            .append_data(&mut header, &name, file.take(metadata.len()))
+            .with_path(&path)?;
+    }
+    files.finish().with_path(bundle)?;
+    drop(files);
+
+    let rootfs = bundle.join("rootfs");
+    let mut builder = tar::Builder::new(&mut *out);
+    // The first path archived for each inode with several links, by device and inode
+    let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for entry in walkdir::WalkDir::new(&rootfs).sort_by_file_name() {
+        let entry = entry.map_err(|e| Error::Io {
+            path: e.path().unwrap_or(rootfs.as_path()).to_path_buf(),
+            source: e.into(),
+        })?;
+        let path = entry.path();
+        let relative = path
+            .strip_prefix(&rootfs)
+            .expect("walked paths are under the rootfs");
+        // The root is archived as `.`, which is how layers name it
+        let name = if relative.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            relative
+        };
+        let metadata = fs::symlink_metadata(path).with_path(path)?;
+        let file_type = metadata.file_type();
+        if file_type.is_socket() {
+            log::warn!("Not archiving socket {}", path.display());
+            continue;
+        }
+        let records = xattr_records(path)?;
+        if file_type.is_file() && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(target) = linked.get(&key) {
+                // The attributes are the inode's, so they're already archived with the target
+                let mut header = header(&metadata, EntryType::Link);
+                builder
+                    .append_link(&mut header, name, target)
+                    .with_path(path)?;
+                continue;
+            }
+            linked.insert(key, name.to_path_buf());
+        }
+        if file_type.is_file() && metadata.blocks() * BLOCK_SIZE < metadata.len() {
+            match data_regions(path, metadata.len()) {
+                Ok(regions) => {
+                    append_sparse(&mut builder, path, name, &metadata, &regions, records)?;
+                    continue;
+                }
+                Err(e) => log::debug!("Archiving {} without its holes: {e}", path.display()),
+            }
+        }
+        append_pax(&mut builder, &records).with_path(path)?;
+        let result = if file_type.is_dir() {
+            builder.append_data(
+                &mut header(&metadata, EntryType::Directory),
+                name,
+                io::empty(),
+            )
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path).with_path(path)?;
+            builder.append_link(&mut header(&metadata, EntryType::Symlink), name, target)
+        } else if file_type.is_file() {
+            let mut header = header(&metadata, EntryType::Regular);
+            header.set_size(metadata.len());
+            let file = File::open(path).with_path(path)?;
+            builder.append_data(&mut header, name, file.take(metadata.len()))
+        } else {
+            let entry_type = if file_type.is_char_device() {
+                EntryType::Char
+            } else if file_type.is_block_device() {
+                EntryType::Block
+            } else {
+                EntryType::Fifo
+            };
+            let mut header = header(&metadata, entry_type);
+            if entry_type != EntryType::Fifo {
+                let (major, minor) = device_numbers(metadata.rdev());
+                header.set_device_major(major).with_path(path)?;
+                header.set_device_minor(minor).with_path(path)?;
+            }
+            builder.append_data(&mut header, name, io::empty())
+        };
+        result.with_path(path)?;
+    }
+    builder.finish().with_path(&rootfs)
+}
+
+/// Returns a header of `entry_type`, with the mode, owner and mtime of `metadata`
+fn header(metadata: &Metadata, entry_type: EntryType) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(metadata.mode() & 0o7777);
+    header.set_uid(metadata.uid().into());
+    header.set_gid(metadata.gid().into());
+    header.set_mtime(u64::try_from(metadata.mtime()).unwrap_or(0));
+    header.set_size(0);
+    header
+}
+
+/// Splits a device number into its major and minor numbers, as glibc's `major` and `minor` do
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    (major as u32, minor as u32)
+}
+
+/// Returns the PAX records of the extended attributes of `path`, which include its ACLs and
+/// capabilities
+fn xattr_records(path: &Path) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    let mut names: Vec<_> = match xattr::list(path) {
+        Ok(names) => names.collect(),
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(records),
+        Err(e) => return Err(e).with_path(path),
+    };
+    names.sort();
+    for name in names {
+        let Some(key) = name.to_str() else {
+            log::warn!(
+                "Not archiving extended attribute {name:?} of {}, whose name isn't UTF-8",
+                path.display()
+            );
+            continue;
+        };
+        if let Some(value) = xattr::get(path, &name).with_path(path)? {
+            pax_record(&mut records, &format!("SCHILY.xattr.{key}"), &value);
+        }
+    }
+    Ok(records)
+}
+
+/// Appends a PAX extended header with `records`, if there are any, to apply to the next entry
+fn append_pax<W: Write>(builder: &mut tar::Builder<W>, records: &[u8]) -> io::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "PaxHeader", records)
+}
+
+/// Appends a PAX extended header record, whose length prefix includes itself
+pub(crate) fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // " key=value\n"
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while (rest + len.to_string().len()) != len {
+        len = rest + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{len} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Returns the offset and length of each region of the file at `path` that holds data, with
+/// `SEEK_DATA` and `SEEK_HOLE`. Regions are widened to whole blocks, as GNU sparse entries need
+/// every region but the last to be, and merged where they then meet.
+fn data_regions(path: &Path, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    let file = File::open(path)?;
+    let seek = |offset: u64, whence: libc::c_int| -> io::Result<Option<u64>> {
+        let offset = i64::try_from(offset).map_err(io::Error::other)?;
+        match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+            -1 => match io::Error::last_os_error() {
+                // No data after the offset
+                e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                e => Err(e),
+            },
+            offset => Ok(Some(offset as u64)),
+        }
+    };
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let Some(start) = seek(offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        let start = start - start % BLOCK_SIZE;
+        let end = end.next_multiple_of(BLOCK_SIZE).min(size);
+        match regions.last_mut() {
+            Some((last_start, last_len)) if *last_start + *last_len >= start => {
+                *last_len = end - *last_start;
+            }
+            _ => regions.push((start, end - start)),
+        }
+        offset = end;
+    }
+    Ok(regions)
+}
+
+/// Appends the file at `path` as an old GNU sparse entry, with only its data `regions` stored,
+/// preceded by `records` and, if `name` is too long for the header, a PAX record of it
+fn append_sparse<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &Metadata,
+    regions: &[(u64, u64)],
+    mut records: Vec<u8>,
+) -> Result<()> {
+    let size = metadata.len();
+    let mut regions = regions.to_vec();
+    // A region ending at the file's end marks a trailing hole
+    if regions.last().map_or(0, |(offset, len)| offset + len) < size {
+        regions.push((size, 0));
+    }
+    let stored: u64 = regions.iter().map(|(_, len)| len).sum();
+
+    let mut header = header(metadata, EntryType::GNUSparse);
+    header.set_size(stored);
+    if header.set_path(name).is_err() {
+        pax_record(&mut records, "path", name.as_os_str().as_bytes());
+    }
+    let (first, rest) = regions.split_at(regions.len().min(SPARSE_HEADER_REGIONS));
+    let gnu = header.as_gnu_mut().expect("the header is a GNU header");
+    for (sparse, (offset, len)) in gnu.sparse.iter_mut().zip(first) {
+        write_number(&mut sparse.offset, *offset);
+        write_number(&mut sparse.numbytes, *len);
+    }
+    gnu.isextended[0] = u8::from(!rest.is_empty());
+    write_number(&mut gnu.realsize, size);
+    header.set_cksum();
+
+    append_pax(builder, &records).with_path(path)?;
+    let out = builder.get_mut();
+    out.write_all(header.as_bytes()).with_path(path)?;
+    let mut extensions = rest.chunks(SPARSE_EXTENSION_REGIONS).peekable();
+    while let Some(chunk) = extensions.next() {
+        let mut block = [0; BLOCK_SIZE as usize];
+        for (i, (offset, len)) in chunk.iter().enumerate() {
+            write_number(&mut block[i * 24..i * 24 + 12], *offset);
+            write_number(&mut block[i * 24 + 12..i * 24 + 24], *len);
+        }
+        block[SPARSE_EXTENSION_REGIONS * 24] = u8::from(extensions.peek().is_some());
+        out.write_all(&block).with_path(path)?;
+    }
+    let mut file = File::open(path).with_path(path)?;
+    for (offset, len) in &regions {
+        file.seek(SeekFrom::Start(*offset)).with_path(path)?;
+        let copied = io::copy(&mut (&mut file).take(*len), out).with_path(path)?;
+        if copied != *len {
+            return Err(Error::Io {
+                path: path.to_path_buf(),
+                source: io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank while archived"),
+            });
+        }
+    }
+    let padding = (BLOCK_SIZE - stored % BLOCK_SIZE) % BLOCK_SIZE;
+    out.write_all(&[0; BLOCK_SIZE as usize][..padding as usize])
+        .with_path(path)
+}
+
+/// Writes `value` into a numeric header field as tar-rs does: in octal, NUL-terminated, or in
+/// base-256 if it's too large for that
+fn write_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < 1 << (3 * digits) {
+        field[..digits].copy_from_slice(format!("{value:0digits$o}").as_bytes());
+        field[digits] = 0;
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Restores the bundle archived in `archive` to `dest`
+pub(crate) fn import(
+    mut archive: impl Read,
+    dest: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    let started = Instant::now();
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut archive)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(Error::Archive)?;
+    let is_zstd = magic == ZSTD_MAGIC;
+    let mut archive = io::Cursor::new(magic).chain(archive);
+    let mut report = if is_zstd {
+        let mut child = spawn_zstd(&["-d", "-q", "-c"])?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        // The archive is extracted on another thread, as `archive` needn't be `Send`
+        let (restored, copied) = thread::scope(|scope| {
+            let extractor = scope.spawn(move || restore(stdout, dest, options));
+            let copied = io::copy(&mut archive, &mut stdin);
+            drop(stdin);
+            let restored = extractor.join().expect("extraction doesn't panic");
+            (restored, copied)
+        });
+        let status = wait_zstd(child);
+        // Extraction failing closes the pipe, which is why copying would have failed
+        let report = restored?;
+        copied.map_err(Error::Archive)?;
+        status?;
+        report
+    } else {
+        restore(archive, dest, options)?
+    };
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Extracts the two streams of an uncompressed archive into `dest`, replacing anything there,
+/// then checks the rootfs against the file manifest if the bundle has one
+fn restore(archive: impl Read, dest: &Path, options: &UnpackOptions) -> Result<UnpackReport> {
+    if dest.exists() {
+        fs::remove_dir_all(dest).with_path(dest)?;
+    }
+    metadata::mark_incomplete(dest)?;
+
+    let mut files = Archive::new(archive);
+    files.set_preserve_mtime(true);
+    let mut has_config = false;
+    for entry in files.entries().map_err(Error::Archive)? {
+        let mut entry = entry.map_err(Error::Archive)?;
+        let path = entry.path().map_err(Error::Archive)?.into_owned();
+        let is_file_name = path.parent() == Some(Path::new("")) && path.file_name().is_some();
+        if !is_file_name
+            || entry.header().entry_type() != EntryType::Regular
+            || path == Path::new("rootfs")
+            || path == Path::new(INCOMPLETE_SENTINEL)
+        {
+            log::warn!("Skipping unexpected bundle file {}", path.display());
+            continue;
+        }
+        has_config |= path == Path::new("config.json");
+        let file = dest.join(&path);
+        entry.unpack(&file).with_path(&file)?;
+    }
+    if !has_config {
+        return Err(Error::Archive(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Archive isn't of a bundle, as it has no config.json",
+        )));
+    }
+
+    // The first stream ends with two blocks of zeros, the first of which ended its entries
+    let mut rest = files.into_inner();
+    let mut block = [0; BLOCK_SIZE as usize];
+    loop {
+        rest.read_exact(&mut block).map_err(Error::Archive)?;
+        if block.iter().any(|&byte| byte != 0) {
+            break;
+        }
+    }
+    let rootfs = dest.join("rootfs");
+    fs::create_dir(&rootfs).with_path(&rootfs)?;
+    let mut layer = Archive::new(io::Cursor::new(block).chain(rest));
+    let mut applied = extract_layer(
+        &mut layer,
+        &rootfs,
+        0,
+        options,
+        &mut LayerChanges::new(false),
+        None,
+    )?;
+    // Read to the end, so that a decompressor writing the rest isn't cut off
+    io::copy(&mut layer.into_inner(), &mut io::sink()).map_err(Error::Archive)?;
+
+    let mut report = UnpackReport {
+        file_capabilities: capabilities::resolve(
+            &rootfs,
+            std::mem::take(&mut applied.capabilities),
+        ),
+        effective_options: EffectiveOptions::new(options),
+        ..UnpackReport::default()
+    };
+    report.extend(applied);
+    if dest.join(verify::FILE_MANIFEST).exists() {
+        check_file_manifest(dest, &report, options)?;
+    }
+    metadata::mark_complete(dest)?;
+    report.bundle = Some(Bundle::open(dest)?);
+    Ok(report)
+}
+
+/// Compares the restored rootfs with the bundle's file manifest, tolerating what the options
+/// changed: owners that weren't preserved, bits the permission policy stripped, and device nodes
+/// emulated as files
+fn check_file_manifest(dest: &Path, report: &UnpackReport, options: &UnpackOptions) -> Result<()> {
+    let mut modified = match verify::compare_with_manifest(dest, &VerifyBundleOptions::default()) {
+        Ok(_) => return Ok(()),
+        Err(Error::BundleModified(modified)) => modified,
+        Err(e) => return Err(e),
+    };
+    let stripped: HashSet<_> = report
+        .stripped_permissions
+        .iter()
+        .map(|stripped| crate::normalize(&stripped.path))
+        .collect();
+    let rootfs = dest.join("rootfs");
+    modified.modified.retain_mut(|path| {
+        if options.ownership == OwnershipMode::Emulate
+            && matches!(
+                xattr::get(rootfs.join(&path.path), rootless::EMULATED_DEVICE_XATTR),
+                Ok(Some(_))
+            )
+        {
+            return false;
+        }
+        path.differences.retain(|difference| match difference {
+            Difference::Ownership => options.ownership == OwnershipMode::Preserve,
+            Difference::Mode => !stripped.contains(&path.path),
+            _ => true,
+        });
+        !path.differences.is_empty()
+    });
+    if modified.is_unchanged() {
+        Ok(())
+    } else {
+        Err(Error::BundleModified(modified))
+    }
+}
+
+fn spawn_zstd(args: &[&str]) -> Result<Child> {
+    Command::new("zstd")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Zstd(e.to_string()))
+}
+
+/// Waits for `zstd` to exit, failing with what it wrote to stderr if it didn't succeed
+fn wait_zstd(child: Child) -> Result<()> {
+    let output = child
+        .wait_with_output()
+        .map_err(|e| Error::Zstd(e.to_string()))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(Error::Zstd(format!(
+        "{}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    )))
+}
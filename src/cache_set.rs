@@ -0,0 +1,151 @@
+//! The local stores a layer's content may already be in, for planning which layers to pull from
+//! a registry with [`missing_layers`].
+
+use crate::blob_cache;
+use crate::blob_path;
+use crate::error::{Error, IoResultExt, Result};
+use crate::layer_digests::UNCOMPRESSED_DIGEST_ANNOTATION;
+use ocidir::oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest, MediaType};
+use ocidir::oci_spec::OciSpecError;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The caches and layouts that layers are looked up in by [`missing_layers`]. The same set can
+/// be given to [`crate::UnpackOptions::caches`], so that unpacking reads layers from where they
+/// were found.
+///
+/// ```no_run
+/// use oci_bundle::{missing_layers, CacheSet, UnpackOptions, Unpacker};
+/// # fn pull(_: &[ocidir::oci_spec::image::Descriptor]) {}
+/// # fn plan(manifest: &ocidir::oci_spec::image::ImageManifest, oci_dir: &ocidir::OciDir) -> oci_bundle::Result<()> {
+///
+/// // The manifest and config are pulled into the layout first
+/// let caches = CacheSet::new()
+///     .layout("/var/lib/images/oci")
+///     .decompressed_blob_cache("/var/cache/layers");
+/// pull(&missing_layers(manifest, &caches)?);
+/// let unpacker = Unpacker::new(UnpackOptions::new().caches(caches.clone()))?;
+/// unpacker.unpack(manifest, oci_dir, "/run/bundles/app".as_ref())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CacheSet {
+    pub(crate) layouts: Vec<PathBuf>,
+    pub(crate) decompressed_blob_cache: Option<PathBuf>,
+    pub(crate) decompressed_blob_cache_max_bytes: Option<u64>,
+}
+
+impl CacheSet {
+    /// Creates an empty set, in which every layer is missing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up blobs by their digest in the OCI layout at `dir`, such as the one images are
+    /// pulled into, and image configs, for the diff IDs of their layers
+    pub fn layout(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.layouts.push(dir.into());
+        self
+    }
+
+    /// Look up layer archives by their diff ID in `dir`, as
+    /// [`crate::UnpackOptions::decompressed_blob_cache`] caches them
+    pub fn decompressed_blob_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.decompressed_blob_cache = Some(dir.into());
+        self
+    }
+
+    /// The size to prune the decompressed blob cache to when unpacking, as with
+    /// [`crate::UnpackOptions::decompressed_blob_cache_max_bytes`]
+    pub fn decompressed_blob_cache_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.decompressed_blob_cache_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Returns whether one of the layouts has the blob with `descriptor`, at its size
+    fn has_blob(&self, descriptor: &Descriptor) -> Result<bool> {
+        for layout in &self.layouts {
+            let path = layout.join(blob_path(descriptor));
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() && metadata.len() == descriptor.size() => {
+                    return Ok(true)
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_path(path),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the config with `descriptor` from the first layout that has it
+    fn config(&self, descriptor: &Descriptor) -> Result<Option<ImageConfiguration>> {
+        for layout in &self.layouts {
+            let path = layout.join(blob_path(descriptor));
+            match fs::read(&path) {
+                Ok(bytes) => {
+                    return serde_json::from_slice(&bytes)
+                        .map(Some)
+                        .map_err(|e| Error::Spec(OciSpecError::SerDe(e)))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_path(path),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Returns the diff ID of each layer of `manifest`, if it's known: from the image config, if one
+/// of the layouts has it, and otherwise from the [`UNCOMPRESSED_DIGEST_ANNOTATION`], or the
+/// digest of an uncompressed layer
+fn diff_ids(manifest: &ImageManifest, caches: &CacheSet) -> Result<Vec<Option<String>>> {
+    let layers = manifest.layers();
+    if let Some(config) = caches.config(manifest.config())? {
+        let diff_ids = config.rootfs().diff_ids();
+        if diff_ids.len() == layers.len() {
+            return Ok(diff_ids.iter().cloned().map(Some).collect());
+        }
+    }
+    Ok(layers
+        .iter()
+        .map(|descriptor| {
+            let annotated = descriptor
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(UNCOMPRESSED_DIGEST_ANNOTATION));
+            match annotated {
+                Some(diff_id) => Some(diff_id.clone()),
+                None => (*descriptor.media_type() == MediaType::ImageLayer)
+                    .then(|| descriptor.digest().to_string()),
+            }
+        })
+        .collect())
+}
+
+/// Returns the descriptors of the layers of `manifest` whose content isn't available from
+/// `caches`, in order, which are the layers to pull before unpacking the image.
+///
+/// A layer is available if one of the layouts has its blob, or the decompressed blob cache has
+/// its archive, looked up by its diff ID. The diff IDs are read from the image config, so pull
+/// it into one of the layouts first. Without it, they're only known for layers with the
+/// [`UNCOMPRESSED_DIGEST_ANNOTATION`] and uncompressed layers.
+///
+/// Nothing is verified: a cached archive is verified against its diff ID when it's read, and a
+/// blob against its digest.
+pub fn missing_layers(manifest: &ImageManifest, caches: &CacheSet) -> Result<Vec<Descriptor>> {
+    let diff_ids = diff_ids(manifest, caches)?;
+    let mut missing = Vec::new();
+    for (descriptor, diff_id) in manifest.layers().iter().zip(diff_ids) {
+        let cached = match (&caches.decompressed_blob_cache, diff_id) {
+            (Some(cache), Some(diff_id)) => blob_cache::contains(cache, &diff_id),
+            _ => false,
+        };
+        if !cached && !caches.has_blob(descriptor)? {
+            missing.push(descriptor.clone());
+        }
+    }
+    Ok(missing)
+}
@@ -0,0 +1,300 @@
+//! File capabilities, the `security.capability` extended attributes that grant a binary
+//! privileges when it's executed, for [`crate::UnpackReport::file_capabilities`].
+
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tar::Entry;
+
+/// The annotation summarizing the file capabilities in the rootfs, with
+/// [`crate::RuntimeConfigOptions::file_capabilities_annotation`]. Its value lists each file, by
+/// absolute path, and its capabilities in the form `getcap` prints them, separated by `; `, such
+/// as `/usr/bin/ping cap_net_raw=ep`.
+pub const FILE_CAPABILITIES_ANNOTATION: &str = "oci-bundle.file-capabilities";
+
+/// The extended attribute holding a file's capabilities
+pub(crate) const XATTR: &str = "security.capability";
+
+const REVISION_MASK: u32 = 0xff00_0000;
+const REVISION_1: u32 = 0x0100_0000;
+const REVISION_2: u32 = 0x0200_0000;
+const REVISION_3: u32 = 0x0300_0000;
+const FLAG_EFFECTIVE: u32 = 0x1;
+
+/// The names of the capabilities, by number
+const NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// A file in the rootfs with capabilities, as listed in
+/// [`crate::UnpackReport::file_capabilities`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileCapability {
+    /// The layer that wrote the file
+    pub layer_index: usize,
+    /// The file's path, relative to the root
+    pub path: PathBuf,
+    /// The names of the permitted capabilities, such as `cap_net_bind_service`, in order of
+    /// their numbers. Capabilities this crate has no name for are named by number, as in
+    /// `cap_41`.
+    pub permitted: Vec<String>,
+    /// The names of the inheritable capabilities
+    pub inheritable: Vec<String>,
+    /// Whether the permitted capabilities are raised in the effective set on execution
+    pub effective: bool,
+    /// The user ID of root in the user namespace the capabilities apply to, for capabilities
+    /// limited to one
+    pub root_id: Option<u32>,
+    /// Whether the attribute couldn't be set, as the unpack wasn't privileged to set it or the
+    /// filesystem doesn't support it, so the file has none of the capabilities the layer gave it
+    /// and will run without them. Each is also a [`crate::WarningKind::CapabilityDropped`]
+    /// warning.
+    pub dropped: bool,
+    /// The attribute's value, as in the layer
+    value: Vec<u8>,
+}
+
+impl FileCapability {
+    /// Describes the capabilities in the `value` of a `security.capability` attribute given to
+    /// `path`, returning `None` if it can't be decoded
+    pub(crate) fn decode(
+        layer_index: usize,
+        path: PathBuf,
+        value: &[u8],
+        dropped: bool,
+    ) -> Option<Self> {
+        let word = |i: usize| {
+            let bytes = value.get(i * 4..i * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let magic = word(0)?;
+        let (words, root_id) = match (magic & REVISION_MASK, value.len()) {
+            (REVISION_1, 12) => (1, None),
+            (REVISION_2, 20) => (2, None),
+            (REVISION_3, 24) => (2, Some(word(5)?)),
+            _ => return None,
+        };
+        let mut permitted = 0u64;
+        let mut inheritable = 0u64;
+        for i in 0..words {
+            permitted |= u64::from(word(1 + i * 2)?) << (32 * i);
+            inheritable |= u64::from(word(2 + i * 2)?) << (32 * i);
+        }
+        Some(Self {
+            layer_index,
+            path,
+            permitted: names(permitted),
+            inheritable: names(inheritable),
+            effective: magic & FLAG_EFFECTIVE != 0,
+            root_id,
+            dropped,
+            value: value.to_vec(),
+        })
+    }
+
+    /// The capabilities in the form `getcap` prints them, such as `cap_net_raw=ep`
+    fn text(&self) -> String {
+        // Capabilities with the same flags are grouped, in order of their numbers
+        let mut groups: Vec<(String, Vec<&str>)> = Vec::new();
+        let all = self.permitted.iter().chain(&self.inheritable);
+        let mut seen = Vec::new();
+        for name in all {
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+            let permitted = self.permitted.contains(name);
+            let mut flags = String::new();
+            if permitted && self.effective {
+                flags.push('e');
+            }
+            if self.inheritable.contains(name) {
+                flags.push('i');
+            }
+            if permitted {
+                flags.push('p');
+            }
+            match groups.iter_mut().find(|(group, _)| *group == flags) {
+                Some((_, names)) => names.push(name),
+                None => groups.push((flags, vec![name])),
+            }
+        }
+        groups
+            .iter()
+            .map(|(flags, names)| format!("{}={flags}", names.join(",")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn names(set: u64) -> Vec<String> {
+    (0..64)
+        .filter(|bit| set & (1 << bit) != 0)
+        .map(|bit| match NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("cap_{bit}"),
+        })
+        .collect()
+}
+
+/// Returns the value of the `security.capability` attribute of `entry`, if it has one
+pub(crate) fn read<R: io::Read>(entry: &mut Entry<R>) -> Result<Option<Vec<u8>>> {
+    let Some(extensions) = entry.pax_extensions().map_err(Error::Archive)? else {
+        return Ok(None);
+    };
+    for extension in extensions {
+        let extension = extension.map_err(Error::Archive)?;
+        let name = extension.key_bytes().strip_prefix(b"SCHILY.xattr.");
+        if name == Some(XATTR.as_bytes()) {
+            return Ok(Some(extension.value_bytes().to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether failing to set `name` with `error` means the attribute is dropped, rather
+/// than the layer failing, which is so for capabilities the unpack isn't privileged to set or
+/// the filesystem doesn't support
+pub(crate) fn is_dropped(name: &OsStr, error: &io::Error) -> bool {
+    name.as_bytes() == XATTR.as_bytes()
+        && matches!(
+            error.raw_os_error(),
+            Some(libc::EPERM | libc::ENOTSUP | libc::EACCES)
+        )
+}
+
+/// Returns the capabilities the files in `rootfs` have, of the `recorded` capabilities written
+/// by each layer in order. Files that later layers removed, or replaced with ones without the
+/// same capabilities, are left out.
+pub(crate) fn resolve(rootfs: &Path, recorded: Vec<FileCapability>) -> Vec<FileCapability> {
+    let mut latest = HashMap::new();
+    for (i, capability) in recorded.iter().enumerate() {
+        latest.insert(&capability.path, i);
+    }
+    let mut kept = Vec::new();
+    for (i, capability) in recorded.iter().enumerate() {
+        if latest[&capability.path] != i {
+            continue;
+        }
+        let path = rootfs.join(&capability.path);
+        let exists = path.symlink_metadata().is_ok_and(|m| m.is_file());
+        let current = exists && (capability.dropped || has_value(&path, &capability.value));
+        if current {
+            kept.push(capability.clone());
+        }
+    }
+    kept
+}
+
+fn has_value(path: &Path, value: &[u8]) -> bool {
+    matches!(xattr::get(path, XATTR), Ok(Some(current)) if current == value)
+}
+
+/// Returns the value of the [`FILE_CAPABILITIES_ANNOTATION`], listing the `capabilities` that
+/// weren't dropped, or `None` if there are none
+pub(crate) fn annotation(capabilities: &[FileCapability]) -> Option<String> {
+    let files: Vec<_> = capabilities
+        .iter()
+        .filter(|capability| !capability.dropped)
+        .map(|capability| {
+            format!(
+                "{} {}",
+                Path::new("/").join(&capability.path).display(),
+                capability.text()
+            )
+        })
+        .collect();
+    (!files.is_empty()).then(|| files.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(magic: u32, words: &[u32]) -> Vec<u8> {
+        std::iter::once(magic)
+            .chain(words.iter().copied())
+            .flat_map(u32::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let v2 = value(REVISION_2 | FLAG_EFFECTIVE, &[1 << 10 | 1 << 13, 0, 0, 0]);
+        let capability = FileCapability::decode(0, "ping".into(), &v2, false).unwrap();
+        assert_eq!(
+            capability.permitted,
+            ["cap_net_bind_service", "cap_net_raw"]
+        );
+        assert!(capability.inheritable.is_empty());
+        assert!(capability.effective);
+        assert_eq!(capability.root_id, None);
+        assert_eq!(capability.text(), "cap_net_bind_service,cap_net_raw=ep");
+
+        let v3 = value(REVISION_3, &[1, 1, 1 << 8, 1 << 9, 1000]);
+        let capability = FileCapability::decode(0, "x".into(), &v3, false).unwrap();
+        assert_eq!(
+            capability.permitted,
+            ["cap_chown", "cap_checkpoint_restore"]
+        );
+        assert_eq!(capability.inheritable, ["cap_chown", "cap_41"]);
+        assert_eq!(capability.root_id, Some(1000));
+        assert_eq!(
+            capability.text(),
+            "cap_chown=ip cap_checkpoint_restore=p cap_41=i"
+        );
+
+        let v1 = value(REVISION_1, &[1 << 7, 0]);
+        let capability = FileCapability::decode(0, "x".into(), &v1, false).unwrap();
+        assert_eq!(capability.permitted, ["cap_setuid"]);
+
+        assert!(FileCapability::decode(0, "x".into(), &v2[..16], false).is_none());
+        assert!(
+            FileCapability::decode(0, "x".into(), &value(0x0400_0000, &[0; 4]), false).is_none()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Listing the paths each layer added, modified or removed.
+//!
+//! As with the accounting in [`crate::waste`], the changes layers record are replayed in layer
+//! order once all are applied, so whether a path existed is known whatever the unpack mode.
+
+use crate::report::{Change, EntryChange, LayerLog, RemovalKind};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The layer that last wrote each entry in the rootfs, as the changes are replayed
+#[derive(Default)]
+struct Replay<'a> {
+    entries: BTreeMap<&'a Path, usize>,
+}
+
+impl<'a> Replay<'a> {
+    /// Returns whether anything is at `path`, including a directory created as the parent of
+    /// entries beneath it
+    fn exists(&self, path: &Path) -> bool {
+        self.entries
+            .range(path..)
+            .next()
+            .is_some_and(|(entry, _)| entry.starts_with(path))
+    }
+
+    /// Removes the entries beneath `path`, and at it if `inclusive`, written by layers that
+    /// `filter` accepts, returning whether there were any
+    fn remove(&mut self, path: &'a Path, inclusive: bool, filter: impl Fn(usize) -> bool) -> bool {
+        let removed: Vec<_> = self
+            .entries
+            .range(path..)
+            .take_while(|(entry, _)| entry.starts_with(path))
+            .filter(|(entry, layer)| (inclusive || **entry != path) && filter(**layer))
+            .map(|(entry, _)| *entry)
+            .collect();
+        for entry in &removed {
+            self.entries.remove(entry);
+        }
+        !removed.is_empty()
+    }
+
+    /// Records that layer `index` wrote an entry at `path`, returning the change it made. Unless
+    /// the entry is a directory, it replaces whatever was at the path.
+    fn write(&mut self, path: &'a Path, index: usize, dir: bool) -> Change {
+        let existed = self.exists(path);
+        if !dir {
+            self.remove(path, true, |_| true);
+        }
+        self.entries.insert(path, index);
+        if existed {
+            Change::Modified(path.to_path_buf())
+        } else {
+            Change::Added(path.to_path_buf())
+        }
+    }
+}
+
+/// Replays the changes of each layer in order, returning the paths changed by each of `layers`
+/// layers
+pub(crate) fn replay(logs: &[LayerLog], layers: usize) -> Vec<Vec<Change>> {
+    let mut logs: Vec<_> = logs.iter().collect();
+    logs.sort_by_key(|log| log.layer_index);
+    let mut replay = Replay::default();
+    let mut changes = vec![Vec::new(); layers];
+    for log in logs {
+        let index = log.layer_index;
+        for change in &log.changes {
+            let change = match change {
+                EntryChange::Write { path, .. } => replay.write(path, index, false),
+                EntryChange::Dir { path, .. } => replay.write(path, index, true),
+                EntryChange::Root { .. } => continue,
+                EntryChange::Whiteout(path) => {
+                    if !replay.remove(path, true, |layer| layer < index) {
+                        continue;
+                    }
+                    Change::Removed(path.clone(), RemovalKind::Whiteout)
+                }
+                EntryChange::Opaque(dir) => {
+                    if !replay.remove(dir, false, |layer| layer < index) {
+                        continue;
+                    }
+                    Change::Removed(dir.clone(), RemovalKind::Opaque)
+                }
+            };
+            changes[index].push(change);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &str) -> EntryChange {
+        EntryChange::Write {
+            path: path.into(),
+            size: 0,
+        }
+    }
+
+    fn dir(path: &str) -> EntryChange {
+        EntryChange::Dir {
+            path: path.into(),
+            mtime: 0,
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let logs = vec![
+            LayerLog {
+                layer_index: 2,
+                changes: vec![
+                    EntryChange::Opaque("opt".into()),
+                    write("etc/motd"),
+                    EntryChange::Whiteout("var".into()),
+                    EntryChange::Whiteout("missing".into()),
+                    dir("etc"),
+                ],
+            },
+            LayerLog {
+                layer_index: 0,
+                changes: vec![
+                    dir("etc"),
+                    write("etc/motd"),
+                    write("var/cache/a"),
+                    write("opt/app"),
+                ],
+            },
+            LayerLog {
+                layer_index: 1,
+                changes: vec![write("etc/motd"), write("etc/motd"), write("etc/new")],
+            },
+        ];
+        let changes = replay(&logs, 4);
+        let added = |path: &str| Change::Added(path.into());
+        let modified = |path: &str| Change::Modified(path.into());
+        assert_eq!(
+            changes,
+            [
+                vec![
+                    added("etc"),
+                    added("etc/motd"),
+                    added("var/cache/a"),
+                    added("opt/app"),
+                ],
+                vec![modified("etc/motd"), modified("etc/motd"), added("etc/new")],
+                vec![
+                    Change::Removed("opt".into(), RemovalKind::Opaque),
+                    modified("etc/motd"),
+                    // `var` was only created as the parent of `var/cache/a`
+                    Change::Removed("var".into(), RemovalKind::Whiteout),
+                    modified("etc"),
+                ],
+                vec![],
+            ]
+        );
+    }
+}
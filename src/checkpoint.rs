@@ -0,0 +1,332 @@
+//! Checkpoints of an unpack's progress, for [`crate::resume_unpack`].
+//!
+//! With [`crate::UnpackOptions::checkpoint_layers`], the layers applied so far are recorded in
+//! the bundle after each one is applied, once the rootfs's filesystem has been synced, so a
+//! checkpoint never records a layer that isn't on disk. An unpack that's interrupted, whether
+//! between layers or part way through one, can be resumed from the layer after the last one
+//! recorded. Applying a layer again over what it had written before the interruption leaves the
+//! rootfs as applying it once would, as every entry it had written is written again.
+//!
+//! A checkpoint is discarded, and the image unpacked from scratch, unless it was recorded for
+//! the same image, by the same version of this crate, with the same options affecting what's
+//! extracted, and the same layers were decided on. The paths it sampled from the rootfs, if any,
+//! must still be as they were.
+
+use crate::error::{IoResultExt, Result};
+use crate::metadata;
+use crate::options::UnpackOptions;
+use crate::report::LayerReport;
+use crate::verify::{self, Record};
+use ocidir::oci_spec::image::ImageManifest;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The file in a bundle recording the layers applied to it, until it's complete
+pub(crate) const CHECKPOINT: &str = ".checkpoint.json";
+
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// The layers applied to a bundle that's being unpacked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    version: u32,
+    crate_version: String,
+    config: String,
+    layers: Vec<String>,
+    /// A digest of the options affecting what's extracted, from [`fingerprint`]
+    options: String,
+    /// The layers applied, in order. Those missing before the last were skipped.
+    applied: Vec<AppliedLayer>,
+    /// The first paths in the rootfs once the last layer was applied
+    #[serde(default)]
+    sample: Vec<Record>,
+}
+
+/// A layer recorded in a [`Checkpoint`], with what it held, for working out what went wrong with
+/// a bundle that isn't as expected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AppliedLayer {
+    index: usize,
+    diff_id: String,
+    /// The number of entries in the layer's archive
+    entries: u64,
+    /// The size of the layer's archive
+    bytes: u64,
+}
+
+impl Checkpoint {
+    fn new(manifest: &ImageManifest, options: &UnpackOptions) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            config: manifest.config().digest().to_string(),
+            layers: manifest
+                .layers()
+                .iter()
+                .map(|layer| layer.digest().to_string())
+                .collect(),
+            options: fingerprint(options),
+            applied: Vec::new(),
+            sample: Vec::new(),
+        }
+    }
+
+    /// The number of the image's layers that were applied or skipped, which the unpack resumes
+    /// after
+    pub(crate) fn layers(&self) -> usize {
+        self.applied.last().map_or(0, |layer| layer.index + 1)
+    }
+
+    /// Returns whether the layers were applied as they would be now: with the image's
+    /// `diff_ids`, skipping the same layers
+    pub(crate) fn matches(&self, diff_ids: &[String], skipped: &[bool]) -> bool {
+        if skipped.len() < self.layers() {
+            return false;
+        }
+        let mut applied = self.applied.iter().peekable();
+        for (index, &skip) in skipped.iter().enumerate().take(self.layers()) {
+            let was_applied = applied.next_if(|layer| layer.index == index);
+            match was_applied {
+                Some(layer) if skip || diff_ids.get(index) != Some(&layer.diff_id) => return false,
+                None if !skip => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+/// Reads the checkpoint of `bundle`, returning `None` if there isn't one, or it can't be resumed
+/// for the image with `manifest` with `options`
+pub(crate) fn load(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    options: &UnpackOptions,
+) -> Result<Option<Checkpoint>> {
+    if !metadata::is_incomplete(bundle) {
+        return Ok(None);
+    }
+    let path = bundle.join(CHECKPOINT);
+    let json = match fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::info!("Bundle {} has no checkpoint", bundle.display());
+            return Ok(None);
+        }
+        Err(e) => return Err(e).with_path(&path),
+    };
+    let checkpoint = match serde_json::from_slice::<Checkpoint>(&json) {
+        Ok(checkpoint) if checkpoint.version == CHECKPOINT_VERSION => checkpoint,
+        Ok(checkpoint) => {
+            log::info!(
+                "Ignoring checkpoint version {} in {}",
+                checkpoint.version,
+                path.display()
+            );
+            return Ok(None);
+        }
+        Err(e) => {
+            log::info!("Ignoring unreadable checkpoint {}: {e}", path.display());
+            return Ok(None);
+        }
+    };
+    let expected = Checkpoint::new(manifest, options);
+    let reason = if (&checkpoint.config, &checkpoint.layers) != (&expected.config, &expected.layers)
+    {
+        "was recorded for a different image"
+    } else if checkpoint.crate_version != expected.crate_version {
+        "was recorded by a different version"
+    } else if checkpoint.options != expected.options {
+        "was recorded with different options"
+    } else if !bundle.join("rootfs").is_dir() {
+        "has no rootfs"
+    } else if !verify::sample_matches(&bundle.join("rootfs"), &checkpoint.sample)? {
+        "has a rootfs that differs from its sample"
+    } else {
+        return Ok(Some(checkpoint));
+    };
+    log::info!(
+        "Not resuming bundle {}, as its checkpoint {reason}",
+        bundle.display()
+    );
+    Ok(None)
+}
+
+/// A digest of the options that affect what layers extract, or that report on every layer's
+/// changes, which a resumed unpack couldn't
+fn fingerprint(options: &UnpackOptions) -> String {
+    let options = format!(
+        "{:?}",
+        (
+            (
+                &options.ownership,
+                &options.permission_policy,
+                &options.hardlinks,
+                &options.parent_symlinks,
+                &options.strictness,
+                &options.windows_layers,
+                &options.apply_mode,
+            ),
+            (
+                &options.unicode_policy,
+                &options.max_layer_entries,
+                &options.max_entry_size,
+                &options.max_link_target_len,
+                &options.protected_paths,
+                &options.translate_acls,
+                &options.hygiene,
+                &options.hygiene_rules,
+            ),
+            (
+                &options.record_changes,
+                &options.analyze_waste,
+                &options.restore_dir_mtimes,
+                &options.per_layer_policy,
+            ),
+        )
+    );
+    format!(
+        "sha256:{}",
+        hex::encode(openssl::sha::sha256(options.as_bytes()))
+    )
+}
+
+/// Records a checkpoint in a bundle after each layer is applied to it
+pub(crate) struct Checkpoints {
+    path: PathBuf,
+    rootfs: PathBuf,
+    diff_ids: Vec<String>,
+    sample_size: usize,
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl Checkpoints {
+    /// Records the checkpoint of `bundle` before any layers are applied, which is `resumed` if
+    /// the unpack is resuming from it
+    pub(crate) fn start(
+        bundle: &Path,
+        manifest: &ImageManifest,
+        diff_ids: &[String],
+        options: &UnpackOptions,
+        resumed: Option<&Checkpoint>,
+    ) -> Result<Self> {
+        let checkpoint = match resumed {
+            Some(checkpoint) => checkpoint.clone(),
+            None => Checkpoint::new(manifest, options),
+        };
+        let checkpoints = Self {
+            path: bundle.join(CHECKPOINT),
+            rootfs: bundle.join("rootfs"),
+            diff_ids: diff_ids.to_vec(),
+            sample_size: options.checkpoint_sample,
+            checkpoint: Mutex::new(checkpoint),
+        };
+        checkpoints.save(&checkpoints.checkpoint.lock().unwrap())?;
+        Ok(checkpoints)
+    }
+
+    /// Records that the layer at `index`, which `report` describes, was applied
+    pub(crate) fn layer_applied(
+        &self,
+        index: usize,
+        report: &LayerReport,
+        uncompressed_size: u64,
+    ) -> Result<()> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.applied.push(AppliedLayer {
+            index,
+            diff_id: self.diff_ids[index].clone(),
+            entries: report
+                .counts
+                .iter()
+                .find(|counts| counts.layer_index == index)
+                .map_or(0, |counts| counts.entries),
+            bytes: uncompressed_size,
+        });
+        checkpoint.sample = verify::sample(&self.rootfs, self.sample_size)?;
+        sync_filesystem(&self.rootfs)?;
+        self.save(&checkpoint)?;
+        log::debug!("Recorded checkpoint after layer {index}");
+        Ok(())
+    }
+
+    /// Removes the checkpoint once the bundle is complete
+    pub(crate) fn finish(self) -> Result<()> {
+        fs::remove_file(&self.path).with_path(&self.path)
+    }
+
+    /// Replaces the checkpoint in the bundle with `checkpoint`, syncing it to disk
+    fn save(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let json = serde_json::to_vec(checkpoint).expect("checkpoint serializes");
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, json)
+            .and_then(|()| File::open(&temp)?.sync_all())
+            .with_path(&temp)?;
+        fs::rename(&temp, &self.path).with_path(&self.path)?;
+        let bundle = self.path.parent().expect("the checkpoint is in a bundle");
+        File::open(bundle)
+            .and_then(|dir| dir.sync_all())
+            .with_path(bundle)
+    }
+}
+
+/// Writes everything written to the filesystem with `path` to disk
+fn sync_filesystem(path: &Path) -> Result<()> {
+    let dir = File::open(path).with_path(path)?;
+    // SAFETY: the file descriptor is open for the duration of the call
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error()).with_path(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(applied: &[usize]) -> Checkpoint {
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            crate_version: String::new(),
+            config: String::new(),
+            layers: Vec::new(),
+            options: String::new(),
+            applied: applied
+                .iter()
+                .map(|&index| AppliedLayer {
+                    index,
+                    diff_id: format!("sha256:{index}"),
+                    entries: 1,
+                    bytes: 1024,
+                })
+                .collect(),
+            sample: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let diff_ids: Vec<_> = (0..4).map(|index| format!("sha256:{index}")).collect();
+        let none = [false; 4];
+        assert_eq!(checkpoint(&[]).layers(), 0);
+        assert!(checkpoint(&[]).matches(&diff_ids, &none));
+        assert_eq!(checkpoint(&[0, 1]).layers(), 2);
+        assert!(checkpoint(&[0, 1]).matches(&diff_ids, &none));
+        // Layers decided on after the checkpoint don't matter
+        assert!(checkpoint(&[0, 1]).matches(&diff_ids, &[false, false, true, true]));
+
+        assert_eq!(checkpoint(&[0, 2]).layers(), 3);
+        assert!(checkpoint(&[0, 2]).matches(&diff_ids, &[false, true, false, false]));
+        assert!(!checkpoint(&[0, 2]).matches(&diff_ids, &none));
+        assert!(!checkpoint(&[0, 1]).matches(&diff_ids, &[true, false, false, false]));
+
+        let mut other = diff_ids.clone();
+        other[1] = "sha256:other".to_string();
+        assert!(!checkpoint(&[0, 1]).matches(&other, &none));
+        assert!(checkpoint(&[0]).matches(&other, &none));
+    }
+}
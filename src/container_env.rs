@@ -0,0 +1,128 @@
+//! The marker files that processes check for to tell whether they're running in a container, for
+//! [`crate::RuntimeConfigOptions::container_env_marker`].
+
+use crate::error::{IoResultExt, Result};
+use crate::options::{ContainerEnvMarker, OwnershipMode};
+use crate::reference::REF_NAME_ANNOTATION;
+use crate::rootless;
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::{Dir, OpenOptions, OpenOptionsExt};
+use ocidir::oci_spec::image::{ImageConfiguration, ImageManifest};
+use std::fs::Permissions;
+use std::io::{self, Write};
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Writes `marker` into `rootfs`, with mode 0644 and owned by root, replacing any file there.
+/// Returns the marker's path in the container, or `None` if there's no marker to write.
+///
+/// The file is written through a handle on the rootfs, so symlinks can't lead outside of it.
+pub(crate) fn create(
+    rootfs: &Path,
+    marker: &ContainerEnvMarker,
+    manifest: &ImageManifest,
+    image_config: &ImageConfiguration,
+    ownership: OwnershipMode,
+) -> Result<Option<PathBuf>> {
+    let (path, contents) = match marker {
+        ContainerEnvMarker::None => return Ok(None),
+        ContainerEnvMarker::DockerEnv => (Path::new(".dockerenv"), String::new()),
+        ContainerEnvMarker::ContainerEnv { contents } => (
+            Path::new("run/.containerenv"),
+            contents
+                .clone()
+                .unwrap_or_else(|| containerenv(manifest, image_config)),
+        ),
+    };
+    let root_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        root_dir.create_dir_all(parent).with_path(parent)?;
+    }
+    match root_dir.remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).with_path(path),
+        _ => {}
+    }
+    let mut file = root_dir
+        .open_with(
+            path,
+            OpenOptions::new().write(true).create_new(true).mode(0o644),
+        )
+        .with_path(path)?
+        .into_std();
+    file.write_all(contents.as_bytes()).with_path(path)?;
+    match ownership {
+        OwnershipMode::Preserve => fchown(&file, Some(0), Some(0)).with_path(path)?,
+        OwnershipMode::Emulate => rootless::record(&file, 0, 0).with_path(path)?,
+        OwnershipMode::Ignore => {}
+    }
+    // The mode is set regardless of the umask
+    file.set_permissions(Permissions::from_mode(0o644))
+        .with_path(path)?;
+    log::info!("Created /{}", path.display());
+    Ok(Some(Path::new("/").join(path)))
+}
+
+/// Returns the contents of a `.containerenv` as Podman writes it, with the image's name, if its
+/// manifest or config has the [`REF_NAME_ANNOTATION`], and its ID, which is its config's digest
+fn containerenv(manifest: &ImageManifest, image_config: &ImageConfiguration) -> String {
+    let name = manifest
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+        .or_else(|| {
+            image_config
+                .config()
+                .as_ref()?
+                .labels()
+                .as_ref()?
+                .get(REF_NAME_ANNOTATION)
+        });
+    let mut contents = format!("engine=\"oci-bundle-{}\"\n", env!("CARGO_PKG_VERSION"));
+    if let Some(name) = name {
+        contents.push_str(&format!("image={name:?}\n"));
+    }
+    contents.push_str(&format!(
+        "imageid=\"{}\"\n",
+        manifest.config().digest().digest()
+    ));
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::image::{DescriptorBuilder, ImageConfigurationBuilder, MediaType};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_containerenv() {
+        let digest = "a".repeat(64);
+        let config = DescriptorBuilder::default()
+            .media_type(MediaType::ImageConfig)
+            .digest(
+                format!("sha256:{digest}")
+                    .parse::<ocidir::oci_spec::image::Digest>()
+                    .unwrap(),
+            )
+            .size(2u64)
+            .build()
+            .unwrap();
+        let mut manifest = ocidir::new_empty_manifest().build().unwrap();
+        manifest.set_config(config);
+        let image_config = ImageConfigurationBuilder::default().build().unwrap();
+        let engine = format!("engine=\"oci-bundle-{}\"\n", env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            containerenv(&manifest, &image_config),
+            format!("{engine}imageid=\"{digest}\"\n")
+        );
+
+        manifest.set_annotations(Some(HashMap::from([(
+            REF_NAME_ANNOTATION.to_string(),
+            "example.com/app:1.0".to_string(),
+        )])));
+        assert_eq!(
+            containerenv(&manifest, &image_config),
+            format!("{engine}image=\"example.com/app:1.0\"\nimageid=\"{digest}\"\n")
+        );
+    }
+}
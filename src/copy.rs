@@ -0,0 +1,375 @@
+use crate::error::{Error, IoResultExt, Result};
+use crate::report::LayerCopy;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{lchown, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// How file data is transferred when populating a directory tree from another one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyStrategy {
+    /// Use reflinks where the filesystem pair supports them, otherwise copy
+    #[default]
+    Auto,
+    /// Hard link files, sharing both data and metadata with the source
+    Hardlink,
+    /// Clone file data copy-on-write (`FICLONE`), giving independent metadata
+    Reflink,
+    /// Copy file data
+    Copy,
+}
+
+/// Recreates the tree at `src` at `dst` using `strategy`, returning the strategy actually used.
+///
+/// Strategies that can't be used for the source and destination filesystems (e.g. hardlinks or
+/// reflinks across devices, or reflinks on a filesystem without support) fall back to copying.
+/// `Auto` probes reflink support once for the pair of filesystems, and is only returned if the
+/// tree contained no regular files to probe with.
+pub fn copy_tree(src: &Path, dst: &Path, strategy: CopyStrategy) -> Result<CopyStrategy> {
+    let src_dev = fs::metadata(src).with_path(src)?.dev();
+    fs::create_dir_all(dst).with_path(dst)?;
+    let dst_dev = fs::metadata(dst).with_path(dst)?.dev();
+
+    let mut used = match strategy {
+        CopyStrategy::Hardlink | CopyStrategy::Reflink | CopyStrategy::Auto
+            if src_dev != dst_dev =>
+        {
+            log::debug!(
+                "{} and {} are on different devices, copying",
+                src.display(),
+                dst.display()
+            );
+            CopyStrategy::Copy
+        }
+        strategy => strategy,
+    };
+
+    // Directories are finalized after their contents, so that their mtimes aren't bumped
+    let mut dirs = Vec::new();
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.map_err(|e| Error::Io {
+            path: e.path().unwrap_or(src).to_path_buf(),
+            source: e.into(),
+        })?;
+        let relative = entry
+            .path()
+            .strip_prefix(src)
+            .expect("walked paths are under the source");
+        let target = dst.join(relative);
+        let metadata = entry.metadata().map_err(|e| Error::Io {
+            path: entry.path().to_path_buf(),
+            source: e.into(),
+        })?;
+        let file_type = metadata.file_type();
+
+        // Whatever is in the way is replaced, which may be a directory where the source has
+        // a file, or the other way round
+        match target.symlink_metadata() {
+            Ok(existing) if file_type.is_dir() && existing.is_dir() => {}
+            Ok(_) => remove_all(&target).with_path(&target)?,
+            Err(_) => {}
+        }
+        if file_type.is_dir() {
+            fs::create_dir_all(&target).with_path(&target)?;
+            dirs.push((target, metadata));
+            continue;
+        }
+        if file_type.is_symlink() {
+            let link = fs::read_link(entry.path()).with_path(entry.path())?;
+            std::os::unix::fs::symlink(link, &target).with_path(&target)?;
+            apply_metadata(&target, &metadata).with_path(&target)?;
+        } else if file_type.is_file() {
+            used = copy_file(entry.path(), &target, used).with_path(entry.path())?;
+            if used != CopyStrategy::Hardlink {
+                apply_metadata(&target, &metadata).with_path(&target)?;
+            }
+        } else {
+            log::warn!("Skipping special file {}", entry.path().display());
+        }
+    }
+
+    for (dir, metadata) in dirs.iter().rev() {
+        apply_metadata(dir, metadata).with_path(dir)?;
+    }
+
+    Ok(used)
+}
+
+/// Moves `src` to `dst` on another filesystem, where it can't be renamed. `dst` mustn't exist.
+///
+/// `src` is copied, with its metadata, extended attributes and special files, to a hidden sibling
+/// of `dst` and synced to disk. The copy is then renamed into place, so `dst` never exposes a
+/// partial copy.
+pub(crate) fn move_across_devices(src: &Path, dst: &Path) -> Result<()> {
+    let name = dst.file_name().expect("moved paths have a file name");
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".oci-bundle-copy");
+    let temp = dst.with_file_name(temp_name);
+    if temp.symlink_metadata().is_ok() {
+        remove_all(&temp).with_path(&temp)?;
+    }
+    copy_entry(src, &temp)?;
+    fs::rename(&temp, dst).with_path(dst)?;
+    let parent = dst.parent().expect("moved paths have a parent");
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .with_path(parent)?;
+    remove_all(src).with_path(src)
+}
+
+/// Copies a single entry, recursively for directories, syncing what it writes
+fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata().with_path(src)?;
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(dst).with_path(dst)?;
+        for entry in fs::read_dir(src).with_path(src)? {
+            let name = entry.with_path(src)?.file_name();
+            copy_entry(&src.join(&name), &dst.join(&name))?;
+        }
+    } else if file_type.is_symlink() {
+        let link = fs::read_link(src).with_path(src)?;
+        std::os::unix::fs::symlink(link, dst).with_path(dst)?;
+    } else if file_type.is_file() {
+        fs::copy(src, dst).with_path(dst)?;
+    } else {
+        let path =
+            std::ffi::CString::new(dst.as_os_str().as_encoded_bytes()).map_err(|e| Error::Io {
+                path: dst.to_path_buf(),
+                source: io::Error::new(io::ErrorKind::InvalidInput, e),
+            })?;
+        // SAFETY: `path` is a valid NUL-terminated string
+        let ret = unsafe { libc::mknod(path.as_ptr(), metadata.mode(), metadata.rdev()) };
+        if ret == -1 {
+            return Err(io::Error::last_os_error()).with_path(dst);
+        }
+    }
+    copy_xattrs(src, dst).with_path(dst)?;
+    apply_metadata(dst, &metadata).with_path(dst)?;
+    if file_type.is_file() || file_type.is_dir() {
+        File::open(dst).and_then(|f| f.sync_all()).with_path(dst)?;
+    }
+    Ok(())
+}
+
+fn copy_xattrs(src: &Path, dst: &Path) -> io::Result<()> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names {
+        if let Some(value) = xattr::get(src, &name)? {
+            xattr::set(dst, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copies a single file, returning the strategy to use for subsequent files
+fn copy_file(src: &Path, dst: &Path, strategy: CopyStrategy) -> io::Result<CopyStrategy> {
+    match strategy {
+        CopyStrategy::Hardlink => match fs::hard_link(src, dst) {
+            Ok(()) => Ok(CopyStrategy::Hardlink),
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                fs::copy(src, dst)?;
+                Ok(CopyStrategy::Copy)
+            }
+            Err(e) => Err(e),
+        },
+        CopyStrategy::Reflink | CopyStrategy::Auto => match reflink(src, dst) {
+            Ok(()) => Ok(CopyStrategy::Reflink),
+            Err(e) if reflink_unsupported(&e) => {
+                log::debug!("Reflinks unsupported ({e}), copying");
+                fs::copy(src, dst)?;
+                Ok(CopyStrategy::Copy)
+            }
+            Err(e) => Err(e),
+        },
+        CopyStrategy::Copy => {
+            // std uses copy_file_range where available, which may still share extents
+            fs::copy(src, dst)?;
+            Ok(CopyStrategy::Copy)
+        }
+    }
+}
+
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    let src = File::open(src)?;
+    let dst = File::create(dst)?;
+    // SAFETY: both file descriptors are valid for the duration of the call
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Clones the content of files from the archive they're extracted from, for layers read from the
+/// decompressed blob cache, as [`crate::UnpackOptions::cache_copy_strategy`] allows. The archive
+/// is still read, and its digest verified, as it's extracted.
+pub(crate) struct ArchiveCloner {
+    archive: File,
+    strategy: CopyStrategy,
+    /// Whether cloning is still worth trying, as it isn't once the filesystems turn out not to
+    /// support it
+    clone: bool,
+    reflinked_files: u64,
+    copied_files: u64,
+}
+
+impl ArchiveCloner {
+    /// Returns a cloner for the layer `archive` with `strategy`, or `None` with
+    /// [`CopyStrategy::Copy`], as nothing is cloned
+    pub(crate) fn new(archive: File, strategy: CopyStrategy) -> Option<Self> {
+        let strategy = match strategy {
+            CopyStrategy::Copy => return None,
+            // A file in an archive can't be hard linked to
+            CopyStrategy::Hardlink => CopyStrategy::Auto,
+            strategy => strategy,
+        };
+        Some(Self {
+            archive,
+            strategy,
+            clone: true,
+            reflinked_files: 0,
+            copied_files: 0,
+        })
+    }
+
+    /// Clones the `len` bytes at `offset` in the archive into `dst`, returning whether they were
+    /// cloned. If they weren't, they must be copied: the filesystems may not support reflinks,
+    /// or be different filesystems, and only ranges aligned to the filesystem's blocks can be
+    /// cloned, which a file's content in an archive usually isn't.
+    pub(crate) fn clone_range(&mut self, offset: u64, len: u64, dst: &File) -> io::Result<bool> {
+        if self.clone {
+            let range = libc::file_clone_range {
+                src_fd: self.archive.as_raw_fd().into(),
+                src_offset: offset,
+                src_length: len,
+                dest_offset: 0,
+            };
+            // SAFETY: both file descriptors are valid for the duration of the call, and `range`
+            // is a valid `file_clone_range`
+            let ret =
+                unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range as *const _) };
+            if ret == 0 {
+                self.reflinked_files += 1;
+                return Ok(true);
+            }
+            match io::Error::last_os_error() {
+                // The range isn't aligned, but others may be
+                e if e.raw_os_error() == Some(libc::EINVAL) => {}
+                e if reflink_unsupported(&e) => {
+                    log::debug!("Reflinks unsupported ({e}), copying");
+                    self.clone = false;
+                }
+                e => return Err(e),
+            }
+        }
+        self.copied_files += 1;
+        Ok(false)
+    }
+
+    /// Returns how the content of the layer at `layer_index` was written
+    pub(crate) fn finish(self, layer_index: usize) -> LayerCopy {
+        let strategy = if self.reflinked_files > 0 {
+            CopyStrategy::Reflink
+        } else if self.copied_files > 0 {
+            CopyStrategy::Copy
+        } else {
+            self.strategy
+        };
+        LayerCopy {
+            layer_index,
+            strategy,
+            reflinked_files: self.reflinked_files,
+            copied_files: self.copied_files,
+        }
+    }
+}
+
+fn reflink_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::EXDEV | libc::ENOSYS)
+    )
+}
+
+fn apply_metadata(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if let Err(e) = lchown(path, Some(metadata.uid()), Some(metadata.gid())) {
+        // Unprivileged callers can only produce trees owned by themselves
+        if e.kind() != io::ErrorKind::PermissionDenied {
+            return Err(e);
+        }
+    }
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(path, fs::Permissions::from_mode(metadata.mode()))?;
+    }
+    let mtime = filetime::FileTime::from_last_modification_time(metadata);
+    filetime::set_symlink_file_times(path, mtime, mtime)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_tree_replaces() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("file"), "file").unwrap();
+        let dst = temp.path().join("dst");
+        // The destination has a directory where the source has a file, and the other way round
+        fs::create_dir_all(dst.join("file/nested")).unwrap();
+        fs::write(dst.join("dir"), "").unwrap();
+
+        copy_tree(&src, &dst, CopyStrategy::Copy).unwrap();
+        assert_eq!(fs::read_to_string(dst.join("file")).unwrap(), "file");
+        assert!(dst.join("dir").is_dir());
+    }
+
+    #[test]
+    fn test_move_across_devices() {
+        // The copy doesn't depend on the paths being on different filesystems
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("dir/file"), "content").unwrap();
+        fs::set_permissions(src.join("dir/file"), fs::Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink("file", src.join("dir/link")).unwrap();
+        let dst = temp.path().join("dst");
+        // Left over from an interrupted move
+        fs::write(temp.path().join(".dst.oci-bundle-copy"), "").unwrap();
+
+        move_across_devices(&src, &dst).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(dst.join("dir/file")).unwrap(), "content");
+        assert_eq!(
+            fs::metadata(dst.join("dir/file")).unwrap().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(
+            fs::read_link(dst.join("dir/link")).unwrap(),
+            Path::new("file")
+        );
+        // Only the destination is left, without the hidden copy
+        let names: Vec<_> = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["dst"]);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The time by which an unpack must finish, and how long each layer has taken so far
+pub(crate) struct Deadline {
+    start: Instant,
+    timeout: Duration,
+    layers: Mutex<LayerTimings>,
+}
+
+struct LayerTimings {
+    last_finished: Instant,
+    durations: Vec<Option<Duration>>,
+}
+
+impl Deadline {
+    pub(crate) fn new(timeout: Duration, layers: usize) -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            timeout,
+            layers: Mutex::new(LayerTimings {
+                last_finished: start,
+                durations: vec![None; layers],
+            }),
+        }
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.timeout.checked_sub(self.start.elapsed())
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match self.remaining() {
+            Some(_) => Ok(()),
+            None => Err(self.error()),
+        }
+    }
+
+    /// Records that the layer at `index` has been applied to the rootfs. Its duration is the time
+    /// since the previous layer was applied, as layers may be read concurrently.
+    pub(crate) fn layer_finished(&self, index: usize) {
+        let mut layers = self.layers.lock().unwrap();
+        let now = Instant::now();
+        layers.durations[index] = Some(now - layers.last_finished);
+        layers.last_finished = now;
+    }
+
+    fn error(&self) -> Error {
+        Error::TimedOut {
+            timeout: self.timeout,
+            layer_durations: self.layers.lock().unwrap().durations.clone(),
+        }
+    }
+
+    /// Converts an I/O error caused by passing the deadline while reading into
+    /// [`Error::TimedOut`], keeping the layer it occurred in
+    pub(crate) fn convert(&self, e: Error) -> Error {
+        match e {
+            Error::Layer {
+                index,
+                digest,
+                media_type,
+                source,
+            } => Error::Layer {
+                index,
+                digest,
+                media_type,
+                source: Box::new(self.convert(*source)),
+            },
+            Error::Archive(ref source) | Error::Io { ref source, .. }
+                if source
+                    .get_ref()
+                    .is_some_and(|inner| inner.is::<DeadlineExceeded>()) =>
+            {
+                self.error()
+            }
+            e => e,
+        }
+    }
+
+    /// Returns a reader of `inner` that fails once the deadline passes, even if a read of `inner`
+    /// is stalled.
+    ///
+    /// `inner` is read on another thread, which is left behind if a read stalls.
+    pub(crate) fn reader(
+        &self,
+        mut inner: impl Read + Send + 'static,
+        chunk_size: usize,
+    ) -> DeadlineReader<'_> {
+        let (sender, receiver) = mpsc::sync_channel(2);
+        std::thread::spawn(move || loop {
+            let mut chunk = vec![0; chunk_size];
+            let result = inner.read(&mut chunk).map(|len| {
+                chunk.truncate(len);
+                chunk
+            });
+            let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            // A send error means the reader was dropped
+            if sender.send(result).is_err() || done {
+                return;
+            }
+        });
+        DeadlineReader {
+            deadline: self,
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Reads chunks read by another thread, giving up when the deadline passes
+pub(crate) struct DeadlineReader<'a> {
+    deadline: &'a Deadline,
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            let timed_out = || io::Error::new(io::ErrorKind::TimedOut, DeadlineExceeded);
+            let remaining = self.deadline.remaining().ok_or_else(timed_out)?;
+            self.chunk = match self.receiver.recv_timeout(remaining) {
+                Ok(chunk) => chunk?,
+                Err(RecvTimeoutError::Timeout) => return Err(timed_out()),
+                // The reading thread stops after the end of the data or an error
+                Err(RecvTimeoutError::Disconnected) => Vec::new(),
+            };
+            self.pos = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,272 @@
+use openssl::sha::{Sha256, Sha384, Sha512};
+use std::io::{IoSliceMut, Read, Result};
+
+/// A digest algorithm that [`DigestReader`] can compute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Algorithm {
+    /// Returns the algorithm with `name` as it's written in a digest, e.g. `sha512`, or `None` if
+    /// it isn't supported
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Algorithm::Sha256),
+            "sha384" => Some(Algorithm::Sha384),
+            "sha512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha384 => "sha384",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Hasher {
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha384 => Hasher::Sha384(Sha384::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(sha) => sha.update(data),
+            Hasher::Sha384(sha) => sha.update(data),
+            Hasher::Sha512(sha) => sha.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(sha) => hex::encode(sha.finish()),
+            Hasher::Sha384(sha) => hex::encode(sha.finish()),
+            Hasher::Sha512(sha) => hex::encode(sha.finish()),
+        }
+    }
+}
+
+/// The hex encoded digests computed by a [`DigestReader`], one for each of its algorithms
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digests(Vec<(Algorithm, String)>);
+
+impl Digests {
+    /// Computes the digests of `data` with each of `algorithms`
+    pub fn of(data: &[u8], algorithms: &[Algorithm]) -> Self {
+        let mut reader = DigestReader::with_algorithms(data, algorithms);
+        reader.update(data);
+        reader.finish().0
+    }
+
+    /// Returns the digest computed with `algorithm`, if it was one of the reader's
+    pub fn get(&self, algorithm: Algorithm) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(a, _)| *a == algorithm)
+            .map(|(_, digest)| digest.as_str())
+    }
+
+    /// Returns the sha256 digest, which is always computed by readers created with
+    /// [`DigestReader::new`]
+    pub fn sha256(&self) -> &str {
+        self.get(Algorithm::Sha256)
+            .expect("sha256 digest wasn't computed")
+    }
+}
+
+/// Wraps a reader and calculates digests of data read from the inner reader, with any number of
+/// algorithms in a single pass
+pub struct DigestReader<R: Read> {
+    inner: R,
+    hashers: Vec<(Algorithm, Hasher)>,
+    bytes_read: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    /// Wraps `inner`, calculating its sha256 digest
+    pub fn new(inner: R) -> Self {
+        Self::with_algorithms(inner, &[Algorithm::Sha256])
+    }
+
+    /// Wraps `inner`, calculating a digest with each of `algorithms`
+    pub fn with_algorithms(inner: R, algorithms: &[Algorithm]) -> Self {
+        let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+        for &algorithm in algorithms {
+            if !hashers.iter().any(|(a, _)| *a == algorithm) {
+                hashers.push((algorithm, Hasher::new(algorithm)));
+            }
+        }
+        Self {
+            inner,
+            hashers,
+            bytes_read: 0,
+        }
+    }
+
+    /// Return a mutable reference to the inner reader. Data read from it directly is not included
+    /// in the digests.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Return the digests of the data read so far, without consuming the reader
+    pub fn digests_so_far(&self) -> Digests {
+        Digests(
+            self.hashers
+                .iter()
+                .map(|(algorithm, hasher)| (*algorithm, hasher.clone().finish()))
+                .collect(),
+        )
+    }
+
+    /// Return the number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Return the digests of the data read so far, and the inner reader.
+    ///
+    /// Unread data is not included in the digests; see [`Self::drain`].
+    pub fn finish(self) -> (Digests, R) {
+        let digests = self
+            .hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finish()))
+            .collect();
+        (Digests(digests), self.inner)
+    }
+
+    /// Read the inner reader to the end, including the data in the digests
+    pub fn drain(&mut self) -> Result<()> {
+        std::io::copy(self, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+        self.bytes_read += data.len() as u64;
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        let mut remaining = self.inner.read_vectored(bufs)?;
+        let len = remaining;
+        for buf in bufs.iter() {
+            if remaining == 0 {
+                break;
+            }
+            let filled = remaining.min(buf.len());
+            self.update(&buf[..filled]);
+            remaining -= filled;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        hex::encode(openssl::sha::sha256(data))
+    }
+
+    #[test]
+    fn test_finish_after_partial_read() {
+        let data = b"hello world";
+        let mut reader = DigestReader::new(&data[..]);
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+
+        assert_eq!(reader.bytes_read(), 5);
+        assert_eq!(reader.digests_so_far().sha256(), sha256_hex(b"hello"));
+        // Taking the digests so far doesn't disturb the running hash
+        assert_eq!(reader.digests_so_far().sha256(), sha256_hex(b"hello"));
+
+        let (digests, mut inner) = reader.finish();
+        assert_eq!(digests.sha256(), sha256_hex(b"hello"));
+        // The rest of the data was left unread
+        let mut rest = Vec::new();
+        inner.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b" world");
+    }
+
+    #[test]
+    fn test_drain_after_partial_read() {
+        let data = b"hello world";
+        let mut reader = DigestReader::new(&data[..]);
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+
+        reader.drain().unwrap();
+        assert_eq!(reader.bytes_read(), data.len() as u64);
+        let (digests, inner) = reader.finish();
+        assert_eq!(digests.sha256(), sha256_hex(data));
+        assert!(inner.is_empty());
+    }
+
+    #[test]
+    fn test_read_vectored() {
+        let data = b"hello world";
+        let mut reader = DigestReader::new(&data[..]);
+        let (mut a, mut b) = ([0; 4], [0; 4]);
+        let len = reader
+            .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)])
+            .unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(reader.bytes_read(), 8);
+        assert_eq!(reader.finish().0.sha256(), sha256_hex(b"hello wo"));
+    }
+
+    #[test]
+    fn test_algorithms() {
+        let data = b"hello world";
+        let mut reader = DigestReader::with_algorithms(
+            &data[..],
+            &[Algorithm::Sha512, Algorithm::Sha256, Algorithm::Sha512],
+        );
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+        let so_far = reader.digests_so_far();
+        assert_eq!(
+            so_far.get(Algorithm::Sha512),
+            Some(hex::encode(openssl::sha::sha512(b"hello")).as_str())
+        );
+
+        reader.drain().unwrap();
+        let (digests, _) = reader.finish();
+        assert_eq!(digests.sha256(), sha256_hex(data));
+        assert_eq!(
+            digests.get(Algorithm::Sha512),
+            Some(hex::encode(openssl::sha::sha512(data)).as_str())
+        );
+        assert_eq!(digests.get(Algorithm::Sha384), None);
+        assert_eq!(digests.0.len(), 2);
+    }
+}
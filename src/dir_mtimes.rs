@@ -0,0 +1,97 @@
+//! Restoring the mtimes of directories once all layers are applied, as writing beneath a
+//! directory changes its mtime, whichever layer the directory came from.
+//!
+//! As with the accounting in [`crate::waste`], the changes layers record are replayed in layer
+//! order to find the directories in the rootfs and the mtimes in their headers.
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::report::{EntryChange, LayerLog};
+use crate::write::file_time;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The layer and mtime of each directory entry in the rootfs, as the changes are replayed
+#[derive(Default)]
+struct Replay<'a> {
+    dirs: BTreeMap<&'a Path, (usize, u64)>,
+    root: Option<u64>,
+}
+
+impl<'a> Replay<'a> {
+    /// Removes the directories beneath `path`, and at it if `inclusive`, from layers that
+    /// `filter` accepts
+    fn remove(&mut self, path: &'a Path, inclusive: bool, filter: impl Fn(usize) -> bool) {
+        let removed: Vec<_> = self
+            .dirs
+            .range(path..)
+            .take_while(|(dir, _)| dir.starts_with(path))
+            .filter(|(dir, (layer, _))| (inclusive || **dir != path) && filter(*layer))
+            .map(|(dir, _)| *dir)
+            .collect();
+        for dir in removed {
+            self.dirs.remove(dir);
+        }
+    }
+}
+
+/// The mtimes in the headers of the directories in the rootfs, and of its root
+pub(crate) struct DirMtimes {
+    dirs: BTreeMap<PathBuf, u64>,
+    root: Option<u64>,
+}
+
+/// Replays the changes of each layer in order, returning the mtimes of the directory entries that
+/// remain
+pub(crate) fn plan(logs: &[LayerLog]) -> DirMtimes {
+    let mut logs: Vec<_> = logs.iter().collect();
+    logs.sort_by_key(|log| log.layer_index);
+    let mut replay = Replay::default();
+    for log in logs {
+        let index = log.layer_index;
+        for change in &log.changes {
+            match change {
+                EntryChange::Write { path, .. } => replay.remove(path, true, |_| true),
+                EntryChange::Dir { path, mtime } => {
+                    replay.dirs.insert(path, (index, *mtime));
+                }
+                EntryChange::Root { mtime } => replay.root = Some(*mtime),
+                EntryChange::Whiteout(path) => replay.remove(path, true, |layer| layer < index),
+                EntryChange::Opaque(dir) => replay.remove(dir, false, |layer| layer < index),
+            }
+        }
+    }
+    DirMtimes {
+        dirs: replay
+            .dirs
+            .into_iter()
+            .map(|(dir, (_, mtime))| (dir.to_path_buf(), mtime))
+            .collect(),
+        root: replay.root,
+    }
+}
+
+/// Sets the mtime of each directory in `rootfs`, deepest first, to that in the header of the
+/// last entry for it, or to `implicit` if no layer has one
+pub(crate) fn restore(rootfs: &Path, mtimes: &DirMtimes, implicit: u64) -> Result<()> {
+    for entry in walkdir::WalkDir::new(rootfs).contents_first(true) {
+        let entry = entry.map_err(|e| Error::Io {
+            path: e.path().unwrap_or(rootfs).to_path_buf(),
+            source: e.into(),
+        })?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(rootfs)
+            .expect("walked paths are under the rootfs");
+        let mtime = if relative.as_os_str().is_empty() {
+            mtimes.root
+        } else {
+            mtimes.dirs.get(relative).copied()
+        };
+        let mtime = file_time(mtime.unwrap_or(implicit));
+        filetime::set_file_times(entry.path(), mtime, mtime).with_path(relative)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,605 @@
+//! Snapshots of the options an unpack used, for [`crate::UnpackReport::effective_options`].
+
+use crate::copy::CopyStrategy;
+use crate::hermetic;
+use crate::hygiene::{HygienePolicy, HygieneRuleset};
+use crate::options::{
+    AdditionalGids, ApplyMode, BaseMismatch, CgroupVersion, ContainerEnvMarker, ControlCharacters,
+    DatabaseSource, HardlinkPolicy, ImplicitDirMtime, Overwrite, OwnershipMode,
+    ParentSymlinkPolicy, PermissionPolicy, RuntimeConfigOptions, Strictness, TimestampSource,
+    UnicodePolicy, UnpackOptions, UserDatabase, UserResolution,
+};
+#[cfg(feature = "rootfs-image")]
+use crate::rootfs_image::{RootfsImageFormat, RootfsImageOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::time::SystemTime;
+
+/// The options that only change how an unpack goes about its work, or whether it happens at all,
+/// rather than what it unpacks, so are left out of [`EffectiveOptions::digest`]
+const PROCEDURAL_OPTIONS: &[&str] = &[
+    "parallel_layers",
+    "prefetch",
+    "prefetch_spool_size",
+    "read_buffer_size",
+    "write_buffer_size",
+    "mmap_blobs",
+    "timeout",
+    "events",
+    "metrics",
+    "retry_attempts",
+    "retry_backoff",
+    "staging_dir",
+    "overwrite",
+    "base_mismatch",
+    "base_copy_strategy",
+    "reuse_sanity_check",
+    "reuse_requires_matching_options",
+    "follow_bundle_symlink",
+    "expected",
+    "decompressed_blob_cache",
+    "decompressed_blob_cache_max_bytes",
+    "cache_copy_strategy",
+    "in_memory_max_bytes",
+    "preallocate_files",
+    "checkpoint_layers",
+    "checkpoint_sample",
+];
+
+/// The options an unpack used, once defaults were applied and what depends on other options was
+/// resolved, such as what [`RuntimeConfigOptions::hermetic`] uses instead of what comes from the
+/// host. Comparing those of two unpacks of the same image is the first step in working out why
+/// their bundles differ.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EffectiveOptions {
+    /// Each of the [`UnpackOptions`], by name, with the [`RuntimeConfigOptions`] as an object
+    /// named `runtime_config`. Options with a natural JSON form have it, such as numbers, paths,
+    /// and the platform, mounts and devices as in OCI documents. Enums are the snake case names
+    /// of their variants, or objects named by them for variants with fields, and other options
+    /// are objects of their settings. Callbacks are `true` if they were set.
+    ///
+    /// The names are fixed, rather than derived from the types, so that the digest of the same
+    /// options stays the same as the crate changes.
+    pub options: Map<String, Value>,
+    /// The SHA-256 digest of the options that can change what's unpacked, which leave out those
+    /// such as [`UnpackOptions::parallel_layers`] that only change how. It's recorded in the
+    /// bundle, for [`crate::is_bundle_current_with_options`].
+    pub digest: String,
+}
+
+impl EffectiveOptions {
+    pub(crate) fn new(options: &UnpackOptions) -> Self {
+        let options = unpack_options(options);
+        let mut content = options.clone();
+        content.retain(|name, _| !PROCEDURAL_OPTIONS.contains(&name.as_str()));
+        // Without preserve_order, objects serialize with their keys sorted
+        let json = serde_json::to_vec(&content).expect("options serialize");
+        let digest = format!("sha256:{}", hex::encode(openssl::sha::sha256(&json)));
+        Self { options, digest }
+    }
+
+    /// Returns the names of the options that differ from those in `other`, in order, with those
+    /// of the runtime config prefixed with `runtime_config.`
+    pub fn differences(&self, other: &Self) -> Vec<String> {
+        let runtime_config = |options: &Map<String, Value>| {
+            options
+                .get("runtime_config")
+                .and_then(Value::as_object)
+                .cloned()
+                .unwrap_or_default()
+        };
+        let mut differences = differing_keys(&self.options, &other.options, "");
+        differences.retain(|name| name != "runtime_config");
+        differences.extend(differing_keys(
+            &runtime_config(&self.options),
+            &runtime_config(&other.options),
+            "runtime_config.",
+        ));
+        differences
+    }
+}
+
+fn differing_keys(a: &Map<String, Value>, b: &Map<String, Value>, prefix: &str) -> Vec<String> {
+    let mut keys: Vec<_> = a.keys().chain(b.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| a.get(*key) != b.get(*key))
+        .map(|key| format!("{prefix}{key}"))
+        .collect()
+}
+
+fn unpack_options(options: &UnpackOptions) -> Map<String, Value> {
+    // Destructured, so that options added later can't be left out
+    let UnpackOptions {
+        parallel_layers,
+        prefetch,
+        prefetch_spool_size,
+        read_buffer_size,
+        write_buffer_size,
+        ownership,
+        verify_digests,
+        mmap_blobs,
+        strictness,
+        timeout,
+        events,
+        metrics,
+        retry_attempts,
+        retry_backoff,
+        record_file_manifest,
+        runtime_config,
+        layer_decision,
+        per_layer_policy,
+        target_platform,
+        permission_policy,
+        staging_dir,
+        content_inspector,
+        hardlinks,
+        parent_symlinks,
+        overwrite,
+        base_mismatch,
+        base_copy_strategy,
+        reuse_sanity_check,
+        reuse_requires_matching_options,
+        follow_bundle_symlink,
+        windows_layers,
+        apply_mode,
+        expected,
+        write_env_summary,
+        analyze_waste,
+        record_changes,
+        restore_dir_mtimes,
+        timestamp_source,
+        check_layer_order,
+        #[cfg(feature = "rootfs-image")]
+        rootfs_image,
+        unicode_policy,
+        max_layer_entries,
+        max_entry_size,
+        max_link_target_len,
+        protected_paths,
+        translate_acls,
+        hygiene,
+        hygiene_rules,
+        decompressed_blob_cache,
+        decompressed_blob_cache_max_bytes,
+        cache_copy_strategy,
+        reference,
+        after_layer,
+        before_config,
+        in_memory_max_bytes,
+        preallocate_files,
+        strip_write_bits,
+        checkpoint_layers,
+        checkpoint_sample,
+    } = options;
+    let timestamp_source = if runtime_config.hermetic {
+        hermetic::timestamp_source(*timestamp_source, &mut Vec::new())
+    } else {
+        *timestamp_source
+    };
+    let protected_paths: Vec<_> = protected_paths.iter().map(ToString::to_string).collect();
+    let options: Map<String, Value> = [
+        ("parallel_layers", json!(parallel_layers)),
+        ("prefetch", json!(prefetch)),
+        ("prefetch_spool_size", json!(prefetch_spool_size)),
+        ("read_buffer_size", json!(read_buffer_size)),
+        ("write_buffer_size", json!(write_buffer_size)),
+        ("ownership", ownership_name(*ownership).into()),
+        ("verify_digests", json!(verify_digests)),
+        ("mmap_blobs", json!(mmap_blobs)),
+        ("strictness", strictness_name(*strictness).into()),
+        ("timeout", json!(timeout)),
+        ("events", json!(events.is_some())),
+        ("metrics", json!(metrics.is_some())),
+        ("retry_attempts", json!(retry_attempts)),
+        ("retry_backoff", json!(retry_backoff)),
+        ("record_file_manifest", json!(record_file_manifest)),
+        ("runtime_config", runtime_config_options(runtime_config)),
+        ("layer_decision", json!(layer_decision.is_some())),
+        ("per_layer_policy", json!(per_layer_policy.is_some())),
+        ("target_platform", json!(target_platform)),
+        (
+            "permission_policy",
+            permission_policy_value(permission_policy),
+        ),
+        ("staging_dir", json!(staging_dir)),
+        ("content_inspector", json!(content_inspector.is_some())),
+        ("hardlinks", hardlinks_name(*hardlinks).into()),
+        (
+            "parent_symlinks",
+            parent_symlinks_name(*parent_symlinks).into(),
+        ),
+        ("overwrite", overwrite_name(*overwrite).into()),
+        ("base_mismatch", base_mismatch_name(*base_mismatch).into()),
+        (
+            "base_copy_strategy",
+            copy_strategy_name(*base_copy_strategy).into(),
+        ),
+        ("reuse_sanity_check", json!(reuse_sanity_check)),
+        (
+            "reuse_requires_matching_options",
+            json!(reuse_requires_matching_options),
+        ),
+        ("follow_bundle_symlink", json!(follow_bundle_symlink)),
+        ("windows_layers", json!(windows_layers)),
+        ("apply_mode", apply_mode_value(*apply_mode)),
+        ("expected", json!(expected)),
+        ("write_env_summary", json!(write_env_summary)),
+        ("analyze_waste", json!(analyze_waste)),
+        ("record_changes", json!(record_changes)),
+        (
+            "restore_dir_mtimes",
+            json!(restore_dir_mtimes.map(dir_mtime_name)),
+        ),
+        ("timestamp_source", timestamp_source_value(timestamp_source)),
+        ("check_layer_order", json!(check_layer_order)),
+        (
+            "unicode_policy",
+            unicode_policy_name(*unicode_policy).into(),
+        ),
+        ("max_layer_entries", json!(max_layer_entries)),
+        ("max_entry_size", json!(max_entry_size)),
+        ("max_link_target_len", json!(max_link_target_len)),
+        ("protected_paths", json!(protected_paths)),
+        ("translate_acls", json!(translate_acls)),
+        ("hygiene", hygiene_name(*hygiene).into()),
+        ("hygiene_rules", hygiene_rules_value(hygiene_rules)),
+        ("decompressed_blob_cache", json!(decompressed_blob_cache)),
+        (
+            "decompressed_blob_cache_max_bytes",
+            json!(decompressed_blob_cache_max_bytes),
+        ),
+        (
+            "cache_copy_strategy",
+            copy_strategy_name(*cache_copy_strategy).into(),
+        ),
+        (
+            "reference",
+            json!(reference.as_ref().map(ToString::to_string)),
+        ),
+        ("after_layer", json!(after_layer.is_some())),
+        ("before_config", json!(before_config.is_some())),
+        ("in_memory_max_bytes", json!(in_memory_max_bytes)),
+        ("preallocate_files", json!(preallocate_files)),
+        ("strip_write_bits", json!(strip_write_bits)),
+        ("checkpoint_layers", json!(checkpoint_layers)),
+        ("checkpoint_sample", json!(checkpoint_sample)),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+    // Only when set, so that the digest of options without it doesn't depend on the feature
+    #[cfg(feature = "rootfs-image")]
+    let mut options = options;
+    #[cfg(feature = "rootfs-image")]
+    if let Some(rootfs_image) = rootfs_image {
+        options.insert("rootfs_image".to_string(), rootfs_image_value(rootfs_image));
+    }
+    options
+}
+
+fn runtime_config_options(options: &RuntimeConfigOptions) -> Value {
+    let RuntimeConfigOptions {
+        user_resolution,
+        user_database,
+        additional_gids,
+        passthrough_unconverted,
+        mounts,
+        devices,
+        allowed_spec_issues,
+        create_working_dir,
+        container_env_marker,
+        entrypoint_override,
+        cmd_override,
+        args_prepend,
+        annotation_control_characters,
+        max_annotation_value_len,
+        max_annotations_size,
+        file_capabilities_annotation,
+        legacy_resource_fields,
+        cgroup_version,
+        readonly_rootfs,
+        hermetic,
+    } = options;
+    let user_resolution = if *hermetic {
+        hermetic::user_resolution(user_resolution, &mut Vec::new())
+    } else {
+        user_resolution.clone()
+    };
+    let allowed_spec_issues: Vec<_> = allowed_spec_issues
+        .iter()
+        .map(|code| code.as_str())
+        .collect();
+    let options: Map<String, Value> = [
+        (
+            "user_resolution",
+            user_resolution_name(&user_resolution).into(),
+        ),
+        ("user_database", user_database_value(user_database)),
+        ("additional_gids", additional_gids_value(additional_gids)),
+        ("passthrough_unconverted", json!(passthrough_unconverted)),
+        ("mounts", json!(mounts)),
+        ("devices", json!(devices)),
+        ("allowed_spec_issues", json!(allowed_spec_issues)),
+        ("create_working_dir", json!(create_working_dir)),
+        (
+            "container_env_marker",
+            container_env_marker_value(container_env_marker),
+        ),
+        ("entrypoint_override", json!(entrypoint_override)),
+        ("cmd_override", json!(cmd_override)),
+        ("args_prepend", json!(args_prepend)),
+        (
+            "annotation_control_characters",
+            control_characters_name(*annotation_control_characters).into(),
+        ),
+        ("max_annotation_value_len", json!(max_annotation_value_len)),
+        ("max_annotations_size", json!(max_annotations_size)),
+        (
+            "file_capabilities_annotation",
+            json!(file_capabilities_annotation),
+        ),
+        ("legacy_resource_fields", json!(legacy_resource_fields)),
+        (
+            "cgroup_version",
+            cgroup_version_name(*cgroup_version).into(),
+        ),
+        ("readonly_rootfs", json!(readonly_rootfs)),
+        ("hermetic", json!(hermetic)),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+    Value::Object(options)
+}
+
+// The names of each option's values, which mustn't change, as the digest recorded in bundles
+// depends on them
+
+fn ownership_name(ownership: OwnershipMode) -> &'static str {
+    match ownership {
+        OwnershipMode::Preserve => "preserve",
+        OwnershipMode::Ignore => "ignore",
+        OwnershipMode::Emulate => "emulate",
+    }
+}
+
+fn strictness_name(strictness: Strictness) -> &'static str {
+    match strictness {
+        Strictness::Strict => "strict",
+        Strictness::Permissive => "permissive",
+    }
+}
+
+fn permission_policy_value(policy: &PermissionPolicy) -> Value {
+    let PermissionPolicy {
+        strip_setuid,
+        strip_setgid,
+        clamp_world_writable,
+        clamp_sticky_dirs,
+    } = policy;
+    json!({
+        "strip_setuid": strip_setuid,
+        "strip_setgid": strip_setgid,
+        "clamp_world_writable": clamp_world_writable,
+        "clamp_sticky_dirs": clamp_sticky_dirs,
+    })
+}
+
+fn hardlinks_name(policy: HardlinkPolicy) -> &'static str {
+    match policy {
+        HardlinkPolicy::Preserve => "preserve",
+        HardlinkPolicy::Copy => "copy",
+    }
+}
+
+fn parent_symlinks_name(policy: ParentSymlinkPolicy) -> &'static str {
+    match policy {
+        ParentSymlinkPolicy::Reject => "reject",
+        ParentSymlinkPolicy::Replace => "replace",
+    }
+}
+
+fn overwrite_name(overwrite: Overwrite) -> &'static str {
+    match overwrite {
+        Overwrite::Always => "always",
+        Overwrite::ReuseIfMatching => "reuse_if_matching",
+    }
+}
+
+fn base_mismatch_name(mismatch: BaseMismatch) -> &'static str {
+    match mismatch {
+        BaseMismatch::Unpack => "unpack",
+        BaseMismatch::Fail => "fail",
+    }
+}
+
+fn copy_strategy_name(strategy: CopyStrategy) -> &'static str {
+    match strategy {
+        CopyStrategy::Auto => "auto",
+        CopyStrategy::Hardlink => "hardlink",
+        CopyStrategy::Reflink => "reflink",
+        CopyStrategy::Copy => "copy",
+    }
+}
+
+fn apply_mode_value(mode: ApplyMode) -> Value {
+    match mode {
+        ApplyMode::Rootfs => json!("rootfs"),
+        ApplyMode::OverlayUpper { userxattr } => {
+            json!({ "overlay_upper": { "userxattr": userxattr } })
+        }
+    }
+}
+
+fn dir_mtime_name(mtime: ImplicitDirMtime) -> &'static str {
+    match mtime {
+        ImplicitDirMtime::Epoch => "epoch",
+        ImplicitDirMtime::ImageCreated => "image_created",
+        ImplicitDirMtime::BundleTimestamp => "bundle_timestamp",
+    }
+}
+
+fn timestamp_source_value(source: TimestampSource) -> Value {
+    match source {
+        TimestampSource::Clock => json!("clock"),
+        TimestampSource::ImageCreated => json!("image_created"),
+        TimestampSource::Fixed(time) => {
+            // As for the timeout, or null for a time before the epoch
+            json!({ "fixed": time.duration_since(SystemTime::UNIX_EPOCH).ok() })
+        }
+    }
+}
+
+fn unicode_policy_name(policy: UnicodePolicy) -> &'static str {
+    match policy {
+        UnicodePolicy::Allow => "allow",
+        #[cfg(feature = "unicode")]
+        UnicodePolicy::Warn => "warn",
+        #[cfg(feature = "unicode")]
+        UnicodePolicy::Reject => "reject",
+        #[cfg(feature = "unicode")]
+        UnicodePolicy::NormalizeNfc => "normalize_nfc",
+    }
+}
+
+fn hygiene_name(policy: HygienePolicy) -> &'static str {
+    match policy {
+        HygienePolicy::Off => "off",
+        HygienePolicy::Report => "report",
+        HygienePolicy::Strip => "strip",
+    }
+}
+
+fn hygiene_rules_value(rules: &HygieneRuleset) -> Value {
+    let HygieneRuleset {
+        rules,
+        max_content_size,
+    } = rules;
+    let rules: Vec<_> = rules
+        .iter()
+        .map(|rule| {
+            let paths: Vec<_> = rule.paths.iter().map(ToString::to_string).collect();
+            let markers: Vec<_> = rule.markers.iter().map(hex::encode).collect();
+            json!({ "name": rule.name, "paths": paths, "markers": markers })
+        })
+        .collect();
+    json!({ "rules": rules, "max_content_size": max_content_size })
+}
+
+#[cfg(feature = "rootfs-image")]
+fn rootfs_image_value(options: &RootfsImageOptions) -> Value {
+    let RootfsImageOptions {
+        format,
+        keep_rootfs_dir,
+        build_time,
+        tool,
+    } = options;
+    let format = match format {
+        RootfsImageFormat::Squashfs => "squashfs",
+        RootfsImageFormat::Erofs => "erofs",
+    };
+    json!({
+        "format": format,
+        "keep_rootfs_dir": keep_rootfs_dir,
+        "build_time": build_time,
+        "tool": tool,
+    })
+}
+
+fn user_resolution_name(resolution: &UserResolution) -> &'static str {
+    match resolution {
+        UserResolution::Rootfs => "rootfs",
+        UserResolution::Host => "host",
+        UserResolution::Skip => "skip",
+        UserResolution::Custom(_) => "custom",
+    }
+}
+
+fn user_database_value(database: &UserDatabase) -> Value {
+    let UserDatabase { passwd, group } = database;
+    let sources = |sources: &[DatabaseSource]| -> Vec<Value> {
+        sources
+            .iter()
+            .map(|source| match source {
+                DatabaseSource::Content(content) => json!({ "content": content }),
+                DatabaseSource::File(path) => json!({ "file": path }),
+            })
+            .collect()
+    };
+    json!({ "passwd": sources(passwd), "group": sources(group) })
+}
+
+fn additional_gids_value(gids: &AdditionalGids) -> Value {
+    match gids {
+        AdditionalGids::Resolve => json!("resolve"),
+        AdditionalGids::Skip => json!("skip"),
+        AdditionalGids::Explicit(gids) => json!({ "explicit": gids }),
+    }
+}
+
+fn container_env_marker_value(marker: &ContainerEnvMarker) -> Value {
+    match marker {
+        ContainerEnvMarker::None => json!("none"),
+        ContainerEnvMarker::DockerEnv => json!("docker_env"),
+        ContainerEnvMarker::ContainerEnv { contents } => {
+            json!({ "container_env": { "contents": contents } })
+        }
+    }
+}
+
+fn control_characters_name(characters: ControlCharacters) -> &'static str {
+    match characters {
+        ControlCharacters::Keep => "keep",
+        ControlCharacters::Strip => "strip",
+        ControlCharacters::Escape => "escape",
+    }
+}
+
+fn cgroup_version_name(version: CgroupVersion) -> &'static str {
+    match version {
+        CgroupVersion::V1 => "v1",
+        CgroupVersion::V2 => "v2",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        let defaults = EffectiveOptions::new(&UnpackOptions::new());
+        assert_eq!(defaults.options["ownership"], "preserve");
+        assert_eq!(defaults.options["layer_decision"], false);
+        assert_eq!(
+            defaults.options["runtime_config"]["user_resolution"],
+            "rootfs"
+        );
+        // Bundles record the digest, so the same options must keep it
+        assert_eq!(
+            defaults.digest,
+            "sha256:275e02641df44e229d1734113627f18397e74611c8678ccbf69abca5872eb8b3"
+        );
+
+        // Options that only change how the image is unpacked don't change the digest
+        let parallel = EffectiveOptions::new(&UnpackOptions::new().parallel_layers(4));
+        assert_eq!(parallel.differences(&defaults), ["parallel_layers"]);
+        assert_eq!(parallel.digest, defaults.digest);
+
+        let ignored = EffectiveOptions::new(&UnpackOptions::new().ownership(OwnershipMode::Ignore));
+        assert_eq!(ignored.differences(&defaults), ["ownership"]);
+        assert_ne!(ignored.digest, defaults.digest);
+
+        let hermetic = EffectiveOptions::new(
+            &UnpackOptions::new().runtime_config(RuntimeConfigOptions::new().hermetic(true)),
+        );
+        assert_eq!(
+            hermetic.differences(&defaults),
+            ["timestamp_source", "runtime_config.hermetic"]
+        );
+        assert_eq!(hermetic.options["timestamp_source"], "image_created");
+    }
+}
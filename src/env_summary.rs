@@ -0,0 +1,110 @@
+use crate::error::{IoResultExt, Result};
+use crate::reference::ImageReference;
+use ocidir::oci_spec::image::ImageConfiguration;
+use ocidir::oci_spec::runtime::Spec;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// The name of the file in a bundle summarizing its runtime config as shell variables, written
+/// by [`crate::UnpackOptions::write_env_summary`]
+pub(crate) const ENV_SUMMARY: &str = "bundle.env";
+
+/// Writes the process's user, arguments and stop signal from `runtime_config`, along with the
+/// image's exposed ports and the reference it was unpacked by, as shell variable assignments that
+/// can be sourced
+pub(crate) fn write(
+    bundle: &Path,
+    runtime_config: &Spec,
+    image_config: &ImageConfiguration,
+    reference: Option<&ImageReference>,
+) -> Result<()> {
+    let process = runtime_config.process().as_ref();
+    let user = process.map(|process| process.user());
+    let args = process
+        .and_then(|process| process.args().as_ref())
+        .map(|args| {
+            args.iter()
+                .map(|arg| quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .unwrap_or_default();
+    let config = image_config.config().as_ref();
+    let stop_signal = config
+        .and_then(|config| config.stop_signal().clone())
+        .unwrap_or_default();
+    let mut ports = config
+        .and_then(|config| config.exposed_ports().clone())
+        .unwrap_or_default();
+    ports.sort();
+
+    let mut summary = String::new();
+    for (name, value) in [
+        (
+            "UID",
+            user.map(|user| user.uid().to_string()).unwrap_or_default(),
+        ),
+        (
+            "GID",
+            user.map(|user| user.gid().to_string()).unwrap_or_default(),
+        ),
+        ("ARGS", args),
+        ("STOP_SIGNAL", stop_signal),
+        ("PORTS", ports.join(" ")),
+        (
+            "IMAGE_REF",
+            reference.map(ImageReference::to_string).unwrap_or_default(),
+        ),
+    ] {
+        let _ = writeln!(summary, "OCI_BUNDLE_{name}={}", quote(&value));
+    }
+    let path = bundle.join(ENV_SUMMARY);
+    fs::write(&path, summary).with_path(&path)
+}
+
+/// Quotes `value` for the shell, in single quotes, within which nothing is special but the
+/// closing quote. Single quotes are written as `'\''`, closing the quotes around an escaped quote.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("$HOME\n`id`"), "'$HOME\n`id`'");
+    }
+
+    #[test]
+    fn test_sourced() {
+        let bundle = tempfile::tempdir().unwrap();
+        let args = ["printf", "%s\\n", "it's", "two\nlines", "\"$HOME\" `id`"];
+        let mut runtime_config = Spec::default();
+        let mut process = runtime_config.process().clone().unwrap_or_default();
+        process.set_args(Some(args.map(str::to_string).to_vec()));
+        runtime_config.set_process(Some(process));
+        let image_config = ocidir::oci_spec::image::ImageConfigurationBuilder::default()
+            .build()
+            .unwrap();
+        write(bundle.path(), &runtime_config, &image_config, None).unwrap();
+
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(r#". "$1" && eval "set -- $OCI_BUNDLE_ARGS" && printf '[%s]' "$@""#)
+            .arg("sh")
+            .arg(bundle.path().join(ENV_SUMMARY))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            args.map(|arg| format!("[{arg}]")).concat()
+        );
+    }
+}
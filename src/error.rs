@@ -0,0 +1,432 @@
+use crate::plan::PlanDeviation;
+use crate::report::{UnicodeCollision, Warning};
+use crate::spec::SpecIssue;
+use crate::verify::VerifyBundleReport;
+use ocidir::oci_spec::image::Descriptor;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A specialized [`Result`](std::result::Result) type for this crate's operations
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by this crate
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Content didn't match the digest it's addressed by
+    #[error("{kind} mismatch. Expected {expected}. Discovered {actual}")]
+    DigestMismatch {
+        /// The index of the layer, or `None` for the image config
+        layer_index: Option<usize>,
+        kind: DigestKind,
+        expected: String,
+        actual: String,
+    },
+    /// A file in an eStargz layer didn't match the digest in the layer's table of contents
+    #[error("Content of {} in layer {layer_index} doesn't match its eStargz TOC. Expected {expected}. Discovered {actual}", .path.display())]
+    TocMismatch {
+        layer_index: usize,
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// A blob's size didn't match the size in its descriptor
+    #[error(
+        "{} size mismatch. Expected size {expected}. Discovered size {actual}",
+        .layer_index.map_or("Config", |_| "Layer")
+    )]
+    SizeMismatch {
+        /// The index of the layer, or `None` for the image config
+        layer_index: Option<usize>,
+        expected: u64,
+        actual: u64,
+    },
+    /// A layer blob continues after the end of its last gzip member with something other than
+    /// another member, such as padding
+    #[error("Layer has {bytes} bytes after the end of its gzip stream")]
+    TrailingData { layer_index: usize, bytes: u64 },
+    /// The manifest's config descriptor has a media type other than that of an OCI image config or
+    /// a Docker container config, as artifacts such as Helm charts and attestations do
+    #[error("Config media type {media_type} isn't an image config")]
+    NotAnImageConfig { media_type: String },
+    /// A layer's media type isn't one that can be unpacked
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    /// A digest uses an algorithm other than SHA-256, SHA-384 or SHA-512
+    #[error("Unsupported digest algorithm: {0}")]
+    UnsupportedDigestAlgorithm(String),
+    /// [`crate::UnpackOptions::layer_decision`] returned [`crate::LayerDecision::Abort`] for a layer
+    #[error("Aborted by the layer decision callback")]
+    LayerAborted,
+    /// The manifest and image config disagree on the number of layers. `diagnostic` describes
+    /// the config's history, which may show which of them is wrong.
+    #[error(
+        "Mismatch between number of layers and diff IDs: {layers} != {diff_ids}\n{diagnostic}"
+    )]
+    LayerCountMismatch {
+        layers: usize,
+        diff_ids: usize,
+        diagnostic: String,
+    },
+    /// The image config's history records a different number of layers than the manifest and
+    /// diff IDs, which is only an error with [`crate::Strictness::Strict`]
+    #[error(
+        "The image config's history records {history_layers} layers, but the image has {layers}\n{diagnostic}"
+    )]
+    HistoryMismatch {
+        layers: usize,
+        history_layers: usize,
+        diagnostic: String,
+    },
+    /// The [`crate::UnpackOptions::content_inspector`] failed on the file at `path`
+    #[error("Failed to inspect {}", .path.display())]
+    ContentInspection {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+    /// The [`crate::UnpackOptions::after_layer`] or [`crate::UnpackOptions::before_config`] hook
+    /// named `hook` failed
+    #[error("The {hook} hook failed")]
+    Hook {
+        hook: &'static str,
+        #[source]
+        source: Box<Error>,
+    },
+    /// The parent of an entry is a symlink to `target`, outside the rootfs, which
+    /// [`crate::ParentSymlinkPolicy::Reject`] refuses to write beneath
+    #[error(
+        "Refusing to write {} beneath {}, a symlink to {} outside the rootfs",
+        .path.display(),
+        .parent.display(),
+        .target.display()
+    )]
+    SymlinkedParent {
+        path: PathBuf,
+        parent: PathBuf,
+        target: PathBuf,
+    },
+    /// An entry would modify `path`, which is protected by `pattern` from
+    /// [`crate::UnpackOptions::protected_paths`]
+    #[error("Entry {} modifies a path protected by {pattern}", .path.display())]
+    ProtectedPath { path: PathBuf, pattern: String },
+    /// A pattern passed to [`crate::GlobPattern::new`] has no components, or has `.` or `..`
+    /// components
+    #[error("Invalid glob pattern: {0}")]
+    InvalidGlobPattern(String),
+    /// The [`crate::RuntimeConfigOptions`] would generate an invalid runtime config
+    #[error("Invalid runtime config options: {0}")]
+    InvalidRuntimeConfigOptions(String),
+    /// The generated runtime config has issues, found with [`crate::Strictness::Strict`], that
+    /// [`crate::RuntimeConfigOptions::allow_spec_issue`] didn't allow. All its issues are listed.
+    #[error("Invalid runtime config: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    InvalidSpec(Vec<SpecIssue>),
+    /// The image config's user or group couldn't be resolved on this host
+    #[error("{0}")]
+    UserResolution(String),
+    /// Reading or decompressing a layer's archive failed
+    #[error("Failed to read layer archive")]
+    Archive(#[source] io::Error),
+    /// A filesystem operation on `path` failed
+    #[error("Failed to access {}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// A blob referenced by the image couldn't be opened
+    #[error(
+        "Failed to read {role} {digest} ({expected_size} bytes) at {}",
+        .path.display()
+    )]
+    Blob {
+        role: BlobRole,
+        digest: String,
+        expected_size: u64,
+        /// The blob's path, which is relative to the layout if the layout's path is unknown
+        path: PathBuf,
+        #[source]
+        source: BlobError,
+    },
+    /// Several blobs referenced by the image couldn't be opened, found before the bundle was
+    /// touched. Each error is an [`Error::Blob`], within an [`Error::Layer`] for layer blobs.
+    #[error("{} blobs couldn't be read:{}", .0.len(), format_errors(.0))]
+    InaccessibleBlobs(Vec<Error>),
+    /// A path differs from another only in its Unicode normalization form, with
+    /// [`crate::UnicodePolicy::Reject`]
+    #[error("{0}")]
+    UnicodeCollision(UnicodeCollision),
+    /// A layer has more entries than [`crate::UnpackOptions::max_layer_entries`] allows
+    #[error("Layer has more than {limit} entries")]
+    TooManyEntries { limit: usize },
+    /// The entry at `path` declares `size` bytes of content, more than
+    /// [`crate::UnpackOptions::max_entry_size`] allows
+    #[error("{} declares {size} bytes, more than the limit of {limit}", .path.display())]
+    EntryTooLarge {
+        path: PathBuf,
+        size: u64,
+        limit: u64,
+    },
+    /// [`crate::extract_path`] found nothing at `path` in the image, as no layer has it or a
+    /// whiteout removed it
+    #[error("{} isn't in the image", .0.display())]
+    NotFound(PathBuf),
+    /// [`crate::unpack_digest`] found no blob with `digest` in the layout's `blobs` directory
+    #[error("{digest} isn't in {}", .blobs.display())]
+    ManifestNotFound {
+        digest: String,
+        /// The `blobs` directory of the layout, or `blobs` if the layout's path is unknown
+        blobs: PathBuf,
+    },
+    /// A string passed to [`crate::ImageReference::parse`] isn't a valid `name[:tag][@digest]`
+    #[error("Invalid image reference {reference:?}: {reason}")]
+    InvalidReference { reference: String, reason: String },
+    /// [`crate::unpack_ref`] found no manifest in the layout's `index.json` whose
+    /// `org.opencontainers.image.ref.name` annotation names `reference`
+    #[error("No manifest in the image index is tagged {reference}")]
+    ReferenceNotFound { reference: String },
+    /// No manifest in an image index is for the target platform, so there's nothing to unpack.
+    /// `available` lists the platforms of those that are, as `os/arch[/variant]`.
+    #[error(
+        "No manifest in the image index is for {platform}. Available: {}",
+        .available.join(", ")
+    )]
+    NoMatchingPlatform {
+        platform: String,
+        available: Vec<String>,
+    },
+    /// The OCI image layout couldn't be read
+    #[error(transparent)]
+    Layout(#[from] ocidir::Error),
+    /// An image or runtime spec couldn't be parsed, built or written
+    #[error(transparent)]
+    Spec(#[from] ocidir::oci_spec::OciSpecError),
+    /// The unpack didn't finish within [`crate::UnpackOptions::timeout`]
+    #[error(
+        "Timed out after {timeout:?}. Layer durations: {}",
+        format_durations(.layer_durations)
+    )]
+    TimedOut {
+        timeout: Duration,
+        /// How long each layer took to apply, or `None` if it wasn't applied
+        layer_durations: Vec<Option<Duration>>,
+    },
+    /// Building the rootfs image requested by `UnpackOptions::rootfs_image` with `tool` failed
+    #[error("Failed to build rootfs image with {}: {message}", .tool.display())]
+    RootfsImage { tool: PathBuf, message: String },
+    /// Running `zstd` to compress or decompress a bundle archive failed
+    #[error("Failed to run zstd: {0}")]
+    Zstd(String),
+    /// The image is for Windows, whose layers are only flattened with
+    /// [`crate::UnpackOptions::windows_layers`]
+    #[error("Image is for Windows, whose layers can only be flattened for inspection")]
+    WindowsImage,
+    /// The bundle path is a symlink, which is only followed with
+    /// [`crate::UnpackOptions::follow_bundle_symlink`]
+    #[error("Bundle {} is a symlink", .0.display())]
+    SymlinkBundle(PathBuf),
+    /// The mappings given to [`crate::remap_bundle_ownership`] are empty, out of range or overlap
+    #[error("Invalid ID mappings: {0}")]
+    InvalidIdMappings(String),
+    /// An interrupted [`crate::remap_bundle_ownership`] of the bundle can't be resumed, as it
+    /// used other mappings or the rootfs has changed since
+    #[error("Bundle {} has an interrupted remap that can't be resumed", .0.display())]
+    InterruptedRemap(PathBuf),
+    /// The base bundle given to [`crate::unpack_derived`] wasn't completely unpacked from a
+    /// prefix of the image's layers, with [`crate::BaseMismatch::Fail`]
+    #[error("Image doesn't extend base bundle {}", .0.display())]
+    BaseMismatch(PathBuf),
+    /// The bundle contains the [`crate::INCOMPLETE_SENTINEL`], so it wasn't completely unpacked
+    #[error("Bundle {} is incomplete", .0.display())]
+    IncompleteBundle(PathBuf),
+    /// [`crate::Bundle::open`] found that the directory at `path` isn't laid out as a bundle
+    #[error("{} isn't a bundle", .path.display())]
+    NotABundle {
+        path: PathBuf,
+        #[source]
+        source: BundleError,
+    },
+    /// A bundle's rootfs differs from the file manifest recorded when it was unpacked
+    #[error("Bundle has been modified: {0}")]
+    BundleModified(Box<VerifyBundleReport>),
+    /// A problem that is only a warning with [`crate::Strictness::Permissive`]
+    #[error("{0}")]
+    Warning(Warning),
+    /// An extracted layer differed from [`crate::UnpackOptions::expected`], with
+    /// [`crate::Strictness::Strict`]
+    #[error("{0}")]
+    PlanDeviation(PlanDeviation),
+    /// Applying the whiteout entry at `path` failed
+    #[error("Failed to apply whiteout {}", .path.display())]
+    Whiteout {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Unpacking a layer failed
+    #[error("Failed to unpack layer {index} ({digest}, {media_type})")]
+    Layer {
+        index: usize,
+        digest: String,
+        media_type: String,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Returns the error that caused this one, looking through the layer and whiteout entry it
+    /// occurred in
+    pub fn without_layer(&self) -> &Error {
+        match self {
+            Error::Layer { source, .. } | Error::Whiteout { source, .. } => source.without_layer(),
+            e => e,
+        }
+    }
+
+    pub(crate) fn in_layer(self, index: usize, descriptor: &Descriptor) -> Self {
+        Error::Layer {
+            index,
+            digest: descriptor.digest().to_string(),
+            media_type: descriptor.media_type().to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    pub(crate) fn in_whiteout(self, path: impl AsRef<Path>) -> Self {
+        Error::Whiteout {
+            path: path.as_ref().to_path_buf(),
+            source: Box::new(self),
+        }
+    }
+}
+
+fn format_errors(errors: &[Error]) -> String {
+    errors
+        .iter()
+        .map(|e| match e.without_layer() {
+            Error::Blob { source, .. } => format!("\n  {}: {source}", e.without_layer()),
+            _ => format!("\n  {e}"),
+        })
+        .collect()
+}
+
+fn format_durations(durations: &[Option<Duration>]) -> String {
+    let durations: Vec<_> = durations
+        .iter()
+        .map(|d| d.map_or("-".to_string(), |d| format!("{d:.1?}")))
+        .collect();
+    durations.join(", ")
+}
+
+/// Which digest of a blob didn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DigestKind {
+    /// The digest a manifest was addressed by, checked by [`crate::unpack_manifest_bytes`]
+    Manifest,
+    /// The digest of the image config blob
+    Config,
+    /// The digest of a compressed layer blob
+    Layer,
+    /// The digest of a layer's uncompressed archive
+    DiffId,
+    /// The digest of an eStargz layer's table of contents
+    Toc,
+}
+
+impl fmt::Display for DigestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DigestKind::Manifest => "Manifest digest",
+            DigestKind::Config => "Config digest",
+            DigestKind::Layer => "Layer digest",
+            DigestKind::DiffId => "Diff ID",
+            DigestKind::Toc => "eStargz TOC digest",
+        })
+    }
+}
+
+/// Attaches the path being operated on to I/O errors
+pub(crate) trait IoResultExt<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// What a blob is used for, for describing it in errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlobRole {
+    Config,
+    /// The layer at `index` of the image's `count` layers, numbered from 1 when displayed
+    Layer {
+        index: usize,
+        count: usize,
+    },
+}
+
+impl fmt::Display for BlobRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobRole::Config => f.write_str("config blob"),
+            BlobRole::Layer { index, count } => write!(f, "layer {} of {count}", index + 1),
+        }
+    }
+}
+
+/// Why a blob couldn't be opened
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BlobError {
+    #[error("Blob is missing from the layout")]
+    Missing(#[source] io::Error),
+    #[error("Permission denied")]
+    PermissionDenied(#[source] io::Error),
+    #[error("Blob is {actual} bytes")]
+    WrongSize { actual: u64 },
+    #[error(transparent)]
+    Other(ocidir::Error),
+}
+
+impl From<ocidir::Error> for BlobError {
+    fn from(e: ocidir::Error) -> Self {
+        match e {
+            ocidir::Error::Io(e) if e.kind() == io::ErrorKind::NotFound => BlobError::Missing(e),
+            ocidir::Error::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                BlobError::PermissionDenied(e)
+            }
+            ocidir::Error::SizeMismatch { found, .. } => BlobError::WrongSize { actual: found },
+            e => BlobError::Other(e),
+        }
+    }
+}
+
+/// Why a directory isn't a bundle
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BundleError {
+    #[error("Directory doesn't exist")]
+    Missing(#[source] io::Error),
+    #[error("Not a directory")]
+    NotADirectory,
+    #[error("Bundle has no rootfs directory")]
+    MissingRootfs,
+    #[error("Bundle has no config.json")]
+    MissingConfig,
+    #[error("Bundle's config.json isn't a runtime config")]
+    InvalidConfig(#[source] serde_json::Error),
+    #[error("Bundle's metadata is unreadable")]
+    InvalidMetadata(#[source] serde_json::Error),
+    #[error("Bundle's metadata has unknown version {version}")]
+    UnsupportedMetadataVersion { version: u32 },
+}
@@ -0,0 +1,247 @@
+//! Verification of eStargz layers against their table of contents.
+//!
+//! An eStargz layer is a gzip compressed tar in which each file's content starts a new gzip
+//! member, followed by a member holding the table of contents (TOC), `stargz.index.json`, and a
+//! footer member recording the TOC's offset. The TOC lists each file with the offset of its
+//! content in the blob and its digest, so each file can be checked on its own.
+
+use crate::digest_reader::DigestReader;
+use crate::error::{DigestKind, Error, IoResultExt, Result};
+use crate::events::Event;
+use crate::UnpackOptions;
+use flate2::bufread::{GzDecoder, MultiGzDecoder};
+use ocidir::oci_spec::image::Descriptor;
+use serde::Deserialize;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// The layer annotation holding the digest of the uncompressed TOC
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+
+/// The name of the tar entry holding the TOC
+pub(crate) const TOC_NAME: &str = "stargz.index.json";
+
+/// Entries at the root of eStargz layers that describe the layer rather than being part of the
+/// rootfs, so aren't extracted
+const RESERVED_NAMES: [&str; 3] = [TOC_NAME, ".prefetch.landmark", ".no.prefetch.landmark"];
+
+/// The footer is the last member, a gzip header with an empty body. Its extra field ends with the
+/// TOC's offset as 16 hex digits, followed by this.
+const FOOTER_MAGIC: &[u8] = b"STARGZ";
+
+/// Enough of the blob's tail to hold the footer, in both its current and legacy formats
+const FOOTER_SEARCH_SIZE: u64 = 64;
+
+#[derive(Deserialize)]
+pub(crate) struct Toc {
+    #[allow(dead_code)]
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    digest: Option<String>,
+    /// The offset in the blob of the gzip member holding this file or chunk's content
+    #[serde(default)]
+    offset: u64,
+    #[serde(default)]
+    chunk_size: u64,
+    #[serde(default)]
+    chunk_digest: Option<String>,
+}
+
+/// Returns whether an entry is one that eStargz reserves, which isn't extracted
+pub(crate) fn is_reserved(path: &Path) -> bool {
+    let mut components = path
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir | Component::RootDir));
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => {
+            RESERVED_NAMES.iter().any(|reserved| name == *reserved)
+        }
+        _ => false,
+    }
+}
+
+/// Checks `blob`, if it's an eStargz layer and digest verification is enabled: the TOC against
+/// the layer's [`TOC_DIGEST_ANNOTATION`], if it has one, and the content of every regular file
+/// against the TOC.
+///
+/// `blob` is left positioned at its start.
+pub(crate) fn check_layer(
+    mut blob: impl Read + Seek,
+    index: usize,
+    descriptor: &Descriptor,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let path = crate::blob_path(descriptor);
+    let toc_offset = find_toc(&mut blob).with_path(&path)?;
+    let result = match toc_offset {
+        Some(offset) if options.verify_digests => {
+            verify(&mut blob, offset, index, descriptor, options)
+        }
+        _ => Ok(()),
+    };
+    blob.rewind().with_path(&path)?;
+    result
+}
+
+/// Returns the offset of the TOC recorded in the footer, or `None` if there's no footer
+fn find_toc(blob: &mut (impl Read + Seek)) -> io::Result<Option<u64>> {
+    let len = blob.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(FOOTER_SEARCH_SIZE);
+    blob.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    blob.take(tail_len).read_to_end(&mut tail)?;
+
+    let Some(magic) = tail
+        .windows(FOOTER_MAGIC.len())
+        .rposition(|window| window == FOOTER_MAGIC)
+    else {
+        return Ok(None);
+    };
+    let Some(hex) = magic.checked_sub(16).map(|start| &tail[start..magic]) else {
+        return Ok(None);
+    };
+    Ok(std::str::from_utf8(hex)
+        .ok()
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .filter(|offset| *offset < len))
+}
+
+fn verify(
+    blob: &mut (impl Read + Seek),
+    toc_offset: u64,
+    index: usize,
+    descriptor: &Descriptor,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let toc_json = read_toc(blob, toc_offset).map_err(Error::Archive)?;
+    if let Some(expected) = descriptor
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(TOC_DIGEST_ANNOTATION))
+    {
+        let actual = format!("sha256:{}", hex::encode(openssl::sha::sha256(&toc_json)));
+        options.emit(&Event::digest_verified(
+            Some(index),
+            DigestKind::Toc,
+            expected,
+            &actual,
+        ));
+        if *expected != actual {
+            return Err(Error::DigestMismatch {
+                layer_index: Some(index),
+                kind: DigestKind::Toc,
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    let toc: Toc = serde_json::from_slice(&toc_json)
+        .map_err(|e| Error::Archive(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    let mut entries = toc.entries.iter().peekable();
+    while let Some(entry) = entries.next() {
+        if entry.entry_type != "reg" {
+            continue;
+        }
+        // A file's content is split into chunks when it's large: the first is described by the
+        // file's own entry, and the rest by the "chunk" entries following it
+        let mut chunks = vec![entry];
+        while let Some(chunk) = entries.next_if(|next| next.entry_type == "chunk") {
+            chunks.push(chunk);
+        }
+        verify_file(blob, index, entry, &chunks)?;
+    }
+    Ok(())
+}
+
+/// Reads the JSON of the TOC, which is the only entry of the tar in the gzip member at `offset`
+fn read_toc(blob: &mut (impl Read + Seek), offset: u64) -> io::Result<Vec<u8>> {
+    blob.seek(SeekFrom::Start(offset))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(blob)));
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing eStargz TOC"))??;
+    if entry.path()?.as_ref() != Path::new(TOC_NAME) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Expected eStargz TOC, found {}", entry.path()?.display()),
+        ));
+    }
+    let mut json = Vec::new();
+    entry.read_to_end(&mut json)?;
+    Ok(json)
+}
+
+/// Checks the content of a regular file against the digests of each of its chunks and of the whole
+fn verify_file(
+    blob: &mut (impl Read + Seek),
+    index: usize,
+    entry: &TocEntry,
+    chunks: &[&TocEntry],
+) -> Result<()> {
+    let path = PathBuf::from(&entry.name);
+    let mut file_hasher = openssl::sha::Sha256::new();
+    let mut remaining = entry.size;
+    for chunk in chunks {
+        let chunk_size = match chunk.chunk_size {
+            0 => remaining,
+            size => size.min(remaining),
+        };
+        blob.seek(SeekFrom::Start(chunk.offset)).with_path(&path)?;
+        let mut reader =
+            DigestReader::new(MultiGzDecoder::new(BufReader::new(&mut *blob)).take(chunk_size));
+        let mut buffer = [0; 8192];
+        loop {
+            let read = reader.read(&mut buffer).map_err(Error::Archive)?;
+            if read == 0 {
+                break;
+            }
+            file_hasher.update(&buffer[..read]);
+        }
+        if reader.bytes_read() != chunk_size {
+            return Err(Error::Archive(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Content of {} ends before its size in the eStargz TOC",
+                    entry.name
+                ),
+            )));
+        }
+        remaining -= chunk_size;
+        if let Some(expected) = &chunk.chunk_digest {
+            let actual = format!("sha256:{}", reader.finish().0.sha256());
+            if *expected != actual {
+                return Err(Error::TocMismatch {
+                    layer_index: index,
+                    path,
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+    }
+    if let Some(expected) = &entry.digest {
+        let actual = format!("sha256:{}", hex::encode(file_hasher.finish()));
+        if *expected != actual {
+            return Err(Error::TocMismatch {
+                layer_index: index,
+                path,
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
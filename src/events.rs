@@ -0,0 +1,189 @@
+use crate::error::{DigestKind, Error};
+use crate::report::{Warning, WarningKind};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The version of the schema of the events written by [`crate::UnpackOptions::event_sink`].
+///
+/// Each event is written as a single line containing a JSON object with these fields, in
+/// addition to those specific to its `event`:
+///
+/// * `version` - this version
+/// * `event` - the name of the event
+///
+/// The events are:
+///
+/// * `layer_skipped` - `layer_index` and `digest`, for each layer skipped by
+///   [`crate::UnpackOptions::layer_decision`], before any layer starts
+/// * `layer_started` - `layer_index`, `digest`, `media_type`, `size`, the compressed size of the
+///   blob in bytes, and `annotations`, the descriptor's annotations as an object. With parallel or
+///   prefetched extraction, layers may start in any order.
+/// * `digest_verified` - `layer_index` (`null` for the image config and manifest), `kind`
+///   (`"manifest"`, for [`crate::unpack_manifest_bytes`], `"config"`, `"layer"`, `"diff_id"` or,
+///   for eStargz layers, `"toc"`), `expected`, `actual` and `matched`. Not emitted if digest
+///   verification is disabled.
+/// * `warning` - `layer_index`, `path` and `kind` (`"unsafe_path"`, `"dangling_whiteout"`,
+///   `"invalid_whiteout"` or `"inspection_failed"`), for each [`crate::Warning`]
+/// * `layer_finished` - `layer_index`, `digest`, `warnings`, the number of warnings in the layer,
+///   `compressed_bytes` and `uncompressed_bytes`, the sizes of its blob and archive, and
+///   `duration_seconds`, once the layer has been applied to the rootfs. Layers finish in order.
+/// * `finished` - `layers`, `warnings`, the total number of warnings, `error`, which is `null` on
+///   success and otherwise a description of the error and its causes, and `duration_seconds`
+///
+/// Fields and events may be added without changing the version, so consumers should ignore those
+/// they don't recognize. Removing or changing the meaning of a field increments the version.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    LayerSkipped {
+        layer_index: usize,
+        digest: String,
+    },
+    LayerStarted {
+        layer_index: usize,
+        digest: String,
+        media_type: String,
+        size: u64,
+        annotations: BTreeMap<&'a str, &'a str>,
+    },
+    DigestVerified {
+        layer_index: Option<usize>,
+        kind: &'static str,
+        expected: &'a str,
+        actual: &'a str,
+        matched: bool,
+    },
+    Warning {
+        layer_index: usize,
+        path: String,
+        kind: &'static str,
+    },
+    LayerFinished {
+        layer_index: usize,
+        digest: String,
+        warnings: usize,
+        compressed_bytes: u64,
+        uncompressed_bytes: u64,
+        duration_seconds: f64,
+    },
+    Finished {
+        layers: usize,
+        warnings: usize,
+        error: Option<String>,
+        duration_seconds: f64,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub(crate) fn digest_verified(
+        layer_index: Option<usize>,
+        kind: DigestKind,
+        expected: &'a str,
+        actual: &'a str,
+    ) -> Self {
+        Event::DigestVerified {
+            layer_index,
+            kind: match kind {
+                DigestKind::Manifest => "manifest",
+                DigestKind::Config => "config",
+                DigestKind::Layer => "layer",
+                DigestKind::DiffId => "diff_id",
+                DigestKind::Toc => "toc",
+            },
+            expected,
+            actual,
+            matched: expected == actual,
+        }
+    }
+
+    pub(crate) fn warning(warning: &Warning) -> Self {
+        Event::Warning {
+            layer_index: warning.layer_index,
+            path: warning.path.to_string_lossy().into_owned(),
+            kind: match warning.kind {
+                WarningKind::UnsafePath => "unsafe_path",
+                WarningKind::DanglingWhiteout => "dangling_whiteout",
+                WarningKind::InvalidWhiteout => "invalid_whiteout",
+                WarningKind::InspectionFailed => "inspection_failed",
+                WarningKind::HistoryOutOfOrder => "history_out_of_order",
+                WarningKind::UnsupportedDigestAlgorithm => "unsupported_digest_algorithm",
+                WarningKind::MissingCreatedTime => "missing_created_time",
+                WarningKind::PlanDeviation => "plan_deviation",
+                WarningKind::InvalidLinkTarget => "invalid_link_target",
+                WarningKind::LinkTargetTooLong => "link_target_too_long",
+                WarningKind::CapabilityDropped => "capability_dropped",
+                WarningKind::ProtectedPath => "protected_path",
+                WarningKind::AclDropped => "acl_dropped",
+                WarningKind::BaseImageMismatch => "base_image_mismatch",
+                WarningKind::AfterLayerHookFailed => "after_layer_hook_failed",
+                WarningKind::BeforeConfigHookFailed => "before_config_hook_failed",
+                WarningKind::AnnotatedDiffIdMismatch => "annotated_diff_id_mismatch",
+                WarningKind::UncompressedSizeMismatch => "uncompressed_size_mismatch",
+                WarningKind::XattrDropped => "xattr_dropped",
+                WarningKind::DeviceNodeSkipped => "device_node_skipped",
+                WarningKind::UnsupportedMediaType => "unsupported_media_type",
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Writes events to a sink, giving up on the sink after the first write error
+pub(crate) struct EventSink {
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl EventSink {
+    pub(crate) fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Some(Box::new(writer))),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: &Event) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(w) = writer.as_mut() else {
+            return;
+        };
+        let envelope = Envelope {
+            version: EVENT_SCHEMA_VERSION,
+            event,
+        };
+        let mut line = serde_json::to_vec(&envelope).expect("events serialize");
+        line.push(b'\n');
+        // Events are flushed as they happen, so that consumers can follow the unpack
+        if let Err(e) = w.write_all(&line).and_then(|()| w.flush()) {
+            log::warn!("Failed to write unpack event, no more events will be written: {e}");
+            *writer = None;
+        }
+    }
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSink").finish_non_exhaustive()
+    }
+}
+
+/// Describes an error and the chain of errors that caused it
+pub(crate) fn describe_error(e: &Error) -> String {
+    let mut description = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        description.push_str(": ");
+        description.push_str(&e.to_string());
+        source = e.source();
+    }
+    description
+}
@@ -0,0 +1,634 @@
+//! Reading a single path out of an image without unpacking it, for [`extract_path`].
+//!
+//! Layers are searched newest first for the path, stopping at the first with an entry at or
+//! beneath it, or that removes it, so the entry found is the one unpacking would leave in place.
+//! Symlinks at the path or its ancestors are followed by searching again for their targets, from
+//! the newest layer, and hard links by searching for their targets from their own layer down.
+
+use crate::apply::{FileKind, Whiteout};
+use crate::blobs::BlobSource;
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::UnpackOptions;
+use crate::report::LayerCompression;
+use crate::user::MAX_SYMLINKS;
+use crate::{history, normalize, read_config, read_layer_content};
+use ocidir::oci_spec::image::{Descriptor, ImageManifest};
+use ocidir::OciDir;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType, Header};
+
+/// How [`extract_path`] writes a directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DirectoryOutput {
+    /// The paths beneath the directory, relative to it, one per line and sorted, with a `/` after
+    /// each directory
+    #[default]
+    Listing,
+    /// A tar archive of everything beneath the directory, with paths relative to it
+    Tar,
+}
+
+/// Options controlling how [`extract_path`] reads a path from an image
+#[derive(Debug, Clone, Default)]
+pub struct ExtractPathOptions {
+    directory_output: DirectoryOutput,
+    unpack_options: UnpackOptions,
+}
+
+impl ExtractPathOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How a directory is written. Defaults to [`DirectoryOutput::Listing`].
+    pub fn directory_output(mut self, directory_output: DirectoryOutput) -> Self {
+        self.directory_output = directory_output;
+        self
+    }
+
+    /// How layers are read, such as whether their digests are verified and whether the
+    /// decompressed blob cache is used. Options that only affect what's written to a bundle are
+    /// ignored. Defaults to [`UnpackOptions::default`].
+    pub fn unpack_options(mut self, unpack_options: UnpackOptions) -> Self {
+        self.unpack_options = unpack_options;
+        self
+    }
+}
+
+/// What [`extract_path`] found and wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExtractedFileInfo {
+    /// The path read, relative to the root of the image, after following symlinks and hard links
+    pub path: PathBuf,
+    /// The type of the entry, which is never [`FileKind::Symlink`], as symlinks are followed.
+    /// Nothing is written for [`FileKind::Other`].
+    pub kind: FileKind,
+    /// The index of the newest layer with an entry at or beneath the path
+    pub layer_index: usize,
+    /// The number of bytes written
+    pub size: u64,
+}
+
+/// Writes the content of `image_path` in an image to `out`, as it would be in the rootfs once the
+/// image is unpacked, without unpacking it.
+///
+/// Layers are searched from the newest, so an entry shadows those at the same path in older
+/// layers, and whiteouts and opaque whiteouts hide them. Symlinks are followed within the image,
+/// with an absolute target or `..` resolved against its root, and fail with `ELOOP` after
+/// following 40. `..` components are resolved lexically, so they go back up past symlinks rather
+/// than out of their targets.
+///
+/// Regular files are streamed to `out`, and directories are written as set by
+/// [`ExtractPathOptions::directory_output`]. Fails with [`Error::NotFound`] if the image has
+/// nothing at the path.
+pub fn extract_path(
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    image_path: &Path,
+    out: impl Write,
+    options: &ExtractPathOptions,
+) -> Result<ExtractedFileInfo> {
+    let unpack_options = &options.unpack_options;
+    let layers = manifest.layers();
+    let blobs = BlobSource::layout(oci_dir);
+    let (image_config, _) = read_config(&blobs, manifest.config(), unpack_options)?;
+    history::check_layer_count(&image_config, layers.len(), unpack_options.strictness)?;
+    let image = Image {
+        blobs,
+        layers,
+        diff_ids: image_config.rootfs().diff_ids(),
+        options: unpack_options,
+    };
+
+    let found = image.find(
+        join_in_root(Path::new(""), image_path),
+        layers.len(),
+        image_path,
+    )?;
+    let mut out = CountingWriter {
+        inner: out,
+        count: 0,
+    };
+    match found.kind {
+        FileKind::File => image.copy(&found, &mut out)?,
+        FileKind::Dir => {
+            let listing = image.list(&found.path, found.layer_index)?;
+            match options.directory_output {
+                DirectoryOutput::Listing => {
+                    write_listing(&listing, &mut out).with_path(image_path)?
+                }
+                DirectoryOutput::Tar => image.write_tar(&found.path, &listing, &mut out)?,
+            }
+        }
+        FileKind::Symlink | FileKind::Other => {}
+    }
+    Ok(ExtractedFileInfo {
+        path: found.path,
+        kind: found.kind,
+        layer_index: found.layer_index,
+        size: out.count,
+    })
+}
+
+/// An image's layers, each read when it's searched
+struct Image<'a> {
+    blobs: BlobSource<'a>,
+    layers: &'a [Descriptor],
+    diff_ids: &'a [String],
+    options: &'a UnpackOptions,
+}
+
+/// What a layer has at a path
+enum Lookup {
+    Nothing,
+    /// The last entry at the path, by its position in the layer, with its link name
+    Entry {
+        position: usize,
+        header: Box<Header>,
+        link: Option<PathBuf>,
+    },
+    /// Entries beneath the path, but none at it, so it's a directory
+    Parent,
+    /// A symlink at `ancestor`, an ancestor of the path, to `target`
+    SymlinkedAncestor {
+        ancestor: PathBuf,
+        target: PathBuf,
+    },
+    /// A whiteout of the path or an ancestor, an opaque whiteout of an ancestor, or an ancestor
+    /// that's neither a directory nor a symlink, so the path isn't in older layers
+    Removed,
+}
+
+/// An entry found in a layer
+struct Found {
+    path: PathBuf,
+    kind: FileKind,
+    layer_index: usize,
+    /// The entry's position in its layer, and its header, or `None` for a directory with no
+    /// entry of its own
+    entry: Option<(usize, Header)>,
+}
+
+/// An entry in a directory
+struct Listed {
+    layer_index: usize,
+    position: usize,
+    header: Header,
+    link: Option<PathBuf>,
+}
+
+impl Image<'_> {
+    /// Passes the archive of the layer at `index` to `f`, then verifies the layer
+    fn read<T>(
+        &self,
+        index: usize,
+        f: impl FnOnce(&mut Archive<&mut dyn Read>) -> Result<T>,
+    ) -> Result<T> {
+        read_layer_content(
+            &self.blobs,
+            self.layers,
+            index,
+            &self.diff_ids[index],
+            self.options,
+            None,
+            &mut LayerCompression::new(index),
+            |reader, _| f(&mut Archive::new(reader)),
+        )
+        .map_err(|e| e.in_layer(index, &self.layers[index]))
+    }
+
+    /// Returns the entry at `path` in the layers below `below`, following symlinks and hard links.
+    /// `requested` is the path originally asked for, which errors name.
+    fn find(&self, mut path: PathBuf, mut below: usize, requested: &Path) -> Result<Found> {
+        let mut followed = 0;
+        let mut follow = || {
+            followed += 1;
+            if followed > MAX_SYMLINKS {
+                Err(io::Error::from_raw_os_error(libc::ELOOP)).with_path(requested)
+            } else {
+                Ok(())
+            }
+        };
+        'search: loop {
+            if path.as_os_str().is_empty() {
+                return Ok(Found {
+                    path,
+                    kind: FileKind::Dir,
+                    layer_index: below.saturating_sub(1),
+                    entry: None,
+                });
+            }
+            for index in (0..below).rev() {
+                match self.lookup(index, &path)? {
+                    Lookup::Nothing => {}
+                    Lookup::Removed => break,
+                    Lookup::Parent => {
+                        return Ok(Found {
+                            path,
+                            kind: FileKind::Dir,
+                            layer_index: index,
+                            entry: None,
+                        })
+                    }
+                    Lookup::SymlinkedAncestor { ancestor, target } => {
+                        follow()?;
+                        let rest = path
+                            .strip_prefix(&ancestor)
+                            .expect("an ancestor of the path");
+                        path = join_in_root(parent(&ancestor), &target).join(rest);
+                        below = self.layers.len();
+                        continue 'search;
+                    }
+                    Lookup::Entry {
+                        position,
+                        header,
+                        link,
+                    } => match header.entry_type() {
+                        EntryType::Symlink => {
+                            follow()?;
+                            let target = link.unwrap_or_default();
+                            path = join_in_root(parent(&path), &target);
+                            below = self.layers.len();
+                            continue 'search;
+                        }
+                        EntryType::Link => {
+                            // Counted like symlinks, as a layer can link an entry to itself
+                            follow()?;
+                            path = normalize(&link.unwrap_or_default());
+                            below = index + 1;
+                            continue 'search;
+                        }
+                        entry_type => {
+                            return Ok(Found {
+                                path,
+                                kind: kind(entry_type),
+                                layer_index: index,
+                                entry: Some((position, *header)),
+                            })
+                        }
+                    },
+                }
+            }
+            return Err(Error::NotFound(requested.to_path_buf()));
+        }
+    }
+
+    /// Returns what the layer at `index` has at `path`, which isn't the root
+    fn lookup(&self, index: usize, path: &Path) -> Result<Lookup> {
+        self.read(index, |archive| {
+            let mut at = None;
+            // The shallowest ancestor that isn't a directory, with its type and link name
+            let mut ancestor: Option<(PathBuf, EntryType, Option<PathBuf>)> = None;
+            let mut beneath = false;
+            let mut removed = false;
+            for (position, entry) in archive.entries().map_err(Error::Archive)?.enumerate() {
+                let entry = entry.map_err(Error::Archive)?;
+                let entry_path = normalize(&crate::entry_path(&entry)?);
+                match Whiteout::parse(&entry_path) {
+                    Some(Whiteout::Entry(removes)) => removed |= path.starts_with(removes),
+                    Some(Whiteout::Opaque(dir)) => removed |= path.starts_with(&dir) && path != dir,
+                    Some(Whiteout::Invalid) => {}
+                    None if entry_path == path => {
+                        let link = entry.link_name().map_err(Error::Archive)?;
+                        at = Some((
+                            position,
+                            entry.header().clone(),
+                            link.map(|l| l.into_owned()),
+                        ))
+                    }
+                    None if entry_path.starts_with(path) => beneath = true,
+                    None if path.starts_with(&entry_path) => {
+                        let entry_type = entry.header().entry_type();
+                        let shallower = ancestor
+                            .as_ref()
+                            .is_none_or(|(a, _, _)| a.starts_with(&entry_path));
+                        if entry_type == EntryType::Directory {
+                            // A directory replacing the ancestor later in the layer
+                            if ancestor.as_ref().is_some_and(|(a, _, _)| *a == entry_path) {
+                                ancestor = None;
+                            }
+                        } else if shallower {
+                            let link = entry.link_name().map_err(Error::Archive)?;
+                            ancestor = Some((entry_path, entry_type, link.map(|l| l.into_owned())));
+                        }
+                    }
+                    None => {}
+                }
+            }
+            Ok(match (ancestor, at) {
+                (Some((ancestor, EntryType::Symlink, target)), _) => Lookup::SymlinkedAncestor {
+                    ancestor,
+                    target: target.unwrap_or_default(),
+                },
+                (Some(_), _) => Lookup::Removed,
+                (None, Some((position, header, link))) => Lookup::Entry {
+                    position,
+                    header: Box::new(header),
+                    link,
+                },
+                (None, None) if beneath => Lookup::Parent,
+                (None, None) if removed => Lookup::Removed,
+                (None, None) => Lookup::Nothing,
+            })
+        })
+    }
+
+    /// Writes the content of a regular file found in a layer to `out`
+    fn copy(&self, found: &Found, out: &mut impl Write) -> Result<()> {
+        let (position, _) = found.entry.as_ref().expect("files have entries");
+        self.read(found.layer_index, |archive| {
+            let mut entry = archive
+                .entries()
+                .map_err(Error::Archive)?
+                .nth(*position)
+                .expect("the layer was already read")
+                .map_err(Error::Archive)?;
+            io::copy(&mut entry, out).with_path(&found.path)?;
+            Ok(())
+        })
+    }
+
+    /// Returns the entries beneath `dir`, relative to it, from the layer at `top` down
+    fn list(&self, dir: &Path, top: usize) -> Result<BTreeMap<PathBuf, Listed>> {
+        let mut listing = BTreeMap::new();
+        // Paths hidden from older layers, with whether the path itself is hidden or only what's
+        // beneath it
+        let mut masks: Vec<(PathBuf, bool)> = Vec::new();
+        // Bounded by the layers for the root of an image with none
+        for index in (0..self.layers.len().min(top + 1)).rev() {
+            let mut ends = false;
+            let mut layer_masks = Vec::new();
+            self.read(index, |archive| {
+                for (position, entry) in archive.entries().map_err(Error::Archive)?.enumerate() {
+                    let entry = entry.map_err(Error::Archive)?;
+                    let path = normalize(&crate::entry_path(&entry)?);
+                    match Whiteout::parse(&path) {
+                        Some(Whiteout::Entry(removes)) => {
+                            if dir.starts_with(&removes) {
+                                ends = true;
+                            } else if let Ok(relative) = removes.strip_prefix(dir) {
+                                layer_masks.push((relative.to_path_buf(), true));
+                            }
+                            continue;
+                        }
+                        Some(Whiteout::Opaque(opaque)) => {
+                            if dir.starts_with(&opaque) && dir != opaque {
+                                ends = true;
+                            } else if let Ok(relative) = opaque.strip_prefix(dir) {
+                                layer_masks.push((relative.to_path_buf(), false));
+                            }
+                            continue;
+                        }
+                        Some(Whiteout::Invalid) => continue,
+                        None => {}
+                    }
+                    let entry_type = entry.header().entry_type();
+                    if dir.starts_with(&path) {
+                        // The directory, or an ancestor, replacing something else
+                        ends |= entry_type != EntryType::Directory;
+                        continue;
+                    }
+                    let Ok(relative) = path.strip_prefix(dir) else {
+                        continue;
+                    };
+                    let masked = masks.iter().any(|(mask, inclusive)| {
+                        relative.starts_with(mask) && (*inclusive || relative != mask)
+                    });
+                    let shadowed = listing
+                        .get(relative)
+                        .is_some_and(|listed: &Listed| listed.layer_index != index);
+                    if masked || shadowed {
+                        continue;
+                    }
+                    if entry_type != EntryType::Directory {
+                        layer_masks.push((relative.to_path_buf(), false));
+                    }
+                    let link = entry.link_name().map_err(Error::Archive)?;
+                    listing.insert(
+                        relative.to_path_buf(),
+                        Listed {
+                            layer_index: index,
+                            position,
+                            header: entry.header().clone(),
+                            link: link.map(|l| l.into_owned()),
+                        },
+                    );
+                }
+                Ok(())
+            })?;
+            masks.extend(layer_masks);
+            if ends {
+                break;
+            }
+        }
+        // Directories created only as the parents of entries, as unpacking would
+        let mut parents = Vec::new();
+        for (path, listed) in &listing {
+            for ancestor in path.ancestors().skip(1) {
+                if ancestor.as_os_str().is_empty() || listing.contains_key(ancestor) {
+                    break;
+                }
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                parents.push((ancestor.to_path_buf(), listed.layer_index, header));
+            }
+        }
+        for (path, layer_index, header) in parents {
+            listing.entry(path).or_insert(Listed {
+                layer_index,
+                position: usize::MAX,
+                header,
+                link: None,
+            });
+        }
+        Ok(listing)
+    }
+
+    /// Writes a tar archive of the entries beneath `dir` to `out`
+    fn write_tar(
+        &self,
+        dir: &Path,
+        listing: &BTreeMap<PathBuf, Listed>,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let mut builder = tar::Builder::new(out);
+        // Files are appended layer by layer, so each layer is read once, and hard links last, so
+        // their targets precede them
+        let mut files: BTreeMap<usize, BTreeMap<usize, &Path>> = BTreeMap::new();
+        let mut hard_links = Vec::new();
+        for (path, listed) in listing {
+            let mut header = listed.header.clone();
+            let result = match header.entry_type() {
+                EntryType::Symlink => {
+                    let target = listed.link.clone().unwrap_or_default();
+                    builder.append_link(&mut header, path, target)
+                }
+                EntryType::Link => {
+                    hard_links.push((path, listed));
+                    continue;
+                }
+                entry_type if kind(entry_type) == FileKind::File => {
+                    files
+                        .entry(listed.layer_index)
+                        .or_default()
+                        .insert(listed.position, path);
+                    continue;
+                }
+                _ => builder.append_data(&mut header, path, io::empty()),
+            };
+            result.with_path(dir.join(path))?;
+        }
+        for (index, positions) in files {
+            self.read(index, |archive| {
+                for (position, entry) in archive.entries().map_err(Error::Archive)?.enumerate() {
+                    let mut entry = entry.map_err(Error::Archive)?;
+                    if let Some(path) = positions.get(&position) {
+                        let mut header = entry.header().clone();
+                        builder
+                            .append_data(&mut header, path, &mut entry)
+                            .with_path(dir.join(path))?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        for (path, listed) in hard_links {
+            let target = normalize(listed.link.as_deref().unwrap_or(Path::new("")));
+            if let Some(relative) = target.strip_prefix(dir).ok().filter(|relative| {
+                // Only the target's entry in the link's own layer has the linked content
+                listing.get(*relative).is_some_and(|target| {
+                    target.layer_index == listed.layer_index && is_file(&target.header)
+                })
+            }) {
+                builder
+                    .append_link(&mut listed.header.clone(), path, relative)
+                    .with_path(dir.join(path))?;
+                continue;
+            }
+            // The target isn't in the archive, so its content is
+            let found = self.find(target, listed.layer_index + 1, &dir.join(path))?;
+            // The linked file's header, as the link's would keep its link name
+            let Some((position, mut header)) = found.entry.filter(|_| found.kind == FileKind::File)
+            else {
+                log::warn!(
+                    "Skipping hard link {}, whose target {} isn't a file",
+                    dir.join(path).display(),
+                    found.path.display()
+                );
+                continue;
+            };
+            self.read(found.layer_index, |archive| {
+                let mut entry = archive
+                    .entries()
+                    .map_err(Error::Archive)?
+                    .nth(position)
+                    .expect("the layer was already read")
+                    .map_err(Error::Archive)?;
+                builder
+                    .append_data(&mut header, path, &mut entry)
+                    .with_path(dir.join(path))
+            })?;
+        }
+        builder.finish().with_path(dir)
+    }
+}
+
+/// Writes the paths in `listing` to `out`, one per line, with a `/` after each directory
+fn write_listing(listing: &BTreeMap<PathBuf, Listed>, out: &mut impl Write) -> io::Result<()> {
+    for (path, listed) in listing {
+        out.write_all(path.as_os_str().as_bytes())?;
+        if listed.header.entry_type() == EntryType::Directory {
+            out.write_all(b"/")?;
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn kind(entry_type: EntryType) -> FileKind {
+    match entry_type {
+        EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => FileKind::File,
+        EntryType::Directory => FileKind::Dir,
+        EntryType::Symlink => FileKind::Symlink,
+        _ => FileKind::Other,
+    }
+}
+
+fn is_file(header: &Header) -> bool {
+    kind(header.entry_type()) == FileKind::File
+}
+
+/// Returns the parent of a path in the image, which is empty for the root and the top level
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Joins `target` to `dir`, resolving `..` lexically and an absolute `target` from the root, and
+/// never going above the root
+fn join_in_root(dir: &Path, target: &Path) -> PathBuf {
+    let mut resolved = if target.has_root() {
+        PathBuf::new()
+    } else {
+        dir.to_path_buf()
+    };
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+    resolved
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_in_root() {
+        assert_eq!(
+            join_in_root(Path::new("a/b"), Path::new("c")),
+            Path::new("a/b/c")
+        );
+        assert_eq!(
+            join_in_root(Path::new("a/b"), Path::new("../c")),
+            Path::new("a/c")
+        );
+        assert_eq!(
+            join_in_root(Path::new("a/b"), Path::new("/c/./d")),
+            Path::new("c/d")
+        );
+        assert_eq!(
+            join_in_root(Path::new("a"), Path::new("../../../c")),
+            Path::new("c")
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use flate2::bufread::GzDecoder;
+use std::io::{self, BufRead, Read};
+
+/// The bytes every gzip member starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decompresses a gzip layer, reading every member of it, as `gzip -d` and container runtimes
+/// do. Some builders compress layers as several concatenated members, as eStargz layers are, and
+/// the archive is the concatenation of what they decompress to, so stopping at the end of the
+/// first would lose the rest of it.
+///
+/// Decompression stops at the end of a member that isn't followed by the start of another,
+/// leaving whatever follows in the reader, to be reported as trailing data.
+pub(crate) struct GzipDecoder<R> {
+    /// The decoder of the current member, which is only taken while starting the next
+    decoder: Option<GzDecoder<R>>,
+    members: u64,
+    finished: bool,
+}
+
+impl<R: BufRead> GzipDecoder<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            decoder: Some(GzDecoder::new(reader)),
+            members: 1,
+            finished: false,
+        }
+    }
+
+    /// The number of members started so far
+    pub(crate) fn members(&self) -> u64 {
+        self.members
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        self.decoder().get_mut()
+    }
+
+    pub(crate) fn into_inner(mut self) -> R {
+        self.decoder
+            .take()
+            .expect("decoder is present")
+            .into_inner()
+    }
+
+    fn decoder(&mut self) -> &mut GzDecoder<R> {
+        self.decoder.as_mut().expect("decoder is present")
+    }
+
+    /// Returns whether the compressed input continues with another member. Only the first byte
+    /// of the magic can be checked if it's the last one buffered, in which case anything else
+    /// starting with it fails as an invalid member rather than as trailing data.
+    fn next_member_follows(&mut self) -> io::Result<bool> {
+        let buffered = self.get_mut().fill_buf()?;
+        Ok(!buffered.is_empty() && GZIP_MAGIC.starts_with(&buffered[..buffered.len().min(2)]))
+    }
+}
+
+impl<R: BufRead> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            let read = self.decoder().read(buf)?;
+            if read > 0 {
+                return Ok(read);
+            }
+            if !self.next_member_follows()? {
+                self.finished = true;
+                return Ok(0);
+            }
+            let reader = self
+                .decoder
+                .take()
+                .expect("decoder is present")
+                .into_inner();
+            self.decoder = Some(GzDecoder::new(reader));
+            self.members += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn compress(members: &[&[u8]], trailing: &[u8]) -> Vec<u8> {
+        let mut blob = Vec::new();
+        for member in members {
+            let mut encoder = GzEncoder::new(&mut blob, Compression::default());
+            encoder.write_all(member).unwrap();
+            encoder.finish().unwrap();
+        }
+        blob.extend_from_slice(trailing);
+        blob
+    }
+
+    #[test]
+    fn test_members() {
+        let blob = compress(&[b"first ", b"", b"second"], b"");
+        let mut decoder = GzipDecoder::new(blob.as_slice());
+        let mut output = String::new();
+        decoder.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "first second");
+        assert_eq!(decoder.members(), 3);
+        assert!(decoder.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let blob = compress(&[b"archive"], b"\0\0garbage");
+        let mut decoder = GzipDecoder::new(blob.as_slice());
+        let mut output = String::new();
+        decoder.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "archive");
+        assert_eq!(decoder.members(), 1);
+        assert_eq!(decoder.into_inner(), b"\0\0garbage");
+
+        // Split across reads of a buffer that only holds the first byte of the next member
+        let blob = compress(&[b"one", b"two"], b"");
+        let reader = io::BufReader::with_capacity(1, blob.as_slice());
+        let mut output = String::new();
+        GzipDecoder::new(reader)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "onetwo");
+    }
+}
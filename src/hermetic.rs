@@ -0,0 +1,188 @@
+//! Keeping what the build host would contribute out of bundles, for
+//! [`crate::RuntimeConfigOptions::hermetic`].
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::{TimestampSource, UserResolution};
+use ocidir::oci_spec::runtime::{MountBuilder, Spec};
+use ocidir::oci_spec::OciSpecError;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Something that would have been derived from the build host, and what
+/// [`crate::RuntimeConfigOptions::hermetic`] used instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HermeticSubstitution {
+    /// What would have been derived from the host: a field of the runtime config, as a JSON
+    /// path such as `process.user` or `mounts[3]`, or `timestamps` for the times of the files
+    /// written into the bundle
+    pub field: String,
+    /// Where on the host it would have come from
+    pub host_source: String,
+    /// What was used instead
+    pub used: String,
+}
+
+impl HermeticSubstitution {
+    fn new(
+        field: impl Into<String>,
+        host_source: impl Into<String>,
+        used: impl Into<String>,
+    ) -> Self {
+        Self {
+            field: field.into(),
+            host_source: host_source.into(),
+            used: used.into(),
+        }
+    }
+}
+
+/// Returns how to resolve `Config.User`, which is from the rootfs rather than the host's user
+/// database
+pub(crate) fn user_resolution(
+    resolution: &UserResolution,
+    substitutions: &mut Vec<HermeticSubstitution>,
+) -> UserResolution {
+    match resolution {
+        UserResolution::Host => {
+            substitutions.push(HermeticSubstitution::new(
+                "process.user",
+                "the host's user database",
+                "the rootfs's /etc/passwd and /etc/group",
+            ));
+            UserResolution::Rootfs
+        }
+        resolution => resolution.clone(),
+    }
+}
+
+/// Returns where the timestamps of the files written into the bundle come from, which is the
+/// image rather than the clock
+pub(crate) fn timestamp_source(
+    source: TimestampSource,
+    substitutions: &mut Vec<HermeticSubstitution>,
+) -> TimestampSource {
+    match source {
+        TimestampSource::Clock => {
+            substitutions.push(HermeticSubstitution::new(
+                "timestamps",
+                "the clock",
+                "the image config's created time",
+            ));
+            TimestampSource::ImageCreated
+        }
+        source => source,
+    }
+}
+
+/// Replaces the bind mounts among the default mounts of `runtime_config`, whose sources are
+/// paths on the host, with empty tmpfs mounts
+pub(crate) fn replace_bind_mounts(
+    runtime_config: &mut Spec,
+    substitutions: &mut Vec<HermeticSubstitution>,
+) -> Result<()> {
+    let Some(mut mounts) = runtime_config.mounts().clone() else {
+        return Ok(());
+    };
+    for (index, mount) in mounts.iter_mut().enumerate() {
+        let options = mount.options().clone().unwrap_or_default();
+        let is_bind = mount.typ().as_deref() == Some("bind")
+            || options
+                .iter()
+                .any(|option| option == "bind" || option == "rbind");
+        if !is_bind {
+            continue;
+        }
+        let source = mount
+            .source()
+            .as_ref()
+            .map_or_else(String::new, |source| source.display().to_string());
+        substitutions.push(HermeticSubstitution::new(
+            format!("mounts[{index}]"),
+            format!("a bind mount of {source}"),
+            "an empty tmpfs",
+        ));
+        *mount = MountBuilder::default()
+            .destination(mount.destination())
+            .typ("tmpfs")
+            .source("tmpfs")
+            .options(vec!["nosuid".to_string(), "nodev".to_string()])
+            .build()?;
+    }
+    runtime_config.set_mounts(Some(mounts));
+    Ok(())
+}
+
+/// Writes `runtime_config` to `path` with the keys of its objects sorted, rather than in the
+/// order of its maps, and its capabilities sorted, rather than in the order of their sets, both of
+/// which differ between runs
+pub(crate) fn save(runtime_config: &Spec, path: &Path) -> Result<()> {
+    let mut value =
+        serde_json::to_value(runtime_config).map_err(|e| Error::Spec(OciSpecError::SerDe(e)))?;
+    if let Some(Value::Object(capabilities)) = value.pointer_mut("/process/capabilities") {
+        for set in capabilities.values_mut() {
+            if let Value::Array(set) = set {
+                set.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+            }
+        }
+    }
+    let mut writer = BufWriter::new(File::create(path).with_path(path)?);
+    serde_json::to_writer_pretty(&mut writer, &value)
+        .map_err(io::Error::from)
+        .and_then(|()| writer.flush())
+        .with_path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::runtime::SpecBuilder;
+
+    #[test]
+    fn test_replace_bind_mounts() {
+        let mut spec = SpecBuilder::default().build().unwrap();
+        let mut mounts = spec.mounts().clone().unwrap_or_default();
+        let defaults = mounts.len();
+        mounts.push(
+            MountBuilder::default()
+                .destination("/etc/resolv.conf")
+                .typ("none")
+                .source("/etc/resolv.conf")
+                .options(vec!["rbind".to_string(), "ro".to_string()])
+                .build()
+                .unwrap(),
+        );
+        spec.set_mounts(Some(mounts));
+
+        let mut substitutions = Vec::new();
+        replace_bind_mounts(&mut spec, &mut substitutions).unwrap();
+        assert_eq!(
+            substitutions,
+            [HermeticSubstitution::new(
+                format!("mounts[{defaults}]"),
+                "a bind mount of /etc/resolv.conf",
+                "an empty tmpfs",
+            )]
+        );
+        let mount = &spec.mounts().as_ref().unwrap()[defaults];
+        assert_eq!(mount.destination(), Path::new("/etc/resolv.conf"));
+        assert_eq!(mount.typ().as_deref(), Some("tmpfs"));
+    }
+
+    #[test]
+    fn test_save_sorts_capabilities() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.json");
+        save(&SpecBuilder::default().build().unwrap(), &path).unwrap();
+        let value: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let capabilities = value["process"]["capabilities"].as_object().unwrap();
+        assert!(!capabilities.is_empty());
+        for set in capabilities.values() {
+            let set: Vec<_> = set.as_array().unwrap().iter().map(Value::as_str).collect();
+            assert!(set.len() > 1);
+            assert!(set.is_sorted(), "{set:?}");
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::error::{Error, Result};
+use crate::options::Strictness;
+use crate::report::{Warning, WarningKind};
+use ocidir::oci_spec::image::{History, ImageConfiguration};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Checks that the manifest's layers, the config's diff IDs and the layers recorded in the
+/// config's history agree.
+///
+/// A mismatch between the layers and diff IDs is always an error, which describes the history to
+/// show which side is wrong. When only the history disagrees, the manifest's layers are trusted
+/// with [`Strictness::Permissive`], as nothing is unpacked from the history.
+pub(crate) fn check_layer_count(
+    image_config: &ImageConfiguration,
+    layers: usize,
+    strictness: Strictness,
+) -> Result<()> {
+    let diff_ids = image_config.rootfs().diff_ids().len();
+    let history = image_config.history();
+    let history_layers = history.iter().filter(|h| creates_layer(h)).count();
+
+    if layers != diff_ids {
+        let summary = if history.is_empty() {
+            "The config has no history to show which is wrong".to_string()
+        } else if history_layers == diff_ids {
+            format!(
+                "The history agrees with the diff IDs, so the manifest has {}",
+                difference(layers, diff_ids, "layer")
+            )
+        } else if history_layers == layers {
+            format!(
+                "The history agrees with the manifest, so the config has {}",
+                difference(diff_ids, layers, "diff ID")
+            )
+        } else {
+            format!("The history records {history_layers} layers, agreeing with neither")
+        };
+        return Err(Error::LayerCountMismatch {
+            layers,
+            diff_ids,
+            diagnostic: diagnostic(&summary, history, layers),
+        });
+    }
+
+    if !history.is_empty() && history_layers != layers {
+        let summary = format!(
+            "The history has {}, compared to the manifest and diff IDs",
+            difference(history_layers, layers, "layer")
+        );
+        let diagnostic = diagnostic(&summary, history, layers);
+        match strictness {
+            Strictness::Strict => {
+                return Err(Error::HistoryMismatch {
+                    layers,
+                    history_layers,
+                    diagnostic,
+                })
+            }
+            Strictness::Permissive => log::warn!(
+                "The image config's history records {history_layers} layers, but the image has \
+                 {layers}; trusting the manifest\n{diagnostic}"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Looks for signs that the layers aren't in the order they were built, for
+/// [`crate::UnpackOptions::check_layer_order`]: a history entry that created a layer but is older
+/// than the one that created the layer below it. Returns a warning for each layer that looks out
+/// of place, or fails on the first with [`Strictness::Strict`].
+///
+/// Layers are only matched with history entries if the history records as many layers as the
+/// manifest, and entries without parseable timestamps are skipped.
+pub(crate) fn check_layer_order(
+    image_config: &ImageConfiguration,
+    layers: usize,
+    strictness: Strictness,
+) -> Result<Vec<Warning>> {
+    let history: Vec<_> = layer_history(image_config).collect();
+    if history.len() != layers {
+        return Ok(Vec::new());
+    }
+    let mut warnings = Vec::new();
+    let mut previous: Option<(Timestamp, &str)> = None;
+    for (layer_index, entry) in history.iter().enumerate() {
+        let Some(created) = entry.created().as_deref() else {
+            continue;
+        };
+        let Some(timestamp) = Timestamp::parse(created) else {
+            log::debug!("Ignoring unparseable history timestamp {created}");
+            continue;
+        };
+        if let Some((previous_timestamp, previous_created)) = previous {
+            if timestamp < previous_timestamp {
+                let warning = Warning {
+                    layer_index,
+                    path: PathBuf::new(),
+                    kind: WarningKind::HistoryOutOfOrder,
+                };
+                if strictness == Strictness::Strict {
+                    return Err(Error::Warning(warning));
+                }
+                log::warn!(
+                    "{warning}: created at {created}, before the layer below at {previous_created}"
+                );
+                warnings.push(warning);
+            }
+        }
+        previous = Some((timestamp, created));
+    }
+    Ok(warnings)
+}
+
+/// Returns the history entries that created layers, in order
+pub(crate) fn layer_history(image_config: &ImageConfiguration) -> impl Iterator<Item = &History> {
+    image_config.history().iter().filter(|h| creates_layer(h))
+}
+
+/// Returns the seconds since the epoch of an RFC 3339 timestamp, such as an image's creation time
+pub(crate) fn unix_time(timestamp: &str) -> Option<i64> {
+    Timestamp::parse(timestamp).map(|timestamp| timestamp.0)
+}
+
+/// An RFC 3339 timestamp, as the seconds and nanoseconds since the epoch, for comparing history
+/// entries, whose timestamps may have different precisions and offsets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Timestamp(i64, u32);
+
+impl Timestamp {
+    fn parse(timestamp: &str) -> Option<Self> {
+        let number = |s: &str| -> Option<i64> {
+            s.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| s.parse().ok())
+                .flatten()
+        };
+        let (date, time) = timestamp.split_once(['T', 't', ' '])?;
+        let mut date = date.splitn(3, '-');
+        let (year, month, day) = (
+            number(date.next()?)?,
+            number(date.next()?)?,
+            number(date.next()?)?,
+        );
+        let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+            Some(i) => time.split_at(i),
+            None => return None,
+        };
+        let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+        let mut time = time.splitn(3, ':');
+        let (hour, minute, second) = (
+            number(time.next()?)?,
+            number(time.next()?)?,
+            number(time.next()?)?,
+        );
+        let nanos = if fraction.is_empty() {
+            0
+        } else {
+            let digits = &fraction[..fraction.len().min(9)];
+            number(digits)? * 10_i64.pow(9 - digits.len() as u32)
+        };
+        let offset = match offset.as_bytes()[0] {
+            b'Z' | b'z' => 0,
+            sign => {
+                let (hours, minutes) = offset[1..].split_once(':')?;
+                let offset = number(hours)? * 3600 + number(minutes)? * 60;
+                if sign == b'-' {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+        };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        let seconds =
+            days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+        Some(Self(seconds, nanos as u32))
+    }
+}
+
+/// Returns the number of days from 1970-01-01 to a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn creates_layer(history: &History) -> bool {
+    !history.empty_layer().unwrap_or(false)
+}
+
+/// Describes how many more or fewer `noun`s there are in `actual` than in `expected`
+fn difference(actual: usize, expected: usize, noun: &str) -> String {
+    let (count, comparison) = if actual > expected {
+        (actual - expected, "extra")
+    } else {
+        (expected - actual, "missing")
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {comparison} {noun}{plural}")
+}
+
+/// Lists the history entries after `summary`, with the index of the layer each one created
+fn diagnostic(summary: &str, history: &[History], layers: usize) -> String {
+    let mut diagnostic = summary.to_string();
+    let mut layer = 0;
+    for (index, entry) in history.iter().enumerate() {
+        let created = if !creates_layer(entry) {
+            "empty".to_string()
+        } else {
+            layer += 1;
+            if layer > layers {
+                format!("layer {} (not in manifest)", layer - 1)
+            } else {
+                format!("layer {}", layer - 1)
+            }
+        };
+        let created_by = entry
+            .created_by()
+            .as_deref()
+            .or(entry.comment().as_deref())
+            .unwrap_or("");
+        let _ = write!(diagnostic, "\n  history[{index}]: {created}: {created_by}");
+    }
+    diagnostic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let parse = |timestamp| Timestamp::parse(timestamp).unwrap();
+        assert_eq!(parse("1970-01-01T00:00:00Z"), Timestamp(0, 0));
+        assert_eq!(
+            parse("2024-02-29T12:34:56.5Z"),
+            Timestamp(1_709_210_096, 500_000_000)
+        );
+        assert_eq!(
+            parse("2024-02-29T14:34:56.500000000+02:00"),
+            parse("2024-02-29T12:34:56.5Z")
+        );
+        // Precision varies, so timestamps must be compared as times rather than strings
+        assert!(parse("2024-01-01T00:00:00.5Z") > parse("2024-01-01T00:00:00Z"));
+        assert!(parse("2023-12-31T23:00:00-02:00") > parse("2024-01-01T00:30:00Z"));
+        for invalid in [
+            "",
+            "2024-01-01",
+            "2024-13-01T00:00:00Z",
+            "2024-01-01T00:00:00",
+        ] {
+            assert_eq!(Timestamp::parse(invalid), None, "{invalid}");
+        }
+    }
+}
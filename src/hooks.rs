@@ -0,0 +1,188 @@
+//! Callbacks run at fixed points of an unpack, for [`crate::UnpackOptions::after_layer`] and
+//! [`crate::UnpackOptions::before_config`].
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::events::Event;
+use crate::hygiene::HygieneFinding;
+use crate::options::{Strictness, UnpackOptions};
+use crate::report::{
+    LayerCounts, LayerReport, LayerTiming, StrippedPermissions, UnpackReport, Warning, WarningKind,
+};
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::Dir;
+use ocidir::oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use std::path::{Path, PathBuf};
+
+pub(crate) type LayerHook = dyn Fn(&LayerContext) -> Result<()> + Send + Sync;
+
+pub(crate) type BundleHook = dyn Fn(&BundleContext) -> Result<()> + Send + Sync;
+
+/// What [`crate::UnpackOptions::after_layer`] is given about a layer that was just applied to the
+/// rootfs
+pub struct LayerContext<'a> {
+    layer_index: usize,
+    descriptor: &'a Descriptor,
+    report: &'a LayerReport,
+    timing: &'a LayerTiming,
+    rootfs: &'a Dir,
+}
+
+impl LayerContext<'_> {
+    pub fn layer_index(&self) -> usize {
+        self.layer_index
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        self.descriptor
+    }
+
+    /// The warnings raised while applying the layer
+    pub fn warnings(&self) -> &[Warning] {
+        &self.report.warnings
+    }
+
+    /// The permissions stripped from the layer's entries
+    pub fn stripped_permissions(&self) -> &[StrippedPermissions] {
+        &self.report.stripped_permissions
+    }
+
+    /// The layer's entries matched by [`crate::UnpackOptions::hygiene_rules`]
+    pub fn hygiene_findings(&self) -> &[HygieneFinding] {
+        &self.report.hygiene
+    }
+
+    /// The entries and whiteouts in the layer
+    pub fn counts(&self) -> Option<&LayerCounts> {
+        self.report.counts.last()
+    }
+
+    /// How long the layer took to apply, not counting the hook
+    pub fn timing(&self) -> &LayerTiming {
+        self.timing
+    }
+
+    /// The rootfs, with this layer and those below it applied
+    pub fn rootfs(&self) -> &Dir {
+        self.rootfs
+    }
+}
+
+/// What [`crate::UnpackOptions::before_config`] is given about a bundle whose rootfs is complete
+pub struct BundleContext<'a> {
+    bundle: &'a Path,
+    manifest: &'a ImageManifest,
+    image_config: &'a ImageConfiguration,
+    report: &'a UnpackReport,
+    rootfs: &'a Dir,
+}
+
+impl BundleContext<'_> {
+    /// The path of the bundle, which has no `config.json` yet
+    pub fn bundle(&self) -> &Path {
+        self.bundle
+    }
+
+    pub fn manifest(&self) -> &ImageManifest {
+        self.manifest
+    }
+
+    pub fn image_config(&self) -> &ImageConfiguration {
+        self.image_config
+    }
+
+    /// The report so far, which has everything about the layers, but nothing about the runtime
+    /// config, such as [`UnpackReport::spec_issues`]
+    pub fn report(&self) -> &UnpackReport {
+        self.report
+    }
+
+    /// The rootfs, with every layer applied
+    pub fn rootfs(&self) -> &Dir {
+        self.rootfs
+    }
+}
+
+/// Runs [`UnpackOptions::after_layer`], if it's set, on the layer at `index`, which was applied
+/// to `rootfs`. If it fails, the error is returned if strict, and otherwise recorded in the layer's
+/// warnings.
+pub(crate) fn after_layer(
+    index: usize,
+    descriptor: &Descriptor,
+    report: &mut LayerReport,
+    timing: &LayerTiming,
+    rootfs: &Path,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let Some(hook) = &options.after_layer else {
+        return Ok(());
+    };
+    let rootfs_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+    let context = LayerContext {
+        layer_index: index,
+        descriptor,
+        report,
+        timing,
+        rootfs: &rootfs_dir,
+    };
+    if let Err(e) = (hook.0)(&context) {
+        let kind = WarningKind::AfterLayerHookFailed;
+        report
+            .warnings
+            .push(failed("after_layer", index, kind, e, options.strictness)?);
+    }
+    Ok(())
+}
+
+/// Runs [`UnpackOptions::before_config`], if it's set, on the bundle whose rootfs is complete. If
+/// it fails, the error is returned if strict, and otherwise recorded in the report's warnings.
+pub(crate) fn before_config(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    image_config: &ImageConfiguration,
+    rootfs: &Path,
+    report: &mut UnpackReport,
+    options: &UnpackOptions,
+) -> Result<()> {
+    let Some(hook) = &options.before_config else {
+        return Ok(());
+    };
+    let rootfs_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+    let context = BundleContext {
+        bundle,
+        manifest,
+        image_config,
+        report,
+        rootfs: &rootfs_dir,
+    };
+    if let Err(e) = (hook.0)(&context) {
+        let kind = WarningKind::BeforeConfigHookFailed;
+        let warning = failed("before_config", 0, kind, e, options.strictness)?;
+        options.emit(&Event::warning(&warning));
+        report.warnings.push(warning);
+    }
+    Ok(())
+}
+
+/// Returns the error from the hook named `hook` if strict, and otherwise the warning to record
+fn failed(
+    hook: &'static str,
+    layer_index: usize,
+    kind: WarningKind,
+    error: Error,
+    strictness: Strictness,
+) -> Result<Warning> {
+    match strictness {
+        Strictness::Strict => Err(Error::Hook {
+            hook,
+            source: Box::new(error),
+        }),
+        Strictness::Permissive => {
+            log::warn!("The {hook} hook failed: {error}");
+            Ok(Warning {
+                layer_index,
+                path: PathBuf::new(),
+                kind,
+            })
+        }
+    }
+}
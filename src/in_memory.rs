@@ -0,0 +1,586 @@
+//! Unpacking small images by applying their layers to a tree in memory first, for
+//! [`crate::UnpackOptions::in_memory_max_bytes`].
+//!
+//! Each layer is read into memory and applied to a [`StagedTree`], which records the entry each
+//! path in the tree comes from. Only the entries still in the tree once every layer is applied
+//! are then written to the rootfs, layer by layer, so files that later layers replace or white out
+//! are never written, and whiteouts have nothing left to remove.
+
+use crate::apply::{FileKind, FsTarget, LayerApplier, Whiteout};
+use crate::blobs::BlobSource;
+use crate::deadline::Deadline;
+use crate::error::{Error, IoResultExt, Result};
+use crate::hygiene::HygienePolicy;
+use crate::layer_digests::size_hint;
+use crate::options::{ApplyMode, Strictness};
+use crate::report::{LayerCompression, LayerReport, LayerTiming, WarningKind, Warnings};
+use crate::retry::retry;
+use crate::{
+    check_link_target, entry_path, extract_layer, layer_applied, link_target, normalize,
+    read_layer, LayerChanges, Layers, UnpackOptions,
+};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::ops::{Bound, Range};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
+use tar::Archive;
+
+/// An entry of a staged layer: the position of the layer among those staged, and of the entry in
+/// its archive
+type EntryId = (usize, usize);
+
+/// Layers read into memory and applied to a tree there
+pub(crate) struct Staged {
+    layers: Vec<StagedLayer>,
+    tree: StagedTree,
+}
+
+struct StagedLayer {
+    index: usize,
+    archive: Vec<u8>,
+    entries: Vec<StagedEntry>,
+    /// The layer's counts, and the warnings about its whiteouts
+    report: LayerReport,
+    timing: LayerTiming,
+    compression: LayerCompression,
+}
+
+struct StagedEntry {
+    /// The bytes of the layer's archive holding the entry, with its extension headers
+    span: Range<usize>,
+    keep: Keep,
+}
+
+/// What to do with an entry of a staged layer
+enum Keep {
+    /// Write it, as nothing in the tree comes from it, so it's left to extraction to skip or warn
+    /// about it
+    Always,
+    /// Write it if it's still what's at its path in the final tree
+    IfCurrent(PathBuf),
+    /// Don't write it, as it's a whiteout, which was applied in memory
+    Never,
+}
+
+/// A hard link of a staged layer, which is only written if its target is still the entry it was
+/// linked to, as otherwise it would be linked to the replacement
+struct StagedLink {
+    path: PathBuf,
+    id: EntryId,
+    target: PathBuf,
+    target_id: Option<EntryId>,
+}
+
+/// Returns whether the layers to extract can be applied in memory: their blobs take no more than
+/// `limit` bytes, the rootfs starts empty, and nothing the unpack does needs to see every entry
+/// written to the rootfs as it's extracted
+pub(crate) fn applies(
+    layers: &Layers,
+    empty_rootfs: bool,
+    options: &UnpackOptions,
+    limit: u64,
+) -> bool {
+    let size: u64 = layers
+        .descriptors
+        .iter()
+        .zip(layers.skipped)
+        .filter(|(_, skipped)| !**skipped)
+        .map(|(descriptor, _)| descriptor.size())
+        .sum();
+    size <= limit
+        && empty_rootfs
+        && layers.windows_dir.is_none()
+        && options.apply_mode == ApplyMode::Rootfs
+        && !options.records_changes()
+        && options.after_layer.is_none()
+        && options.content_inspector.is_none()
+        && options.per_layer_policy.is_none()
+        && options.protected_paths.is_empty()
+        && options.hygiene == HygienePolicy::Off
+}
+
+/// Reads the layers to extract into memory, verifying their digests, and applies them to a tree
+/// there. Returns `None` if their archives take more than `limit` bytes, or if applying them
+/// needs something the tree doesn't model, such as an entry beneath a symlink, in which case they
+/// must be extracted as usual.
+pub(crate) fn stage(
+    blobs: &BlobSource,
+    layers: &Layers,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    limit: u64,
+) -> Result<Option<Staged>> {
+    let Layers {
+        descriptors,
+        diff_ids,
+        skipped,
+        ..
+    } = *layers;
+    let mut tree = StagedTree::default();
+    let mut staged = Vec::new();
+    let mut links = Vec::new();
+    let mut held = 0;
+    for (index, (descriptor, diff_id)) in descriptors.iter().zip(diff_ids).enumerate() {
+        if skipped[index] {
+            continue;
+        }
+        if let Some(deadline) = deadline {
+            deadline.check()?;
+        }
+        let started = Instant::now();
+        let mut timing = LayerTiming::new(index);
+        let mut compression = LayerCompression::new(index);
+        let remaining = limit.saturating_sub(held);
+        // Nothing is applied until the layer is read, so a failed attempt has nothing to undo
+        let archive = retry(index, options, deadline, |_| {
+            read_layer(
+                blobs,
+                descriptors,
+                index,
+                diff_id,
+                options,
+                deadline,
+                &mut timing,
+                &mut compression,
+                |reader, _| {
+                    let hint = size_hint(descriptor).unwrap_or(0).min(remaining);
+                    let mut archive = Vec::with_capacity(hint as usize);
+                    reader
+                        .take(remaining + 1)
+                        .read_to_end(&mut archive)
+                        .map_err(Error::Archive)?;
+                    Ok(archive)
+                },
+            )
+        })
+        .map_err(|e| e.in_layer(index, descriptor))?;
+        held += archive.len() as u64;
+        if held > limit {
+            log::debug!("Layers are larger than {limit} bytes, so extracting them as usual");
+            return Ok(None);
+        }
+        let entries = stage_layer(
+            &mut tree,
+            staged.len(),
+            index,
+            &archive,
+            options,
+            &mut links,
+        )
+        .map_err(|e| e.in_layer(index, descriptor))?;
+        let Some((entries, report)) = entries else {
+            log::debug!("Layer {index} can't be applied in memory, so extracting layers as usual");
+            return Ok(None);
+        };
+        timing.duration = started.elapsed();
+        staged.push(StagedLayer {
+            index,
+            archive,
+            entries,
+            report,
+            timing,
+            compression,
+        });
+    }
+
+    for link in &links {
+        if tree.entry(&link.path) == Some(link.id) && tree.entry(&link.target) != link.target_id {
+            log::debug!(
+                "The target of hard link {} was replaced, so extracting layers as usual",
+                link.path.display()
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(Staged {
+        layers: staged,
+        tree,
+    }))
+}
+
+/// Applies `archive`, of the layer at `index`, the `position`th staged, to `tree`, returning its
+/// entries and its counts and whiteout warnings. Returns `None` if it can't be applied in memory.
+fn stage_layer(
+    tree: &mut StagedTree,
+    position: usize,
+    index: usize,
+    archive: &[u8],
+    options: &UnpackOptions,
+    links: &mut Vec<StagedLink>,
+) -> Result<Option<(Vec<StagedEntry>, LayerReport)>> {
+    let mut warnings = Warnings::new(options.strictness, index);
+    // Link targets are checked as extraction does, but warned about when the entry is written
+    let mut link_warnings = Warnings::new(Strictness::Permissive, index);
+    let mut applier = LayerApplier::new(std::mem::take(tree));
+    let mut entries = Vec::new();
+    let mut dirs = Vec::new();
+
+    let read = Rc::new(Cell::new(0));
+    let mut tar = Archive::new(CountingReader {
+        data: archive,
+        read: read.clone(),
+    });
+    let mut start = 0;
+    for (ordinal, entry) in tar.entries().map_err(Error::Archive)?.enumerate() {
+        options.check_entry_count(ordinal)?;
+        let mut entry = entry.map_err(Error::Archive)?;
+        warnings.entry();
+        let id = (position, ordinal);
+        let path = options
+            .unicode_policy
+            .path(&entry_path(&entry)?)
+            .into_owned();
+        options.check_entry_size(&path, entry.size())?;
+        let normalized = normalize(&path);
+        let target = applier.target();
+        let is_unsafe = path.components().any(|c| c == Component::ParentDir);
+        #[cfg(feature = "estargz")]
+        let is_unsafe = is_unsafe || crate::estargz::is_reserved(&path);
+
+        let keep = if entry.header().entry_type().is_dir() {
+            if is_unsafe || normalized.as_os_str().is_empty() {
+                Keep::Always
+            } else if target.blocked(&normalized)
+                || target.kind(&normalized)? == Some(FileKind::Symlink)
+            {
+                return Ok(None);
+            } else {
+                // Directories are created at the end of the layer, as when extracting
+                applier.replace_with_dir(&normalized)?;
+                dirs.push((normalized.clone(), id));
+                Keep::IfCurrent(normalized)
+            }
+        } else if is_unsafe || path.file_name().is_none() {
+            Keep::Always
+        } else {
+            match Whiteout::parse(&path) {
+                Some(Whiteout::Invalid) => Keep::Always,
+                Some(Whiteout::Opaque(dir)) => {
+                    let dir = normalize(&dir);
+                    if target.blocked(&dir) || target.kind(&dir)? == Some(FileKind::Symlink) {
+                        return Ok(None);
+                    }
+                    warnings.whiteout();
+                    if !applier.opaque_whiteout(&dir)? {
+                        warnings.warn(&path, WarningKind::DanglingWhiteout)?;
+                    }
+                    Keep::Never
+                }
+                Some(Whiteout::Entry(removed)) => {
+                    let removed = normalize(&removed);
+                    if target.blocked(&removed) {
+                        return Ok(None);
+                    }
+                    warnings.whiteout();
+                    if !applier.whiteout(&removed)? {
+                        warnings.warn(&path, WarningKind::DanglingWhiteout)?;
+                    }
+                    Keep::Never
+                }
+                None if !check_link_target(&entry, &path, options, &mut link_warnings)? => {
+                    Keep::Always
+                }
+                None => {
+                    if target.blocked(&normalized) {
+                        return Ok(None);
+                    }
+                    let entry_type = entry.header().entry_type();
+                    if entry_type.is_hard_link() {
+                        let link = link_target(&entry, &path)?;
+                        let link = normalize(&options.unicode_policy.path(&link));
+                        links.push(StagedLink {
+                            path: normalized.clone(),
+                            id,
+                            target_id: target.entry(&link),
+                            target: link,
+                        });
+                    }
+                    let kind = if entry_type.is_symlink() {
+                        FileKind::Symlink
+                    } else {
+                        FileKind::File
+                    };
+                    applier.replace(&normalized)?;
+                    applier.target_mut().insert(&normalized, kind, Some(id))?;
+                    Keep::IfCurrent(normalized)
+                }
+            }
+        };
+        // The entry ends where its content does, padded to a whole block
+        io::copy(&mut entry, &mut io::sink()).map_err(Error::Archive)?;
+        let end = read.get().div_ceil(512) * 512;
+        entries.push(StagedEntry {
+            span: start..end,
+            keep,
+        });
+        start = end;
+    }
+
+    let mut tree_after = applier.into_target();
+    for (path, id) in dirs {
+        if !tree_after.claim_dir(&path, id)? {
+            return Ok(None);
+        }
+    }
+    *tree = tree_after;
+    Ok(Some((entries, warnings.finish())))
+}
+
+/// Writes the entries of the staged layers that are in the final tree to `rootfs`, each layer's
+/// with the usual checks and warnings
+pub(crate) fn write(
+    staged: Staged,
+    layers: &Layers,
+    rootfs: &Path,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+) -> Result<LayerReport> {
+    let Staged {
+        layers: staged,
+        tree,
+    } = staged;
+    let mut report = LayerReport::default();
+    for (position, layer) in staged.into_iter().enumerate() {
+        let StagedLayer {
+            index,
+            archive,
+            entries,
+            report: staged_report,
+            mut timing,
+            compression,
+        } = layer;
+        let descriptor = &layers.descriptors[index];
+        if let Some(deadline) = deadline {
+            deadline.check()?;
+        }
+        let writing = Instant::now();
+        let mut flattened = Vec::new();
+        for (ordinal, entry) in entries.into_iter().enumerate() {
+            let kept = match entry.keep {
+                Keep::Always => true,
+                Keep::IfCurrent(path) => tree.entry(&path) == Some((position, ordinal)),
+                Keep::Never => false,
+            };
+            if kept {
+                flattened.extend_from_slice(&archive[entry.span]);
+            }
+        }
+        // The end of the archive
+        flattened.resize(flattened.len() + 1024, 0);
+        let mut layer_report = extract_layer(
+            &mut Archive::new(flattened.as_slice()),
+            rootfs,
+            index,
+            options,
+            &mut LayerChanges::new(false),
+            None,
+        )
+        .map_err(|e| e.in_layer(index, descriptor))?;
+        // The counts are of the whole layer, not only the entries written
+        layer_report.counts = staged_report.counts;
+        let mut warnings = staged_report.warnings;
+        warnings.append(&mut layer_report.warnings);
+        layer_report.warnings = warnings;
+        timing.write += writing.elapsed();
+        timing.duration += writing.elapsed();
+        layer_applied(
+            index,
+            descriptor,
+            &mut layer_report,
+            &timing,
+            rootfs,
+            options,
+            deadline,
+            layers.checkpoints,
+        )?;
+        layer_report.timings.push(timing);
+        layer_report.compression.push(compression);
+        report.append(layer_report);
+    }
+    Ok(report)
+}
+
+/// Counts the bytes read through it, to find where each entry of an archive ends
+struct CountingReader<'a> {
+    data: &'a [u8],
+    read: Rc<Cell<usize>>,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        self.read.set(self.read.get() + read);
+        Ok(read)
+    }
+}
+
+/// An entry in a [`StagedTree`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StagedNode {
+    kind: FileKind,
+    /// The entry that put it there, which directories created as the parents of others don't
+    /// have
+    entry: Option<EntryId>,
+}
+
+/// A tree held in memory that records which entry of the staged layers each path comes from, as
+/// a [`crate::MemoryTree`] records their content. Symlinks are never followed.
+#[derive(Debug, Default)]
+struct StagedTree {
+    /// The entries, other than the root, which is always a directory
+    nodes: BTreeMap<PathBuf, StagedNode>,
+}
+
+impl StagedTree {
+    /// Returns the entry that put what's at `path` there
+    fn entry(&self, path: &Path) -> Option<EntryId> {
+        self.nodes.get(path).and_then(|node| node.entry)
+    }
+
+    /// Returns whether one of the parents of `path` isn't a directory, so that applying an entry
+    /// there would go through a symlink, or fail
+    fn blocked(&self, path: &Path) -> bool {
+        path.ancestors().skip(1).any(|parent| {
+            self.nodes
+                .get(parent)
+                .is_some_and(|node| node.kind != FileKind::Dir)
+        })
+    }
+
+    /// Inserts an entry of `kind` at `path`, replacing what's there, creating the missing parents
+    fn insert(&mut self, path: &Path, kind: FileKind, entry: Option<EntryId>) -> Result<()> {
+        self.mkdir(path.parent().unwrap_or(Path::new("")))?;
+        self.nodes
+            .insert(path.to_path_buf(), StagedNode { kind, entry });
+        Ok(())
+    }
+
+    /// Records the directory entry `id` as what's at `path`, creating the directory unless it's
+    /// there. Returns false if something else is there.
+    fn claim_dir(&mut self, path: &Path, id: EntryId) -> Result<bool> {
+        if self.kind(path)?.is_some_and(|kind| kind != FileKind::Dir) {
+            return Ok(false);
+        }
+        self.insert(path, FileKind::Dir, Some(id))?;
+        Ok(true)
+    }
+
+    /// Returns the paths beneath `path`, in order
+    fn descendants<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+        self.nodes
+            .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+            .map(|(descendant, _)| descendant)
+            .take_while(move |descendant| descendant.starts_with(path))
+    }
+}
+
+impl FsTarget for StagedTree {
+    fn write_file(&mut self, path: &Path, content: &mut dyn Read) -> Result<()> {
+        io::copy(content, &mut io::sink()).with_path(path)?;
+        self.insert(path, FileKind::File, None)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<()> {
+        let mut current = PathBuf::new();
+        for component in path.components() {
+            current.push(component);
+            match self.nodes.get(&current) {
+                Some(node) if node.kind == FileKind::Dir => {}
+                Some(_) => {
+                    return Err(io::Error::from(io::ErrorKind::NotADirectory)).with_path(&current)
+                }
+                None => {
+                    let node = StagedNode {
+                        kind: FileKind::Dir,
+                        entry: None,
+                    };
+                    self.nodes.insert(current.clone(), node);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn symlink(&mut self, path: &Path, _target: &Path) -> Result<()> {
+        self.insert(path, FileKind::Symlink, None)
+    }
+
+    fn hard_link(&mut self, path: &Path, target: &Path) -> Result<()> {
+        match self.nodes.get(target) {
+            Some(node) if node.kind != FileKind::Dir => self.insert(path, node.kind, None),
+            Some(_) => Err(io::Error::from(io::ErrorKind::PermissionDenied)).with_path(target),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)).with_path(target),
+        }
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.descendants(path).next().is_some() {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty)).with_path(path);
+        }
+        match self.nodes.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)).with_path(path),
+        }
+    }
+
+    fn remove_all(&mut self, path: &Path) -> Result<()> {
+        if self.nodes.remove(path).is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound)).with_path(path);
+        }
+        let descendants: Vec<_> = self.descendants(path).cloned().collect();
+        for descendant in descendants {
+            self.nodes.remove(&descendant);
+        }
+        Ok(())
+    }
+
+    fn kind(&self, path: &Path) -> Result<Option<FileKind>> {
+        if path.as_os_str().is_empty() {
+            return Ok(Some(FileKind::Dir));
+        }
+        Ok(self.nodes.get(path).map(|node| node.kind))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        if self.kind(path)? != Some(FileKind::Dir) {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory)).with_path(path);
+        }
+        Ok(self
+            .descendants(path)
+            .filter(|descendant| descendant.parent() == Some(path))
+            .filter_map(|descendant| descendant.file_name().map(OsStr::to_os_string))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staged_tree() {
+        let mut tree = StagedTree::default();
+        tree.insert(Path::new("a/b/file"), FileKind::File, Some((0, 1)))
+            .unwrap();
+        tree.insert(Path::new("a/link"), FileKind::Symlink, Some((0, 2)))
+            .unwrap();
+        tree.insert(Path::new("a-b"), FileKind::File, Some((0, 3)))
+            .unwrap();
+        assert_eq!(tree.entry(Path::new("a/b/file")), Some((0, 1)));
+        assert_eq!(tree.entry(Path::new("a/b")), None);
+        assert_eq!(tree.read_dir(Path::new("a")).unwrap(), ["b", "link"]);
+        assert!(tree.blocked(Path::new("a/link/x")));
+        assert!(!tree.blocked(Path::new("a/b/x")));
+        assert!(!tree.claim_dir(Path::new("a/link"), (1, 0)).unwrap());
+        assert!(tree.claim_dir(Path::new("a/b"), (1, 1)).unwrap());
+        assert_eq!(tree.entry(Path::new("a/b")), Some((1, 1)));
+
+        tree.remove_all(Path::new("a")).unwrap();
+        let paths: Vec<_> = tree.nodes.keys().collect();
+        assert_eq!(paths, [Path::new("a-b")]);
+    }
+}
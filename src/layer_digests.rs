@@ -0,0 +1,399 @@
+//! The digests a layer is verified against: its descriptor's digest and diff ID, and any given by
+//! annotations on its descriptor. Each is computed in the same pass over the layer. Also the size
+//! of its archive that annotations give.
+
+use crate::digest_reader::{Algorithm, Digests};
+use crate::error::{DigestKind, Error, Result};
+use crate::events::Event;
+use crate::options::{Strictness, UnpackOptions};
+use crate::report::{LayerCompression, Warning, WarningKind};
+use ocidir::oci_spec::image::Descriptor;
+use std::path::PathBuf;
+
+/// The annotation containerd records a layer's diff ID in. Annotations named by it, a `/` and an
+/// algorithm, such as `containerd.io/uncompressed/sha512`, give the digest of the layer's archive
+/// with that algorithm, either in full or as just its hex encoding. Each of them is verified along
+/// with the diff ID.
+pub const UNCOMPRESSED_DIGEST_ANNOTATION: &str = "containerd.io/uncompressed";
+
+/// The annotation eStargz layers, as pushed by containerd's tools, record the size of their archive
+/// in, as a decimal number of bytes. The size is reported in
+/// [`crate::LayerCompression::uncompressed_size_hint`], compared with the archive's when its
+/// digests are verified, and used to size the buffers that layers are read into.
+pub const UNCOMPRESSED_SIZE_ANNOTATION: &str = "io.containers.estargz.uncompressed-size";
+
+/// A digest of a layer's blob or archive that was checked while unpacking it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VerifiedDigest {
+    /// [`DigestKind::Layer`] for a digest of the blob, or [`DigestKind::DiffId`] for one of its
+    /// archive
+    pub kind: DigestKind,
+    /// The digest, e.g. `sha512:...`
+    pub digest: String,
+    /// The annotation on the layer's descriptor that gave the digest, or `None` for the
+    /// descriptor's digest or the diff ID
+    pub annotation: Option<String>,
+}
+
+/// A digest that a layer's blob or archive should have
+#[derive(Debug)]
+struct Expected {
+    algorithm: Algorithm,
+    digest: String,
+    annotation: Option<String>,
+}
+
+/// The digests expected of a layer's blob and archive
+#[derive(Debug)]
+pub(crate) struct LayerDigests {
+    blob: Vec<Expected>,
+    /// Starting with the diff ID
+    archive: Vec<Expected>,
+}
+
+impl LayerDigests {
+    pub(crate) fn new(descriptor: &Descriptor, diff_id: &str) -> Self {
+        let mut archive = vec![Expected::required(diff_id)];
+        // Annotations that contradict the diff ID are warned about by check_annotations, as the
+        // archive is verified against the diff ID regardless
+        archive.extend(
+            annotated(descriptor)
+                .into_iter()
+                .filter(|(_, digest)| !contradicts(digest, diff_id))
+                .filter_map(|(key, digest)| {
+                    Some(Expected {
+                        algorithm: algorithm(&digest)?,
+                        digest,
+                        annotation: Some(key.to_string()),
+                    })
+                }),
+        );
+        Self {
+            blob: vec![Expected::required(descriptor.digest().as_ref())],
+            archive,
+        }
+    }
+
+    /// The algorithms to hash the blob with
+    pub(crate) fn blob_algorithms(&self) -> Vec<Algorithm> {
+        self.blob
+            .iter()
+            .map(|expected| expected.algorithm)
+            .collect()
+    }
+
+    /// The algorithms to hash the archive with
+    pub(crate) fn archive_algorithms(&self) -> Vec<Algorithm> {
+        self.archive
+            .iter()
+            .map(|expected| expected.algorithm)
+            .collect()
+    }
+
+    /// Returns whether the archive's digests include its diff ID
+    pub(crate) fn matches_diff_id(&self, digests: &Digests) -> bool {
+        self.archive[0].matches(digests)
+    }
+
+    /// Checks the digests of the layer's blob, recording those checked in `verified`
+    pub(crate) fn check_blob(
+        &self,
+        index: usize,
+        digests: &Digests,
+        options: &UnpackOptions,
+        verified: &mut Vec<VerifiedDigest>,
+    ) -> Result<()> {
+        check(
+            &self.blob,
+            DigestKind::Layer,
+            index,
+            digests,
+            options,
+            verified,
+        )
+    }
+
+    /// Checks the digests of the layer's archive, recording those checked in `verified`
+    pub(crate) fn check_archive(
+        &self,
+        index: usize,
+        digests: &Digests,
+        options: &UnpackOptions,
+        verified: &mut Vec<VerifiedDigest>,
+    ) -> Result<()> {
+        check(
+            &self.archive,
+            DigestKind::DiffId,
+            index,
+            digests,
+            options,
+            verified,
+        )
+    }
+}
+
+impl Expected {
+    /// A digest that must be checked. If its algorithm isn't supported, it's compared with the
+    /// sha256 digest, which can't match.
+    fn required(digest: &str) -> Self {
+        Self {
+            algorithm: algorithm(digest).unwrap_or(Algorithm::Sha256),
+            digest: digest.to_string(),
+            annotation: None,
+        }
+    }
+
+    fn actual(&self, digests: &Digests) -> String {
+        let hex = digests.get(self.algorithm).unwrap_or_default();
+        format!("{}:{hex}", self.algorithm.as_str())
+    }
+
+    fn matches(&self, digests: &Digests) -> bool {
+        self.actual(digests) == self.digest
+    }
+}
+
+fn check(
+    expected: &[Expected],
+    kind: DigestKind,
+    index: usize,
+    digests: &Digests,
+    options: &UnpackOptions,
+    verified: &mut Vec<VerifiedDigest>,
+) -> Result<()> {
+    for expected in expected {
+        let actual = expected.actual(digests);
+        options.emit(&Event::digest_verified(
+            Some(index),
+            kind,
+            &expected.digest,
+            &actual,
+        ));
+        if actual != expected.digest {
+            return Err(Error::DigestMismatch {
+                layer_index: Some(index),
+                kind,
+                expected: expected.digest.clone(),
+                actual,
+            });
+        }
+        verified.push(VerifiedDigest {
+            kind,
+            digest: actual,
+            annotation: expected.annotation.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the supported algorithm of `digest`
+fn algorithm(digest: &str) -> Option<Algorithm> {
+    Algorithm::parse(digest.split_once(':')?.0)
+}
+
+/// Returns whether `digest` is a different digest than `diff_id` with the same algorithm
+fn contradicts(digest: &str, diff_id: &str) -> bool {
+    digest != diff_id
+        && digest.split_once(':').map(|(a, _)| a) == diff_id.split_once(':').map(|(a, _)| a)
+}
+
+/// Returns the size of the layer's archive given by the [`UNCOMPRESSED_SIZE_ANNOTATION`] on
+/// `descriptor`, if it has one that can be parsed
+pub(crate) fn size_hint(descriptor: &Descriptor) -> Option<u64> {
+    descriptor
+        .annotations()
+        .as_ref()?
+        .get(UNCOMPRESSED_SIZE_ANNOTATION)?
+        .parse()
+        .ok()
+}
+
+/// Returns the annotations on `descriptor` that give a digest of its archive, with the digest, in
+/// the order of their names
+fn annotated(descriptor: &Descriptor) -> Vec<(&str, String)> {
+    let mut annotated: Vec<_> = descriptor
+        .annotations()
+        .iter()
+        .flatten()
+        .filter_map(|(key, value)| {
+            let suffix = key.strip_prefix(UNCOMPRESSED_DIGEST_ANNOTATION)?;
+            if !suffix.is_empty() && !suffix.starts_with('/') {
+                return None;
+            }
+            let digest = if value.contains(':') {
+                value.clone()
+            } else {
+                format!("{}:{value}", suffix.strip_prefix('/')?)
+            };
+            Some((key.as_str(), digest))
+        })
+        .collect();
+    annotated.sort();
+    annotated
+}
+
+/// Returns a warning for each layer, other than those `skipped`, with an annotation giving a
+/// digest with an algorithm that isn't supported, which isn't verified, and for each with an
+/// annotation giving a different digest than its diff ID in `diff_ids`, failing on those instead
+/// with [`Strictness::Strict`]
+pub(crate) fn check_annotations(
+    layers: &[Descriptor],
+    diff_ids: &[String],
+    skipped: &[bool],
+    strictness: Strictness,
+) -> Result<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    for (layer_index, (descriptor, diff_id)) in layers.iter().zip(diff_ids).enumerate() {
+        if skipped[layer_index] {
+            continue;
+        }
+        for (key, digest) in annotated(descriptor) {
+            if algorithm(&digest).is_none() {
+                log::warn!("Not verifying digest {digest} from annotation {key} of layer {layer_index}, as its algorithm isn't supported");
+                warnings.push(Warning {
+                    layer_index,
+                    path: PathBuf::new(),
+                    kind: WarningKind::UnsupportedDigestAlgorithm,
+                });
+            } else if contradicts(&digest, diff_id) {
+                let warning = Warning {
+                    layer_index,
+                    path: PathBuf::new(),
+                    kind: WarningKind::AnnotatedDiffIdMismatch,
+                };
+                if strictness == Strictness::Strict {
+                    return Err(Error::Warning(warning));
+                }
+                log::warn!("Annotation {key} of layer {layer_index} gives digest {digest}, but its diff ID is {diff_id}");
+                warnings.push(warning);
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// Returns a warning for each layer in `compression` whose archive's size differs from its
+/// [`LayerCompression::uncompressed_size_hint`], failing on the first instead with
+/// [`Strictness::Strict`]. Only meaningful once every layer was read in full, as when their
+/// digests are verified.
+pub(crate) fn check_size_hints(
+    compression: &[LayerCompression],
+    strictness: Strictness,
+) -> Result<Vec<Warning>> {
+    let mut warnings = Vec::new();
+    for layer in compression {
+        let Some(hint) = layer.uncompressed_size_hint else {
+            continue;
+        };
+        if hint == layer.uncompressed_bytes {
+            continue;
+        }
+        let warning = Warning {
+            layer_index: layer.layer_index,
+            path: PathBuf::new(),
+            kind: WarningKind::UncompressedSizeMismatch,
+        };
+        if strictness == Strictness::Strict {
+            return Err(Error::Warning(warning));
+        }
+        log::warn!(
+            "Layer {} has an archive of {} bytes, but its {UNCOMPRESSED_SIZE_ANNOTATION} annotation gives {hint}",
+            layer.layer_index,
+            layer.uncompressed_bytes
+        );
+        warnings.push(warning);
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::image::{DescriptorBuilder, MediaType};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_annotated() {
+        let annotations = HashMap::from([
+            (UNCOMPRESSED_DIGEST_ANNOTATION, "sha256:aa"),
+            ("containerd.io/uncompressed/sha512", "bb"),
+            ("containerd.io/uncompressed/blake3", "cc"),
+            ("containerd.io/uncompressed/sha384", "sha384:dd"),
+            ("containerd.io/uncompressedx", "sha256:ee"),
+            ("org.example/digest", "sha256:ff"),
+        ]);
+        let descriptor = DescriptorBuilder::default()
+            .media_type(MediaType::ImageLayerGzip)
+            .digest(
+                "sha256:0000000000000000000000000000000000000000000000000000000000000000"
+                    .parse::<ocidir::oci_spec::image::Digest>()
+                    .unwrap(),
+            )
+            .size(0u64)
+            .annotations(
+                annotations
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect::<HashMap<_, _>>(),
+            )
+            .build()
+            .unwrap();
+        let found: Vec<_> = annotated(&descriptor)
+            .into_iter()
+            .map(|(key, digest)| (key.to_string(), digest))
+            .collect();
+        let expected = [
+            (UNCOMPRESSED_DIGEST_ANNOTATION, "sha256:aa"),
+            ("containerd.io/uncompressed/blake3", "blake3:cc"),
+            ("containerd.io/uncompressed/sha384", "sha384:dd"),
+            ("containerd.io/uncompressed/sha512", "sha512:bb"),
+        ];
+        assert_eq!(
+            found,
+            expected.map(|(key, digest)| (key.to_string(), digest.to_string()))
+        );
+
+        let digests = LayerDigests::new(&descriptor, "sha256:aa");
+        assert_eq!(
+            digests.archive_algorithms(),
+            vec![
+                Algorithm::Sha256,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+                Algorithm::Sha512
+            ]
+        );
+        let layers = [descriptor];
+        let diff_ids = ["sha256:aa".to_string()];
+        let check = |skipped| check_annotations(&layers, &diff_ids, skipped, Strictness::Strict);
+        assert_eq!(check(&[false]).unwrap().len(), 1);
+        assert!(check(&[true]).unwrap().is_empty());
+
+        // The annotated sha256 digest contradicts this diff ID, so only the others are verified
+        let digests = LayerDigests::new(&layers[0], "sha256:11");
+        assert_eq!(
+            digests.archive_algorithms(),
+            vec![Algorithm::Sha256, Algorithm::Sha384, Algorithm::Sha512]
+        );
+        let diff_ids = ["sha256:11".to_string()];
+        let warnings =
+            check_annotations(&layers, &diff_ids, &[false], Strictness::Permissive).unwrap();
+        // In the order of the annotations' keys, containerd.io/uncompressed first
+        let kinds: Vec<_> = warnings.iter().map(|warning| warning.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                WarningKind::AnnotatedDiffIdMismatch,
+                WarningKind::UnsupportedDigestAlgorithm
+            ]
+        );
+        assert!(matches!(
+            check_annotations(&layers, &diff_ids, &[false], Strictness::Strict),
+            Err(Error::Warning(Warning {
+                kind: WarningKind::AnnotatedDiffIdMismatch,
+                ..
+            }))
+        ));
+    }
+}
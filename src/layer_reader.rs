@@ -0,0 +1,168 @@
+//! Decompressing and hashing a layer blob as it's read, which is how unpacking verifies layers
+//! and how [`crate::compute_diff_id`] computes their digests.
+
+use crate::blob_cache::{NewEntry, Tee};
+use crate::digest_reader::{Algorithm, DigestReader, Digests};
+use crate::error::{Error, Result};
+use crate::gzip::GzipDecoder;
+use ocidir::oci_spec::image::MediaType;
+use std::io::{self, BufRead, BufReader, Read, Seek};
+
+/// The size of the buffer [`compute_diff_id`] reads blobs with
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// The digests and sizes of a layer blob, computed by [`compute_diff_id`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerDigest {
+    /// The digest of the uncompressed archive, e.g. `sha256:...`, as listed in the diff IDs of
+    /// an image config
+    pub diff_id: String,
+    /// The digest of the blob, as in the layer's descriptor
+    pub blob_digest: String,
+    /// The size of the uncompressed archive in bytes
+    pub uncompressed_size: u64,
+    /// The size of the blob in bytes
+    pub compressed_size: u64,
+}
+
+/// Computes the SHA-256 diff ID and digest of a layer blob with `media_type`, decompressing it
+/// as unpacking would without extracting anything.
+///
+/// As when unpacking, the diff ID is the digest of the whole archive, which for a blob of several
+/// concatenated gzip members is the concatenation of what each decompresses to. A blob that
+/// continues after its last member with anything but another fails with
+/// [`Error::TrailingData`], whose `layer_index` is 0. Media types that can't be unpacked fail
+/// with [`Error::UnsupportedMediaType`]. `blob` is read from its start.
+pub fn compute_diff_id(media_type: &MediaType, mut blob: impl Read + Seek) -> Result<LayerDigest> {
+    if *media_type != MediaType::ImageLayerGzip {
+        return Err(Error::UnsupportedMediaType(media_type.to_string()));
+    }
+    blob.rewind().map_err(Error::Archive)?;
+    let algorithms = [Algorithm::Sha256];
+    let layer = LayerReader::stream(blob, &algorithms, &algorithms, None, BUFFER_SIZE);
+    let finished = layer.finish_blob()?;
+    if finished.trailing > 0 {
+        return Err(Error::TrailingData {
+            layer_index: 0,
+            bytes: finished.trailing,
+        });
+    }
+    Ok(LayerDigest {
+        diff_id: format!("sha256:{}", finished.archive.sha256()),
+        blob_digest: format!("sha256:{}", finished.blob.sha256()),
+        uncompressed_size: finished.archive_bytes,
+        compressed_size: finished.blob_bytes,
+    })
+}
+
+/// Decompresses a gzip layer read from `B`, hashing its archive as it's read, and copying the
+/// archive into a decompressed blob cache entry if there is one. Reading from it reads the
+/// archive.
+pub(crate) struct LayerReader<B: BufRead> {
+    reader: DigestReader<Tee<GzipDecoder<B>>>,
+}
+
+/// A layer that was read to the end of its gzip stream
+pub(crate) struct FinishedLayer<B> {
+    /// The digests of the archive
+    pub(crate) archive: Digests,
+    pub(crate) archive_bytes: u64,
+    /// The number of gzip members
+    pub(crate) members: u64,
+    pub(crate) cache_entry: Option<NewEntry>,
+    /// The compressed input, after the end of the gzip stream
+    pub(crate) rest: B,
+}
+
+/// A streamed layer whose blob was read to its end
+pub(crate) struct FinishedBlob {
+    pub(crate) archive: Digests,
+    pub(crate) archive_bytes: u64,
+    pub(crate) members: u64,
+    pub(crate) cache_entry: Option<NewEntry>,
+    /// The digests of the blob
+    pub(crate) blob: Digests,
+    pub(crate) blob_bytes: u64,
+    /// The number of bytes after the end of the gzip stream
+    pub(crate) trailing: u64,
+}
+
+impl<B: BufRead> LayerReader<B> {
+    /// Decompresses every gzip member of `compressed`, and hashes the archive with
+    /// `archive_algorithms`
+    pub(crate) fn new(
+        compressed: B,
+        archive_algorithms: &[Algorithm],
+        cache_entry: Option<NewEntry>,
+    ) -> Self {
+        Self {
+            reader: DigestReader::with_algorithms(
+                Tee::new(GzipDecoder::new(compressed), cache_entry),
+                archive_algorithms,
+            ),
+        }
+    }
+
+    pub(crate) fn decoder(&mut self) -> &mut GzipDecoder<B> {
+        self.reader.get_mut().get_mut()
+    }
+
+    /// Reads whatever is left of the archive, so that it's all hashed
+    pub(crate) fn finish(mut self) -> Result<FinishedLayer<B>> {
+        self.reader.drain().map_err(Error::Archive)?;
+        let archive_bytes = self.reader.bytes_read();
+        let (archive, tee) = self.reader.finish();
+        let (decoder, cache_entry) = tee.into_parts();
+        Ok(FinishedLayer {
+            archive,
+            archive_bytes,
+            members: decoder.members(),
+            cache_entry,
+            rest: decoder.into_inner(),
+        })
+    }
+}
+
+impl<R: Read> LayerReader<BufReader<DigestReader<R>>> {
+    /// Reads a layer from `blob`, buffering `buffer_size` bytes of it at a time, and hashing it
+    /// with `blob_algorithms` before it's decompressed
+    pub(crate) fn stream(
+        blob: R,
+        blob_algorithms: &[Algorithm],
+        archive_algorithms: &[Algorithm],
+        cache_entry: Option<NewEntry>,
+        buffer_size: usize,
+    ) -> Self {
+        let blob = DigestReader::with_algorithms(blob, blob_algorithms);
+        Self::new(
+            BufReader::with_capacity(buffer_size, blob),
+            archive_algorithms,
+            cache_entry,
+        )
+    }
+
+    /// As [`LayerReader::finish`], then reads the rest of the blob, so that it's all hashed
+    pub(crate) fn finish_blob(self) -> Result<FinishedBlob> {
+        let finished = self.finish()?;
+        let buffered = finished.rest.buffer().len() as u64;
+        let mut blob = finished.rest.into_inner();
+        let unread = io::copy(&mut blob, &mut io::sink()).map_err(Error::Archive)?;
+        let blob_bytes = blob.bytes_read();
+        Ok(FinishedBlob {
+            archive: finished.archive,
+            archive_bytes: finished.archive_bytes,
+            members: finished.members,
+            cache_entry: finished.cache_entry,
+            blob: blob.finish().0,
+            blob_bytes,
+            trailing: buffered + unread,
+        })
+    }
+}
+
+impl<B: BufRead> Read for LayerReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
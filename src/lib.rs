@@ -1,197 +1,1943 @@
-use anyhow::{bail, Context, Result};
-use flate2::read::GzDecoder;
+use apply::{DirTarget, Whiteout};
+use blob_cache::NewEntry;
+use blobs::BlobSource;
+use copy::ArchiveCloner;
+use deadline::Deadline;
+use digest_reader::{DigestReader, Digests};
+use error::IoResultExt;
+use events::Event;
+use filetime::FileTime;
+use gzip::GzipDecoder;
+use layer_digests::LayerDigests;
+use layer_reader::LayerReader;
+use mmap::Mmap;
 use ocidir::cap_std::ambient_authority;
 use ocidir::cap_std::fs::Dir;
-use ocidir::oci_spec::image::{ImageConfiguration, ImageManifest, MediaType};
+use ocidir::oci_spec::image::{
+    Descriptor, Digest, DigestAlgorithm, ImageConfiguration, ImageIndex, ImageManifest, MediaType,
+    Os, Platform,
+};
 use ocidir::oci_spec::runtime::{ProcessBuilder, UserBuilder};
 use ocidir::OciDir;
-use sha256_reader::Sha256Reader;
-use std::collections::HashMap;
+use protected::Modification;
+use report::{EntryChange, LayerReport, Warnings};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self};
-use std::io::{self};
+use std::io::{self, BufReader, Read};
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use tar::Archive;
-use users::{get_user_by_name, get_user_by_uid, get_group_by_name, get_group_by_gid, get_groups};
+use update::Base;
 
-mod sha256_reader;
+mod acl;
+mod annotations;
+mod apply;
+mod args;
+mod base_image;
+mod blob_cache;
+mod blobs;
+mod bundle;
+mod bundle_archive;
+mod cache_set;
+mod capabilities;
+mod changes;
+mod checkpoint;
+mod container_env;
+mod copy;
+mod deadline;
+mod digest_reader;
+mod dir_mtimes;
+mod effective;
+mod env_summary;
+mod error;
+#[cfg(feature = "estargz")]
+mod estargz;
+mod events;
+mod extract;
+mod gzip;
+mod hermetic;
+mod history;
+mod hooks;
+mod hygiene;
+mod in_memory;
+mod layer_digests;
+mod layer_reader;
+mod metadata;
+mod metrics;
+mod mmap;
+mod mounts;
+mod options;
+mod parallel;
+mod parents;
+mod passthrough;
+mod plan;
+mod platform;
+mod prefetch;
+mod protected;
+mod reference;
+mod remap;
+mod report;
+mod resources;
+mod retry;
+#[cfg(feature = "rootfs-image")]
+mod rootfs_image;
+mod rootless;
+mod spec;
+#[cfg(feature = "test-util")]
+pub mod testing;
+mod timestamps;
+mod timing;
+mod unicode;
+mod unpacker;
+mod update;
+mod user;
+mod verify;
+mod waste;
+mod windows;
+mod working_dir;
+mod write;
+mod write_bits;
+
+pub use apply::{FileKind, FsTarget, LayerApplier, MemoryNode, MemoryTree};
+pub use base_image::{base_image_of, BaseImageRef, BASE_DIGEST_ANNOTATION, BASE_NAME_ANNOTATION};
+pub use blob_cache::prune_decompressed_blob_cache;
+pub use bundle::Bundle;
+pub use bundle_archive::{ArchiveCompression, ExportOptions};
+pub use cache_set::{missing_layers, CacheSet};
+pub use capabilities::{FileCapability, FILE_CAPABILITIES_ANNOTATION};
+pub use copy::{copy_tree, CopyStrategy};
+pub use effective::EffectiveOptions;
+pub use error::{BlobError, BlobRole, BundleError, DigestKind, Error, Result};
+#[cfg(feature = "estargz")]
+pub use estargz::TOC_DIGEST_ANNOTATION;
+pub use events::EVENT_SCHEMA_VERSION;
+pub use extract::{extract_path, DirectoryOutput, ExtractPathOptions, ExtractedFileInfo};
+pub use hermetic::HermeticSubstitution;
+pub use hooks::{BundleContext, LayerContext};
+pub use hygiene::{HygieneFinding, HygienePolicy, HygieneRule, HygieneRuleset};
+pub use layer_digests::{
+    VerifiedDigest, UNCOMPRESSED_DIGEST_ANNOTATION, UNCOMPRESSED_SIZE_ANNOTATION,
+};
+pub use layer_reader::{compute_diff_id, LayerDigest};
+pub use metadata::{BundleMetadata, INCOMPLETE_SENTINEL};
+pub use metrics::{InMemoryMetrics, MetricsSink, NoopMetrics};
+pub use options::{
+    AdditionalGids, ApplyMode, BaseMismatch, CgroupVersion, ContainerEnvMarker, ControlCharacters,
+    HardlinkPolicy, ImplicitDirMtime, LayerDecision, LayerPolicyOverride, Overwrite, OwnershipMode,
+    ParentSymlinkPolicy, PermissionPolicy, RuntimeConfigOptions, Strictness, TimestampSource,
+    UnicodePolicy, UnpackOptions, UserDatabase, UserResolution,
+};
+pub use passthrough::UNCONVERTED_ANNOTATION_PREFIX;
+pub use plan::{PlanDeviation, PlanSummary, PlannedField, PlannedLayer};
+pub use platform::host_platform;
+pub use protected::GlobPattern;
+pub use reference::ImageReference;
+pub use remap::{IdMapping, RemapReport};
+pub use report::{
+    Change, LayerCompression, LayerCopy, LayerCounts, LayerTiming, RemovalKind,
+    StrippedPermissions, UnicodeCollision, UnpackReport, Warning, WarningKind, WastedPath,
+};
+#[cfg(feature = "rootfs-image")]
+pub use rootfs_image::{RootfsImage, RootfsImageFormat, RootfsImageOptions};
+pub use rootless::{EMULATED_DEVICE_XATTR, EMULATED_OWNERSHIP_ANNOTATION, ROOTLESS_XATTR};
+pub use spec::{validate_spec, SpecIssue, SpecIssueCode};
+pub use unpacker::Unpacker;
+pub use update::UpdateReport;
+pub use user::UNRESOLVED_USER_ANNOTATION;
+pub use verify::{Difference, ModifiedPath, VerifyBundleOptions, VerifyBundleReport};
+pub use windows::WindowsLayer;
 
 /// Unpacks the layers of an OCI image into a directory
 /// # Arguments
 /// * `manifest` - The manifest of the image
 /// * `oci_dir` - The OCI directory containing the image
 /// * `root` - The directory to unpack the image into. It will be created if it doesn't exist
+///
+/// Any warnings are logged; use [`unpack_with_options`] to inspect them.
+///
+/// # Concurrency
+/// Images may be unpacked into different bundles concurrently, from any number of threads
+/// sharing one [`OciDir`] and [`UnpackOptions`], and from other processes. The layout is only
+/// read, staging areas are unique to each unpack, and the
+/// [`UnpackOptions::decompressed_blob_cache`] locks each entry while it's populated. Unpacking
+/// into the same bundle concurrently isn't supported.
 pub fn unpack(manifest: &ImageManifest, oci_dir: &OciDir, bundle: &Path) -> Result<()> {
-    if bundle.exists() {
-        fs::remove_dir_all(bundle).context("Failed to remove existing bundle directory")?;
+    unpack_with_options(manifest, oci_dir, bundle, &UnpackOptions::default())?;
+    Ok(())
+}
+
+/// Unpacks the layers of an OCI image into a directory, as [`unpack`] does, configured by `options`.
+///
+/// The options are checked and set up for each call; use an [`Unpacker`] to do that once for
+/// many images.
+pub fn unpack_with_options(
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    bundle: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    unpacker_for(manifest, options)?.unpack(manifest, oci_dir, bundle)
+}
+
+/// Returns an [`Unpacker`] with `options`, for unpacking the image with `manifest`, emitting the
+/// [`Event::Finished`] an unpack would if the options are invalid
+fn unpacker_for(manifest: &ImageManifest, options: &UnpackOptions) -> Result<Unpacker> {
+    Unpacker::new(options.clone()).inspect_err(|e| {
+        options.emit(&Event::Finished {
+            layers: manifest.layers().len(),
+            warnings: 0,
+            error: Some(events::describe_error(e)),
+            duration_seconds: 0.0,
+        })
+    })
+}
+
+/// Unpacks an image into `bundle` over a copy of `base_bundle`, a bundle unpacked from the
+/// image's first layers, such as the image it was built from, extracting only the rest. The
+/// runtime config is generated from the image's config, as [`unpack_with_options`] would.
+///
+/// The base bundle is used if its recorded diff IDs are a prefix of the image's, and it was
+/// completely unpacked without skipping layers. Otherwise, every layer is unpacked, or with
+/// [`BaseMismatch::Fail`], the unpack fails. Its rootfs is copied with
+/// [`UnpackOptions::base_copy_strategy`], and left as it is. The number of layers taken from it
+/// is recorded in [`UnpackReport::base_layers`].
+///
+/// The base should have been unpacked with the same options, as the copy has whatever they
+/// did to its layers. Options that report on what layers changed, such as
+/// [`UnpackOptions::record_changes`], only see the layers applied on top.
+pub fn unpack_derived(
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    base_bundle: &Path,
+    bundle: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    unpacker_for(manifest, options)?.unpack_derived(manifest, oci_dir, base_bundle, bundle)
+}
+
+/// Updates `bundle`, unpacked from an earlier build of an image, to the image with `manifest`,
+/// extracting only the layers that changed rather than unpacking every layer again.
+///
+/// The bundle must have been completely unpacked with [`UnpackOptions::record_changes`], which
+/// records the paths each layer changed in the bundle. The layers it shares with the image, up
+/// to the first whose diff ID differs, are kept. The paths the rest changed are removed and
+/// extracted again from the layers that are kept, which means reading again those with entries
+/// at them, and then the image's remaining layers are applied in place. Otherwise, or if a hard
+/// link in a kept layer can't be restored, every layer is unpacked, as [`unpack_with_options`]
+/// would, and the report says so.
+///
+/// The bundle should be updated with the options it was unpacked with, as the kept layers have
+/// whatever those did to them. Options that report on what layers changed, such as
+/// [`UnpackOptions::record_changes`], only see the layers applied over the kept ones, as for
+/// [`unpack_derived`].
+pub fn update_bundle(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    options: &UnpackOptions,
+) -> Result<UpdateReport> {
+    unpacker_for(manifest, options)?.update_bundle(manifest, oci_dir, bundle)
+}
+
+/// Continues unpacking the image with `manifest` into `bundle`, after an unpack with
+/// [`UnpackOptions::checkpoint_layers`] was interrupted, from the layer after the last one its
+/// checkpoint records. The layers it resumed after are recorded in
+/// [`UnpackReport::resumed_layers`], and aren't otherwise reported on.
+///
+/// The checkpoint is only used if it was recorded for the same image with the same options, the
+/// same layers are decided on by [`UnpackOptions::layer_decision`], and the paths sampled with
+/// [`UnpackOptions::checkpoint_sample`] are unchanged. Options that take effect once the layers
+/// are applied, such as the runtime config's, may differ. Otherwise, or if the bundle has no
+/// checkpoint, every layer is unpacked, as [`unpack_with_options`] would. The hooks and
+/// inspectors in the options can't be compared, so should behave as they did.
+pub fn resume_unpack(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    unpacker_for(manifest, options)?.resume_unpack(manifest, oci_dir, bundle)
+}
+
+/// Unpacks the image whose manifest is `manifest_bytes`, as [`unpack_with_options`] does, having
+/// first checked the bytes against `expected_digest`, the digest the manifest was addressed by.
+///
+/// Unpacking verifies every blob the manifest references against it, but nothing verifies the
+/// manifest, so callers that fetch a manifest themselves should pass its digest here rather than
+/// parse it. The digest may use SHA-256, SHA-384 or SHA-512.
+pub fn unpack_manifest_bytes(
+    manifest_bytes: &[u8],
+    expected_digest: Option<&str>,
+    oci_dir: &OciDir,
+    bundle: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    if let Some(expected) = expected_digest {
+        check_manifest_digest(manifest_bytes, expected, options)?;
     }
-    let rootfs = bundle.join("rootfs");
-    fs::create_dir_all(&rootfs).context("Failed to create rootfs directory")?;
+    let manifest = ImageManifest::from_reader(manifest_bytes)?;
+    unpack_with_options(&manifest, oci_dir, bundle, options)
+}
 
-    // Load image configuration so we can verify layer diff IDs
-    let image_config = ImageConfiguration::from_reader(oci_dir.read_blob(manifest.config())?)?;
+/// Unpacks the image whose manifest is the blob with `manifest_digest` in `oci_dir`, as
+/// [`unpack_manifest_bytes`] does, having checked the blob against its digest. If the blob is an
+/// image index, the first manifest in it for [`UnpackOptions::target_platform`] is unpacked,
+/// failing with [`Error::NoMatchingPlatform`] if there's none.
+///
+/// Fails with [`Error::ManifestNotFound`] if the layout has no blob with the digest.
+pub fn unpack_digest(
+    oci_dir: &OciDir,
+    manifest_digest: &str,
+    bundle: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    let digest: Digest = manifest_digest.parse()?;
+    let path = Path::new("blobs")
+        .join(digest.algorithm().as_ref())
+        .join(digest.digest());
+    let bytes = match oci_dir.dir.read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::ManifestNotFound {
+                digest: manifest_digest.to_string(),
+                blobs: layout_path(oci_dir).join("blobs"),
+            })
+        }
+        Err(e) => return Err(e).with_path(layout_path(oci_dir).join(path)),
+    };
+    check_manifest_digest(&bytes, manifest_digest, options)?;
 
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Probe {
+        media_type: Option<String>,
+        manifests: Option<serde_json::Value>,
+    }
+    let probe: Probe = serde_json::from_slice(&bytes)
+        .map_err(|e| Error::Spec(ocidir::oci_spec::OciSpecError::SerDe(e)))?;
+    let is_index = match probe.media_type.as_deref() {
+        Some("application/vnd.oci.image.index.v1+json")
+        | Some("application/vnd.docker.distribution.manifest.list.v2+json") => true,
+        Some("application/vnd.oci.image.manifest.v1+json")
+        | Some("application/vnd.docker.distribution.manifest.v2+json") => false,
+        Some(other) => return Err(Error::UnsupportedMediaType(other.to_string())),
+        // The media type is optional, so tell them apart by their fields
+        None => probe.manifests.is_some(),
+    };
+    if !is_index {
+        return unpack_manifest_bytes(&bytes, None, oci_dir, bundle, options);
+    }
+    let index = ImageIndex::from_reader(bytes.as_slice())?;
+    if options.runtime_config.hermetic && options.target_platform.is_none() {
+        return Err(Error::InvalidRuntimeConfigOptions(
+            "A hermetic unpack of an image index needs a target platform, not the host's"
+                .to_string(),
+        ));
+    }
+    let descriptor = platform::select(&index, &options.platform())?;
+    log::info!(
+        "Selected manifest {} from image index {manifest_digest}",
+        descriptor.digest()
+    );
+    unpack_digest(oci_dir, descriptor.digest().as_ref(), bundle, options)
+}
+
+/// Unpacks the image that `reference` names in `oci_dir`, as [`unpack_digest`] does, recording
+/// the reference in the bundle as [`UnpackOptions::reference`] does.
+///
+/// A reference with a digest names the manifest or index with it. Otherwise, it names the one
+/// in the layout's `index.json` whose `org.opencontainers.image.ref.name` annotation is either
+/// the whole reference or its tag, failing with [`Error::ReferenceNotFound`] if there's none.
+pub fn unpack_ref(
+    oci_dir: &OciDir,
+    reference: &ImageReference,
+    bundle: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    let digest = reference::resolve(oci_dir, reference)?;
+    log::info!("Resolved {reference} to {digest}");
+    let options = options.clone().reference(reference.clone());
+    unpack_digest(oci_dir, &digest, bundle, &options)
+}
+
+/// Checks the bytes of a manifest or index against `expected`, the digest it was addressed by
+fn check_manifest_digest(bytes: &[u8], expected: &str, options: &UnpackOptions) -> Result<()> {
+    let digest: Digest = expected.parse()?;
+    let hash = match digest.algorithm() {
+        DigestAlgorithm::Sha256 => hex::encode(openssl::sha::sha256(bytes)),
+        DigestAlgorithm::Sha384 => hex::encode(openssl::sha::sha384(bytes)),
+        DigestAlgorithm::Sha512 => hex::encode(openssl::sha::sha512(bytes)),
+        other => return Err(Error::UnsupportedDigestAlgorithm(other.to_string())),
+    };
+    let actual = format!("{}:{hash}", digest.algorithm());
+    options.emit(&Event::digest_verified(
+        None,
+        DigestKind::Manifest,
+        expected,
+        &actual,
+    ));
+    if expected != actual {
+        return Err(Error::DigestMismatch {
+            layer_index: None,
+            kind: DigestKind::Manifest,
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn unpack_bundle(
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    bundle: &Path,
+    options: &UnpackOptions,
+    platform: &Platform,
+    base: Base,
+    deadline: Option<&Deadline>,
+) -> Result<UnpackReport> {
+    let effective_options = EffectiveOptions::new(options);
+    // Only read when the bundle may be reused, as it's replaced regardless otherwise
+    let is_current = || {
+        if options.reuse_requires_matching_options {
+            metadata::is_current_with_options(bundle, manifest, &effective_options)
+        } else {
+            metadata::is_current(bundle, manifest)
+        }
+    };
+    if options.overwrite == Overwrite::ReuseIfMatching
+        && options.layer_decision.is_none()
+        && is_current()?
+        && (!options.reuse_sanity_check || metadata::is_intact(bundle)?)
+    {
+        log::info!("Reusing bundle {}", bundle.display());
+        return Ok(UnpackReport {
+            reused: true,
+            reference: options.reference.clone(),
+            effective_options,
+            // Without the sanity check, the bundle may not open
+            bundle: Bundle::open(bundle).ok(),
+            ..UnpackReport::default()
+        });
+    }
     let layers = manifest.layers();
-    let diff_ids = image_config.rootfs().diff_ids();
+    let (skipped, mut image_warnings) = decide_layers(layers, options)?;
+    // Nothing in the bundle is touched until every blob it needs is open, and they're read
+    // through those handles even if they're removed from the layout while unpacking
+    let cache = options.decompressed_blob_cache.as_deref();
+    let blobs = BlobSource::pin(oci_dir, manifest, &skipped, cache)?;
+
+    // Load image configuration so we can verify layer diff IDs
+    let (image_config, raw_config) = read_config(&blobs, manifest.config(), options)?;
+    platform::check_platform(&image_config, platform);
+    let is_windows = *image_config.os() == Os::Windows;
+    if is_windows && !options.windows_layers {
+        return Err(Error::WindowsImage);
+    }
 
-    if layers.len() != diff_ids.len() {
-        bail!(
-            "Mismatch between number of layers and diff IDs: {} != {}",
+    let diff_ids = image_config.rootfs().diff_ids();
+    let base = match base {
+        Base::Resumed(checkpoint) if !checkpoint.matches(diff_ids, &skipped) => {
+            log::info!(
+                "Not resuming bundle {}, as its checkpoint decided on different layers",
+                bundle.display()
+            );
+            Base::Empty
+        }
+        base => base,
+    };
+    let (base_layers, base_rootfs) = match base {
+        Base::Empty => (0, None),
+        Base::Bundle(base) => match check_base(base, diff_ids, options)? {
+            Some(layers) => (layers, Some(base)),
+            None => (0, None),
+        },
+        Base::Reverted { layers, .. } => (layers, None),
+        Base::Resumed(checkpoint) => (checkpoint.layers(), None),
+    };
+    // The base bundle's layers are already in its rootfs, whatever was decided for them
+    let mut bypassed = skipped.clone();
+    bypassed
+        .iter_mut()
+        .take(base_layers)
+        .for_each(|skip| *skip = true);
+    history::check_layer_count(&image_config, layers.len(), options.strictness)?;
+    if options.check_layer_order {
+        image_warnings.extend(history::check_layer_order(
+            &image_config,
             layers.len(),
-            diff_ids.len()
-        );
+            options.strictness,
+        )?);
+    }
+    if options.verify_digests {
+        image_warnings.extend(layer_digests::check_annotations(
+            layers,
+            diff_ids,
+            &bypassed,
+            options.strictness,
+        )?);
+    }
+    let mut hermetic_substitutions = Vec::new();
+    let timestamp_source = if options.runtime_config.hermetic {
+        hermetic::timestamp_source(options.timestamp_source, &mut hermetic_substitutions)
+    } else {
+        options.timestamp_source
+    };
+    let (bundle_time, warning) =
+        timestamps::resolve(timestamp_source, &image_config, options.strictness)?;
+    image_warnings.extend(warning);
+    let (base_image, warning) = base_image::resolve(manifest, &image_config, options.strictness)?;
+    image_warnings.extend(warning);
+    for warning in &image_warnings {
+        options.emit(&Event::warning(warning));
     }
 
-    for (descriptor, expected_diff_id) in manifest.layers().iter().zip(diff_ids.iter()) {
-        match descriptor.media_type() {
-            MediaType::ImageLayerGzip => {
-                let mut archive = Archive::new(Sha256Reader::new(GzDecoder::new(
-                    Sha256Reader::new(oci_dir.read_blob(descriptor)?),
-                )));
-                extract_layer(&mut archive, &rootfs)?;
-                // Note that the diff_id is the uncompressed digest, which is the first digest...
-                let (discovered_diff_id, gz_decoder) = archive.into_inner().finish()?;
-                if format!("sha256:{discovered_diff_id}") != *expected_diff_id {
-                    bail!(
-                        "Diff ID mismatch. Expected diff ID {}. Discovered diff ID {}",
-                        expected_diff_id,
-                        discovered_diff_id,
-                    );
+    if let Base::Reverted { .. } | Base::Resumed(_) = base {
+        update::clear_bundle(bundle)?;
+    } else if bundle.exists() {
+        if metadata::is_incomplete(bundle) {
+            log::info!("Replacing incomplete bundle {}", bundle.display());
+        }
+        fs::remove_dir_all(bundle).with_path(bundle)?;
+    }
+    metadata::mark_incomplete(bundle)?;
+    let rootfs = bundle.join("rootfs");
+    match base_rootfs {
+        Some(base) => {
+            let strategy = copy_tree(&base.join("rootfs"), &rootfs, options.base_copy_strategy)?;
+            log::info!(
+                "Copied the rootfs of base bundle {} with {strategy:?}",
+                base.display()
+            );
+        }
+        None => fs::create_dir_all(&rootfs).with_path(&rootfs)?,
+    }
+    let checkpoints = match base {
+        Base::Empty | Base::Resumed(_)
+            if options.checkpoint_layers && !options.records_changes() =>
+        {
+            let resumed = match base {
+                Base::Resumed(checkpoint) => Some(checkpoint),
+                _ => None,
+            };
+            Some(checkpoint::Checkpoints::start(
+                bundle, manifest, diff_ids, options, resumed,
+            )?)
+        }
+        _ => None,
+    };
+
+    // The layers a resumed unpack skipped are as much the bundle's as those it skips now
+    let (reported_from, resumed_layers) = match base {
+        Base::Resumed(_) => {
+            log::info!(
+                "Resuming unpack of {} after {base_layers} layers",
+                bundle.display()
+            );
+            (0, base_layers)
+        }
+        _ => (base_layers, 0),
+    };
+    let mut report = UnpackReport {
+        warnings: image_warnings,
+        skipped_layers: (reported_from..layers.len())
+            .filter(|&i| skipped[i])
+            .collect(),
+        base_layers: base_layers - resumed_layers,
+        resumed_layers,
+        hermetic_substitutions,
+        base_image,
+        reference: options.reference.clone(),
+        effective_options,
+        ..UnpackReport::default()
+    };
+    let windows_dir = bundle.join(windows::WINDOWS_LAYERS);
+    let image_layers = Layers {
+        descriptors: layers,
+        diff_ids,
+        skipped: &bypassed,
+        windows_dir: is_windows.then_some(windows_dir.as_path()),
+        checkpoints: checkpoints.as_ref(),
+    };
+    let mut applied = LayerReport::default();
+    let empty_rootfs = base_layers == 0 && matches!(base, Base::Empty | Base::Bundle(_));
+    let staged = match options.in_memory_max_bytes {
+        Some(limit) if in_memory::applies(&image_layers, empty_rootfs, options, limit) => {
+            in_memory::stage(&blobs, &image_layers, options, deadline, limit)?
+        }
+        _ => None,
+    };
+    if let Some(staged) = staged {
+        report.applied_in_memory = true;
+        applied = in_memory::write(staged, &image_layers, &rootfs, options, deadline)?;
+    } else if options.parallel_layers > 1 && layers.len() > 1 {
+        let staging = create_staging_dir(bundle, options)?;
+        applied =
+            parallel::extract_layers(&blobs, &image_layers, &staging, &rootfs, options, deadline)?;
+    } else if options.prefetch && layers.len() > 1 {
+        let staging = create_staging_dir(bundle, options)?;
+        applied =
+            prefetch::extract_layers(&blobs, &image_layers, &staging, &rootfs, options, deadline)?;
+    } else {
+        for (index, (descriptor, expected_diff_id)) in layers.iter().zip(diff_ids).enumerate() {
+            if bypassed[index] {
+                continue;
+            }
+            if let Some(deadline) = deadline {
+                deadline.check()?;
+            }
+            let started = Instant::now();
+            let mut timing = LayerTiming::new(index);
+            let mut compression = LayerCompression::new(index);
+            let mut changes = LayerChanges::new(options.retry_attempts > 0);
+            let layer_options = options.for_layer(descriptor);
+            let mut layer_report = retry::retry(index, options, deadline, |attempt| {
+                if attempt > 0 {
+                    changes.roll_back(&rootfs)?;
                 }
+                read_layer(
+                    &blobs,
+                    layers,
+                    index,
+                    expected_diff_id,
+                    options,
+                    deadline,
+                    &mut timing,
+                    &mut compression,
+                    |reader, cloner| {
+                        // Flattening rewrites the archive, so its content can't be cloned
+                        let cloner = cloner.filter(|_| image_layers.windows_dir.is_none());
+                        let (mut report, windows) =
+                            windows::flatten(reader, image_layers.windows_dir, index, |reader| {
+                                extract_layer(
+                                    &mut Archive::new(reader),
+                                    &rootfs,
+                                    index,
+                                    &layer_options,
+                                    &mut changes,
+                                    cloner,
+                                )
+                            })?;
+                        report.windows.extend(windows);
+                        Ok(report)
+                    },
+                )
+            })
+            .map_err(|e| e.in_layer(index, descriptor))?;
+            timing.duration = started.elapsed();
+            layer_applied(
+                index,
+                descriptor,
+                &mut layer_report,
+                &timing,
+                &rootfs,
+                options,
+                deadline,
+                image_layers.checkpoints,
+            )?;
+            layer_report.timings.push(timing);
+            layer_report.compression.push(compression);
+            applied.append(layer_report);
+        }
+    }
+    if options.unicode_policy.compares() {
+        report.unicode_collisions = unicode::check(&applied.changes, options.unicode_policy)?;
+    }
+    if options.record_changes {
+        report.changes = changes::replay(&applied.changes, layers.len());
+        update::record(bundle, base, base_layers, diff_ids, &applied.changes)?;
+    }
+    let dir_mtimes = options
+        .restore_dir_mtimes
+        .map(|implicit| (dir_mtimes::plan(&applied.changes), implicit));
+    if options.analyze_waste {
+        (report.wasted_bytes, report.largest_wasted_paths) =
+            waste::analyze(std::mem::take(&mut applied.changes), layers.len());
+    }
+    report.file_capabilities =
+        capabilities::resolve(&rootfs, std::mem::take(&mut applied.capabilities));
+    report.extend(applied);
+    if options.verify_digests {
+        let warnings =
+            layer_digests::check_size_hints(&report.layer_compression, options.strictness)?;
+        for warning in &warnings {
+            options.emit(&Event::warning(warning));
+        }
+        report.warnings.extend(warnings);
+    }
+    if let Some(plan) = &options.expected {
+        let (deviations, warnings) = plan::check(
+            plan,
+            &report.layer_counts,
+            &report.layer_compression,
+            options.strictness,
+        )?;
+        for warning in &warnings {
+            options.emit(&Event::warning(warning));
+        }
+        report.plan_deviations = deviations;
+        report.warnings.extend(warnings);
+    }
+    hooks::before_config(
+        bundle,
+        manifest,
+        &image_config,
+        &rootfs,
+        &mut report,
+        options,
+    )?;
 
-                // ...and the overall layer digest is the second digest
-                let (discovered_digest, _) = gz_decoder.into_inner().finish()?;
-                if descriptor.digest().digest() != discovered_digest {
-                    bail!(
-                        "Layer digest mismatch. Expected digest {}. Discovered digest {}",
-                        descriptor.digest().digest(),
-                        discovered_digest,
-                    );
+    // convert manifest config per https://github.com/opencontainers/image-spec/blob/main/conversion.md#verbatim-fields
+    let runtime_config = create_runtime_config(
+        &image_config,
+        &raw_config,
+        &rootfs,
+        &options.runtime_config,
+        &report.file_capabilities,
+        report.base_image.as_ref(),
+        options.strictness,
+        &mut report.spec_issues,
+        &mut report.hermetic_substitutions,
+    )?;
+    #[cfg(feature = "rootfs-image")]
+    let runtime_config =
+        rootfs_image::mount_read_only(runtime_config, options.rootfs_image.as_ref());
+    let runtime_config = rootless::annotate(runtime_config, options.ownership);
+    let runtime_config = reference::annotate(runtime_config, options.reference.as_ref());
+    if options.runtime_config.create_working_dir {
+        report.created_working_dir =
+            working_dir::create(&rootfs, &runtime_config, options.ownership)?;
+    }
+    report.container_env_marker = container_env::create(
+        &rootfs,
+        &options.runtime_config.container_env_marker,
+        manifest,
+        &image_config,
+        options.ownership,
+    )?;
+    if let Some((mtimes, implicit)) = &dir_mtimes {
+        let implicit = match implicit {
+            ImplicitDirMtime::Epoch => None,
+            ImplicitDirMtime::ImageCreated => image_config
+                .created()
+                .as_deref()
+                .and_then(history::unix_time),
+            ImplicitDirMtime::BundleTimestamp => {
+                Some(bundle_time.unwrap_or_else(FileTime::now).unix_seconds())
+            }
+        };
+        let implicit = implicit.map_or(0, |time| u64::try_from(time).unwrap_or(0));
+        dir_mtimes::restore(&rootfs, mtimes, implicit)?;
+    }
+    if options.runtime_config.hermetic {
+        hermetic::save(&runtime_config, &bundle.join("config.json"))?;
+    } else {
+        runtime_config.save(bundle.join("config.json"))?;
+    }
+    if options.write_env_summary {
+        env_summary::write(
+            bundle,
+            &runtime_config,
+            &image_config,
+            options.reference.as_ref(),
+        )?;
+    }
+    if options.record_file_manifest {
+        verify::write_file_manifest(bundle, &rootfs, options.strip_write_bits)?;
+    }
+    if options.strip_write_bits {
+        write_bits::strip(&rootfs)?;
+    }
+    #[cfg(feature = "rootfs-image")]
+    if let Some(image_options) = &options.rootfs_image {
+        report.rootfs_image = Some(rootfs_image::build(bundle, &rootfs, image_options)?);
+    }
+    if let Some(checkpoints) = checkpoints {
+        checkpoints.finish()?;
+    }
+    metadata::write(
+        bundle,
+        manifest,
+        &image_config,
+        &report.skipped_layers,
+        report.base_image.as_ref(),
+        options.reference.as_ref(),
+        &report.effective_options,
+    )?;
+    metadata::mark_complete(bundle)?;
+    if let Some(time) = bundle_time {
+        let mut written = vec![
+            bundle.join("config.json"),
+            bundle.join(metadata::BUNDLE_METADATA),
+            bundle.join(env_summary::ENV_SUMMARY),
+            bundle.join(verify::FILE_MANIFEST),
+            bundle.join(update::LAYER_CHANGES),
+        ];
+        #[cfg(feature = "rootfs-image")]
+        written.push(bundle.join(rootfs_image::ROOTFS_IMAGE));
+        // Last, as writing the other files changes the bundle's mtime
+        written.push(bundle.to_path_buf());
+        timestamps::stamp(&written, time)?;
+    }
+    report.bundle = Some(Bundle::open(bundle)?);
+    Ok(report)
+}
+
+/// Returns whether `bundle` was completely unpacked from the image with `manifest`, so that
+/// unpacking it again would change nothing, according to the metadata recorded in the bundle.
+///
+/// The image's config and layer digests are compared with those recorded. Bundles unpacked with
+/// skipped layers, or by versions of this crate that didn't record metadata, aren't current, and
+/// nor are those containing the [`INCOMPLETE_SENTINEL`]. The bundle's content isn't checked; see [`verify_bundle`] for that.
+pub fn is_bundle_current(bundle: &Path, manifest: &ImageManifest) -> Result<bool> {
+    metadata::is_current(bundle, manifest)
+}
+
+/// Returns whether `bundle` is current, as [`is_bundle_current`] says, and was unpacked with
+/// options that unpack the same bundle as `options`, so that unpacking it again with them would
+/// change nothing.
+///
+/// The options are compared by the [`EffectiveOptions::digest`] recorded in the bundle, which
+/// leaves out options that only change how an unpack goes about its work, such as
+/// [`UnpackOptions::parallel_layers`]. Bundles unpacked by versions of this crate that didn't
+/// record it aren't current.
+pub fn is_bundle_current_with_options(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    options: &UnpackOptions,
+) -> Result<bool> {
+    metadata::is_current_with_options(bundle, manifest, &EffectiveOptions::new(options))
+}
+
+/// Checks that the rootfs of a bundle hasn't changed since it was unpacked, comparing each path's
+/// type, mode, ownership, size and content with the file manifest recorded by
+/// [`UnpackOptions::record_file_manifest`].
+///
+/// Returns [`Error::BundleModified`], with a report of the added, removed and modified paths, if
+/// anything differs, or [`Error::IncompleteBundle`] if the bundle contains the
+/// [`INCOMPLETE_SENTINEL`]. The bundle is opened as [`Bundle::open`] does, so this fails with
+/// [`Error::NotABundle`] if it isn't laid out as one.
+pub fn verify_bundle(bundle: &Path) -> Result<VerifyBundleReport> {
+    verify_bundle_with_options(bundle, &VerifyBundleOptions::default())
+}
+
+/// Checks a bundle, as [`verify_bundle`] does, configured by `options`
+pub fn verify_bundle_with_options(
+    bundle: &Path,
+    options: &VerifyBundleOptions,
+) -> Result<VerifyBundleReport> {
+    Bundle::open(bundle)?.verify(options)
+}
+
+/// Changes the owner and group of every path in a bundle's rootfs from the container IDs of
+/// `mappings` to their host IDs, and records `mappings` as the UID and GID mappings of its
+/// runtime config, for when the mappings aren't known until after the bundle is unpacked.
+///
+/// Symlinks themselves are changed rather than their targets, a file with several hard links is
+/// changed once, and setuid and setgid bits cleared by the change are set again. Paths with an
+/// owner or group in no mapping keep it, and are listed in the report.
+///
+/// Progress is saved in the bundle as the rootfs is walked, so if the remap is interrupted,
+/// calling this again with the same mappings resumes it. Calling it again once it's finished
+/// does nothing. The bundle is opened as [`Bundle::open`] does, so must be complete.
+pub fn remap_bundle_ownership(bundle: &Path, mappings: &[IdMapping]) -> Result<RemapReport> {
+    Bundle::open(bundle)?.remap_ownership(mappings)
+}
+
+/// Writes an archive of a bundle to `out`, for restoring with [`import_bundle`] on another host.
+///
+/// The archive is a tar stream of the files at the top of the bundle, such as `config.json`, the
+/// bundle metadata and the file manifest, followed by a tar stream of the rootfs, with the owner,
+/// mode, mtime and extended attributes of each path. Hard links are archived as links, holes in
+/// sparse files as old GNU sparse entries, and sockets and other directories in the bundle are
+/// left out. The archive is compressed as [`ExportOptions::compression`] says.
+///
+/// Fails with [`Error::IncompleteBundle`] if the bundle contains the [`INCOMPLETE_SENTINEL`], or
+/// with [`Error::NotABundle`] if it can't be opened, as for [`Bundle::open`].
+pub fn export_bundle(bundle: &Path, out: impl io::Write, options: &ExportOptions) -> Result<()> {
+    Bundle::open(bundle)?.export(out, options)
+}
+
+/// Restores a bundle archived by [`export_bundle`] into `dest`, replacing anything there. A
+/// zstd-compressed archive is recognized, and decompressed with the `zstd` program.
+///
+/// The rootfs is extracted as a layer would be, so the options that decide what's kept of each
+/// entry, such as [`UnpackOptions::ownership`] and [`UnpackOptions::permission_policy`], apply,
+/// and the report describes it as one layer. Options about images and their layers don't. If the
+/// bundle has a file manifest, recorded by [`UnpackOptions::record_file_manifest`], the rootfs is
+/// checked against it, as [`verify_bundle`] would, apart from what the options changed, and the
+/// import fails with [`Error::BundleModified`] if it differs. Until the import succeeds, the
+/// bundle contains the [`INCOMPLETE_SENTINEL`].
+pub fn import_bundle(
+    archive: impl io::Read,
+    dest: &Path,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    bundle_archive::import(archive, dest, options)
+}
+
+/// Returns the path of a blob within an image layout, for error messages
+fn blob_path(descriptor: &Descriptor) -> PathBuf {
+    let digest = descriptor.digest();
+    Path::new("blobs")
+        .join(digest.algorithm().as_ref())
+        .join(digest.digest())
+}
+
+/// Returns the path of the layout, or an empty path if it can't be found
+fn layout_path(oci_dir: &OciDir) -> PathBuf {
+    // The layout's path isn't known, but the directory's file descriptor leads to it
+    fs::read_link(format!("/proc/self/fd/{}", oci_dir.dir.as_raw_fd())).unwrap_or_default()
+}
+
+/// Opens a blob, describing it by `role` if it's missing, unreadable or the wrong size
+fn open_blob(oci_dir: &OciDir, descriptor: &Descriptor, role: BlobRole) -> Result<fs::File> {
+    oci_dir.read_blob(descriptor).map_err(|e| Error::Blob {
+        role,
+        digest: descriptor.digest().to_string(),
+        expected_size: descriptor.size(),
+        path: layout_path(oci_dir).join(blob_path(descriptor)),
+        source: e.into(),
+    })
+}
+
+/// Returns how many of the image's layers, with `diff_ids`, are already in `base`, if it was
+/// completely unpacked from a prefix of them. Otherwise returns `None`, to unpack every layer, or
+/// fails with [`BaseMismatch::Fail`].
+fn check_base(base: &Path, diff_ids: &[String], options: &UnpackOptions) -> Result<Option<usize>> {
+    let recorded = metadata::complete_diff_ids(base)?;
+    match recorded {
+        Some(recorded) if diff_ids.starts_with(&recorded) && base.join("rootfs").is_dir() => {
+            log::info!(
+                "Applying {} of {} layers over base bundle {}",
+                diff_ids.len() - recorded.len(),
+                diff_ids.len(),
+                base.display()
+            );
+            Ok(Some(recorded.len()))
+        }
+        _ if options.base_mismatch == BaseMismatch::Fail => {
+            Err(Error::BaseMismatch(base.to_path_buf()))
+        }
+        _ => {
+            log::info!(
+                "Image doesn't extend base bundle {}, unpacking every layer",
+                base.display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// The media type of Docker's image config, which is the same as the OCI image config
+const DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.container.image.v1+json";
+
+/// Reads the image configuration, verifying its media type, size and digest against its
+/// descriptor. Returns it along with its JSON.
+fn read_config(
+    blobs: &BlobSource,
+    descriptor: &Descriptor,
+    options: &UnpackOptions,
+) -> Result<(ImageConfiguration, Vec<u8>)> {
+    let media_type = descriptor.media_type();
+    if *media_type != MediaType::ImageConfig && media_type.to_string() != DOCKER_CONFIG_MEDIA_TYPE {
+        return Err(Error::NotAnImageConfig {
+            media_type: media_type.to_string(),
+        });
+    }
+    let mut reader = DigestReader::new(blobs.config(descriptor)?);
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .with_path(blob_path(descriptor))?;
+    let discovered_digest = reader.digests_so_far().sha256().to_string();
+
+    if reader.bytes_read() != descriptor.size() {
+        return Err(Error::SizeMismatch {
+            layer_index: None,
+            expected: descriptor.size(),
+            actual: reader.bytes_read(),
+        });
+    }
+    let expected = descriptor.digest().to_string();
+    let actual = format!("sha256:{discovered_digest}");
+    options.emit(&Event::digest_verified(
+        None,
+        DigestKind::Config,
+        &expected,
+        &actual,
+    ));
+    if expected != actual {
+        return Err(Error::DigestMismatch {
+            layer_index: None,
+            kind: DigestKind::Config,
+            expected,
+            actual,
+        });
+    }
+    let image_config = ImageConfiguration::from_reader(bytes.as_slice())?;
+    Ok((image_config, bytes))
+}
+
+/// The layers of an image, with their diff IDs and whether each is skipped
+pub(crate) struct Layers<'a> {
+    pub(crate) descriptors: &'a [Descriptor],
+    pub(crate) diff_ids: &'a [String],
+    pub(crate) skipped: &'a [bool],
+    /// Where the hives and records of Windows layers are saved, if they're flattened
+    pub(crate) windows_dir: Option<&'a Path>,
+    /// Where each layer applied is recorded, with [`UnpackOptions::checkpoint_layers`]
+    pub(crate) checkpoints: Option<&'a checkpoint::Checkpoints>,
+}
+
+/// Asks [`UnpackOptions::layer_decision`] what to do with each layer, returning which are skipped.
+///
+/// Descriptors whose media types aren't those of layers are skipped without asking, with a
+/// [`WarningKind::UnsupportedMediaType`] warning for each, or with [`Strictness::Strict`], an
+/// error.
+fn decide_layers(
+    layers: &[Descriptor],
+    options: &UnpackOptions,
+) -> Result<(Vec<bool>, Vec<Warning>)> {
+    let mut skipped = Vec::with_capacity(layers.len());
+    let mut warnings = Vec::new();
+    for (index, descriptor) in layers.iter().enumerate() {
+        let decision = if !is_layer(descriptor.media_type()) {
+            let warning = Warning {
+                layer_index: index,
+                path: PathBuf::new(),
+                kind: WarningKind::UnsupportedMediaType,
+            };
+            if options.strictness == Strictness::Strict {
+                return Err(Error::Warning(warning));
+            }
+            log::warn!("{warning}: {}", descriptor.media_type());
+            warnings.push(warning);
+            LayerDecision::Skip
+        } else if let Some(decide) = &options.layer_decision {
+            decide.0(descriptor)
+        } else {
+            LayerDecision::Extract
+        };
+        match decision {
+            LayerDecision::Extract => skipped.push(false),
+            LayerDecision::Skip => {
+                log::debug!("Skipping layer {} ({})", index, descriptor.digest());
+                options.emit(&Event::LayerSkipped {
+                    layer_index: index,
+                    digest: descriptor.digest().to_string(),
+                });
+                skipped.push(true);
+            }
+            LayerDecision::Abort => return Err(Error::LayerAborted.in_layer(index, descriptor)),
+        }
+    }
+    Ok((skipped, warnings))
+}
+
+/// Returns whether `media_type` is that of a layer, whether or not it can be unpacked, rather than
+/// of an artifact such as an attestation
+fn is_layer(media_type: &MediaType) -> bool {
+    let media_type = media_type.to_string();
+    media_type.starts_with("application/vnd.oci.image.layer.")
+        || media_type.starts_with("application/vnd.docker.image.rootfs.")
+}
+
+/// Passes the uncompressed content of the layer at `index` in `layers` to `f`, then verifies the
+/// layer's digests and size unless verification is disabled.
+///
+/// The time spent reading the layer and in `f`, less its reads, is added to `timing`, and how the
+/// layer was compressed is recorded in `compression`.
+#[allow(clippy::too_many_arguments)]
+fn read_layer<T>(
+    blobs: &BlobSource,
+    layers: &[Descriptor],
+    index: usize,
+    expected_diff_id: &str,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    timing: &mut LayerTiming,
+    compression: &mut LayerCompression,
+    f: impl FnOnce(&mut dyn io::Read, Option<ArchiveCloner>) -> Result<T>,
+) -> Result<T> {
+    let started = Instant::now();
+    let written = timing.write;
+    let result = read_layer_content(
+        blobs,
+        layers,
+        index,
+        expected_diff_id,
+        options,
+        deadline,
+        compression,
+        |reader, cloner| timing::time_writes(timing, reader, |reader| f(reader, cloner)),
+    );
+    timing.compressed_size = layers[index].size();
+    timing.read += started.elapsed().saturating_sub(timing.write - written);
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn read_layer_content<T>(
+    blobs: &BlobSource,
+    layers: &[Descriptor],
+    index: usize,
+    expected_diff_id: &str,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    compression: &mut LayerCompression,
+    f: impl FnOnce(&mut dyn io::Read, Option<ArchiveCloner>) -> Result<T>,
+) -> Result<T> {
+    let descriptor = &layers[index];
+    compression.media_type = descriptor.media_type().to_string();
+    compression.compressed_bytes = descriptor.size();
+    compression.uncompressed_size_hint = layer_digests::size_hint(descriptor);
+    compression.verified_digests.clear();
+    let digests = LayerDigests::new(descriptor, expected_diff_id);
+    options.emit(&Event::LayerStarted {
+        layer_index: index,
+        digest: descriptor.digest().to_string(),
+        media_type: descriptor.media_type().to_string(),
+        size: descriptor.size(),
+        annotations: descriptor
+            .annotations()
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect(),
+    });
+    let mut cache_entry = None;
+    if let Some(cache) = &options.decompressed_blob_cache {
+        // Only gzip layers are cached, once they're verified
+        let populate =
+            options.verify_digests && *descriptor.media_type() == MediaType::ImageLayerGzip;
+        match blob_cache::lookup(cache, expected_diff_id, populate, deadline) {
+            blob_cache::Lookup::Hit(file) => {
+                return read_cached_layer(
+                    file,
+                    cache,
+                    index,
+                    expected_diff_id,
+                    &digests,
+                    options,
+                    deadline,
+                    compression,
+                    f,
+                )
+            }
+            blob_cache::Lookup::Miss(entry) => cache_entry = entry,
+        }
+    }
+    match descriptor.media_type() {
+        MediaType::ImageLayerGzip => {
+            let blob = blobs.layer(layers, index)?;
+            #[cfg(feature = "estargz")]
+            estargz::check_layer(&blob, index, descriptor, options)?;
+            // Reads of a map can't be abandoned, so it isn't used with a deadline
+            if options.mmap_blobs && deadline.is_none() {
+                match Mmap::map(&blob) {
+                    Ok(map) => {
+                        return read_mapped_layer(
+                            &map,
+                            index,
+                            descriptor,
+                            &digests,
+                            cache_entry,
+                            options,
+                            compression,
+                            f,
+                        )
+                    }
+                    Err(e) => {
+                        log::debug!("Streaming unmappable layer {}: {e}", descriptor.digest())
+                    }
                 }
             }
-            _ => {
-                bail!("Unsupported media type: {}", descriptor.media_type());
+
+            let blob: Box<dyn Read + Send + '_> = match deadline {
+                Some(deadline) => Box::new(deadline.reader(blob, options.read_buffer_size)),
+                None => Box::new(blob),
+            };
+            let mut layer = LayerReader::stream(
+                blob,
+                &digests.blob_algorithms(),
+                &digests.archive_algorithms(),
+                cache_entry,
+                options.read_buffer_size,
+            );
+            // tar-rs reads in 512 byte blocks, so read ahead of it. Anything read into the buffer
+            // has been hashed, and whatever isn't read is drained when finishing the digest.
+            let output = f(
+                &mut BufReader::with_capacity(options.read_buffer_size, &mut layer),
+                None,
+            )
+            .map_err(|e| explain_archive_error(index, layer.decoder(), e))?;
+
+            // The blob is read to the end even without verification, so trailing data is found
+            let finished = layer.finish_blob()?;
+            compression.uncompressed_bytes = finished.archive_bytes;
+            compression.compressed_bytes = finished.blob_bytes;
+            compression.multi_member = Some(finished.members > 1);
+            check_gzip_end(index, finished.trailing)?;
+            if !options.verify_digests {
+                return Ok(output);
+            }
+
+            // Note that the diff_id is the uncompressed digest of every gzip member...
+            digests.check_archive(
+                index,
+                &finished.archive,
+                options,
+                &mut compression.verified_digests,
+            )?;
+
+            // ...and the overall layer digest is the second digest
+            check_layer_size(index, descriptor, finished.blob_bytes)?;
+            digests.check_blob(
+                index,
+                &finished.blob,
+                options,
+                &mut compression.verified_digests,
+            )?;
+            if let Some(entry) = finished.cache_entry {
+                entry.commit(options.decompressed_blob_cache_max_bytes);
             }
+            Ok(output)
         }
+        media_type => Err(Error::UnsupportedMediaType(media_type.to_string())),
     }
+}
 
-    // convert manifest config per https://github.com/opencontainers/image-spec/blob/main/conversion.md#verbatim-fields
-    let image_config = ImageConfiguration::from_reader(oci_dir.read_blob(manifest.config())?)?;
-    let runtime_config = create_runtime_config(&image_config)?;
-    runtime_config.save(bundle.join("config.json"))?;
+/// As [`read_layer`], but decompressing and hashing a mapped blob.
+///
+/// The compressed digest is checked in a single pass over the map before anything is extracted.
+#[allow(clippy::too_many_arguments)]
+fn read_mapped_layer<T>(
+    map: &[u8],
+    index: usize,
+    descriptor: &Descriptor,
+    digests: &LayerDigests,
+    cache_entry: Option<NewEntry>,
+    options: &UnpackOptions,
+    compression: &mut LayerCompression,
+    f: impl FnOnce(&mut dyn io::Read, Option<ArchiveCloner>) -> Result<T>,
+) -> Result<T> {
+    // Check the blob wasn't truncated before it was mapped, before reading any of it
+    check_layer_size(index, descriptor, map.len() as u64)?;
+    if options.verify_digests {
+        digests.check_blob(
+            index,
+            &Digests::of(map, &digests.blob_algorithms()),
+            options,
+            &mut compression.verified_digests,
+        )?;
+    }
+    let mut layer = LayerReader::new(map, &digests.archive_algorithms(), cache_entry);
+    let output = f(
+        &mut BufReader::with_capacity(options.read_buffer_size, &mut layer),
+        None,
+    )
+    .map_err(|e| explain_archive_error(index, layer.decoder(), e))?;
+    let finished = layer.finish()?;
+    compression.uncompressed_bytes = finished.archive_bytes;
+    compression.multi_member = Some(finished.members > 1);
+    check_gzip_end(index, finished.rest.len() as u64)?;
+    if !options.verify_digests {
+        return Ok(output);
+    }
+    digests.check_archive(
+        index,
+        &finished.archive,
+        options,
+        &mut compression.verified_digests,
+    )?;
+    if let Some(entry) = finished.cache_entry {
+        entry.commit(options.decompressed_blob_cache_max_bytes);
+    }
+    Ok(output)
+}
+
+/// As [`read_layer`], but reading the layer's archive from the decompressed blob cache in `cache`.
+/// The archive is verified against the diff ID even if digest verification is disabled, and
+/// removed from the cache if it doesn't match.
+#[allow(clippy::too_many_arguments)]
+fn read_cached_layer<T>(
+    file: fs::File,
+    cache: &Path,
+    index: usize,
+    expected_diff_id: &str,
+    digests: &LayerDigests,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    compression: &mut LayerCompression,
+    f: impl FnOnce(&mut dyn io::Read, Option<ArchiveCloner>) -> Result<T>,
+) -> Result<T> {
+    log::debug!("Reading layer {index} from the decompressed blob cache");
+    let metadata = file.metadata().with_path(cache)?;
+    let cloner = match file.try_clone() {
+        Ok(archive) => ArchiveCloner::new(archive, options.cache_copy_strategy),
+        Err(e) => {
+            log::debug!("Copying layer {index}, as the cached archive can't be cloned: {e}");
+            None
+        }
+    };
+    let file: Box<dyn Read + Send> = match deadline {
+        Some(deadline) => Box::new(deadline.reader(file, options.read_buffer_size)),
+        None => Box::new(file),
+    };
+    let mut reader = DigestReader::with_algorithms(file, &digests.archive_algorithms());
+    let output = f(
+        &mut BufReader::with_capacity(options.read_buffer_size, &mut reader),
+        cloner,
+    );
+    let drained = reader.drain();
+    compression.uncompressed_bytes = reader.bytes_read();
+    let archive_digests = drained.map(|()| reader.finish().0);
+    if let Ok(archive_digests) = &archive_digests {
+        if !digests.matches_diff_id(archive_digests) {
+            blob_cache::remove(cache, expected_diff_id, &metadata);
+        }
+    }
+    let output = output?;
+    digests.check_archive(
+        index,
+        &archive_digests.map_err(Error::Archive)?,
+        options,
+        &mut compression.verified_digests,
+    )?;
+    Ok(output)
+}
+
+/// Checks that the blob ends with its last gzip member. Decoding stops at anything that doesn't
+/// start another member, so whatever follows would otherwise be silently ignored.
+fn check_gzip_end(index: usize, trailing: u64) -> Result<()> {
+    if trailing > 0 {
+        return Err(Error::TrailingData {
+            layer_index: index,
+            bytes: trailing,
+        });
+    }
+    Ok(())
+}
+
+/// Replaces an error reading a layer's archive with [`Error::TrailingData`] if the archive was cut
+/// short by the end of the gzip stream, as happens when something other than a gzip member
+/// follows a member part way through the archive
+fn explain_archive_error<R: io::BufRead>(
+    index: usize,
+    gz_decoder: &mut GzipDecoder<R>,
+    e: Error,
+) -> Error {
+    if !matches!(e, Error::Archive(_)) || !matches!(gz_decoder.read(&mut [0]), Ok(0)) {
+        return e;
+    }
+    match io::copy(gz_decoder.get_mut(), &mut io::sink()) {
+        Ok(trailing) if trailing > 0 => Error::TrailingData {
+            layer_index: index,
+            bytes: trailing,
+        },
+        _ => e,
+    }
+}
+
+fn check_layer_size(index: usize, descriptor: &Descriptor, size: u64) -> Result<()> {
+    if size != descriptor.size() {
+        return Err(Error::SizeMismatch {
+            layer_index: Some(index),
+            expected: descriptor.size(),
+            actual: size,
+        });
+    }
+    Ok(())
+}
+
+/// Records that the layer at `index` has been applied to `rootfs`, taking `timing`, having run
+/// [`UnpackOptions::after_layer`] on it, and checkpoints it in `checkpoints`
+#[allow(clippy::too_many_arguments)]
+fn layer_applied(
+    index: usize,
+    descriptor: &Descriptor,
+    report: &mut LayerReport,
+    timing: &LayerTiming,
+    rootfs: &Path,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    checkpoints: Option<&checkpoint::Checkpoints>,
+) -> Result<()> {
+    hooks::after_layer(index, descriptor, report, timing, rootfs, options)
+        .map_err(|e| e.in_layer(index, descriptor))?;
+    if let Some(checkpoints) = checkpoints {
+        checkpoints
+            .layer_applied(index, report, timing.uncompressed_size)
+            .map_err(|e| e.in_layer(index, descriptor))?;
+    }
+    if let Some(deadline) = deadline {
+        deadline.layer_finished(index);
+    }
+    let warnings = &report.warnings;
+    for warning in warnings {
+        options.emit(&Event::warning(warning));
+    }
+    options.emit(&Event::LayerFinished {
+        layer_index: index,
+        digest: descriptor.digest().to_string(),
+        warnings: warnings.len(),
+        compressed_bytes: timing.compressed_size,
+        uncompressed_bytes: timing.uncompressed_size,
+        duration_seconds: timing.duration.as_secs_f64(),
+    });
     Ok(())
 }
 
-fn extract_layer<R: io::Read>(archive: &mut Archive<R>, root: &Path) -> Result<()> {
+/// What attempts to extract a layer have changed, so that a failed attempt can be rolled back
+/// before the layer is extracted again
+struct LayerChanges<'a> {
+    track: bool,
+    /// Paths created by the current attempt, which didn't exist before it
+    created: Vec<PathBuf>,
+    /// Whiteouts applied by any attempt, which have nothing left to remove when retried
+    whiteouts: HashSet<PathBuf>,
+    /// The paths being restored when reverting layers for an update, outside of which entries
+    /// are skipped
+    scope: Option<&'a update::Scope<'a>>,
+    /// Whether a hard link in the scope was skipped, as a later layer changed its target outside
+    /// the scope
+    found_stale_link: bool,
+}
+
+impl<'a> LayerChanges<'a> {
+    /// Creates a tracker, which only records changes if `track` is set
+    fn new(track: bool) -> Self {
+        Self {
+            track,
+            created: Vec::new(),
+            whiteouts: HashSet::new(),
+            scope: None,
+            found_stale_link: false,
+        }
+    }
+
+    /// Only applies the entries that restore the paths in `scope`
+    fn restoring(mut self, scope: &'a update::Scope<'a>) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Returns whether to apply the entry for the normalized `path`, which is a directory if
+    /// `dir`. Other entries that replace or remove `path` remove the restored paths beneath it
+    /// instead, as applying them would have.
+    fn restores(&self, root_dir: &Dir, path: &Path, dir: bool) -> Result<bool> {
+        let Some(scope) = self.scope else {
+            return Ok(true);
+        };
+        if scope.covers(path) || (dir && scope.restores_dir(path)) {
+            return Ok(true);
+        }
+        if !dir {
+            scope.remove_beneath(root_dir, path)?;
+        }
+        Ok(false)
+    }
+
+    /// Returns whether to apply the non-directory `entry` at `path` in the layer at `index`, as
+    /// [`LayerChanges::restores`] does. Hard links outside the scope to restored targets are
+    /// linked again, unless a later layer replaced them. Hard links in the scope to targets
+    /// outside it that a later layer changed can't be restored, which is recorded.
+    fn restores_entry<R: io::Read>(
+        &mut self,
+        root_dir: &Dir,
+        entry: &tar::Entry<R>,
+        path: &Path,
+        index: usize,
+    ) -> Result<bool> {
+        let Some(scope) = self.scope else {
+            return Ok(true);
+        };
+        let normalized = normalize(path);
+        let target = if entry.header().entry_type().is_hard_link() {
+            Some(link_target(entry, path)?)
+        } else {
+            None
+        };
+        if scope.covers(&normalized) {
+            let stale = target.is_some_and(|target| {
+                !scope.covers(&target) && scope.changed_later(&target, index)
+            });
+            self.found_stale_link |= stale;
+            return Ok(!stale);
+        }
+        if target.is_some_and(|target| scope.covers(&target))
+            && !scope.changed_later(&normalized, index)
+        {
+            return Ok(true);
+        }
+        self.restores(root_dir, &normalized, false)
+    }
+
+    fn write(&mut self, root_dir: &Dir, path: &Path) {
+        if self.track {
+            let path: PathBuf = path
+                .components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect();
+            if root_dir.symlink_metadata(&path).is_err() {
+                self.created.push(path);
+            }
+        }
+    }
+
+    /// Removes the paths created by the current attempt
+    fn roll_back(&mut self, root: &Path) -> Result<()> {
+        let root_dir = Dir::open_ambient_dir(root, ambient_authority()).with_path(root)?;
+        for path in self.created.drain(..).rev() {
+            // Opaque whiteouts and later entries may have removed or replaced created paths
+            let result = match root_dir.symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => root_dir.remove_dir_all(&path),
+                Ok(_) => root_dir.remove_file(&path),
+                Err(_) => continue,
+            };
+            result.with_path(root.join(&path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts an uncompressed layer archive onto `root`, for fuzzing the handling of malformed
+/// archives
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn fuzz_extract_layer(
+    archive: &[u8],
+    root: &Path,
+    options: &UnpackOptions,
+) -> Result<Vec<Warning>> {
+    extract_layer(
+        &mut Archive::new(archive),
+        root,
+        0,
+        options,
+        &mut LayerChanges::new(false),
+        None,
+    )
+    .map(|report| report.warnings)
+}
+
+/// Extracts the layer at `index` onto `root`, returning any warnings and stripped permissions.
+/// Files' content is cloned from the archive with `cloner`, if it's given.
+fn extract_layer<R: io::Read>(
+    archive: &mut Archive<R>,
+    root: &Path,
+    index: usize,
+    options: &UnpackOptions,
+    changes: &mut LayerChanges,
+    cloner: Option<ArchiveCloner>,
+) -> Result<LayerReport> {
+    let mut warnings =
+        Warnings::new(options.strictness, index).record_changes(options.records_changes());
     // Use cap_std::fs to do deletions as an extra protection against deleting outside the root
     // We also skip entries with ".." in them to avoid traversing outside the root
-    let root_dir = Dir::open_ambient_dir(root, ambient_authority())?;
+    let root_dir = Dir::open_ambient_dir(root, ambient_authority()).with_path(root)?;
+    let mut writer =
+        write::FileWriter::new(&root_dir, options.write_buffer_size, options.ownership)
+            .inspector(options.content_inspector.as_ref().map(|c| &*c.0))
+            .translate_acls(options.translate_acls)
+            .sniff(hygiene::sniff_limit(options))
+            .preallocate(options.preallocate_files)
+            .clone_from(cloner);
 
     archive.set_overwrite(true);
     archive.set_preserve_mtime(true);
-    archive.set_preserve_ownerships(true);
+    archive.set_preserve_ownerships(options.ownership == OwnershipMode::Preserve);
     archive.set_preserve_permissions(true);
     archive.set_unpack_xattrs(true);
 
-    // Keep track of files added this layer, as if we encounter a whiteout file
-    // whose target is also added in this layer then we mustn't remove it.
-    let mut files = Vec::new();
+    // Keeps track of files added this layer, as if we encounter a whiteout file whose target is
+    // also added in this layer then we mustn't remove it.
+    let mut applier = LayerApplier::new(DirTarget::new(&root_dir)).apply_mode(options.apply_mode);
+    let mut parents = parents::ParentGuard::new(&root_dir, options.parent_symlinks);
 
     // Add directories at the end at the end. See [0] for details.
     //
     // [0]: <https://github.com/alexcrichton/tar-rs/issues/242>
     let mut dirs = Vec::new();
+    // The layer's entry for the root, which is applied after everything beneath it
+    let mut root_entry = None;
 
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-        let path = entry.path()?;
+    for (count, entry) in archive.entries().map_err(Error::Archive)?.enumerate() {
+        options.check_entry_count(count)?;
+        let mut entry = entry.map_err(Error::Archive)?;
+        warnings.entry();
+        let path = options
+            .unicode_policy
+            .path(&entry_path(&entry)?)
+            .into_owned();
         log::trace!("Found archive entry {}", path.display());
+        options.check_entry_size(&path, entry.size())?;
+        #[cfg(feature = "estargz")]
+        if estargz::is_reserved(&path) {
+            continue;
+        }
+        // Paths with ".." in them are skipped, to avoid traversing outside the root
+        let is_unsafe = path.components().any(|c| c.as_os_str() == OsStr::new(".."));
 
         if entry.header().entry_type().is_dir() {
             log::trace!("Entry is directory");
-            dirs.push(entry);
+            parents.add_dir(&path);
+            if is_unsafe {
+                continue;
+            }
+            let mask = options
+                .permission_policy
+                .mask(entry.header())
+                .map_err(Error::Archive)?;
+            if !normalize(&path).as_os_str().is_empty() {
+                if !changes.restores(&root_dir, &normalize(&path), true)?
+                    || hygiene::check_path(options, &path, &normalize(&path), &mut warnings)
+                {
+                    continue;
+                }
+                protected::check(
+                    options,
+                    &path,
+                    &normalize(&path),
+                    Modification::Dir,
+                    &mut warnings,
+                )?;
+                parents.check(&path)?;
+                applier.replace_with_dir(&path)?;
+                let dir = write::DeferredDir::new(&mut entry, normalize(&path), mask)?;
+                warnings.change(|| EntryChange::Dir {
+                    path: dir.path.clone(),
+                    mtime: dir.mtime,
+                });
+                dirs.push(dir);
+            } else if is_root_entry(&path) && changes.restores(&root_dir, Path::new(""), true)? {
+                let root = write::DeferredDir::root(&mut entry, mask)?;
+                warnings.change(|| EntryChange::Root { mtime: root.mtime });
+                root_entry = Some(root);
+            }
             continue;
-        } else if let Some(file_name) = path.file_name() {
-            // Ignore paths with ".." in them, to avoid traversing outside the root
-            if path.components().any(|c| c.as_os_str() == OsStr::new("..")) {
-                log::warn!("Ignoring path with '..'");
+        } else if path.file_name().is_some() {
+            if is_unsafe {
+                warnings.warn(&path, WarningKind::UnsafePath)?;
                 continue;
             }
 
-            let slice = file_name.as_encoded_bytes();
-            // Handle whiteouts
-            if slice.len() > 4 && slice[0..4] == *b".wh." {
+            let whiteout = Whiteout::parse(&path);
+            if let Some(whiteout) = whiteout {
                 log::trace!("Detected whiteout");
-                if slice == b".wh..wh..opq" {
-                    log::trace!("Opaque whiteout");
-                    let dir_to_clear = path
-                        .parent()
-                        .map(|p| root.join(p))
-                        .unwrap_or(root.to_path_buf());
-                    // Delete all entries in the directory, except those added this layer!
-                    for entry in fs::read_dir(&dir_to_clear)? {
-                        let entry = entry?;
-                        let path = entry.path();
-                        if path.is_dir() {
-                            log::trace!("Examining directory {}", path.display());
-                            // Retain any files and their parents that were added in this layer
-                            for sub_entry in walkdir::WalkDir::new(&path) {
-                                let sub_entry = sub_entry?;
-                                let sub_path = sub_entry.path();
-                                // Delete this path only if it, nor its ancestors, were added this layer
-                                if !files
-                                    .iter()
-                                    .any(|p| p == sub_path || sub_path.ancestors().any(|a| a == p))
-                                {
-                                    if sub_path.is_dir() {
-                                        log::trace!("Removing directory {}", sub_path.display());
-                                        root_dir.remove_dir_all(sub_path.strip_prefix(root)?)?;
-                                    } else {
-                                        log::trace!("Removing file {}", sub_path.display());
-                                        root_dir.remove_file(sub_path.strip_prefix(root)?)?;
-                                    }
-                                }
-                            }
-                        } else if !files.contains(&path) {
-                            log::trace!("Removing file {}", path.display());
-                            root_dir.remove_file(&path)?;
+                let removed = match whiteout {
+                    Whiteout::Invalid => {
+                        warnings.warn(&path, WarningKind::InvalidWhiteout)?;
+                        continue;
+                    }
+                    Whiteout::Opaque(dir) => {
+                        log::trace!("Opaque whiteout");
+                        if !changes.restores(&root_dir, &normalize(&dir), false)?
+                            || hygiene::skips_whiteout(options, &normalize(&dir))
+                        {
+                            continue;
                         }
+                        protected::check(
+                            options,
+                            &path,
+                            &normalize(&dir),
+                            Modification::Opaque,
+                            &mut warnings,
+                        )?;
+                        warnings.change(|| EntryChange::Opaque(normalize(&dir)));
+                        applier.opaque_whiteout(&dir)
                     }
-                } else {
-                    log::trace!("Regular whiteout");
-                    // SAFETY: we checked above that the first 4 bytes of slice are b".wh."
-                    let file_name = unsafe { OsStr::from_encoded_bytes_unchecked(&slice[4..]) };
-
-                    let file_to_remove = path
-                        .parent()
-                        .map(|p| p.join(file_name))
-                        .unwrap_or(PathBuf::from(file_name));
-
-                    if root_dir.exists(&file_to_remove) {
-                        log::trace!("Removing file {}", file_to_remove.display());
-                        root_dir.remove_file(&file_to_remove)?;
+                    Whiteout::Entry(removed) => {
+                        log::trace!("Regular whiteout");
+                        if !changes.restores(&root_dir, &normalize(&removed), false)?
+                            || hygiene::skips_whiteout(options, &normalize(&removed))
+                        {
+                            continue;
+                        }
+                        protected::check(
+                            options,
+                            &path,
+                            &normalize(&removed),
+                            Modification::Replace,
+                            &mut warnings,
+                        )?;
+                        warnings.change(|| EntryChange::Whiteout(normalize(&removed)));
+                        applier.whiteout(&removed)
+                    }
+                };
+                warnings.whiteout();
+                if removed.map_err(|e| e.in_whiteout(&path))? {
+                    if changes.track {
+                        changes.whiteouts.insert(path.to_path_buf());
                     }
+                } else if !changes.whiteouts.contains(&path) {
+                    warnings.warn(&path, WarningKind::DanglingWhiteout)?;
                 }
             } else {
                 // Non-whiteout file
-                files.push(path.to_path_buf());
-                entry.unpack_in(root)?;
+                if !check_link_target(&entry, &path, options, &mut warnings)?
+                    || !changes.restores_entry(&root_dir, &entry, &path, index)?
+                    || hygiene::check_path(options, &path, &normalize(&path), &mut warnings)
+                    || hygiene::check_link(options, &entry, &path, &mut warnings)?
+                {
+                    continue;
+                }
+                protected::check(
+                    options,
+                    &path,
+                    &normalize(&path),
+                    Modification::Replace,
+                    &mut warnings,
+                )?;
+                parents.check(&path)?;
+                changes.write(&root_dir, &path);
+                applier.replace(&path)?;
+                let mask = permission_mask(&mut entry, &path, options, &mut warnings)?;
+                let entry_type = entry.header().entry_type();
+                warnings.change(|| EntryChange::Write {
+                    path: normalize(&path),
+                    size: written_size(&entry),
+                });
+                if write::is_regular_file(entry_type) {
+                    let capability = capabilities::read(&mut entry)?;
+                    if let Some(error) = writer.write(&mut entry, &path, mask)? {
+                        warnings.inspection_failed(&path, error)?;
+                    }
+                    if let Some(content) = writer.take_sniffed() {
+                        if hygiene::check_content(options, &path, &content, &mut warnings) {
+                            root_dir.remove_file(normalize(&path)).with_path(&path)?;
+                            continue;
+                        }
+                    }
+                    if let Some(value) = capability {
+                        warnings.capability(&path, &value, writer.capability_dropped())?;
+                    }
+                    if writer.acl_dropped() {
+                        warnings.warn(&path, WarningKind::AclDropped)?;
+                    }
+                    if writer.xattr_dropped() {
+                        warnings.warn(&path, WarningKind::XattrDropped)?;
+                    }
+                } else if entry_type.is_hard_link() && options.hardlinks == HardlinkPolicy::Copy {
+                    let target = link_target(&entry, &path)?;
+                    let target = options.unicode_policy.path(&target);
+                    write::copy_link_target(
+                        &root_dir,
+                        &target,
+                        &normalize(&path),
+                        options.ownership,
+                    )?;
+                } else if entry_type.is_hard_link() && options.unicode_policy.normalizes() {
+                    let target = link_target(&entry, &path)?;
+                    let target = options.unicode_policy.path(&target);
+                    root_dir
+                        .hard_link(&target, &root_dir, normalize(&path))
+                        .with_path(&path)?;
+                } else if options.ownership == OwnershipMode::Emulate
+                    && rootless::is_device(entry_type)
+                {
+                    rootless::write_device(&root_dir, &entry, &normalize(&path), mask)?;
+                } else if write::is_special_file(entry_type) {
+                    let dropped = write::write_special(
+                        &root_dir,
+                        &mut entry,
+                        &normalize(&path),
+                        mask,
+                        options.ownership,
+                    )?;
+                    if let Some(kind) = dropped {
+                        warnings.warn(&path, kind)?;
+                    }
+                } else {
+                    write::unpack_in(&mut entry, root, &root_dir, &path)?;
+                }
             }
         }
     }
 
-    dirs.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
-    for mut dir in dirs {
-        dir.unpack_in(root)?;
+    dirs.sort_by(|a, b| b.path.as_os_str().cmp(a.path.as_os_str()));
+    for dir in dirs {
+        parents.check_dir(&dir.path)?;
+        changes.write(&root_dir, &dir.path);
+        if dir.mask != 0 {
+            warnings.strip(&dir.path, dir.mode & 0o7777, dir.mask);
+        }
+        for kind in dir.create(&root_dir, options.ownership, options.translate_acls)? {
+            warnings.warn(&dir.path, kind)?;
+        }
+    }
+    if let Some(root) = root_entry {
+        if root.mask != 0 {
+            warnings.strip(&root.path, root.mode & 0o7777, root.mask);
+        }
+        for kind in root.create(&root_dir, options.ownership, options.translate_acls)? {
+            warnings.warn(&root.path, kind)?;
+        }
+    }
+    if let Some(copy) = writer.finish_cloning(index) {
+        warnings.copied(copy);
+    }
+
+    Ok(warnings.finish())
+}
+
+/// Returns the bytes of content `entry` writes, which only regular files have
+pub(crate) fn written_size<R: io::Read>(entry: &tar::Entry<R>) -> u64 {
+    if write::is_regular_file(entry.header().entry_type()) {
+        entry.size()
+    } else {
+        0
     }
+}
 
-    Ok(())
+/// Returns the normalized target of the hard link `entry` at `path`
+pub(crate) fn link_target<R: io::Read>(entry: &tar::Entry<R>, path: &Path) -> Result<PathBuf> {
+    match entry.link_name().map_err(Error::Archive)? {
+        Some(target) => Ok(normalize(&target)),
+        None => Err(Error::Archive(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Hard link {} has no target", path.display()),
+        ))),
+    }
 }
 
+/// Returns whether the target of `entry` at `path`, if it's a symlink or hard link, can be
+/// created. Targets containing a NUL byte, or longer than [`UnpackOptions::max_link_target_len`],
+/// are warned about, or with [`Strictness::Strict`] fail the layer, and the entry is skipped.
+pub(crate) fn check_link_target<R: io::Read>(
+    entry: &tar::Entry<R>,
+    path: &Path,
+    options: &UnpackOptions,
+    warnings: &mut Warnings,
+) -> Result<bool> {
+    let entry_type = entry.header().entry_type();
+    if !(entry_type.is_symlink() || entry_type.is_hard_link()) {
+        return Ok(true);
+    }
+    let Some(target) = entry.link_name_bytes() else {
+        return Ok(true);
+    };
+    let kind = if target.contains(&0) {
+        WarningKind::InvalidLinkTarget
+    } else if target.len() > options.max_link_target_len {
+        WarningKind::LinkTargetTooLong
+    } else {
+        return Ok(true);
+    };
+    warnings.warn(path, kind)?;
+    Ok(false)
+}
+
+/// Sets the mask of `entry` to the bits the permission policy removes from its mode, recording
+/// them if there are any, and returns it
+pub(crate) fn permission_mask<R: io::Read>(
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    options: &UnpackOptions,
+    warnings: &mut Warnings,
+) -> Result<u32> {
+    let mask = options
+        .permission_policy
+        .mask(entry.header())
+        .map_err(Error::Archive)?;
+    if mask != 0 {
+        let mode = entry.header().mode().map_err(Error::Archive)? & 0o7777;
+        warnings.strip(path, mode, mask);
+        entry.set_mask(mask);
+    }
+    Ok(mask)
+}
+
+/// Creates the staging area for the parallel and prefetch modes, returning its path
+fn create_staging_dir(bundle: &Path, options: &UnpackOptions) -> Result<PathBuf> {
+    static UNPACKS: AtomicUsize = AtomicUsize::new(0);
+    let Some(dir) = &options.staging_dir else {
+        // The bundle was just created by this unpack
+        let staging = bundle.join(".staging");
+        fs::create_dir(&staging).with_path(&staging)?;
+        return Ok(staging);
+    };
+    fs::create_dir_all(dir).with_path(dir)?;
+    loop {
+        // Unique to this unpack, as others may share the directory. One left behind by a process
+        // that had the same ID is skipped rather than reused.
+        let staging = dir.join(format!(
+            "oci-bundle-{}-{}",
+            std::process::id(),
+            UNPACKS.fetch_add(1, Ordering::Relaxed)
+        ));
+        match fs::create_dir(&staging) {
+            Ok(()) => return Ok(staging),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e).with_path(staging),
+        }
+    }
+}
+
+/// Returns the path of `entry` without the leading `/` that old tools and `tar -P` write, or a
+/// leading `./`, so that everything downstream sees the same path however the archive was made.
+/// A bare `/` is returned as it is, as it doesn't name the root of the layer; see
+/// [`is_root_entry`].
+pub(crate) fn entry_path<R: io::Read>(entry: &tar::Entry<R>) -> Result<PathBuf> {
+    use std::path::Component;
+
+    let path = entry.path().map_err(Error::Archive)?;
+    let mut components = path.components().peekable();
+    while components
+        .next_if(|c| matches!(c, Component::RootDir | Component::CurDir))
+        .is_some()
+    {}
+    let stripped: PathBuf = components.collect();
+    if stripped.as_os_str().is_empty() {
+        return Ok(path.into_owned());
+    }
+    Ok(stripped)
+}
+
+/// Strips root and `.` components from an archive path
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+/// Returns whether `path`, which normalizes to the root, names it relative to the layer, as `./`
+/// does. An absolute `/` is ignored rather than applied to the rootfs.
+pub(crate) fn is_root_entry(path: &Path) -> bool {
+    !path.has_root()
+}
+
+/// Returns `.` for an empty path, which is how the root is named when opening it relative to itself
+pub(crate) fn or_dot(path: &Path) -> &Path {
+    if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn create_runtime_config(
     image_config: &ImageConfiguration,
-) -> Result<ocidir::oci_spec::runtime::Spec, anyhow::Error> {
+    raw_config: &[u8],
+    rootfs: &Path,
+    options: &RuntimeConfigOptions,
+    file_capabilities: &[FileCapability],
+    base_image: Option<&BaseImageRef>,
+    strictness: Strictness,
+    issues: &mut Vec<SpecIssue>,
+    substitutions: &mut Vec<HermeticSubstitution>,
+) -> Result<ocidir::oci_spec::runtime::Spec> {
     let mut runtime_config = ocidir::oci_spec::runtime::SpecBuilder::default().build()?;
+    if let Some(readonly) = options.readonly_rootfs {
+        if let Some(root) = runtime_config.root_mut() {
+            root.set_readonly(Some(readonly));
+        }
+    }
     let mut annotations = HashMap::new();
     annotations.insert(
         "org.opencontainers.image.os".to_string(),
@@ -231,6 +1977,11 @@ fn create_runtime_config(
             created.to_string(),
         );
     }
+    if options.file_capabilities_annotation {
+        if let Some(summary) = capabilities::annotation(file_capabilities) {
+            annotations.insert(FILE_CAPABILITIES_ANNOTATION.to_string(), summary);
+        }
+    }
     if let Some(config) = image_config.config() {
         let mut process = ProcessBuilder::default().build().unwrap();
 
@@ -238,44 +1989,40 @@ fn create_runtime_config(
             process.set_cwd(PathBuf::from(dir));
         }
 
-        match (config.entrypoint(), config.cmd()) {
-            (None, None) => {}
-            (None, Some(cmd)) => {
-                process.set_args(Some(cmd.clone()));
-            }
-            (Some(entrypoint), None) => {
-                process.set_args(Some(entrypoint.clone()));
-            }
-            (Some(entrypoint), Some(cmd)) => {
-                let mut args = entrypoint.clone();
-                args.append(&mut cmd.clone());
-                process.set_args(Some(args));
-            }
+        if let Some(args) =
+            args::compose(config.entrypoint().as_ref(), config.cmd().as_ref(), options)
+        {
+            process.set_args(Some(args));
         }
 
         process.set_env(config.env().clone());
 
         if let Some(user) = config.user() {
-            let user_parts: Vec<&str> = user.split(':').collect();
-            let (uid, gid) = match user_parts.as_slice() {
-                [user] => resolve_user(user)?,
-                [user, group] => (resolve_user(user)?.0, resolve_group(group)?),
-                _ => bail!("Invalid user format in Config.User"),
-            };
-
-            let additional_gids = if user_parts.len() == 1 {
-                resolve_additional_gids(uid)?
+            let resolution = if options.hermetic {
+                hermetic::user_resolution(&options.user_resolution, substitutions)
             } else {
-                Vec::new()
+                options.user_resolution.clone()
             };
-
-            process.set_user(Some(
-                UserBuilder::default()
-                    .uid(uid)
-                    .gid(gid)
-                    .additional_gids(additional_gids)
-                    .build()?,
-            ));
+            match user::resolve(
+                user,
+                rootfs,
+                &resolution,
+                &options.user_database,
+                &options.additional_gids,
+            )? {
+                Some(resolved) => {
+                    process.set_user(
+                        UserBuilder::default()
+                            .uid(resolved.uid)
+                            .gid(resolved.gid)
+                            .additional_gids(resolved.additional_gids)
+                            .build()?,
+                    );
+                }
+                None => {
+                    annotations.insert(UNRESOLVED_USER_ANNOTATION.to_string(), user.clone());
+                }
+            }
         }
 
         runtime_config.set_process(Some(process));
@@ -287,37 +2034,45 @@ fn create_runtime_config(
             );
         }
 
+        if options.passthrough_unconverted {
+            let args_escaped = format!("{UNCONVERTED_ANNOTATION_PREFIX}ArgsEscaped");
+            annotations.extend(
+                passthrough::unconverted_annotations(raw_config)?
+                    .into_iter()
+                    .filter(|(key, _)| !(args::overridden(options) && *key == args_escaped))
+                    .filter(|(key, _)| {
+                        let field = key.strip_prefix(UNCONVERTED_ANNOTATION_PREFIX);
+                        !(options.legacy_resource_fields
+                            && field.is_some_and(|field| resources::FIELDS.contains(&field)))
+                    }),
+            );
+        }
+
         // Config.Labels takes precedence over other annotations, so set that last
         if let Some(labels) = config.labels() {
             annotations.extend(labels.iter().map(|(k, v)| (k.clone(), v.clone())));
         }
+    } else if let Some(args) = args::compose(None, None, options) {
+        if let Some(process) = runtime_config.process_mut() {
+            process.set_args(Some(args));
+        }
     }
-    runtime_config.set_annotations(Some(annotations));
-    Ok(runtime_config)
-}
-
-fn resolve_user(user: &str) -> Result<(u32, u32)> {
-    if let Ok(uid) = user.parse::<u32>() {
-        let user = get_user_by_uid(uid).ok_or_else(|| anyhow::anyhow!("User ID {} not found", uid))?;
-        Ok((user.uid(), user.primary_group_id()))
-    } else {
-        let user = get_user_by_name(user).ok_or_else(|| anyhow::anyhow!("User {} not found", user))?;
-        Ok((user.uid(), user.primary_group_id()))
+    // The base image is as the manifest records it, even over Config.Labels
+    if let Some(base_image) = base_image {
+        annotations.extend(base_image.annotations());
     }
-}
-
-fn resolve_group(group: &str) -> Result<u32> {
-    if let Ok(gid) = group.parse::<u32>() {
-        get_group_by_gid(gid).ok_or_else(|| anyhow::anyhow!("Group ID {} not found", gid))?;
-        Ok(gid)
-    } else {
-        let group = get_group_by_name(group).ok_or_else(|| anyhow::anyhow!("Group {} not found", group))?;
-        Ok(group.gid())
+    let labels = image_config
+        .config()
+        .as_ref()
+        .and_then(|config| config.labels().clone())
+        .unwrap_or_default();
+    let normalized = annotations::normalize(&mut annotations, &labels, options);
+    runtime_config.set_annotations(Some(annotations));
+    if options.hermetic {
+        hermetic::replace_bind_mounts(&mut runtime_config, substitutions)?;
     }
-}
-
-fn resolve_additional_gids(uid: u32) -> Result<Vec<u32>> {
-    let user = get_user_by_uid(uid).ok_or_else(|| anyhow::anyhow!("User ID {} not found", uid))?;
-    let groups = get_groups().filter(|g| g.members().contains(&user.name())).map(|g| g.gid()).collect();
-    Ok(groups)
+    mounts::apply(&mut runtime_config, options);
+    resources::apply(&mut runtime_config, raw_config, options)?;
+    *issues = spec::check(&runtime_config, options, strictness, normalized)?;
+    Ok(runtime_config)
 }
@@ -0,0 +1,313 @@
+use crate::base_image::BaseImageRef;
+use crate::effective::EffectiveOptions;
+use crate::error::{BundleError, Error, IoResultExt, Result};
+use crate::history;
+use crate::reference::ImageReference;
+use ocidir::oci_spec::image::{ImageConfiguration, ImageManifest};
+use ocidir::oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The name of the file in a bundle that records the image it was unpacked from
+pub(crate) const BUNDLE_METADATA: &str = "oci-bundle.json";
+
+const BUNDLE_METADATA_VERSION: u32 = 1;
+
+/// The name of the file in a bundle that marks it as being unpacked. It's created before anything
+/// else in the bundle and removed once the bundle is complete, so a bundle that contains it was
+/// either being unpacked or was left behind by an unpack that failed or was interrupted.
+pub const INCOMPLETE_SENTINEL: &str = ".incomplete";
+
+/// How far the number of entries in a reused bundle's rootfs may be from the number recorded,
+/// as a fraction of it, before the bundle is unpacked again
+const ENTRY_COUNT_TOLERANCE: f64 = 0.01;
+
+/// The image a bundle was unpacked from, as recorded in the bundle once it was complete, from
+/// [`crate::Bundle::metadata`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleMetadata {
+    version: u32,
+    config: String,
+    layers: Vec<String>,
+    diff_ids: Vec<String>,
+    skipped_layers: Vec<usize>,
+    /// The number of entries in the rootfs, excluding the root itself
+    entries: u64,
+    /// The layers in the order they were applied, for working out what went wrong with a bundle
+    /// that isn't as expected
+    #[serde(default)]
+    applied_layers: Vec<AppliedLayer>,
+    /// The base image the image was built on, for provenance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_image: Option<BaseImageRef>,
+    /// The reference the image was unpacked by, from [`crate::UnpackOptions::reference`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reference: Option<String>,
+    /// The digest of the options the bundle was unpacked with, from
+    /// [`crate::EffectiveOptions::digest`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<String>,
+}
+
+/// A layer applied to the bundle, with the history entry that created it, if the history records
+/// as many layers as the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AppliedLayer {
+    index: usize,
+    digest: String,
+    diff_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+}
+
+impl BundleMetadata {
+    /// The digest of the image config
+    pub fn config_digest(&self) -> &str {
+        &self.config
+    }
+
+    /// The digests of the image's layers, including any that were skipped
+    pub fn layer_digests(&self) -> &[String] {
+        &self.layers
+    }
+
+    /// The diff IDs of the image's layers, from its config
+    pub fn diff_ids(&self) -> &[String] {
+        &self.diff_ids
+    }
+
+    /// The indices of the layers skipped by [`crate::UnpackOptions::layer_decision`]
+    pub fn skipped_layers(&self) -> &[usize] {
+        &self.skipped_layers
+    }
+
+    /// The base image the image was built on, if its annotations say
+    pub fn base_image(&self) -> Option<&BaseImageRef> {
+        self.base_image.as_ref()
+    }
+
+    /// The reference the image was unpacked by, from [`crate::UnpackOptions::reference`]
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// The [`crate::EffectiveOptions::digest`] of the options the bundle was unpacked with
+    pub fn options_digest(&self) -> Option<&str> {
+        self.options.as_deref()
+    }
+}
+
+/// Records the image that the completed bundle was unpacked from. The file is written last, but
+/// for removing the [`INCOMPLETE_SENTINEL`], so that it's never found in a bundle that's only
+/// partly unpacked.
+pub(crate) fn write(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    image_config: &ImageConfiguration,
+    skipped_layers: &[usize],
+    base_image: Option<&BaseImageRef>,
+    reference: Option<&ImageReference>,
+    options: &EffectiveOptions,
+) -> Result<()> {
+    let metadata = BundleMetadata {
+        version: BUNDLE_METADATA_VERSION,
+        config: manifest.config().digest().to_string(),
+        layers: layer_digests(manifest),
+        diff_ids: image_config.rootfs().diff_ids().clone(),
+        skipped_layers: skipped_layers.to_vec(),
+        entries: count_entries(&bundle.join("rootfs")),
+        applied_layers: applied_layers(manifest, image_config, skipped_layers),
+        base_image: base_image.cloned(),
+        reference: reference.map(ImageReference::to_string),
+        options: Some(options.digest.clone()),
+    };
+    let path = bundle.join(BUNDLE_METADATA);
+    let json = serde_json::to_vec(&metadata).expect("metadata serializes");
+    fs::write(&path, json).with_path(&path)
+}
+
+/// Returns whether `bundle` was completely unpacked from the image with `manifest`, without
+/// skipping any layers, according to its metadata. The config's digest pins its diff IDs, so
+/// they match if it does.
+pub(crate) fn is_current(bundle: &Path, manifest: &ImageManifest) -> Result<bool> {
+    if is_incomplete(bundle) {
+        log::info!("Bundle {} is incomplete", bundle.display());
+        return Ok(false);
+    }
+    Ok(read(bundle)?.is_some_and(|metadata| {
+        metadata.config == manifest.config().digest().to_string()
+            && metadata.layers == layer_digests(manifest)
+            && metadata.skipped_layers.is_empty()
+    }))
+}
+
+/// Returns whether `bundle` is current, as [`is_current`] says, and was unpacked with options
+/// whose [`EffectiveOptions::digest`] matches that of `options`. Bundles unpacked by versions of
+/// this crate that didn't record it don't match.
+pub(crate) fn is_current_with_options(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    options: &EffectiveOptions,
+) -> Result<bool> {
+    if !is_current(bundle, manifest)? {
+        return Ok(false);
+    }
+    let recorded = read(bundle)?.and_then(|metadata| metadata.options);
+    if recorded.as_ref() != Some(&options.digest) {
+        log::info!(
+            "Bundle {} was unpacked with different options",
+            bundle.display()
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Returns the diff IDs of the layers `bundle` was unpacked from, if it was completely unpacked
+/// without skipping any layers, according to its metadata
+pub(crate) fn complete_diff_ids(bundle: &Path) -> Result<Option<Vec<String>>> {
+    if is_incomplete(bundle) {
+        return Ok(None);
+    }
+    Ok(read(bundle)?
+        .filter(|metadata| metadata.skipped_layers.is_empty())
+        .map(|metadata| metadata.diff_ids))
+}
+
+/// Checks that a bundle that's current still looks intact: its runtime config parses, and its
+/// rootfs exists with about as many entries as when it was unpacked
+pub(crate) fn is_intact(bundle: &Path) -> Result<bool> {
+    let Some(metadata) = read(bundle)? else {
+        return Ok(false);
+    };
+    if let Err(e) = Spec::load(bundle.join("config.json")) {
+        log::debug!("Not reusing bundle {}: {e}", bundle.display());
+        return Ok(false);
+    }
+    let rootfs = bundle.join("rootfs");
+    if !rootfs.is_dir() {
+        log::debug!("Not reusing bundle {}: rootfs is missing", bundle.display());
+        return Ok(false);
+    }
+    let entries = count_entries(&rootfs);
+    let difference = entries.abs_diff(metadata.entries) as f64;
+    if difference > metadata.entries as f64 * ENTRY_COUNT_TOLERANCE {
+        log::debug!(
+            "Not reusing bundle {}: rootfs has {entries} entries, but {} were unpacked",
+            bundle.display(),
+            metadata.entries
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Creates `bundle`, if need be, with the [`INCOMPLETE_SENTINEL`] in it
+pub(crate) fn mark_incomplete(bundle: &Path) -> Result<()> {
+    fs::create_dir_all(bundle).with_path(bundle)?;
+    let path = bundle.join(INCOMPLETE_SENTINEL);
+    fs::write(&path, b"").with_path(&path)
+}
+
+/// Removes the [`INCOMPLETE_SENTINEL`] from a bundle once it's complete
+pub(crate) fn mark_complete(bundle: &Path) -> Result<()> {
+    let path = bundle.join(INCOMPLETE_SENTINEL);
+    fs::remove_file(&path).with_path(&path)
+}
+
+/// Returns whether `bundle` contains the [`INCOMPLETE_SENTINEL`]
+pub(crate) fn is_incomplete(bundle: &Path) -> bool {
+    bundle.join(INCOMPLETE_SENTINEL).symlink_metadata().is_ok()
+}
+
+/// Reads the bundle's metadata, returning `None` if there isn't any, or it's from an unknown
+/// version, in which case the bundle is unpacked again
+fn read(bundle: &Path) -> Result<Option<BundleMetadata>> {
+    let path = bundle.join(BUNDLE_METADATA);
+    let json = match fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_path(&path),
+    };
+    match serde_json::from_slice::<BundleMetadata>(&json) {
+        Ok(metadata) if metadata.version == BUNDLE_METADATA_VERSION => Ok(Some(metadata)),
+        Ok(metadata) => {
+            log::debug!(
+                "Ignoring bundle metadata version {} in {}",
+                metadata.version,
+                path.display()
+            );
+            Ok(None)
+        }
+        Err(e) => {
+            log::debug!(
+                "Ignoring unreadable bundle metadata {}: {e}",
+                path.display()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Parses the metadata of the bundle at `path` for [`crate::Bundle::open`], which unlike
+/// reusing a bundle, fails if it's unreadable or from an unknown version
+pub(crate) fn parse(path: &Path, json: &[u8]) -> Result<BundleMetadata> {
+    let not_a_bundle = |source| Error::NotABundle {
+        path: path.to_path_buf(),
+        source,
+    };
+    let metadata: BundleMetadata =
+        serde_json::from_slice(json).map_err(|e| not_a_bundle(BundleError::InvalidMetadata(e)))?;
+    if metadata.version != BUNDLE_METADATA_VERSION {
+        return Err(not_a_bundle(BundleError::UnsupportedMetadataVersion {
+            version: metadata.version,
+        }));
+    }
+    Ok(metadata)
+}
+
+fn applied_layers(
+    manifest: &ImageManifest,
+    image_config: &ImageConfiguration,
+    skipped_layers: &[usize],
+) -> Vec<AppliedLayer> {
+    let history: Vec<_> = history::layer_history(image_config).collect();
+    let history = (history.len() == manifest.layers().len()).then_some(history);
+    let diff_ids = image_config.rootfs().diff_ids();
+    manifest
+        .layers()
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !skipped_layers.contains(index))
+        .map(|(index, layer)| {
+            let entry = history.as_ref().map(|history| history[index]);
+            AppliedLayer {
+                index,
+                digest: layer.digest().to_string(),
+                diff_id: diff_ids.get(index).cloned(),
+                created: entry.and_then(|entry| entry.created().clone()),
+                created_by: entry.and_then(|entry| entry.created_by().clone()),
+            }
+        })
+        .collect()
+}
+
+fn layer_digests(manifest: &ImageManifest) -> Vec<String> {
+    manifest
+        .layers()
+        .iter()
+        .map(|layer| layer.digest().to_string())
+        .collect()
+}
+
+fn count_entries(rootfs: &Path) -> u64 {
+    walkdir::WalkDir::new(rootfs)
+        .min_depth(1)
+        .into_iter()
+        .filter(Result::is_ok)
+        .count() as u64
+}
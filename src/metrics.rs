@@ -0,0 +1,149 @@
+//! Metrics of unpacks, for [`crate::UnpackOptions::metrics_sink`]. They're derived from the same
+//! points as the events of [`crate::UnpackOptions::event_sink`], so the two always agree.
+
+use crate::events::Event;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Receives Prometheus-style metrics of unpacks, for [`crate::UnpackOptions::metrics_sink`].
+///
+/// The metrics, whose names and labels are stable, are:
+///
+/// * `oci_bundle_unpacks_total` - a counter of finished unpacks, labelled with `result`,
+///   `"success"` or `"error"`
+/// * `oci_bundle_unpack_duration_seconds` - a histogram of the wall time of unpacks, labelled
+///   with `result`
+/// * `oci_bundle_layers_extracted_total` - a counter of layers applied to a rootfs
+/// * `oci_bundle_layers_skipped_total` - a counter of layers skipped by
+///   [`crate::UnpackOptions::layer_decision`]
+/// * `oci_bundle_layer_duration_seconds` - a histogram of the wall time of each applied layer,
+///   as in [`crate::LayerTiming::duration`]
+/// * `oci_bundle_compressed_bytes_total` - a counter of the bytes of the blobs of applied layers
+/// * `oci_bundle_decompressed_bytes_total` - a counter of the bytes of the archives of applied
+///   layers
+/// * `oci_bundle_digests_verified_total` - a counter of digests checked, labelled with `kind`,
+///   the `kind` of the `digest_verified` event, whether or not they matched
+/// * `oci_bundle_digest_failures_total` - a counter of digests that didn't match, labelled with
+///   `kind`
+/// * `oci_bundle_warnings_total` - a counter of [`crate::Warning`]s, labelled with `kind`, the
+///   `kind` of the `warning` event
+///
+/// Metrics may be added, but those above won't be renamed or have their labels changed. Both
+/// methods default to doing nothing, and are called from whichever thread the metric is recorded
+/// on, so should be quick.
+pub trait MetricsSink: Send + Sync {
+    /// Adds `value` to the counter `name` with `labels`
+    fn incr_counter(&self, name: &str, value: u64, labels: &[(&str, &str)]) {
+        let _ = (name, value, labels);
+    }
+
+    /// Records `value` in the histogram `name` with `labels`
+    fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        let _ = (name, value, labels);
+    }
+}
+
+/// A [`MetricsSink`] that discards every metric
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {}
+
+/// A metric's name and its labels, sorted
+type MetricKey = (String, Vec<(String, String)>);
+
+/// A [`MetricsSink`] that keeps every metric in memory, for tests and simple tools
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    counters: Mutex<BTreeMap<MetricKey, u64>>,
+    histograms: Mutex<BTreeMap<MetricKey, Vec<f64>>>,
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of the counter `name` with exactly `labels`, in any order, or 0 if it was never
+    /// incremented
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        counters.get(&key(name, labels)).copied().unwrap_or(0)
+    }
+
+    /// The values observed by the histogram `name` with exactly `labels`, in any order
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Vec<f64> {
+        let histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .get(&key(name, labels))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl MetricsSink for InMemoryMetrics {
+    fn incr_counter(&self, name: &str, value: u64, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        *counters.entry(key(name, labels)).or_default() += value;
+    }
+
+    fn observe_histogram(&self, name: &str, value: f64, labels: &[(&str, &str)]) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        histograms.entry(key(name, labels)).or_default().push(value);
+    }
+}
+
+fn key(name: &str, labels: &[(&str, &str)]) -> MetricKey {
+    let mut labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    labels.sort();
+    (name.to_string(), labels)
+}
+
+/// Records the metrics of `event` in `sink`
+pub(crate) fn record(sink: &dyn MetricsSink, event: &Event) {
+    match event {
+        Event::LayerSkipped { .. } => sink.incr_counter("oci_bundle_layers_skipped_total", 1, &[]),
+        Event::LayerStarted { .. } => {}
+        Event::DigestVerified { kind, matched, .. } => {
+            let labels = [("kind", *kind)];
+            sink.incr_counter("oci_bundle_digests_verified_total", 1, &labels);
+            if !matched {
+                sink.incr_counter("oci_bundle_digest_failures_total", 1, &labels);
+            }
+        }
+        Event::Warning { kind, .. } => {
+            sink.incr_counter("oci_bundle_warnings_total", 1, &[("kind", kind)])
+        }
+        Event::LayerFinished {
+            compressed_bytes,
+            uncompressed_bytes,
+            duration_seconds,
+            ..
+        } => {
+            sink.incr_counter("oci_bundle_layers_extracted_total", 1, &[]);
+            sink.observe_histogram("oci_bundle_layer_duration_seconds", *duration_seconds, &[]);
+            sink.incr_counter("oci_bundle_compressed_bytes_total", *compressed_bytes, &[]);
+            sink.incr_counter(
+                "oci_bundle_decompressed_bytes_total",
+                *uncompressed_bytes,
+                &[],
+            );
+        }
+        Event::Finished {
+            error,
+            duration_seconds,
+            ..
+        } => {
+            let labels = [("result", if error.is_some() { "error" } else { "success" })];
+            sink.incr_counter("oci_bundle_unpacks_total", 1, &labels);
+            sink.observe_histogram(
+                "oci_bundle_unpack_duration_seconds",
+                *duration_seconds,
+                &labels,
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::ptr::NonNull;
+
+/// A read-only memory mapping of a whole file
+pub(crate) struct Mmap {
+    ptr: NonNull<libc::c_void>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned, so it can be shared and sent like a `Box<[u8]>`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps the whole of `file`.
+    ///
+    /// Reading pages of the map that are past the end of the file raises SIGBUS, so callers should
+    /// check the map is as long as they expect before reading it, which catches files truncated
+    /// before they were mapped. Empty files can't be mapped.
+    pub(crate) fn map(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len()).map_err(io::Error::other)?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't map an empty file",
+            ));
+        }
+
+        // SAFETY: we map a fresh region, and check the result before using it
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Blobs are read once from start to end. This is only advice, so failure doesn't matter.
+        // SAFETY: ptr and len describe the mapping created above
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self {
+            ptr: NonNull::new(ptr).expect("mmap returned a null mapping"),
+            len,
+        })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is readable and len bytes long until it's dropped
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: ptr and len describe a mapping we own, which no borrow outlives
+        unsafe { libc::munmap(self.ptr.as_ptr(), self.len) };
+    }
+}
@@ -0,0 +1,65 @@
+use crate::error::{Error, Result};
+use crate::options::RuntimeConfigOptions;
+use ocidir::oci_spec::runtime::{LinuxDeviceCgroup, Spec};
+use std::collections::HashSet;
+
+/// Checks the mounts and devices added by `options`, so that an invalid runtime config is found
+/// before the bundle is touched
+pub(crate) fn validate(options: &RuntimeConfigOptions) -> Result<()> {
+    for mount in &options.mounts {
+        if !mount.destination().is_absolute() {
+            return Err(Error::InvalidRuntimeConfigOptions(format!(
+                "Mount destination {} isn't absolute",
+                mount.destination().display()
+            )));
+        }
+    }
+    let mut paths = HashSet::new();
+    for device in &options.devices {
+        if !device.path().is_absolute() {
+            return Err(Error::InvalidRuntimeConfigOptions(format!(
+                "Device path {} isn't absolute",
+                device.path().display()
+            )));
+        }
+        if !paths.insert(device.path()) {
+            return Err(Error::InvalidRuntimeConfigOptions(format!(
+                "Device {} is added more than once",
+                device.path().display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the mounts and devices from `options` to `runtime_config`, with cgroup rules allowing
+/// the devices' use
+pub(crate) fn apply(runtime_config: &mut Spec, options: &RuntimeConfigOptions) {
+    if !options.mounts.is_empty() {
+        let mut mounts = runtime_config.mounts().clone().unwrap_or_default();
+        for mount in &options.mounts {
+            match mounts
+                .iter_mut()
+                .find(|existing| existing.destination() == mount.destination())
+            {
+                Some(existing) => *existing = mount.clone(),
+                None => mounts.push(mount.clone()),
+            }
+        }
+        runtime_config.set_mounts(Some(mounts));
+    }
+
+    if !options.devices.is_empty() {
+        let mut linux = runtime_config.linux().clone().unwrap_or_default();
+        let mut devices = linux.devices().clone().unwrap_or_default();
+        devices.extend(options.devices.iter().cloned());
+        linux.set_devices(Some(devices));
+
+        let mut resources = linux.resources().clone().unwrap_or_default();
+        let mut rules = resources.devices().clone().unwrap_or_default();
+        rules.extend(options.devices.iter().map(LinuxDeviceCgroup::from));
+        resources.set_devices(Some(rules));
+        linux.set_resources(Some(resources));
+        runtime_config.set_linux(Some(linux));
+    }
+}
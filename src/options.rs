@@ -0,0 +1,1710 @@
+use crate::cache_set::CacheSet;
+use crate::copy::CopyStrategy;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventSink};
+use crate::hooks::{BundleContext, BundleHook, LayerContext, LayerHook};
+use crate::hygiene::{HygienePolicy, HygieneRuleset};
+use crate::metrics::{self, MetricsSink};
+use crate::plan::PlanSummary;
+use crate::protected::GlobPattern;
+use crate::reference::ImageReference;
+#[cfg(feature = "rootfs-image")]
+use crate::rootfs_image::RootfsImageOptions;
+use crate::spec::SpecIssueCode;
+use ocidir::oci_spec::image::{Descriptor, Platform};
+use ocidir::oci_spec::runtime::{LinuxDevice, Mount};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Whether problems with an image that can be tolerated are reported as warnings or errors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Fail on the first problem
+    Strict,
+    /// Continue past problems, recording them in [`crate::UnpackReport::warnings`]
+    #[default]
+    Permissive,
+}
+
+/// How `Config.User` is resolved to the user and groups of the bundle's `process.user`
+#[derive(Clone, Default)]
+pub enum UserResolution {
+    /// Look up names in the unpacked rootfs's `/etc/passwd` and `/etc/group`, as container runtimes
+    /// do. Numeric IDs missing from them are used as they are.
+    #[default]
+    Rootfs,
+    /// Look up names in the host's user database, which may involve NSS
+    Host,
+    /// Don't resolve names, leaving `process.user` as 0:0 and recording `Config.User` in the
+    /// [`crate::UNRESOLVED_USER_ANNOTATION`] annotation. Purely numeric users are still used.
+    Skip,
+    /// Resolve `Config.User` with a function returning the uid, gid and additional gids
+    #[allow(clippy::type_complexity)]
+    Custom(Arc<dyn Fn(&str) -> Result<(u32, u32, Vec<u32>)> + Send + Sync>),
+}
+
+impl fmt::Debug for UserResolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserResolution::Rootfs => f.write_str("Rootfs"),
+            UserResolution::Host => f.write_str("Host"),
+            UserResolution::Skip => f.write_str("Skip"),
+            UserResolution::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// `/etc/passwd` and `/etc/group` entries that [`UserResolution::Rootfs`] consults after the
+/// rootfs's own, for images that create their users at runtime, such as distroless images
+/// without either file. Entries are given as the contents of the files, as files on the host,
+/// which are read when the runtime config is generated, or one at a time.
+///
+/// ```
+/// use oci_bundle::UserDatabase;
+///
+/// let database = UserDatabase::new()
+///     .passwd("app:x:1000:1000::/home/app:/sbin/nologin\n")
+///     .group_entry("app", 1000, &[])
+///     .group_entry("metrics", 2000, &["app"]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserDatabase {
+    pub(crate) passwd: Vec<DatabaseSource>,
+    pub(crate) group: Vec<DatabaseSource>,
+}
+
+/// Lines in the format of `/etc/passwd` or `/etc/group`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DatabaseSource {
+    Content(String),
+    File(PathBuf),
+}
+
+impl UserDatabase {
+    /// Creates an empty database
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the entries in `content`, in the format of `/etc/passwd`
+    pub fn passwd(mut self, content: impl Into<String>) -> Self {
+        self.passwd.push(DatabaseSource::Content(content.into()));
+        self
+    }
+
+    /// Adds the entries in `content`, in the format of `/etc/group`
+    pub fn group(mut self, content: impl Into<String>) -> Self {
+        self.group.push(DatabaseSource::Content(content.into()));
+        self
+    }
+
+    /// Adds the entries in the file at `path` on the host, in the format of `/etc/passwd`
+    pub fn passwd_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.passwd.push(DatabaseSource::File(path.into()));
+        self
+    }
+
+    /// Adds the entries in the file at `path` on the host, in the format of `/etc/group`
+    pub fn group_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.group.push(DatabaseSource::File(path.into()));
+        self
+    }
+
+    /// Adds a user named `name` with `uid` and the primary group `gid`
+    pub fn user_entry(self, name: &str, uid: u32, gid: u32) -> Self {
+        self.passwd(format!("{name}:x:{uid}:{gid}::/:/sbin/nologin"))
+    }
+
+    /// Adds a group named `name` with `gid`, of which `members` are additional members
+    pub fn group_entry(self, name: &str, gid: u32, members: &[&str]) -> Self {
+        self.group(format!("{name}:x:{gid}:{}", members.join(",")))
+    }
+}
+
+/// Which additional groups the bundle's `process.user` has
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AdditionalGids {
+    /// Those the [`UserResolution`] finds for the user, if `Config.User` has no group. On the host,
+    /// they are looked up with a single `getgrouplist(3)` call, rather than by enumerating every
+    /// group.
+    #[default]
+    Resolve,
+    /// None, so that the groups database isn't searched for the user's groups
+    Skip,
+    /// These, whatever the [`UserResolution`] finds
+    Explicit(Vec<u32>),
+}
+
+/// The cgroup version of the hosts that will run the bundle, which decides how
+/// [`RuntimeConfigOptions::legacy_resource_fields`] records CPU shares
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CgroupVersion {
+    /// cgroup v1, with CPU shares as `linux.resources.cpu.shares`
+    #[default]
+    V1,
+    /// cgroup v2, with CPU shares converted to a weight in `linux.resources.unified`
+    V2,
+}
+
+/// The file marking the rootfs as a container's, which entrypoint scripts check for to tell
+/// whether they're running in a container
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContainerEnvMarker {
+    /// No marker
+    #[default]
+    None,
+    /// An empty `/.dockerenv`, as Docker creates
+    DockerEnv,
+    /// `/run/.containerenv`, as Podman creates, containing `contents`, or if there are none,
+    /// Podman-style `key="value"` lines with the image's ID and, if its manifest or config has the
+    /// `org.opencontainers.image.ref.name` annotation, its name
+    ContainerEnv { contents: Option<String> },
+}
+
+/// How hard links in layers are written to the rootfs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HardlinkPolicy {
+    /// Create hard links, as recorded in the layers
+    #[default]
+    Preserve,
+    /// Write each hard link as a copy of its target, with the target's mode, ownership, mtime and
+    /// extended attributes, for filesystems and transports that don't support hard links
+    Copy,
+}
+
+/// What to do when a lower layer has left a symlink pointing outside the rootfs where a layer
+/// writes beneath a directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParentSymlinkPolicy {
+    /// Fail with [`crate::Error::SymlinkedParent`]
+    #[default]
+    Reject,
+    /// Replace the symlink with an empty directory, as runc's unpacker does, and as overlayfs
+    /// presents a directory in an upper layer over a symlink in a lower one
+    Replace,
+}
+
+/// The mtime [`UnpackOptions::restore_dir_mtimes`] gives directories that no layer lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImplicitDirMtime {
+    /// The Unix epoch
+    Epoch,
+    /// The image config's `created` time, or the epoch if it has none
+    ImageCreated,
+    /// The time [`UnpackOptions::timestamp_source`] gives the files this crate writes
+    BundleTimestamp,
+}
+
+/// Where the timestamps of the files this crate writes into a bundle, rather than extracts from
+/// layers, come from, for [`UnpackOptions::timestamp_source`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimestampSource {
+    /// The time the files are written
+    #[default]
+    Clock,
+    /// The image config's `created` time. Images without one get the epoch, with a
+    /// [`crate::WarningKind::MissingCreatedTime`] warning.
+    ImageCreated,
+    /// A fixed time
+    Fixed(SystemTime),
+}
+
+/// What to do with control characters, such as newlines, in the values of the annotations of the
+/// generated runtime config, which some runtimes and tools built on line- or YAML-based formats
+/// mishandle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ControlCharacters {
+    /// Keep them, reporting each annotation with any as a
+    /// [`crate::SpecIssueCode::AnnotationControlCharacter`] issue
+    #[default]
+    Keep,
+    /// Remove them
+    Strip,
+    /// Replace them with escape sequences: `\n`, `\r` and `\t`, or `\u` and four hex digits.
+    /// Backslashes already in the value are left as they are.
+    Escape,
+}
+
+/// How whiteouts are applied to the rootfs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ApplyMode {
+    /// Remove what whiteouts hide, so the rootfs holds the image's files
+    #[default]
+    Rootfs,
+    /// Write whiteouts as overlayfs does, for unpacking upper layers into the upperdir of an
+    /// overlay whose lowerdir holds the lower ones, which can't be seen to remove from. A whiteout
+    /// becomes a character device with device number 0/0, and an opaque whiteout sets the
+    /// `overlay.opaque` extended attribute of its directory to `y`, in the `user.` namespace if
+    /// `userxattr`, for overlays mounted with the `userxattr` option, and otherwise in
+    /// `trusted.`.
+    ///
+    /// What's in the upperdir, from the layers applied to it, is still replaced or cleared by
+    /// whiteouts as usual, and whiteouts of paths that aren't there aren't dangling, as they
+    /// hide paths in the lowerdir.
+    ///
+    /// Creating the devices needs `CAP_MKNOD`, or a user namespace on Linux 5.8 or later, which
+    /// allows whiteout devices there. Setting `trusted.` attributes needs `CAP_SYS_ADMIN` in the
+    /// initial user namespace, so in others, use `userxattr`.
+    OverlayUpper { userxattr: bool },
+}
+
+/// Who owns the unpacked files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OwnershipMode {
+    /// The owners recorded in the layers, which needs `CAP_CHOWN`, or a user namespace mapping
+    /// them
+    #[default]
+    Preserve,
+    /// The user unpacking the image, which avoids a `chown` for every entry
+    Ignore,
+    /// The user unpacking the image, with the owners recorded in the layers kept in the
+    /// [`crate::ROOTLESS_XATTR`] extended attribute of each file and directory, as rootless tools
+    /// such as umoci do, for a runtime in a user namespace to apply. Nothing needs privilege.
+    ///
+    /// Device nodes, which can't be created without privilege, are written as empty regular files,
+    /// with the device they stand for in the [`crate::EMULATED_DEVICE_XATTR`] attribute. Symlinks
+    /// and FIFOs can't have `user.` attributes on Linux, so their owners aren't recorded. The
+    /// generated runtime config has the [`crate::EMULATED_OWNERSHIP_ANNOTATION`].
+    Emulate,
+}
+
+/// What to do with paths that differ only in their Unicode normalization form, such as the NFC and
+/// NFD encodings of `é`. They are distinct files on Linux, but the same file on filesystems that
+/// normalize names, and a whiteout written in one form doesn't remove a file written in the other.
+///
+/// Policies other than [`UnicodePolicy::Allow`] need the `unicode` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnicodePolicy {
+    /// Write paths as they are, without comparing them
+    #[default]
+    Allow,
+    /// Write paths as they are, reporting each path that collides with another in
+    /// [`crate::UnpackReport::unicode_collisions`]
+    #[cfg(feature = "unicode")]
+    Warn,
+    /// Fail with [`crate::Error::UnicodeCollision`] once all layers are applied if any path collides
+    /// with another
+    #[cfg(feature = "unicode")]
+    Reject,
+    /// Write every path in NFC, and resolve whiteouts and hard links to NFC targets, so paths that
+    /// collide refer to the same file as they would on a normalizing filesystem
+    #[cfg(feature = "unicode")]
+    NormalizeNfc,
+}
+
+/// What [`crate::unpack_with_options`] does with an existing bundle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Remove the bundle and unpack the image again
+    #[default]
+    Always,
+    /// Leave the bundle as it is if it was completely unpacked from the same image, as
+    /// [`crate::is_bundle_current`] says, returning a report with
+    /// [`crate::UnpackReport::reused`] set. Otherwise, unpack the image as usual.
+    ReuseIfMatching,
+}
+
+/// What [`crate::unpack_derived`] does when the image doesn't extend the base bundle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BaseMismatch {
+    /// Unpack every layer of the image, as [`crate::unpack_with_options`] does
+    #[default]
+    Unpack,
+    /// Fail with [`crate::Error::BaseMismatch`]
+    Fail,
+}
+
+/// What to do with a layer, as decided by [`UnpackOptions::layer_decision`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerDecision {
+    /// Extract the layer as usual
+    Extract,
+    /// Leave the layer out of the rootfs, without reading or verifying it
+    Skip,
+    /// Fail the unpack with [`crate::Error::LayerAborted`]
+    Abort,
+}
+
+/// A callback set in the options, which are debug-printed without it
+pub(crate) struct Callback<F: ?Sized>(pub(crate) Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback(..)")
+    }
+}
+
+pub(crate) type LayerDecider = dyn Fn(&Descriptor) -> LayerDecision + Send + Sync;
+
+pub(crate) type LayerPolicy = dyn Fn(&Descriptor) -> LayerPolicyOverride + Send + Sync;
+
+pub(crate) type ContentInspector = dyn FnMut(&Path, &mut dyn Read) -> Result<()> + Send;
+
+/// Which mode bits recorded in layers are removed when their entries are unpacked, so that
+/// privileged or writable files from untrusted images aren't reproduced faithfully.
+///
+/// Everything is off by default. Symlinks and hard links are never changed, as their own modes
+/// are meaningless. Removed bits are listed in [`crate::UnpackReport::stripped_permissions`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PermissionPolicy {
+    pub(crate) strip_setuid: bool,
+    pub(crate) strip_setgid: bool,
+    pub(crate) clamp_world_writable: bool,
+    pub(crate) clamp_sticky_dirs: bool,
+}
+
+impl PermissionPolicy {
+    /// Creates a policy that keeps every bit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove the setuid bit from files. Directories are exempt, as Linux ignores it on them.
+    pub fn strip_setuid(mut self, strip: bool) -> Self {
+        self.strip_setuid = strip;
+        self
+    }
+
+    /// Remove the setgid bit from files. Directories are exempt, as on them it only makes new
+    /// entries inherit the directory's group.
+    pub fn strip_setgid(mut self, strip: bool) -> Self {
+        self.strip_setgid = strip;
+        self
+    }
+
+    /// Remove write permission for others (`0o002`) from files and directories. World-writable
+    /// directories with the sticky bit, such as `/tmp`, are exempt unless
+    /// [`PermissionPolicy::clamp_sticky_dirs`] is also set.
+    pub fn clamp_world_writable(mut self, clamp: bool) -> Self {
+        self.clamp_world_writable = clamp;
+        self
+    }
+
+    /// Also remove write permission for others from directories with the sticky bit
+    pub fn clamp_sticky_dirs(mut self, clamp: bool) -> Self {
+        self.clamp_sticky_dirs = clamp;
+        self
+    }
+
+    /// Returns the bits to remove from the mode of an entry
+    pub(crate) fn mask(&self, header: &tar::Header) -> io::Result<u32> {
+        let entry_type = header.entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            return Ok(0);
+        }
+        let mode = header.mode()?;
+        let is_dir = entry_type.is_dir();
+        let mut mask = 0;
+        if self.strip_setuid && !is_dir {
+            mask |= 0o4000;
+        }
+        if self.strip_setgid && !is_dir {
+            mask |= 0o2000;
+        }
+        if self.clamp_world_writable && !(is_dir && mode & 0o1000 != 0 && !self.clamp_sticky_dirs) {
+            mask |= 0o002;
+        }
+        Ok(mode & mask)
+    }
+}
+
+/// Options that [`UnpackOptions::per_layer_policy`] sets for a single layer, in place of those
+/// of the unpack. Options that aren't set are left as they are.
+///
+/// Only options that concern each entry on its own can be set here. Those that decide how one
+/// layer's entries and whiteouts apply to what the layers below it left, such as
+/// [`UnpackOptions::apply_mode`], [`UnpackOptions::hardlinks`],
+/// [`UnpackOptions::parent_symlinks`], [`UnpackOptions::ownership`] and
+/// [`UnpackOptions::strictness`], apply to every layer alike.
+#[derive(Debug, Clone, Default)]
+pub struct LayerPolicyOverride {
+    pub(crate) max_layer_entries: Option<usize>,
+    pub(crate) max_entry_size: Option<u64>,
+    pub(crate) max_link_target_len: Option<usize>,
+    pub(crate) permission_policy: Option<PermissionPolicy>,
+    pub(crate) translate_acls: Option<bool>,
+    pub(crate) protected_paths: Option<Vec<GlobPattern>>,
+    pub(crate) hygiene: Option<HygienePolicy>,
+    pub(crate) hygiene_rules: Option<HygieneRuleset>,
+}
+
+impl LayerPolicyOverride {
+    /// Creates an override that sets nothing, leaving the layer to the unpack's options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The layer's [`UnpackOptions::max_layer_entries`]
+    pub fn max_layer_entries(mut self, limit: usize) -> Self {
+        self.max_layer_entries = Some(limit);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::max_entry_size`]
+    pub fn max_entry_size(mut self, limit: u64) -> Self {
+        self.max_entry_size = Some(limit);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::max_link_target_len`]
+    pub fn max_link_target_len(mut self, len: usize) -> Self {
+        self.max_link_target_len = Some(len);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::permission_policy`]
+    pub fn permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.permission_policy = Some(policy);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::translate_acls`], which decides what's done with the ACL
+    /// xattrs that can't be set
+    pub fn translate_acls(mut self, translate: bool) -> Self {
+        self.translate_acls = Some(translate);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::protected_paths`]
+    pub fn protected_paths(mut self, patterns: Vec<GlobPattern>) -> Self {
+        self.protected_paths = Some(patterns);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::hygiene`]
+    pub fn hygiene(mut self, policy: HygienePolicy) -> Self {
+        self.hygiene = Some(policy);
+        self
+    }
+
+    /// The layer's [`UnpackOptions::hygiene_rules`]
+    pub fn hygiene_rules(mut self, rules: HygieneRuleset) -> Self {
+        self.hygiene_rules = Some(rules);
+        self
+    }
+
+    /// Sets the options this overrides in `options`
+    fn apply(self, options: &mut UnpackOptions) {
+        let Self {
+            max_layer_entries,
+            max_entry_size,
+            max_link_target_len,
+            permission_policy,
+            translate_acls,
+            protected_paths,
+            hygiene,
+            hygiene_rules,
+        } = self;
+        if max_layer_entries.is_some() {
+            options.max_layer_entries = max_layer_entries;
+        }
+        if max_entry_size.is_some() {
+            options.max_entry_size = max_entry_size;
+        }
+        if let Some(len) = max_link_target_len {
+            options.max_link_target_len = len;
+        }
+        if let Some(policy) = permission_policy {
+            options.permission_policy = policy;
+        }
+        if let Some(translate) = translate_acls {
+            options.translate_acls = translate;
+        }
+        if let Some(patterns) = protected_paths {
+            options.protected_paths = patterns;
+        }
+        if let Some(policy) = hygiene {
+            options.hygiene = policy;
+        }
+        if let Some(rules) = hygiene_rules {
+            options.hygiene_rules = rules;
+        }
+    }
+}
+
+/// Options controlling how the bundle's runtime config is generated from the image config
+#[derive(Debug, Clone)]
+pub struct RuntimeConfigOptions {
+    pub(crate) user_resolution: UserResolution,
+    pub(crate) user_database: UserDatabase,
+    pub(crate) additional_gids: AdditionalGids,
+    pub(crate) passthrough_unconverted: bool,
+    pub(crate) mounts: Vec<Mount>,
+    pub(crate) devices: Vec<LinuxDevice>,
+    pub(crate) allowed_spec_issues: Vec<SpecIssueCode>,
+    pub(crate) create_working_dir: bool,
+    pub(crate) container_env_marker: ContainerEnvMarker,
+    pub(crate) entrypoint_override: Option<Vec<String>>,
+    pub(crate) cmd_override: Option<Vec<String>>,
+    pub(crate) args_prepend: Vec<String>,
+    pub(crate) annotation_control_characters: ControlCharacters,
+    pub(crate) max_annotation_value_len: usize,
+    pub(crate) max_annotations_size: usize,
+    pub(crate) file_capabilities_annotation: bool,
+    pub(crate) legacy_resource_fields: bool,
+    pub(crate) cgroup_version: CgroupVersion,
+    pub(crate) readonly_rootfs: Option<bool>,
+    pub(crate) hermetic: bool,
+}
+
+impl Default for RuntimeConfigOptions {
+    fn default() -> Self {
+        Self {
+            user_resolution: UserResolution::default(),
+            user_database: UserDatabase::default(),
+            additional_gids: AdditionalGids::default(),
+            passthrough_unconverted: false,
+            mounts: Vec::new(),
+            devices: Vec::new(),
+            allowed_spec_issues: Vec::new(),
+            create_working_dir: true,
+            container_env_marker: ContainerEnvMarker::default(),
+            entrypoint_override: None,
+            cmd_override: None,
+            args_prepend: Vec::new(),
+            annotation_control_characters: ControlCharacters::default(),
+            max_annotation_value_len: 64 * 1024,
+            max_annotations_size: 256 * 1024,
+            file_capabilities_annotation: false,
+            legacy_resource_fields: false,
+            cgroup_version: CgroupVersion::default(),
+            readonly_rootfs: None,
+            hermetic: false,
+        }
+    }
+}
+
+impl RuntimeConfigOptions {
+    /// Creates the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How `Config.User` is resolved. Defaults to [`UserResolution::Rootfs`].
+    ///
+    /// Only [`UserResolution::Host`] consults the host's user database, which may hang or fail in
+    /// environments without a working NSS configuration.
+    pub fn user_resolution(mut self, resolution: UserResolution) -> Self {
+        self.user_resolution = resolution;
+        self
+    }
+
+    /// Entries that [`UserResolution::Rootfs`] looks users and groups up in when the rootfs's
+    /// `/etc/passwd` and `/etc/group` are missing or don't have them. The rootfs's entries take
+    /// precedence, and what neither has is handled as it would be without this: numeric IDs are
+    /// used as they are, and unknown names fail. Defaults to an empty database.
+    ///
+    /// Files in the database that can't be read fail the unpack.
+    pub fn user_database(mut self, database: UserDatabase) -> Self {
+        self.user_database = database;
+        self
+    }
+
+    /// Which additional groups `process.user` has. Defaults to [`AdditionalGids::Resolve`].
+    ///
+    /// Users that are left unresolved have none, whatever this is.
+    pub fn additional_gids(mut self, additional_gids: AdditionalGids) -> Self {
+        self.additional_gids = additional_gids;
+        self
+    }
+
+    /// Whether to record the fields of the image config's `config` that have no equivalent in the
+    /// runtime config, such as `OnBuild`, `Shell`, `ArgsEscaped` and the resource limits of legacy
+    /// Docker configs, as annotations prefixed with [`crate::UNCONVERTED_ANNOTATION_PREFIX`].
+    /// Defaults to false.
+    ///
+    /// The fields are read from the config's JSON, so those unknown to
+    /// [`ocidir::oci_spec::image::Config`] are kept too. Strings are recorded as they are, and
+    /// other values as JSON. `Config.Labels` take precedence over these annotations.
+    pub fn passthrough_unconverted(mut self, passthrough: bool) -> Self {
+        self.passthrough_unconverted = passthrough;
+        self
+    }
+
+    /// Adds `mount` after the default mounts. A mount replaces an earlier one, whether a default
+    /// or added, with the same destination, in that one's place, so that it's still mounted
+    /// beneath the mounts of its parents.
+    ///
+    /// Unpacking fails with [`crate::Error::InvalidRuntimeConfigOptions`] before touching the
+    /// bundle if the destination isn't absolute.
+    pub fn mount(mut self, mount: Mount) -> Self {
+        self.mounts.push(mount);
+        self
+    }
+
+    /// Adds `device` to `linux.devices`, with a cgroup rule allowing its use to
+    /// `linux.resources.devices`.
+    ///
+    /// Unpacking fails with [`crate::Error::InvalidRuntimeConfigOptions`] before touching the
+    /// bundle if the device's path isn't absolute, or another device has the same path.
+    pub fn device(mut self, device: LinuxDevice) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    /// Allows issues with `code` in the generated runtime config. The runtime config is checked
+    /// with [`crate::validate_spec`] once it's generated, and its issues are recorded in
+    /// [`crate::UnpackReport::spec_issues`], or with [`Strictness::Strict`], fail the unpack,
+    /// unless they're all allowed. Defaults to allowing none.
+    pub fn allow_spec_issue(mut self, code: SpecIssueCode) -> Self {
+        self.allowed_spec_issues.push(code);
+        self
+    }
+
+    /// What to do with control characters in annotation values, which come in part from the
+    /// image's `Config.Labels`. Defaults to [`ControlCharacters::Keep`].
+    pub fn annotation_control_characters(mut self, control_characters: ControlCharacters) -> Self {
+        self.annotation_control_characters = control_characters;
+        self
+    }
+
+    /// The length in bytes that annotation values are truncated to, reporting each truncated one
+    /// as a [`SpecIssueCode::AnnotationTooLong`] issue. Defaults to 64 KiB.
+    pub fn max_annotation_value_len(mut self, len: usize) -> Self {
+        self.max_annotation_value_len = len;
+        self
+    }
+
+    /// The total size in bytes of the keys and values of the annotations. If they're larger, the
+    /// largest annotations are dropped until they fit, reporting each as a
+    /// [`SpecIssueCode::AnnotationsTooLarge`] issue. Defaults to 256 KiB, the limit Kubernetes
+    /// places on an object's annotations.
+    pub fn max_annotations_size(mut self, size: usize) -> Self {
+        self.max_annotations_size = size;
+        self
+    }
+
+    /// Whether to create the image config's `WorkingDir` in the rootfs, with mode 0755 and owned
+    /// by the process's user, if no layer did, as Docker and containerd do. Runtimes fail to
+    /// start a container whose working directory is missing. Whether it was created is recorded
+    /// in [`crate::UnpackReport::created_working_dir`]. Defaults to true.
+    ///
+    /// A `WorkingDir` containing `..` is left alone, and symlinks are resolved within the
+    /// rootfs.
+    pub fn create_working_dir(mut self, create: bool) -> Self {
+        self.create_working_dir = create;
+        self
+    }
+
+    /// The marker file to write into the rootfs once the layers are extracted, replacing any a
+    /// layer added, with mode 0644 and owned by root as [`crate::UnpackOptions::ownership`]
+    /// allows. Its path is recorded in [`crate::UnpackReport::container_env_marker`]. Defaults to
+    /// [`ContainerEnvMarker::None`].
+    pub fn container_env_marker(mut self, marker: ContainerEnvMarker) -> Self {
+        self.container_env_marker = marker;
+        self
+    }
+
+    /// Replaces the image config's `Entrypoint`, as `docker run --entrypoint` does, discarding
+    /// its `Cmd` unless [`Self::cmd_override`] is also set. An empty entrypoint leaves the process
+    /// with only the overridden `Cmd`, if any. Defaults to the image's.
+    ///
+    /// Overriding either `Entrypoint` or `Cmd` means the image's `ArgsEscaped`, which describes
+    /// how its `Cmd` was written for Windows, no longer applies, so it isn't recorded by
+    /// [`Self::passthrough_unconverted`]. `Shell` only applies to builds, so it's unaffected.
+    pub fn entrypoint_override(mut self, entrypoint: Vec<String>) -> Self {
+        self.entrypoint_override = Some(entrypoint);
+        self
+    }
+
+    /// Replaces the image config's `Cmd`, which follows the entrypoint in the process's
+    /// arguments, as the arguments to `docker run` do. Defaults to the image's, unless
+    /// [`Self::entrypoint_override`] is set.
+    pub fn cmd_override(mut self, cmd: Vec<String>) -> Self {
+        self.cmd_override = Some(cmd);
+        self
+    }
+
+    /// Arguments to put before the entrypoint and `Cmd`, such as an init process that runs them.
+    /// Unlike the overrides, these are added even if the image has nothing to run. Defaults to
+    /// none.
+    pub fn args_prepend(mut self, args: Vec<String>) -> Self {
+        self.args_prepend = args;
+        self
+    }
+
+    /// Summarize the files in the rootfs with capabilities, as listed in
+    /// [`crate::UnpackReport::file_capabilities`], in the
+    /// [`crate::FILE_CAPABILITIES_ANNOTATION`]. Capabilities that were dropped aren't included.
+    /// Defaults to false.
+    pub fn file_capabilities_annotation(mut self, annotate: bool) -> Self {
+        self.file_capabilities_annotation = annotate;
+        self
+    }
+
+    /// Converts the `Memory`, `MemorySwap` and `CpuShares` fields that images converted from
+    /// legacy Docker configs may have into `linux.resources`, as Docker applied them. Memory
+    /// limits are in bytes, and `MemorySwap` is memory and swap together, as in the runtime
+    /// config. Fields that are 0 are unset. CPU shares are recorded as
+    /// [`Self::cgroup_version`] requires. The converted fields aren't recorded by
+    /// [`Self::passthrough_unconverted`]. Defaults to false.
+    pub fn legacy_resource_fields(mut self, convert: bool) -> Self {
+        self.legacy_resource_fields = convert;
+        self
+    }
+
+    /// The cgroup version that [`Self::legacy_resource_fields`] records CPU shares for. Defaults
+    /// to [`CgroupVersion::V1`].
+    pub fn cgroup_version(mut self, version: CgroupVersion) -> Self {
+        self.cgroup_version = version;
+        self
+    }
+
+    /// Sets `root.readonly`, so that runtimes mount the rootfs read-only in the container.
+    /// Defaults to leaving it as the generated runtime config has it.
+    ///
+    /// The root is always read-only when the rootfs is only a mount point for
+    /// [`crate::UnpackOptions::rootfs_image`], whatever this is. To make the rootfs unwritable on
+    /// the host too, see [`crate::UnpackOptions::strip_write_bits`].
+    pub fn readonly_rootfs(mut self, readonly: bool) -> Self {
+        self.readonly_rootfs = Some(readonly);
+        self
+    }
+
+    /// Keep anything derived from the host the bundle is unpacked on out of it, so that bundles
+    /// built on one machine run the same on another. Defaults to false.
+    ///
+    /// [`UserResolution::Host`] resolves users in the rootfs instead, bind mounts of host paths
+    /// among the default mounts are replaced with empty tmpfs mounts, and
+    /// [`TimestampSource::Clock`] takes the image's created time instead. Each is recorded in
+    /// [`crate::UnpackReport::hermetic_substitutions`]. `config.json` is written with the keys
+    /// of its objects sorted, so the same image and options always give the same bytes.
+    /// Selecting a manifest from an image index fails with
+    /// [`crate::Error::InvalidRuntimeConfigOptions`] unless
+    /// [`crate::UnpackOptions::target_platform`] is set, rather than select the host's.
+    ///
+    /// What's given explicitly, such as [`Self::mount`], [`Self::user_database`] and
+    /// [`UserResolution::Custom`], is used as it is.
+    pub fn hermetic(mut self, hermetic: bool) -> Self {
+        self.hermetic = hermetic;
+        self
+    }
+}
+
+/// Options controlling how [`crate::unpack_with_options`] unpacks an image
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    pub(crate) parallel_layers: usize,
+    pub(crate) prefetch: bool,
+    pub(crate) prefetch_spool_size: usize,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) ownership: OwnershipMode,
+    pub(crate) verify_digests: bool,
+    pub(crate) mmap_blobs: bool,
+    pub(crate) strictness: Strictness,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) events: Option<Arc<EventSink>>,
+    pub(crate) metrics: Option<Callback<dyn MetricsSink>>,
+    pub(crate) retry_attempts: u32,
+    pub(crate) retry_backoff: Duration,
+    pub(crate) record_file_manifest: bool,
+    pub(crate) runtime_config: RuntimeConfigOptions,
+    pub(crate) layer_decision: Option<Callback<LayerDecider>>,
+    pub(crate) per_layer_policy: Option<Callback<LayerPolicy>>,
+    pub(crate) target_platform: Option<Platform>,
+    pub(crate) permission_policy: PermissionPolicy,
+    pub(crate) staging_dir: Option<PathBuf>,
+    pub(crate) content_inspector: Option<Callback<Mutex<ContentInspector>>>,
+    pub(crate) hardlinks: HardlinkPolicy,
+    pub(crate) parent_symlinks: ParentSymlinkPolicy,
+    pub(crate) overwrite: Overwrite,
+    pub(crate) base_mismatch: BaseMismatch,
+    pub(crate) base_copy_strategy: CopyStrategy,
+    pub(crate) reuse_sanity_check: bool,
+    pub(crate) reuse_requires_matching_options: bool,
+    pub(crate) follow_bundle_symlink: bool,
+    pub(crate) windows_layers: bool,
+    pub(crate) apply_mode: ApplyMode,
+    pub(crate) expected: Option<PlanSummary>,
+    pub(crate) write_env_summary: bool,
+    pub(crate) analyze_waste: bool,
+    pub(crate) record_changes: bool,
+    pub(crate) restore_dir_mtimes: Option<ImplicitDirMtime>,
+    pub(crate) timestamp_source: TimestampSource,
+    pub(crate) check_layer_order: bool,
+    #[cfg(feature = "rootfs-image")]
+    pub(crate) rootfs_image: Option<RootfsImageOptions>,
+    pub(crate) unicode_policy: UnicodePolicy,
+    pub(crate) max_layer_entries: Option<usize>,
+    pub(crate) max_entry_size: Option<u64>,
+    pub(crate) max_link_target_len: usize,
+    pub(crate) protected_paths: Vec<GlobPattern>,
+    pub(crate) translate_acls: bool,
+    pub(crate) hygiene: HygienePolicy,
+    pub(crate) hygiene_rules: HygieneRuleset,
+    pub(crate) decompressed_blob_cache: Option<PathBuf>,
+    pub(crate) decompressed_blob_cache_max_bytes: Option<u64>,
+    pub(crate) cache_copy_strategy: CopyStrategy,
+    pub(crate) reference: Option<ImageReference>,
+    pub(crate) after_layer: Option<Callback<LayerHook>>,
+    pub(crate) before_config: Option<Callback<BundleHook>>,
+    pub(crate) in_memory_max_bytes: Option<u64>,
+    pub(crate) preallocate_files: Option<u64>,
+    pub(crate) strip_write_bits: bool,
+    pub(crate) checkpoint_layers: bool,
+    pub(crate) checkpoint_sample: usize,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        Self {
+            parallel_layers: 1,
+            prefetch: false,
+            prefetch_spool_size: 64 * 1024 * 1024,
+            read_buffer_size: 128 * 1024,
+            write_buffer_size: 64 * 1024,
+            ownership: OwnershipMode::Preserve,
+            verify_digests: true,
+            mmap_blobs: false,
+            strictness: Strictness::Permissive,
+            timeout: None,
+            events: None,
+            metrics: None,
+            retry_attempts: 0,
+            retry_backoff: Duration::from_millis(100),
+            record_file_manifest: false,
+            runtime_config: RuntimeConfigOptions::default(),
+            layer_decision: None,
+            per_layer_policy: None,
+            target_platform: None,
+            permission_policy: PermissionPolicy::default(),
+            staging_dir: None,
+            content_inspector: None,
+            hardlinks: HardlinkPolicy::Preserve,
+            parent_symlinks: ParentSymlinkPolicy::Reject,
+            overwrite: Overwrite::Always,
+            base_mismatch: BaseMismatch::Unpack,
+            base_copy_strategy: CopyStrategy::Auto,
+            reuse_sanity_check: true,
+            reuse_requires_matching_options: false,
+            follow_bundle_symlink: false,
+            windows_layers: false,
+            apply_mode: ApplyMode::Rootfs,
+            expected: None,
+            write_env_summary: false,
+            analyze_waste: false,
+            record_changes: false,
+            restore_dir_mtimes: None,
+            timestamp_source: TimestampSource::Clock,
+            check_layer_order: false,
+            #[cfg(feature = "rootfs-image")]
+            rootfs_image: None,
+            unicode_policy: UnicodePolicy::Allow,
+            max_layer_entries: None,
+            max_entry_size: None,
+            max_link_target_len: 4095,
+            protected_paths: Vec::new(),
+            translate_acls: false,
+            hygiene: HygienePolicy::Off,
+            hygiene_rules: HygieneRuleset::builtin(),
+            decompressed_blob_cache: None,
+            decompressed_blob_cache_max_bytes: None,
+            cache_copy_strategy: CopyStrategy::Auto,
+            reference: None,
+            after_layer: None,
+            before_config: None,
+            in_memory_max_bytes: None,
+            preallocate_files: None,
+            strip_write_bits: false,
+            checkpoint_layers: false,
+            checkpoint_sample: 0,
+        }
+    }
+}
+
+impl UnpackOptions {
+    /// Creates the default options, which match the behavior of [`crate::unpack`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Extract up to `jobs` layers concurrently.
+    ///
+    /// Each layer is extracted into its own staging directory inside the bundle, and the staged
+    /// layers are then merged onto the rootfs in order, applying their whiteouts as they go. At
+    /// most `jobs` layers are staged at any one time, which bounds the extra disk space used.
+    /// With `1`, the default, layers are extracted sequentially straight into the rootfs.
+    pub fn parallel_layers(mut self, jobs: usize) -> Self {
+        self.parallel_layers = jobs.max(1);
+        self
+    }
+
+    /// Create the staging area for parallel extraction and prefetch spools in a new directory
+    /// under `dir`, rather than in the bundle. The directory is removed once the layers are
+    /// applied.
+    ///
+    /// `dir` may be on a different filesystem than the bundle, such as a large scratch disk.
+    /// Staged entries are then copied into the rootfs rather than renamed, each to a hidden
+    /// sibling that's synced and renamed into place, so no partial copy is ever visible.
+    pub fn staging_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.staging_dir = Some(dir.into());
+        self
+    }
+
+    /// What to do with an existing bundle. Defaults to [`Overwrite::Always`].
+    ///
+    /// With [`Overwrite::ReuseIfMatching`], a bundle is only reused when no
+    /// [`Self::layer_decision`] is set, as it's unpacked from every layer. A bundle containing the
+    /// [`crate::INCOMPLETE_SENTINEL`] is always replaced.
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Whether a bundle that matches the image is checked before it's reused by
+    /// [`Overwrite::ReuseIfMatching`]: that its `config.json` parses, and that its rootfs exists
+    /// with within 1% of the number of entries it was unpacked with. A bundle that fails the check
+    /// is unpacked again. Defaults to true.
+    pub fn reuse_sanity_check(mut self, check: bool) -> Self {
+        self.reuse_sanity_check = check;
+        self
+    }
+
+    /// Whether [`Overwrite::ReuseIfMatching`] only reuses a bundle unpacked with options that
+    /// unpack the same bundle, as [`crate::is_bundle_current_with_options`] says, rather than any
+    /// unpacked from the image. Defaults to false.
+    pub fn reuse_requires_matching_options(mut self, require: bool) -> Self {
+        self.reuse_requires_matching_options = require;
+        self
+    }
+
+    /// Whether a bundle path that's a symlink is resolved, and the bundle unpacked into, or
+    /// replaced at, its target. The symlink itself is never removed. Otherwise unpacking into it
+    /// fails with [`crate::Error::SymlinkBundle`]. Defaults to false.
+    ///
+    /// The path is resolved once, at the start of each unpack, so the target is used throughout,
+    /// even if the symlink is changed meanwhile.
+    pub fn follow_bundle_symlink(mut self, follow: bool) -> Self {
+        self.follow_bundle_symlink = follow;
+        self
+    }
+
+    /// What [`crate::unpack_derived`] does if the base bundle wasn't completely unpacked from a
+    /// prefix of the image's layers. Defaults to [`BaseMismatch::Unpack`].
+    pub fn base_mismatch(mut self, mismatch: BaseMismatch) -> Self {
+        self.base_mismatch = mismatch;
+        self
+    }
+
+    /// How [`crate::unpack_derived`] copies the base bundle's rootfs, as in [`crate::copy_tree`].
+    /// Defaults to [`CopyStrategy::Auto`].
+    ///
+    /// With [`CopyStrategy::Hardlink`], files are shared with the base, which must not be modified
+    /// while derived bundles use it. Layers applied on top replace files rather than writing to
+    /// them, but a container writing to a shared file changes it in the base and every other
+    /// derived bundle.
+    pub fn base_copy_strategy(mut self, strategy: CopyStrategy) -> Self {
+        self.base_copy_strategy = strategy;
+        self
+    }
+
+    /// How whiteouts are applied: by removing what they hide, or, to unpack into the upperdir of
+    /// an overlay, by writing them as overlayfs whiteouts. Defaults to [`ApplyMode::Rootfs`].
+    pub fn apply_mode(mut self, mode: ApplyMode) -> Self {
+        self.apply_mode = mode;
+        self
+    }
+
+    /// Flatten the layers of images for Windows, for inspecting them, rather than failing with
+    /// [`crate::Error::WindowsImage`]. Defaults to false.
+    ///
+    /// The files under each layer's `Files/` are extracted into the rootfs, and those under
+    /// `Hives/` into `windows-layers/<index>/hives` in the bundle. The `MSWINDOWS.*` PAX records
+    /// of entries, such as their security descriptors, are saved in
+    /// `windows-layers/<index>/records.json`. Reparse points other than symlinks, and entries
+    /// outside `Files/` and `Hives/`, are skipped. What was done with each layer is reported in
+    /// [`crate::UnpackReport::windows_layers`]. Layers' digests are verified as usual.
+    pub fn windows_layers(mut self, flatten: bool) -> Self {
+        self.windows_layers = flatten;
+        self
+    }
+
+    /// Read and decompress the next layer while the current one is being written.
+    ///
+    /// The next layer is decompressed into a spool by a second thread, and its digests are verified
+    /// before any of it is written. The thread waits for the current layer to be written before
+    /// starting on the one after, so at most two layers are spooled at a time. This has no effect
+    /// when layers are extracted in parallel.
+    pub fn prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Apply the layers to a tree in memory, and then write only what's in the final tree to the
+    /// rootfs, if their archives take no more than `max_bytes` in all. Files that later layers
+    /// replace or white out are never written, which saves most of the time unpacking images of
+    /// many small layers. By default layers are always extracted straight to the rootfs.
+    ///
+    /// Each layer is read and its digests verified before any of it is applied, and the archives
+    /// are held in memory until the rootfs is written, so `max_bytes` bounds the memory used. If
+    /// the layers turn out to be larger, or they have an entry beneath a symlink, or a hard link
+    /// whose target a later layer replaces, they're extracted as usual, having been read twice.
+    /// They're also extracted as usual when the rootfs doesn't start empty, for an image for
+    /// Windows, with [`ApplyMode::OverlayUpper`], or with an option that looks at every entry as
+    /// it's extracted: [`UnpackOptions::analyze_waste`], [`UnpackOptions::record_changes`],
+    /// [`UnpackOptions::restore_dir_mtimes`], a [`UnicodePolicy`] that compares paths,
+    /// [`UnpackOptions::after_layer`], [`UnpackOptions::content_inspector`],
+    /// [`UnpackOptions::protected_paths`], [`UnpackOptions::hygiene`] or
+    /// [`UnpackOptions::per_layer_policy`]. This takes precedence
+    /// over [`UnpackOptions::parallel_layers`] and [`UnpackOptions::prefetch`].
+    ///
+    /// Warnings and stripped permissions are only reported for the entries written, other than
+    /// dangling whiteouts. Whether layers were applied in memory is reported in
+    /// [`crate::UnpackReport::applied_in_memory`].
+    pub fn in_memory_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.in_memory_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// The size in bytes above which a prefetched layer is spooled to a file in the bundle's
+    /// staging area rather than held in memory. Defaults to 64 MiB.
+    pub fn prefetch_spool_size(mut self, size: usize) -> Self {
+        self.prefetch_spool_size = size;
+        self
+    }
+
+    /// The capacity in bytes of the buffers used when reading and decompressing layer blobs.
+    /// Defaults to 128 KiB.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size.max(1);
+        self
+    }
+
+    /// The capacity in bytes of the buffer used when writing regular files. Defaults to 64 KiB.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size.max(1);
+        self
+    }
+
+    /// Allocate the space of each regular file of at least `min_size` bytes, as given by its
+    /// entry, before writing it, so that large files are less fragmented. Filesystems that can't
+    /// preallocate space write the file as usual. By default files aren't preallocated.
+    pub fn preallocate_files(mut self, min_size: u64) -> Self {
+        self.preallocate_files = Some(min_size);
+        self
+    }
+
+    /// Give unpacked files the owners recorded in their layers. Defaults to true.
+    ///
+    /// When disabled, files are owned by the user unpacking the image, which avoids a `chown` for
+    /// every entry. The same as [`Self::ownership`] with [`OwnershipMode::Preserve`] or
+    /// [`OwnershipMode::Ignore`].
+    pub fn preserve_ownership(mut self, preserve: bool) -> Self {
+        self.ownership = if preserve {
+            OwnershipMode::Preserve
+        } else {
+            OwnershipMode::Ignore
+        };
+        self
+    }
+
+    /// Who owns the unpacked files. Defaults to [`OwnershipMode::Preserve`].
+    pub fn ownership(mut self, mode: OwnershipMode) -> Self {
+        self.ownership = mode;
+        self
+    }
+
+    /// Verify each layer's size, digest and diff ID. Defaults to true.
+    ///
+    /// Every layer blob is read to the end either way, so that data after its last gzip member
+    /// fails with [`Error::TrailingData`]. Only disable verification when the layout's content is
+    /// already trusted.
+    pub fn verify_digests(mut self, verify: bool) -> Self {
+        self.verify_digests = verify;
+        self
+    }
+
+    /// Memory-map layer blobs rather than reading them, so hashing and decompression work directly
+    /// on the page cache. Defaults to false.
+    ///
+    /// Reading a mapped file that shrinks or becomes unavailable raises SIGBUS, which aborts the
+    /// process, so only enable this for layouts on local filesystems that nothing else modifies.
+    /// Blobs that can't be mapped, such as empty ones, are read as usual.
+    pub fn mmap_blobs(mut self, mmap: bool) -> Self {
+        self.mmap_blobs = mmap;
+        self
+    }
+
+    /// Whether tolerable problems, such as whiteouts with nothing to remove, fail the unpack.
+    /// Defaults to [`Strictness::Permissive`].
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// Whether to look for signs that the layers aren't in the order they were built, such as a
+    /// mirror shuffling them along with the config's diff IDs, before unpacking. Each layer whose
+    /// history entry is older than that of the layer below it is a
+    /// [`crate::WarningKind::HistoryOutOfOrder`] warning, or with [`Strictness::Strict`], fails
+    /// the unpack. Defaults to false.
+    ///
+    /// Nothing proves the order beyond the diff IDs, which verifying digests checks each layer
+    /// against, so this is only a heuristic. The order layers were applied in, with their digests
+    /// and history, is recorded in the bundle regardless, for investigating a bundle that isn't
+    /// as expected.
+    pub fn check_layer_order(mut self, check: bool) -> Self {
+        self.check_layer_order = check;
+        self
+    }
+
+    /// Pack the rootfs into a read-only squashfs or erofs image at `rootfs.img` in the bundle,
+    /// once everything else is unpacked. Unless [`RootfsImageOptions::keep_rootfs_dir`] is set,
+    /// the rootfs directory is then emptied, to be the mount point of the image, and the runtime
+    /// config's root is made read-only. The image, and the filesystem type to mount it with,
+    /// are described by [`crate::UnpackReport::rootfs_image`]. Defaults to no image.
+    ///
+    /// The image is built by running `mksquashfs` or `mkfs.erofs`, which must be installed.
+    /// [`Self::record_file_manifest`] records the rootfs before it's emptied, so
+    /// [`crate::verify_bundle`] only succeeds if the directory is kept.
+    #[cfg(feature = "rootfs-image")]
+    pub fn rootfs_image(mut self, options: RootfsImageOptions) -> Self {
+        self.rootfs_image = Some(options);
+        self
+    }
+
+    /// Fail with [`crate::Error::TimedOut`] if unpacking takes longer than `timeout`.
+    ///
+    /// The deadline is checked between layers and each time a read buffer of
+    /// [`Self::read_buffer_size`] bytes of a blob is read, so the error can come up to one
+    /// buffer's processing after the deadline, but not later. Blobs are read on a separate
+    /// thread, so that a read that stalls, e.g. on an unresponsive network filesystem, can be
+    /// abandoned. A stalled thread can't be stopped, so it keeps running, with the blob open,
+    /// after the error is returned, until its read returns. Blobs aren't memory-mapped when a
+    /// timeout is set, as reads of a map can't be abandoned.
+    ///
+    /// A bundle created by the unpack that timed out is removed. A bundle that was already there,
+    /// such as one being updated by [`crate::update_bundle`], is left as it is, which may be
+    /// partly unpacked, with the [`crate::INCOMPLETE_SENTINEL`] marking it as incomplete.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Write a JSON object describing each significant event of the unpack to `sink`, one per
+    /// line. See the [`crate::EVENT_SCHEMA_VERSION`] documentation for the schema.
+    ///
+    /// Each event is flushed as it's written. If writing an event fails, a warning is logged and
+    /// no further events are written, but the unpack continues.
+    pub fn event_sink(mut self, sink: impl Write + Send + 'static) -> Self {
+        self.events = Some(Arc::new(EventSink::new(sink)));
+        self
+    }
+
+    /// Check the entries, whiteouts and uncompressed bytes of each extracted layer against
+    /// `plan`, once every layer is extracted. Each difference is a
+    /// [`crate::WarningKind::PlanDeviation`] warning, listed in
+    /// [`crate::UnpackReport::plan_deviations`], or with [`Strictness::Strict`], fails the unpack
+    /// with [`crate::Error::PlanDeviation`]. Only what `plan` records is checked. Defaults to no
+    /// check.
+    pub fn expected(mut self, plan: PlanSummary) -> Self {
+        self.expected = Some(plan);
+        self
+    }
+
+    /// Record counters and histograms of the unpack in `sink`, such as the layers extracted and
+    /// the digests that failed to verify. See [`MetricsSink`] for the metrics. Defaults to
+    /// recording none.
+    pub fn metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Some(Callback(sink));
+        self
+    }
+
+    /// Retry a layer up to `attempts` times if reading it fails with an error that may be
+    /// transient, such as `EIO` or a reset connection, waiting `backoff` before the first retry
+    /// and doubling the wait for each subsequent one. Defaults to no retries.
+    ///
+    /// The blob is reopened and the whole layer is applied again. Anything the failed attempt
+    /// created is removed first, and files it overwrote are overwritten again. Digest and size
+    /// mismatches are retried at most once, as they almost always indicate corruption.
+    pub fn retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.retry_attempts = attempts;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Record the type, mode, ownership, size and content digest of every path in the rootfs in
+    /// the bundle once it's unpacked, so that [`crate::verify_bundle`] can later check it for
+    /// changes. This reads every file in the rootfs again. Defaults to `false`.
+    pub fn record_file_manifest(mut self, record: bool) -> Self {
+        self.record_file_manifest = record;
+        self
+    }
+
+    /// Remove the write bits from the mode of every file and directory in the rootfs once it's
+    /// unpacked, as a last pass before any rootfs image is built, so that it can't be changed by
+    /// anyone but root on the host. Symlinks, whose modes aren't used, are left alone. Defaults
+    /// to `false`.
+    ///
+    /// The file manifest that [`Self::record_file_manifest`] writes keeps the original modes and
+    /// records that write bits were removed, so [`crate::verify_bundle`] compares the rootfs with
+    /// the modes it's expected to have. Users other than root can't remove the rootfs, or update
+    /// or remap it, without first restoring the write bits of its directories.
+    pub fn strip_write_bits(mut self, strip: bool) -> Self {
+        self.strip_write_bits = strip;
+        self
+    }
+
+    /// Write `bundle.env` to the bundle, summarizing its runtime config as shell variables for
+    /// launchers that can't parse JSON. Defaults to `false`.
+    ///
+    /// The file assigns `OCI_BUNDLE_UID`, `OCI_BUNDLE_GID`, `OCI_BUNDLE_ARGS`,
+    /// `OCI_BUNDLE_STOP_SIGNAL`, `OCI_BUNDLE_PORTS` and `OCI_BUNDLE_IMAGE_REF`, each
+    /// single-quoted so that it can be sourced by `sh` whatever it contains. The arguments are
+    /// themselves quoted and separated by spaces, so `eval "set -- $OCI_BUNDLE_ARGS"` recovers
+    /// them, and the exposed ports, such as `80/tcp`, are separated by spaces. The image
+    /// reference is [`Self::reference`]. Values that are unknown are empty.
+    pub fn write_env_summary(mut self, write: bool) -> Self {
+        self.write_env_summary = write;
+        self
+    }
+
+    /// Account for the bytes each layer writes that later layers overwrite or remove, reporting
+    /// them in [`crate::UnpackReport::wasted_bytes`] and
+    /// [`crate::UnpackReport::largest_wasted_paths`]. Defaults to `false`.
+    ///
+    /// The path, layer and size of every entry in the image are kept until all layers are
+    /// applied, so this costs memory in proportion to the number of entries.
+    pub fn analyze_waste(mut self, analyze: bool) -> Self {
+        self.analyze_waste = analyze;
+        self
+    }
+
+    /// List the paths each layer added, modified or removed in [`crate::UnpackReport::changes`].
+    /// Defaults to `false`.
+    ///
+    /// Telling additions from modifications keeps the path of every entry in the image until all
+    /// layers are applied, as [`UnpackOptions::analyze_waste`] does. The paths are also recorded
+    /// in the bundle, so that [`crate::update_bundle`] can update it to a rebuild of the image.
+    pub fn record_changes(mut self, record: bool) -> Self {
+        self.record_changes = record;
+        self
+    }
+
+    /// Record a checkpoint in the bundle after each layer is applied, with the layer's diff ID
+    /// and the number of entries and bytes in its archive, so that [`crate::resume_unpack`] can
+    /// continue an unpack that was interrupted from the layer after the last one recorded.
+    /// Defaults to `false`.
+    ///
+    /// The rootfs's filesystem is synced before each checkpoint is written, which can be slow
+    /// for filesystems with other writes in flight. The checkpoint is removed once the bundle is
+    /// complete. Unpacks onto a base bundle, and those with options that report on every layer's
+    /// changes, such as [`Self::record_changes`], aren't checkpointed.
+    pub fn checkpoint_layers(mut self, checkpoint: bool) -> Self {
+        self.checkpoint_layers = checkpoint;
+        self
+    }
+
+    /// The number of paths in the rootfs, the first in the order of the file manifest, whose
+    /// type, mode, ownership, size and symlink target are recorded with each checkpoint, and
+    /// checked when resuming from it. A checkpoint whose sample doesn't match is discarded.
+    /// Defaults to 0, checking none.
+    pub fn checkpoint_sample(mut self, paths: usize) -> Self {
+        self.checkpoint_sample = paths;
+        self
+    }
+
+    /// Once all layers are applied, set the mtime of each directory in the rootfs to that in the
+    /// header of the last layer's entry for it, deepest first, as writing beneath a directory
+    /// changes its mtime. Directories that no layer lists, such as those created as the parents of
+    /// entries, are given the mtime `implicit` describes. By default, only the root's mtime is
+    /// set, from a layer's entry for it.
+    ///
+    /// As for other entries, zero mtimes are set as one second after the epoch. Restoring keeps
+    /// the path of every entry in the image until all layers are applied, as
+    /// [`UnpackOptions::analyze_waste`] does.
+    pub fn restore_dir_mtimes(mut self, implicit: ImplicitDirMtime) -> Self {
+        self.restore_dir_mtimes = Some(implicit);
+        self
+    }
+
+    /// Where the mtimes of the files this crate writes into the bundle come from: the runtime
+    /// config, the bundle's metadata, and the environment summary and file manifest if they're
+    /// written. The bundle directory is given the same mtime once it's complete. Defaults to
+    /// [`TimestampSource::Clock`].
+    ///
+    /// With any other source, unpacking the same image gives the same bundle timestamps, for
+    /// caching bundles by their content. [`ImplicitDirMtime::BundleTimestamp`] gives the same time
+    /// to directories in the rootfs.
+    pub fn timestamp_source(mut self, source: TimestampSource) -> Self {
+        self.timestamp_source = source;
+        self
+    }
+
+    /// What to do with paths that differ only in their Unicode normalization form. Defaults to
+    /// [`UnicodePolicy::Allow`].
+    ///
+    /// Comparing paths keeps the path and layer of every entry in the image until all layers are
+    /// applied, as [`UnpackOptions::analyze_waste`] does.
+    pub fn unicode_policy(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode_policy = policy;
+        self
+    }
+
+    /// Returns whether layers record the changes they make, for analyses that run once all layers
+    /// are applied
+    pub(crate) fn records_changes(&self) -> bool {
+        self.analyze_waste
+            || self.record_changes
+            || self.restore_dir_mtimes.is_some()
+            || self.unicode_policy.compares()
+    }
+
+    /// Fail with [`crate::Error::TooManyEntries`] on reaching a layer with more than `limit`
+    /// entries, including whiteouts. By default there's no limit.
+    ///
+    /// Extracting a layer keeps its directories' paths and metadata, and the paths of the other
+    /// entries it adds, until the layer is applied, so memory use grows with the number of entries
+    /// in the largest layer. The limit stops a hostile or broken layer from exhausting memory.
+    pub fn max_layer_entries(mut self, limit: usize) -> Self {
+        self.max_layer_entries = Some(limit);
+        self
+    }
+
+    /// Fail with [`crate::Error::EntryTooLarge`] on reaching an entry whose header declares more
+    /// than `limit` bytes of content, before any of it is written. By default there's no limit.
+    ///
+    /// A tar header can declare a size of up to 8 GiB, or any size at all with a PAX `size`
+    /// record, and a sparse entry can declare a size far larger than the data in the layer. The
+    /// limit stops a hostile or broken layer from filling the disk the bundle is on.
+    pub fn max_entry_size(mut self, limit: u64) -> Self {
+        self.max_entry_size = Some(limit);
+        self
+    }
+
+    /// Skip symlinks and hard links whose targets are longer than `len` bytes, with a
+    /// [`crate::WarningKind::LinkTargetTooLong`] warning, or with [`Strictness::Strict`], fail.
+    /// Defaults to 4095, the longest target Linux can create.
+    ///
+    /// Links whose targets contain a NUL byte, which can only come from a malformed long link
+    /// name or PAX `linkpath` record, are likewise skipped with a
+    /// [`crate::WarningKind::InvalidLinkTarget`] warning.
+    pub fn max_link_target_len(mut self, len: usize) -> Self {
+        self.max_link_target_len = len;
+        self
+    }
+
+    /// Fail if a layer modifies a path matched by one of `patterns`, with an
+    /// [`Error::ProtectedPath`] naming the entry and pattern, wrapped in an [`Error::Layer`]
+    /// naming the layer. With [`Strictness::Permissive`], the entry is applied with a
+    /// [`crate::WarningKind::ProtectedPath`] warning instead. Defaults to no protected paths.
+    ///
+    /// Entries written at a protected path or beneath one modify it, as do whiteouts of it or of
+    /// its ancestors. Opaque whiteouts modify the paths beneath their directory, so one of a
+    /// protected path's ancestors is a modification, without regard to whether the path exists
+    /// in the layers below. So is a non-directory entry at an ancestor, which would replace it.
+    ///
+    /// Every layer that's applied is checked, including those that create the protected paths, so
+    /// to keep the paths of a base image from being modified by the layers above it, unpack them
+    /// over the base's bundle with [`crate::unpack_derived`].
+    pub fn protected_paths(mut self, patterns: Vec<GlobPattern>) -> Self {
+        self.protected_paths = patterns;
+        self
+    }
+
+    /// When a file's access ACL can't be set, as the unpack isn't privileged to or the
+    /// filesystem doesn't support ACLs, apply it as the file's permission bits instead, if it
+    /// grants nothing to named users or groups, so that they can represent it losslessly. The
+    /// permission policy's mask still applies. Defaults to false.
+    ///
+    /// ACLs that are set are applied as they are, and those that can't be, including every
+    /// default ACL, are reported as [`crate::WarningKind::AclDropped`] warnings, or with
+    /// [`Strictness::Strict`], fail the unpack.
+    pub fn translate_acls(mut self, translate: bool) -> Self {
+        self.translate_acls = translate;
+        self
+    }
+
+    /// Check each entry against [`Self::hygiene_rules`], for secrets and caches that a build left
+    /// in its layers. Defaults to [`HygienePolicy::Off`].
+    ///
+    /// Entries are matched by their path, and regular files also by their content, which is
+    /// checked as it's written. Each match is listed in [`crate::UnpackReport::hygiene_findings`].
+    /// With [`HygienePolicy::Strip`], entries matched by path are skipped, along with whiteouts of
+    /// their paths and hard links to them, while files matched by content are removed once
+    /// they're written, so whatever lower layers had at their paths is removed too.
+    pub fn hygiene(mut self, policy: HygienePolicy) -> Self {
+        self.hygiene = policy;
+        self
+    }
+
+    /// The rules [`Self::hygiene`] checks entries against. Defaults to
+    /// [`HygieneRuleset::builtin`].
+    pub fn hygiene_rules(mut self, rules: HygieneRuleset) -> Self {
+        self.hygiene_rules = rules;
+        self
+    }
+
+    /// Cache the decompressed archive of each gzip layer in `dir`, as `<diff ID>.tar`, and read
+    /// layers whose archives are cached from there instead of decompressing their blobs. Defaults
+    /// to no cache.
+    ///
+    /// Archives are cached once the layer's digests are verified, so only when
+    /// [`UnpackOptions::verify_digests`] is set, and are verified against the diff ID each time
+    /// they're read. Blobs read from the cache aren't opened, so their digests aren't checked,
+    /// but the diff IDs they're verified against are part of the verified image config.
+    ///
+    /// The directory may be shared by concurrent unpacks, in any process. Each entry is locked
+    /// while it's populated, so unpacks of the same layer wait for the first to cache it rather
+    /// than all decompressing it.
+    pub fn decompressed_blob_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.decompressed_blob_cache = Some(dir.into());
+        self
+    }
+
+    /// After caching a layer, remove the least recently used archives from the
+    /// [`UnpackOptions::decompressed_blob_cache`] until the rest take no more than `max_bytes`,
+    /// as [`crate::prune_decompressed_blob_cache`] does. By default the cache isn't pruned.
+    pub fn decompressed_blob_cache_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.decompressed_blob_cache_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// How the content of files is written from archives in the
+    /// [`UnpackOptions::decompressed_blob_cache`]. Defaults to [`CopyStrategy::Auto`].
+    ///
+    /// With [`CopyStrategy::Auto`] or [`CopyStrategy::Reflink`], each file's content is cloned
+    /// from the archive where the filesystems allow, so it shares the archive's blocks
+    /// copy-on-write, and otherwise copied. Only content aligned to the filesystem's blocks in
+    /// the archive can be cloned, and nothing can across filesystems. A file in an archive can't
+    /// be hard linked to, so [`CopyStrategy::Hardlink`] is taken as [`CopyStrategy::Auto`].
+    /// Content passed to the [`UnpackOptions::content_inspector`], or checked by
+    /// [`UnpackOptions::hygiene`], is always copied, as are the layers of Windows images and
+    /// those extracted with [`UnpackOptions::prefetch`] or in memory.
+    ///
+    /// How each layer's content was written is in [`crate::UnpackReport::layer_copies`].
+    pub fn cache_copy_strategy(mut self, strategy: CopyStrategy) -> Self {
+        self.cache_copy_strategy = strategy;
+        self
+    }
+
+    /// Use the decompressed blob cache of `caches`, with its size limit, as set by
+    /// [`UnpackOptions::decompressed_blob_cache`] and
+    /// [`UnpackOptions::decompressed_blob_cache_max_bytes`], so that layers
+    /// [`crate::missing_layers`] found in it are read from there. Their blobs needn't be in the
+    /// layout. The layouts of `caches` aren't used, as blobs are read from the one being
+    /// unpacked.
+    pub fn caches(mut self, caches: CacheSet) -> Self {
+        self.decompressed_blob_cache = caches.decompressed_blob_cache;
+        self.decompressed_blob_cache_max_bytes = caches.decompressed_blob_cache_max_bytes;
+        self
+    }
+
+    /// The reference the image is known by, such as the one it was pulled by, to record in the
+    /// bundle so that it can be mapped back to the image. Defaults to none, and is set by
+    /// [`crate::unpack_ref`].
+    ///
+    /// It's recorded in the bundle's metadata, in [`crate::UnpackReport::reference`], as the
+    /// `org.opencontainers.image.ref.name` annotation of `config.json`, and as
+    /// `OCI_BUNDLE_IMAGE_REF` in the [`Self::write_env_summary`] file. It's recorded as given, so
+    /// use [`ImageReference::normalized`] to record the full reference.
+    pub fn reference(mut self, reference: ImageReference) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    /// Call `hook` after each layer is applied to the rootfs, with a handle on the rootfs and
+    /// what was found in the layer, e.g. to snapshot the rootfs at each layer.
+    ///
+    /// Layers are applied in order, so `hook` sees the rootfs with the layer and those below it,
+    /// and none above, whether layers are extracted in parallel or not. It's called once a
+    /// layer's attempts have succeeded, so never for an attempt that's retried, and not for
+    /// skipped layers, nor the layers of a base bundle. A failure fails the unpack with
+    /// [`Error::Hook`], or with [`Strictness::Permissive`], is recorded as a
+    /// [`crate::WarningKind::AfterLayerHookFailed`] warning.
+    ///
+    /// Changes `hook` makes to the rootfs are invisible to the checks and records made as
+    /// entries are extracted, such as [`Self::record_changes`], [`Self::analyze_waste`],
+    /// [`Self::protected_paths`] and [`Self::hygiene`], and a later layer's whiteouts and
+    /// entries apply to them like anything else in the rootfs. The file manifest of
+    /// [`Self::record_file_manifest`] is computed after both hooks, so it includes their
+    /// changes. Neither hook is called when [`Overwrite::ReuseIfMatching`] reuses a bundle.
+    pub fn after_layer(
+        mut self,
+        hook: impl Fn(&LayerContext) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.after_layer = Some(Callback(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` once every layer is applied to the rootfs, and before the runtime config is
+    /// generated or `config.json` written, with a handle on the rootfs and the report so far,
+    /// e.g. to add a CA bundle to the rootfs.
+    ///
+    /// As it's called before the runtime config is generated, users that `hook` adds to
+    /// `/etc/passwd` are resolved, and the working directory and
+    /// [`crate::RuntimeConfigOptions::container_env_marker`] are created after it. A failure
+    /// fails the unpack with [`Error::Hook`], or with [`Strictness::Permissive`], is recorded as
+    /// a [`crate::WarningKind::BeforeConfigHookFailed`] warning. What's recorded about the rootfs
+    /// is as described for [`Self::after_layer`].
+    pub fn before_config(
+        mut self,
+        hook: impl Fn(&BundleContext) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.before_config = Some(Callback(Arc::new(hook)));
+        self
+    }
+
+    /// Fails if a layer has more entries than allowed, having read `count` before the next
+    pub(crate) fn check_entry_count(&self, count: usize) -> Result<()> {
+        match self.max_layer_entries {
+            Some(limit) if count >= limit => Err(Error::TooManyEntries { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if the entry at `path` declares more content than allowed, `size` bytes
+    pub(crate) fn check_entry_size(&self, path: &Path, size: u64) -> Result<()> {
+        match self.max_entry_size {
+            Some(limit) if size > limit => Err(Error::EntryTooLarge {
+                path: path.to_path_buf(),
+                size,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// How the bundle's runtime config is generated from the image config
+    pub fn runtime_config(mut self, options: RuntimeConfigOptions) -> Self {
+        self.runtime_config = options;
+        self
+    }
+
+    /// Decide whether to extract, skip or abort on each layer, given its descriptor, including
+    /// its annotations.
+    ///
+    /// `decide` is called for every layer, in order, before any layer is extracted, except those
+    /// whose media types aren't layers', which are skipped with a
+    /// [`crate::WarningKind::UnsupportedMediaType`] warning. Skipped layers are listed in
+    /// [`crate::UnpackReport::skipped_layers`], and their diff IDs are skipped with them. The
+    /// resulting rootfs is as if the layer weren't in the image, so later layers' whiteouts of its
+    /// content have nothing to remove.
+    pub fn layer_decision(
+        mut self,
+        decide: impl Fn(&Descriptor) -> LayerDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.layer_decision = Some(Callback(Arc::new(decide)));
+        self
+    }
+
+    /// Extract each layer with the options that `policy` overrides for it, given its descriptor,
+    /// including its annotations, e.g. to apply stricter limits and checks to layers that aren't
+    /// trusted. See [`LayerPolicyOverride`] for which options can be overridden. By default every
+    /// layer is extracted with the same options.
+    ///
+    /// `policy` is called as each layer is extracted, and may be called more than once for a
+    /// layer, so it should return the same override each time.
+    pub fn per_layer_policy(
+        mut self,
+        policy: impl Fn(&Descriptor) -> LayerPolicyOverride + Send + Sync + 'static,
+    ) -> Self {
+        self.per_layer_policy = Some(Callback(Arc::new(policy)));
+        self
+    }
+
+    /// Pass the content of every regular file to `inspect` as it's extracted, along with its path
+    /// in the layer, e.g. to generate an SBOM or scan for malware without reading the rootfs
+    /// again.
+    ///
+    /// The content is written to disk as `inspect` reads it, so it's only decompressed once, and
+    /// whatever `inspect` doesn't read is written after it returns. It's called for files that a
+    /// later layer removes or replaces, as well as for those in the final rootfs, but not for hard
+    /// links. A layer that's retried is inspected again. With parallel extraction, layers are
+    /// inspected concurrently, though never more than one file at a time.
+    ///
+    /// If `inspect` fails, the file is still extracted. The failure is a
+    /// [`crate::WarningKind::InspectionFailed`] warning, or with [`Strictness::Strict`], fails the
+    /// unpack with [`crate::Error::ContentInspection`].
+    pub fn content_inspector(
+        mut self,
+        inspect: impl FnMut(&Path, &mut dyn Read) -> Result<()> + Send + 'static,
+    ) -> Self {
+        self.content_inspector = Some(Callback(Arc::new(Mutex::new(inspect))));
+        self
+    }
+
+    /// How hard links are written. Defaults to [`HardlinkPolicy::Preserve`].
+    ///
+    /// With [`HardlinkPolicy::Copy`], each link is a copy of its target as extracted so far, read
+    /// back from the rootfs. The copy is independent of the target, so it survives a later
+    /// layer's whiteout of the target, and keeps its content if a later layer replaces the target,
+    /// just as a hard link would.
+    pub fn hardlinks(mut self, policy: HardlinkPolicy) -> Self {
+        self.hardlinks = policy;
+        self
+    }
+
+    /// What to do when an entry's parent is a symlink pointing outside the rootfs, which a lower
+    /// layer left in place of a directory. Defaults to [`ParentSymlinkPolicy::Reject`].
+    ///
+    /// Symlinks are only replaced regardless of the policy when the layer has an entry for the
+    /// directory itself. Symlinks that stay within the rootfs are followed, as they are by
+    /// overlayfs.
+    pub fn parent_symlinks(mut self, policy: ParentSymlinkPolicy) -> Self {
+        self.parent_symlinks = policy;
+        self
+    }
+
+    /// Which mode bits to remove from entries as they're unpacked. Defaults to keeping them all.
+    pub fn permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.permission_policy = policy;
+        self
+    }
+
+    /// The platform the bundle is for, in place of [`crate::host_platform`]. Defaults to the host's.
+    ///
+    /// Set this when preparing bundles on one machine to run on another, such as an arm64 board
+    /// provisioned from an x86 build host, so that the target rather than the build host is
+    /// compared with the image's platform.
+    pub fn target_platform(mut self, platform: Platform) -> Self {
+        self.target_platform = Some(platform);
+        self
+    }
+
+    /// The target platform, or the host's if none was set
+    pub(crate) fn platform(&self) -> Platform {
+        self.target_platform
+            .clone()
+            .unwrap_or_else(crate::host_platform)
+    }
+
+    /// Returns the options to extract the layer `descriptor` describes with, with the override
+    /// of [`Self::per_layer_policy`] for it applied
+    pub(crate) fn for_layer(&self, descriptor: &Descriptor) -> Cow<'_, Self> {
+        let Some(policy) = &self.per_layer_policy else {
+            return Cow::Borrowed(self);
+        };
+        let mut options = self.clone();
+        policy.0(descriptor).apply(&mut options);
+        Cow::Owned(options)
+    }
+
+    pub(crate) fn emit(&self, event: &Event) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+        if let Some(sink) = &self.metrics {
+            metrics::record(&*sink.0, event);
+        }
+    }
+}
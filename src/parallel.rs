@@ -0,0 +1,620 @@
+use crate::acl;
+use crate::apply::{DirTarget, LayerApplier, Whiteout};
+use crate::blobs::BlobSource;
+use crate::capabilities;
+use crate::copy::{self, ArchiveCloner};
+use crate::deadline::Deadline;
+use crate::error::{Error, IoResultExt, Result};
+use crate::hygiene;
+use crate::parents::ParentGuard;
+use crate::protected::{self, Modification};
+use crate::report::{EntryChange, LayerCompression, LayerReport, LayerTiming, Warnings};
+use crate::retry::retry;
+use crate::windows::{self, WindowsLayer};
+use crate::{
+    check_link_target, layer_applied, link_target, normalize, or_dot, permission_mask, read_layer,
+    rootless, write, written_size, HardlinkPolicy, Layers, OwnershipMode, UnpackOptions,
+    WarningKind,
+};
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::{Dir, Permissions};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+use tar::Archive;
+
+/// A layer extracted into a staging directory, with whiteouts and hard links still to be applied
+struct StagedLayer {
+    dir: PathBuf,
+    /// Directories whose contents from lower layers are removed
+    opaque_dirs: Vec<PathBuf>,
+    /// Paths from lower layers that are removed
+    whiteouts: Vec<PathBuf>,
+    /// Directories that had entries in the layer, as opposed to being created as parents
+    dirs: Vec<PathBuf>,
+    /// The layer's entry for the root, applied to the rootfs once the layer is merged
+    root: Option<write::DeferredDir>,
+    /// Hard links and their targets, which may be in lower layers
+    hardlinks: Vec<(PathBuf, PathBuf)>,
+    /// Files removed by [`UnpackOptions::hygiene`] once they were written, and device nodes that
+    /// couldn't be created, whose paths are removed from lower layers as they would be had they
+    /// been merged
+    stripped: Vec<PathBuf>,
+    warnings: Warnings,
+    /// What was done with the layer, if it's a flattened Windows layer
+    windows: Option<WindowsLayer>,
+    timing: LayerTiming,
+    compression: LayerCompression,
+    /// When staging the layer started
+    started: Instant,
+    /// When the layer was staged, ready to merge
+    staged: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    next: usize,
+    merged: usize,
+    failed: bool,
+    /// Staged layers, or `None` for skipped ones
+    staged: HashMap<usize, Result<Option<StagedLayer>>>,
+}
+
+/// Extracts `layers` concurrently into staging directories under `staging` using `jobs` threads,
+/// merging each onto `rootfs` in order as soon as it and all layers below it are staged.
+pub(crate) fn extract_layers(
+    blobs: &BlobSource,
+    layers: &Layers,
+    staging: &Path,
+    rootfs: &Path,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+) -> Result<LayerReport> {
+    let Layers {
+        descriptors: layers,
+        diff_ids,
+        skipped,
+        windows_dir,
+        checkpoints,
+    } = *layers;
+    let jobs = options.parallel_layers;
+    let state = Mutex::new(State::default());
+    let cond = Condvar::new();
+
+    let result = std::thread::scope(|scope| {
+        for _ in 0..jobs.min(layers.len()) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut state = state.lock().unwrap();
+                    // Bound the number of staged but unmerged layers
+                    while !state.failed
+                        && state.next < layers.len()
+                        && state.next >= state.merged + jobs
+                    {
+                        state = cond.wait(state).unwrap();
+                    }
+                    if state.failed || state.next >= layers.len() {
+                        return;
+                    }
+                    state.next += 1;
+                    state.next - 1
+                };
+
+                if skipped[index] {
+                    let mut state = state.lock().unwrap();
+                    state.staged.insert(index, Ok(None));
+                    cond.notify_all();
+                    continue;
+                }
+                let descriptor = &layers[index];
+                log::debug!("Staging layer {} ({})", index, descriptor.digest());
+                let dir = staging.join(index.to_string());
+                let started = Instant::now();
+                let mut timing = LayerTiming::new(index);
+                let mut compression = LayerCompression::new(index);
+                let layer_options = options.for_layer(descriptor);
+                let staged = deadline
+                    .map_or(Ok(()), Deadline::check)
+                    .and_then(|()| {
+                        retry(index, options, deadline, |attempt| {
+                            if attempt > 0 && dir.exists() {
+                                fs::remove_dir_all(&dir).with_path(&dir)?;
+                            }
+                            read_layer(
+                                blobs,
+                                layers,
+                                index,
+                                &diff_ids[index],
+                                options,
+                                deadline,
+                                &mut timing,
+                                &mut compression,
+                                |reader, cloner| {
+                                    // Flattening rewrites the archive, so its content can't be
+                                    // cloned
+                                    let cloner = cloner.filter(|_| windows_dir.is_none());
+                                    let (staged, windows) =
+                                        windows::flatten(reader, windows_dir, index, |reader| {
+                                            stage_layer(
+                                                &mut Archive::new(reader),
+                                                &dir,
+                                                index,
+                                                &layer_options,
+                                                cloner,
+                                            )
+                                        })?;
+                                    Ok(StagedLayer { windows, ..staged })
+                                },
+                            )
+                        })
+                    })
+                    .map(|staged| {
+                        Some(StagedLayer {
+                            timing,
+                            compression,
+                            started,
+                            staged: Instant::now(),
+                            ..staged
+                        })
+                    })
+                    .map_err(|e| e.in_layer(index, descriptor));
+
+                let mut state = state.lock().unwrap();
+                state.staged.insert(index, staged);
+                cond.notify_all();
+            });
+        }
+
+        let merge_all = || -> Result<LayerReport> {
+            let mut report = LayerReport::default();
+            let root_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+            for (index, descriptor) in layers.iter().enumerate() {
+                let waiting = Instant::now();
+                let staged = {
+                    let mut state = state.lock().unwrap();
+                    loop {
+                        if let Some(staged) = state.staged.remove(&index) {
+                            break staged;
+                        }
+                        state = cond.wait(state).unwrap();
+                    }
+                }?;
+                let Some(mut staged) = staged else {
+                    state.lock().unwrap().merged += 1;
+                    cond.notify_all();
+                    continue;
+                };
+                if let Some(deadline) = deadline {
+                    deadline.check()?;
+                }
+
+                log::debug!("Merging layer {} ({})", index, descriptor.digest());
+                let merging = Instant::now();
+                let mut timing = staged.timing.clone();
+                timing.write_blocked = merging.saturating_duration_since(waiting);
+                timing.read_blocked = merging.saturating_duration_since(staged.staged);
+                merge_layer(
+                    &mut staged,
+                    &root_dir,
+                    rootfs,
+                    &options.for_layer(descriptor),
+                )
+                .map_err(|e| e.in_layer(index, descriptor))?;
+                fs::remove_dir_all(&staged.dir).with_path(&staged.dir)?;
+                let mut layer_report = staged.warnings.finish();
+                layer_report.windows.extend(staged.windows);
+                timing.write += merging.elapsed();
+                timing.duration = staged.started.elapsed();
+                layer_applied(
+                    index,
+                    descriptor,
+                    &mut layer_report,
+                    &timing,
+                    rootfs,
+                    options,
+                    deadline,
+                    checkpoints,
+                )?;
+                layer_report.timings.push(timing);
+                layer_report.compression.push(staged.compression.clone());
+                report.append(layer_report);
+
+                state.lock().unwrap().merged += 1;
+                cond.notify_all();
+            }
+            Ok(report)
+        };
+        let result = merge_all();
+        if result.is_err() {
+            state.lock().unwrap().failed = true;
+            cond.notify_all();
+        }
+        result
+    });
+
+    if let Err(e) = fs::remove_dir_all(staging) {
+        log::warn!("Failed to remove staging directory: {e}");
+    }
+    result
+}
+
+/// Extracts the layer at `index` into `dir`, recording rather than applying its whiteouts and
+/// hard links. Files' content is cloned from the archive with `cloner`, if it's given.
+fn stage_layer<R: io::Read>(
+    archive: &mut Archive<R>,
+    dir: &Path,
+    index: usize,
+    options: &UnpackOptions,
+    cloner: Option<ArchiveCloner>,
+) -> Result<StagedLayer> {
+    fs::create_dir_all(dir).with_path(dir)?;
+    let stage_dir = Dir::open_ambient_dir(dir, ambient_authority()).with_path(dir)?;
+    let mut writer =
+        write::FileWriter::new(&stage_dir, options.write_buffer_size, options.ownership)
+            .inspector(options.content_inspector.as_ref().map(|c| &*c.0))
+            .translate_acls(options.translate_acls)
+            .sniff(hygiene::sniff_limit(options))
+            .preallocate(options.preallocate_files)
+            .clone_from(cloner);
+    archive.set_overwrite(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(options.ownership == OwnershipMode::Preserve);
+    archive.set_preserve_permissions(true);
+    archive.set_unpack_xattrs(true);
+
+    let mut staged = StagedLayer {
+        dir: dir.to_path_buf(),
+        opaque_dirs: Vec::new(),
+        whiteouts: Vec::new(),
+        dirs: Vec::new(),
+        root: None,
+        hardlinks: Vec::new(),
+        stripped: Vec::new(),
+        warnings: Warnings::new(options.strictness, index)
+            .record_changes(options.records_changes()),
+        timing: LayerTiming::new(index),
+        compression: LayerCompression::new(index),
+        windows: None,
+        started: Instant::now(),
+        staged: Instant::now(),
+    };
+    let mut dirs = Vec::new();
+
+    for (count, entry) in archive.entries().map_err(Error::Archive)?.enumerate() {
+        options.check_entry_count(count)?;
+        let mut entry = entry.map_err(Error::Archive)?;
+        staged.warnings.entry();
+        let path = crate::entry_path(&entry)?;
+        options.check_entry_size(&path, entry.size())?;
+        // Ignore paths with ".." in them, to avoid traversing outside the root
+        if path.components().any(|c| c == Component::ParentDir) {
+            staged.warnings.warn(&path, WarningKind::UnsafePath)?;
+            continue;
+        }
+        let is_root_entry = crate::is_root_entry(&path);
+        let path = normalize(&options.unicode_policy.path(&path));
+        if path.as_os_str().is_empty() {
+            // Entries for the root itself are applied to the rootfs when the layer is merged
+            if is_root_entry && entry.header().entry_type().is_dir() {
+                let mask = options
+                    .permission_policy
+                    .mask(entry.header())
+                    .map_err(Error::Archive)?;
+                let root = write::DeferredDir::root(&mut entry, mask)?;
+                staged
+                    .warnings
+                    .change(|| EntryChange::Root { mtime: root.mtime });
+                staged.root = Some(root);
+            }
+            continue;
+        }
+        #[cfg(feature = "estargz")]
+        if crate::estargz::is_reserved(&path) {
+            continue;
+        }
+
+        if entry.header().entry_type().is_dir() {
+            if hygiene::check_path(options, &path, &path, &mut staged.warnings) {
+                continue;
+            }
+            protected::check(
+                options,
+                &path,
+                &path,
+                Modification::Dir,
+                &mut staged.warnings,
+            )?;
+            let mask = options
+                .permission_policy
+                .mask(entry.header())
+                .map_err(Error::Archive)?;
+            let dir = write::DeferredDir::new(&mut entry, path, mask)?;
+            staged.warnings.change(|| EntryChange::Dir {
+                path: dir.path.clone(),
+                mtime: dir.mtime,
+            });
+            dirs.push(dir);
+            continue;
+        }
+
+        match Whiteout::parse(&path) {
+            Some(Whiteout::Invalid) => {
+                staged.warnings.warn(&path, WarningKind::InvalidWhiteout)?;
+                continue;
+            }
+            Some(Whiteout::Opaque(dir)) => {
+                if hygiene::skips_whiteout(options, &dir) {
+                    continue;
+                }
+                protected::check(
+                    options,
+                    &path,
+                    &dir,
+                    Modification::Opaque,
+                    &mut staged.warnings,
+                )?;
+                staged.warnings.whiteout();
+                staged.warnings.change(|| EntryChange::Opaque(dir.clone()));
+                staged.opaque_dirs.push(dir);
+                continue;
+            }
+            Some(Whiteout::Entry(removed)) => {
+                if hygiene::skips_whiteout(options, &removed) {
+                    continue;
+                }
+                protected::check(
+                    options,
+                    &path,
+                    &removed,
+                    Modification::Replace,
+                    &mut staged.warnings,
+                )?;
+                staged.warnings.whiteout();
+                staged
+                    .warnings
+                    .change(|| EntryChange::Whiteout(removed.clone()));
+                staged.whiteouts.push(removed);
+                continue;
+            }
+            None => {}
+        }
+        if !check_link_target(&entry, &path, options, &mut staged.warnings)?
+            || hygiene::check_path(options, &path, &path, &mut staged.warnings)
+            || hygiene::check_link(options, &entry, &path, &mut staged.warnings)?
+        {
+            continue;
+        }
+        protected::check(
+            options,
+            &path,
+            &path,
+            Modification::Replace,
+            &mut staged.warnings,
+        )?;
+        staged.warnings.change(|| EntryChange::Write {
+            path: path.clone(),
+            size: written_size(&entry),
+        });
+        if entry.header().entry_type().is_hard_link() {
+            let target = link_target(&entry, &path)?;
+            let target = options.unicode_policy.path(&target).into_owned();
+            staged.hardlinks.push((path, target));
+        } else {
+            let mask = permission_mask(&mut entry, &path, options, &mut staged.warnings)?;
+            if write::is_regular_file(entry.header().entry_type()) {
+                let capability = capabilities::read(&mut entry)?;
+                if let Some(error) = writer.write(&mut entry, &path, mask)? {
+                    staged.warnings.inspection_failed(&path, error)?;
+                }
+                if let Some(content) = writer.take_sniffed() {
+                    if hygiene::check_content(options, &path, &content, &mut staged.warnings) {
+                        stage_dir.remove_file(&path).with_path(&path)?;
+                        staged.stripped.push(path);
+                        continue;
+                    }
+                }
+                if let Some(value) = capability {
+                    let dropped = writer.capability_dropped();
+                    staged.warnings.capability(&path, &value, dropped)?;
+                }
+                if writer.acl_dropped() {
+                    staged.warnings.warn(&path, WarningKind::AclDropped)?;
+                }
+                if writer.xattr_dropped() {
+                    staged.warnings.warn(&path, WarningKind::XattrDropped)?;
+                }
+            } else if options.ownership == OwnershipMode::Emulate
+                && rootless::is_device(entry.header().entry_type())
+            {
+                rootless::write_device(&stage_dir, &entry, &path, mask)?;
+            } else if write::is_special_file(entry.header().entry_type()) {
+                let dropped =
+                    write::write_special(&stage_dir, &mut entry, &path, mask, options.ownership)?;
+                if let Some(kind) = dropped {
+                    staged.warnings.warn(&path, kind)?;
+                    if kind == WarningKind::DeviceNodeSkipped {
+                        staged.stripped.push(path);
+                    }
+                }
+            } else {
+                write::unpack_in(&mut entry, dir, &stage_dir, &path)?;
+            }
+        }
+    }
+
+    dirs.sort_by(|a, b| b.path.as_os_str().cmp(a.path.as_os_str()));
+    for dir in &dirs {
+        if dir.mask != 0 {
+            staged
+                .warnings
+                .strip(&dir.path, dir.mode & 0o7777, dir.mask);
+        }
+        for kind in dir.create(&stage_dir, options.ownership, options.translate_acls)? {
+            staged.warnings.warn(&dir.path, kind)?;
+        }
+    }
+    if let Some(root) = &staged.root {
+        if root.mask != 0 {
+            staged
+                .warnings
+                .strip(&root.path, root.mode & 0o7777, root.mask);
+        }
+    }
+    if let Some(copy) = writer.finish_cloning(index) {
+        staged.warnings.copied(copy);
+    }
+    staged.dirs = dirs.into_iter().map(|dir| dir.path).collect();
+    Ok(staged)
+}
+
+/// Applies a staged layer's whiteouts to the rootfs, then moves its contents into place
+fn merge_layer(
+    staged: &mut StagedLayer,
+    root_dir: &Dir,
+    rootfs: &Path,
+    options: &UnpackOptions,
+) -> Result<()> {
+    // The staged entries aren't in the rootfs yet, so opaque whiteouts clear everything
+    let mut applier = LayerApplier::new(DirTarget::new(root_dir)).apply_mode(options.apply_mode);
+    for dir in &staged.opaque_dirs {
+        let entry = dir.join(".wh..wh..opq");
+        if !applier
+            .opaque_whiteout(dir)
+            .map_err(|e| e.in_whiteout(&entry))?
+        {
+            staged
+                .warnings
+                .warn(&entry, WarningKind::DanglingWhiteout)?;
+        }
+    }
+    for path in &staged.whiteouts {
+        let entry = whiteout_entry(path);
+        if !applier.whiteout(path).map_err(|e| e.in_whiteout(&entry))? {
+            staged
+                .warnings
+                .warn(&entry, WarningKind::DanglingWhiteout)?;
+        }
+    }
+    for path in &staged.stripped {
+        if root_dir.symlink_metadata(path).is_ok() {
+            remove_all(root_dir, path).with_path(path)?;
+        }
+    }
+
+    let mut parents = ParentGuard::new(root_dir, options.parent_symlinks);
+    for dir in &staged.dirs {
+        parents.add_dir(dir);
+    }
+    let stage_dir =
+        Dir::open_ambient_dir(&staged.dir, ambient_authority()).with_path(&staged.dir)?;
+    move_tree(
+        &stage_dir,
+        root_dir,
+        Path::new(""),
+        (&staged.dir, rootfs),
+        &parents,
+    )?;
+
+    for (path, target) in &staged.hardlinks {
+        parents.check(path)?;
+        if options.hardlinks == HardlinkPolicy::Copy {
+            write::copy_link_target(root_dir, target, path, options.ownership)?;
+            continue;
+        }
+        if root_dir.symlink_metadata(path).is_ok() {
+            remove_all(root_dir, path).with_path(path)?;
+        }
+        root_dir.hard_link(target, root_dir, path).with_path(path)?;
+    }
+
+    // Directories merged into existing ones weren't moved, so copy over their metadata. Deepest
+    // first, to match the order they're created when extracting sequentially.
+    let mut dirs: Vec<_> = staged.dirs.iter().collect();
+    dirs.sort_by(|a, b| b.as_os_str().cmp(a.as_os_str()));
+    for dir in dirs {
+        let Ok(metadata) = fs::symlink_metadata(staged.dir.join(dir)) else {
+            continue;
+        };
+        if !metadata.is_dir() {
+            continue;
+        }
+        lchown(rootfs.join(dir), Some(metadata.uid()), Some(metadata.gid())).with_path(dir)?;
+        if options.ownership == OwnershipMode::Emulate {
+            rootless::copy(&staged.dir.join(dir), &rootfs.join(dir))?;
+        }
+        root_dir
+            .set_permissions(or_dot(dir), Permissions::from_std(metadata.permissions()))
+            .with_path(dir)?;
+        acl::copy(&staged.dir.join(dir), &rootfs.join(dir)).with_path(dir)?;
+    }
+    if let Some(root) = &staged.root {
+        for kind in root.create(root_dir, options.ownership, options.translate_acls)? {
+            staged.warnings.warn(&root.path, kind)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the contents of `path` in `src` into `dst`, merging directories present in both.
+/// `roots` are the paths of `src` and `dst`, for copying entries when they're on different
+/// filesystems. Symlinks in `dst` that directories replace are checked with `parents` first.
+fn move_tree(
+    src: &Dir,
+    dst: &Dir,
+    path: &Path,
+    roots: (&Path, &Path),
+    parents: &ParentGuard,
+) -> Result<()> {
+    for entry in src.read_dir(or_dot(path)).with_path(path)? {
+        let entry = entry.with_path(path)?;
+        let path = path.join(entry.file_name());
+        let is_dir = entry.file_type().with_path(&path)?.is_dir();
+        if is_dir && dst.symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+            // Name an entry beneath the directory, if it has any, as the one being written
+            let beneath = src
+                .read_dir(&path)
+                .with_path(&path)?
+                .next()
+                .transpose()
+                .with_path(&path)?
+                .map_or_else(|| path.clone(), |child| path.join(child.file_name()));
+            parents.replacing(&beneath, &path)?;
+        }
+        match dst.symlink_metadata(&path) {
+            Ok(existing) if existing.is_dir() && is_dir => {
+                move_tree(src, dst, &path, roots, parents)?;
+                continue;
+            }
+            Ok(_) => remove_all(dst, &path).with_path(&path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_path(&path),
+        }
+        match src.rename(&path, dst, &path) {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                copy::move_across_devices(&roots.0.join(&path), &roots.1.join(&path))?
+            }
+            result => result.with_path(&path)?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of the whiteout entry that removes `path`
+fn whiteout_entry(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".wh.");
+    file_name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(file_name)
+}
+
+fn remove_all(dir: &Dir, path: &Path) -> io::Result<()> {
+    if dir.symlink_metadata(path)?.is_dir() {
+        dir.remove_dir_all(path)
+    } else {
+        dir.remove_file(path)
+    }
+}
@@ -0,0 +1,151 @@
+//! Guards against writing through symlinks that lower layers left in place of directories.
+//!
+//! A layer can replace a directory with a symlink pointing outside the rootfs, so that a higher
+//! layer's entries beneath the directory would be written outside it. cap-std refuses to follow
+//! such symlinks, and tar-rs checks the paths it unpacks, but neither says why, nor offers to
+//! replace the symlink with a directory, as runc's unpacker does.
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::ParentSymlinkPolicy;
+use ocidir::cap_std::fs::{Dir, Permissions};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Checks the parents of each entry written by a layer for symlinks pointing outside the rootfs
+pub(crate) struct ParentGuard<'a> {
+    root_dir: &'a Dir,
+    policy: ParentSymlinkPolicy,
+    /// Directories that had entries in the layer, which replace symlinks regardless of the policy
+    dirs: HashSet<PathBuf>,
+}
+
+impl<'a> ParentGuard<'a> {
+    pub(crate) fn new(root_dir: &'a Dir, policy: ParentSymlinkPolicy) -> Self {
+        Self {
+            root_dir,
+            policy,
+            dirs: HashSet::new(),
+        }
+    }
+
+    /// Records that the layer has an entry for the directory at `path`
+    pub(crate) fn add_dir(&mut self, path: &Path) {
+        self.dirs.insert(crate::normalize(path));
+    }
+
+    /// Checks each parent of `path`, from the root down, replacing those that are symlinks
+    /// pointing outside the rootfs with directories, or failing, as the policy says
+    pub(crate) fn check(&self, path: &Path) -> Result<()> {
+        let path = crate::normalize(path);
+        match path.parent() {
+            Some(parent) => self.check_components(&path, parent),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the directory entry at `path` as [`Self::check`] does, along with `path` itself,
+    /// which would otherwise be followed to set the directory's metadata
+    pub(crate) fn check_dir(&self, path: &Path) -> Result<()> {
+        let path = crate::normalize(path);
+        self.check_components(&path, &path)
+    }
+
+    fn check_components(&self, path: &Path, dir: &Path) -> Result<()> {
+        let mut current = PathBuf::new();
+        for component in dir.components() {
+            current.push(component);
+            match self.root_dir.symlink_metadata(&current) {
+                Ok(metadata) if metadata.is_symlink() => self.replacing(path, &current)?,
+                Ok(_) => {}
+                // The rest will be created as directories, replacing any file in the way if the
+                // layer has an entry for it
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                    ) =>
+                {
+                    return Ok(())
+                }
+                Err(e) => return Err(e).with_path(&current),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the symlink at `parent`, which is in the way of `path`, with a directory if it
+    /// points outside the rootfs. Fails instead if the policy is to reject it, unless the layer
+    /// has an entry for the directory. Symlinks within the rootfs are left alone.
+    pub(crate) fn replacing(&self, path: &Path, parent: &Path) -> Result<()> {
+        let target = self.root_dir.read_link_contents(parent).with_path(parent)?;
+        if !escapes(parent, &target) {
+            return Ok(());
+        }
+        if self.policy == ParentSymlinkPolicy::Reject && !self.dirs.contains(parent) {
+            return Err(Error::SymlinkedParent {
+                path: path.to_path_buf(),
+                parent: parent.to_path_buf(),
+                target,
+            });
+        }
+        log::debug!(
+            "Replacing symlink {} to {} with a directory",
+            parent.display(),
+            target.display()
+        );
+        self.root_dir.remove_file(parent).with_path(parent)?;
+        self.root_dir.create_dir(parent).with_path(parent)?;
+        self.root_dir
+            .set_permissions(
+                parent,
+                Permissions::from_std(fs::Permissions::from_mode(0o755)),
+            )
+            .with_path(parent)
+    }
+}
+
+/// Returns whether the symlink at `link`, relative to the rootfs, points outside it. Absolute
+/// targets are taken to point outside, as they would when followed on the host.
+fn escapes(link: &Path, target: &Path) -> bool {
+    let mut depth = link
+        .parent()
+        .map_or(0, |parent| parent.components().count());
+    for component in target.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return true,
+            Component::CurDir => {}
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escapes() {
+        for (link, target, expected) in [
+            ("opt/app", "/etc", true),
+            ("opt/app", "../../etc", true),
+            ("opt/app", "../srv/../../etc", true),
+            ("opt/app", "../etc", false),
+            ("opt/app", "data", false),
+            ("opt/app", "./../srv/./app", false),
+            ("lib", "usr/lib", false),
+            ("lib", "../lib", true),
+        ] {
+            assert_eq!(
+                escapes(Path::new(link), Path::new(target)),
+                expected,
+                "{link} -> {target}"
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::error::{Error, Result};
+use ocidir::oci_spec::OciSpecError;
+use serde_json::Value;
+
+/// The prefix of the annotations recording image config fields that aren't converted to the
+/// runtime config, when [`crate::RuntimeConfigOptions::passthrough_unconverted`] is set. The
+/// field's name in `config` follows, such as `oci-bundle.config.OnBuild`.
+pub const UNCONVERTED_ANNOTATION_PREFIX: &str = "oci-bundle.config.";
+
+/// The fields of `config` that are converted to the runtime config
+const CONVERTED_FIELDS: [&str; 7] = [
+    "User",
+    "Env",
+    "Entrypoint",
+    "Cmd",
+    "WorkingDir",
+    "Labels",
+    "StopSignal",
+];
+
+/// Returns annotations for the fields of the raw image config's `config` that aren't converted,
+/// such as `OnBuild`, `Shell` and legacy Docker resource limits, including those unknown to
+/// [`ocidir::oci_spec::image::Config`]. Strings are recorded as they are, and other values as
+/// JSON. Null fields are left out.
+pub(crate) fn unconverted_annotations(raw_config: &[u8]) -> Result<Vec<(String, String)>> {
+    let value: Value =
+        serde_json::from_slice(raw_config).map_err(|e| Error::Spec(OciSpecError::SerDe(e)))?;
+    let Some(Value::Object(config)) = value.get("config") else {
+        return Ok(Vec::new());
+    };
+    Ok(config
+        .iter()
+        .filter(|(key, value)| !CONVERTED_FIELDS.contains(&key.as_str()) && !value.is_null())
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            (format!("{UNCONVERTED_ANNOTATION_PREFIX}{key}"), value)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unconverted_annotations() {
+        let config = br#"{
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {
+                "Cmd": ["sh"],
+                "User": "1000",
+                "OnBuild": ["RUN make"],
+                "Shell": ["/bin/bash", "-c"],
+                "ArgsEscaped": true,
+                "Memory": 0,
+                "Domainname": "",
+                "Healthcheck": {"Test": ["CMD", "true"]},
+                "Volumes": null
+            },
+            "rootfs": {"type": "layers", "diff_ids": []}
+        }"#;
+        let mut annotations = unconverted_annotations(config).unwrap();
+        annotations.sort();
+        let expected = [
+            ("ArgsEscaped", "true"),
+            ("Domainname", ""),
+            ("Healthcheck", r#"{"Test":["CMD","true"]}"#),
+            ("Memory", "0"),
+            ("OnBuild", r#"["RUN make"]"#),
+            ("Shell", r#"["/bin/bash","-c"]"#),
+        ]
+        .map(|(key, value)| (format!("oci-bundle.config.{key}"), value.to_string()));
+        assert_eq!(annotations, expected);
+
+        assert!(unconverted_annotations(br#"{"architecture": "amd64"}"#)
+            .unwrap()
+            .is_empty());
+    }
+}
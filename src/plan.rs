@@ -0,0 +1,226 @@
+//! Checking what an unpack extracted against what was expected of it, for
+//! [`crate::UnpackOptions::expected`].
+
+use crate::error::{Error, Result};
+use crate::options::Strictness;
+use crate::report::{LayerCompression, LayerCounts, UnpackReport, Warning, WarningKind};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+/// What an unpack is expected to extract from each layer, for
+/// [`crate::UnpackOptions::expected`], such as a summary of an earlier unpack of the same image
+/// from [`Self::from_report`].
+///
+/// Only what the summary records is checked: fields left as `None` and layers that aren't listed
+/// are ignored, as are those of skipped layers. Serialized, fields that are `None` are omitted,
+/// and missing fields are read as `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct PlanSummary {
+    #[serde(default)]
+    pub layers: Vec<PlannedLayer>,
+}
+
+impl PlanSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds what's expected of a layer
+    pub fn layer(mut self, layer: PlannedLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Returns a summary of what was extracted by the unpack that returned `report`, recording
+    /// every field
+    pub fn from_report(report: &UnpackReport) -> Self {
+        let layers = report
+            .layer_counts
+            .iter()
+            .map(|counts| {
+                let layer = PlannedLayer::new(counts.layer_index)
+                    .entries(counts.entries)
+                    .whiteouts(counts.whiteouts);
+                match compression(&report.layer_compression, counts.layer_index) {
+                    Some(compression) => layer.uncompressed_bytes(compression.uncompressed_bytes),
+                    None => layer,
+                }
+            })
+            .collect();
+        Self { layers }
+    }
+}
+
+/// What's expected of the layer at [`Self::layer_index`], in a [`PlanSummary`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct PlannedLayer {
+    pub layer_index: usize,
+    /// The entries in the layer's archive, as counted by [`LayerCounts::entries`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries: Option<u64>,
+    /// The whiteouts in the layer's archive, as counted by [`LayerCounts::whiteouts`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub whiteouts: Option<u64>,
+    /// The size of the layer's archive, as in [`LayerCompression::uncompressed_bytes`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncompressed_bytes: Option<u64>,
+}
+
+impl PlannedLayer {
+    /// Creates an expectation of the layer at `layer_index` that records nothing
+    pub fn new(layer_index: usize) -> Self {
+        Self {
+            layer_index,
+            entries: None,
+            whiteouts: None,
+            uncompressed_bytes: None,
+        }
+    }
+
+    pub fn entries(mut self, entries: u64) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn whiteouts(mut self, whiteouts: u64) -> Self {
+        self.whiteouts = Some(whiteouts);
+        self
+    }
+
+    pub fn uncompressed_bytes(mut self, bytes: u64) -> Self {
+        self.uncompressed_bytes = Some(bytes);
+        self
+    }
+}
+
+/// A way an extracted layer differed from its [`PlannedLayer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PlanDeviation {
+    pub layer_index: usize,
+    pub field: PlannedField,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for PlanDeviation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Layer {} had {} {}, but {} were expected",
+            self.layer_index, self.actual, self.field, self.expected
+        )
+    }
+}
+
+/// The field of a [`PlannedLayer`] that a [`PlanDeviation`] is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PlannedField {
+    Entries,
+    Whiteouts,
+    UncompressedBytes,
+}
+
+impl fmt::Display for PlannedField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PlannedField::Entries => "entries",
+            PlannedField::Whiteouts => "whiteouts",
+            PlannedField::UncompressedBytes => "uncompressed bytes",
+        })
+    }
+}
+
+/// Compares the `counts` and `compression` of the extracted layers with `plan`, returning how they
+/// differ, each with a warning. Fails on the first difference instead with
+/// [`Strictness::Strict`].
+pub(crate) fn check(
+    plan: &PlanSummary,
+    counts: &[LayerCounts],
+    compression: &[LayerCompression],
+    strictness: Strictness,
+) -> Result<(Vec<PlanDeviation>, Vec<Warning>)> {
+    let mut deviations = Vec::new();
+    for counts in counts {
+        let Some(planned) = plan
+            .layers
+            .iter()
+            .find(|layer| layer.layer_index == counts.layer_index)
+        else {
+            continue;
+        };
+        let uncompressed_bytes =
+            self::compression(compression, counts.layer_index).map(|c| c.uncompressed_bytes);
+        let fields = [
+            (PlannedField::Entries, planned.entries, Some(counts.entries)),
+            (
+                PlannedField::Whiteouts,
+                planned.whiteouts,
+                Some(counts.whiteouts),
+            ),
+            (
+                PlannedField::UncompressedBytes,
+                planned.uncompressed_bytes,
+                uncompressed_bytes,
+            ),
+        ];
+        for (field, expected, actual) in fields {
+            let (Some(expected), Some(actual)) = (expected, actual) else {
+                continue;
+            };
+            if expected != actual {
+                let deviation = PlanDeviation {
+                    layer_index: counts.layer_index,
+                    field,
+                    expected,
+                    actual,
+                };
+                if strictness == Strictness::Strict {
+                    return Err(Error::PlanDeviation(deviation));
+                }
+                log::warn!("{deviation}");
+                deviations.push(deviation);
+            }
+        }
+    }
+    let warnings = deviations
+        .iter()
+        .map(|deviation| Warning {
+            layer_index: deviation.layer_index,
+            path: PathBuf::new(),
+            kind: WarningKind::PlanDeviation,
+        })
+        .collect();
+    Ok((deviations, warnings))
+}
+
+fn compression(compression: &[LayerCompression], index: usize) -> Option<&LayerCompression> {
+    compression.iter().find(|layer| layer.layer_index == index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_partial() {
+        let plan: PlanSummary =
+            serde_json::from_str(r#"{"layers": [{"layer_index": 1, "whiteouts": 2}]}"#).unwrap();
+        assert_eq!(
+            plan,
+            PlanSummary::new().layer(PlannedLayer::new(1).whiteouts(2))
+        );
+        assert_eq!(
+            serde_json::to_string(&plan).unwrap(),
+            r#"{"layers":[{"layer_index":1,"whiteouts":2}]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<PlanSummary>("{}").unwrap(),
+            PlanSummary::new()
+        );
+    }
+}
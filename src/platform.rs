@@ -0,0 +1,242 @@
+use crate::error::{Error, Result};
+use ocidir::oci_spec::image::{
+    Arch, Descriptor, ImageConfiguration, ImageIndex, Os, Platform, PlatformBuilder,
+};
+
+/// Returns the platform of the host, in the terms used by OCI images: `GOARCH` and `GOOS` names,
+/// with the CPU variant for ARM.
+///
+/// Use [`crate::UnpackOptions::target_platform`] to unpack for a different platform.
+pub fn host_platform() -> Platform {
+    let arch = match std::env::consts::ARCH {
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        arch => arch,
+    };
+    let variant = match arch {
+        "arm" => arm_variant(),
+        _ => None,
+    };
+    let (architecture, variant) = normalize_arch(arch, variant.as_deref());
+    let mut platform = PlatformBuilder::default()
+        .architecture(architecture)
+        .os(normalize_os(std::env::consts::OS))
+        .build()
+        .expect("architecture and OS are set");
+    platform.set_variant(variant);
+    platform
+}
+
+/// Normalizes the name of an architecture, whether it's Rust's, the kernel's or Go's, and its
+/// variant, as containerd does
+pub(crate) fn normalize_arch(arch: &str, variant: Option<&str>) -> (Arch, Option<String>) {
+    let arch = arch.to_ascii_lowercase();
+    let variant = variant
+        .map(str::to_ascii_lowercase)
+        .filter(|v| !v.is_empty());
+    let prefixed = |variant: Option<String>| {
+        variant.map(|v| match v.strip_prefix('v') {
+            Some(_) => v,
+            None => format!("v{v}"),
+        })
+    };
+    match arch.as_str() {
+        "i386" | "i486" | "i586" | "i686" | "x86" => (Arch::i386, None),
+        "x86_64" | "x86-64" | "amd64" => (Arch::Amd64, None),
+        "aarch64" | "arm64" => (
+            Arch::ARM64,
+            match prefixed(variant).as_deref() {
+                None | Some("v8" | "v8.0") => Some("v8".to_string()),
+                Some(variant) => Some(variant.to_string()),
+            },
+        ),
+        "armhf" => (Arch::ARM, Some("v7".to_string())),
+        "armel" => (Arch::ARM, Some("v6".to_string())),
+        "arm" | "armv5l" | "armv6l" | "armv7l" | "armv8l" => {
+            let variant = variant.or_else(|| arch.get(4..5).map(str::to_string));
+            (
+                Arch::ARM,
+                prefixed(variant).or_else(|| Some("v7".to_string())),
+            )
+        }
+        "powerpc" => (Arch::PowerPC, variant),
+        "powerpc64le" => (Arch::PowerPC64le, variant),
+        "loongarch64" => (Arch::LoongArch64, variant),
+        "riscv64gc" => (Arch::RISCV64, variant),
+        "wasm32" => (Arch::Wasm, variant),
+        arch => (Arch::from(arch), variant),
+    }
+}
+
+/// Normalizes the name of an operating system, whether it's Rust's or Go's
+pub(crate) fn normalize_os(os: &str) -> Os {
+    match os.to_ascii_lowercase().as_str() {
+        "macos" => Os::Darwin,
+        os => Os::from(os),
+    }
+}
+
+/// Detects the variant of a 32-bit ARM CPU: the platform the kernel reports in the auxiliary
+/// vector, falling back to the architecture in `/proc/cpuinfo`
+fn arm_variant() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: AT_PLATFORM is either 0 or points to a NUL-terminated string that lives as long
+        // as the process
+        let platform = unsafe { libc::getauxval(libc::AT_PLATFORM) };
+        if platform != 0 {
+            let platform = unsafe { std::ffi::CStr::from_ptr(platform as *const libc::c_char) };
+            if let Some(variant) = platform.to_str().ok().and_then(auxv_arm_variant) {
+                return Some(variant);
+            }
+        }
+    }
+    std::fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|cpuinfo| cpuinfo_arm_variant(&cpuinfo))
+}
+
+/// Parses the variant from an ARM AT_PLATFORM string, such as `v7l`
+fn auxv_arm_variant(platform: &str) -> Option<String> {
+    let digit = platform.strip_prefix('v')?.chars().next()?;
+    digit.is_ascii_digit().then(|| format!("v{digit}"))
+}
+
+/// Parses the variant from the `CPU architecture` line of `/proc/cpuinfo`, which is a number,
+/// possibly followed by a suffix, such as `7` or `5TEJ`
+fn cpuinfo_arm_variant(cpuinfo: &str) -> Option<String> {
+    let value = cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case("CPU architecture")
+            .then(|| value.trim())
+    })?;
+    let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+    (!digits.is_empty()).then(|| format!("v{digits}"))
+}
+
+/// Logs a warning if the image is for a different platform than `target`. The variants are only
+/// compared when both are known.
+pub(crate) fn check_platform(image_config: &ImageConfiguration, target: &Platform) {
+    let (arch, variant) = normalize_arch(
+        &image_config.architecture().to_string(),
+        image_config.variant().as_deref(),
+    );
+    let (target_arch, target_variant) = normalize_arch(
+        &target.architecture().to_string(),
+        target.variant().as_deref(),
+    );
+    let variants_differ = matches!((&variant, &target_variant), (Some(a), Some(b)) if a != b);
+    if arch != target_arch || image_config.os() != target.os() || variants_differ {
+        log::warn!(
+            "Image is for {}, not the target platform {}",
+            describe(image_config.os(), &arch, variant.as_deref()),
+            describe(target.os(), &target_arch, target_variant.as_deref()),
+        );
+    }
+}
+
+/// Returns the first manifest in `index` for `target`, comparing architectures as
+/// [`check_platform`] does. Manifests without a platform aren't for any. Fails with
+/// [`Error::NoMatchingPlatform`] if there's none.
+pub(crate) fn select<'a>(index: &'a ImageIndex, target: &Platform) -> Result<&'a Descriptor> {
+    let (target_arch, target_variant) = normalize_arch(
+        &target.architecture().to_string(),
+        target.variant().as_deref(),
+    );
+    let mut available = Vec::new();
+    for descriptor in index.manifests() {
+        let Some(platform) = descriptor.platform() else {
+            continue;
+        };
+        let (arch, variant) = normalize_arch(
+            &platform.architecture().to_string(),
+            platform.variant().as_deref(),
+        );
+        let variants_differ = matches!((&variant, &target_variant), (Some(a), Some(b)) if a != b);
+        if arch == target_arch && platform.os() == target.os() && !variants_differ {
+            return Ok(descriptor);
+        }
+        available.push(describe(platform.os(), &arch, variant.as_deref()));
+    }
+    Err(Error::NoMatchingPlatform {
+        platform: describe(target.os(), &target_arch, target_variant.as_deref()),
+        available,
+    })
+}
+
+fn describe(os: &Os, arch: &Arch, variant: Option<&str>) -> String {
+    match variant {
+        Some(variant) => format!("{os}/{arch}/{variant}"),
+        None => format!("{os}/{arch}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_arch() {
+        for (arch, variant, expected_arch, expected_variant) in [
+            ("x86_64", None, Arch::Amd64, None),
+            ("amd64", None, Arch::Amd64, None),
+            ("x86", None, Arch::i386, None),
+            ("i686", None, Arch::i386, None),
+            ("386", None, Arch::i386, None),
+            ("aarch64", None, Arch::ARM64, Some("v8")),
+            ("arm64", Some("8"), Arch::ARM64, Some("v8")),
+            ("arm64", Some("v8.0"), Arch::ARM64, Some("v8")),
+            ("ARM64", Some("v9"), Arch::ARM64, Some("v9")),
+            ("arm", None, Arch::ARM, Some("v7")),
+            ("arm", Some("6"), Arch::ARM, Some("v6")),
+            ("arm", Some("v5"), Arch::ARM, Some("v5")),
+            ("armv6l", None, Arch::ARM, Some("v6")),
+            ("armv7l", None, Arch::ARM, Some("v7")),
+            ("armhf", None, Arch::ARM, Some("v7")),
+            ("armel", None, Arch::ARM, Some("v6")),
+            ("powerpc64le", None, Arch::PowerPC64le, None),
+            ("ppc64le", None, Arch::PowerPC64le, None),
+            ("s390x", None, Arch::s390x, None),
+            ("riscv64", None, Arch::RISCV64, None),
+            ("loongarch64", None, Arch::LoongArch64, None),
+            ("sparc64", None, Arch::SPARC64, None),
+            ("m68k", None, Arch::Other("m68k".to_string()), None),
+        ] {
+            assert_eq!(
+                normalize_arch(arch, variant),
+                (expected_arch, expected_variant.map(str::to_string)),
+                "{arch} {variant:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_os() {
+        assert_eq!(normalize_os("linux"), Os::Linux);
+        assert_eq!(normalize_os("macos"), Os::Darwin);
+        assert_eq!(normalize_os("windows"), Os::Windows);
+    }
+
+    #[test]
+    fn test_arm_variant() {
+        assert_eq!(auxv_arm_variant("v7l").as_deref(), Some("v7"));
+        assert_eq!(auxv_arm_variant("v6l").as_deref(), Some("v6"));
+        assert_eq!(auxv_arm_variant("aarch64"), None);
+
+        let cpuinfo = "processor\t: 0\nmodel name\t: ARMv6-compatible processor rev 7 (v6l)\n\
+                       CPU architecture: 7\nCPU variant\t: 0x0\n";
+        assert_eq!(cpuinfo_arm_variant(cpuinfo).as_deref(), Some("v7"));
+        assert_eq!(
+            cpuinfo_arm_variant("CPU architecture: 5TEJ\n").as_deref(),
+            Some("v5")
+        );
+        assert_eq!(cpuinfo_arm_variant("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn test_host_platform() {
+        let platform = host_platform();
+        assert!(!matches!(platform.architecture(), Arch::Other(_)));
+        assert_eq!(*platform.os(), Os::default());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::blobs::BlobSource;
+use crate::deadline::Deadline;
+use crate::error::{Error, IoResultExt, Result};
+use crate::layer_digests::size_hint;
+use crate::report::{LayerCompression, LayerReport, LayerTiming};
+use crate::retry::retry;
+use crate::windows;
+use crate::{extract_layer, layer_applied, read_layer, LayerChanges, Layers, UnpackOptions};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Instant;
+use tar::Archive;
+
+/// Uncompressed layer content, held in memory until it outgrows a threshold and then in a file
+struct Spool {
+    buffer: Vec<u8>,
+    file: Option<File>,
+    threshold: usize,
+    path: PathBuf,
+}
+
+impl Spool {
+    /// Creates a spool for an archive whose size is `hint`, if it's known, so the buffer needn't
+    /// grow while it's written, unless it's spilled
+    fn new(path: PathBuf, threshold: usize, hint: Option<u64>) -> Self {
+        let capacity = hint.map_or(0, |hint| hint.min(threshold as u64) as usize);
+        Self {
+            buffer: Vec::with_capacity(capacity),
+            file: None,
+            threshold,
+            path,
+        }
+    }
+
+    fn into_reader(self) -> io::Result<Box<dyn io::Read + Send>> {
+        match self.file {
+            Some(mut file) => {
+                file.rewind()?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+            None => Ok(Box::new(Cursor::new(self.buffer))),
+        }
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.buffer.len() + buf.len() > self.threshold {
+            log::debug!("Spilling prefetched layer to {}", self.path.display());
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&self.path)?;
+            // The open handle is all that's needed, so don't leave the file behind
+            fs::remove_file(&self.path)?;
+            file.write_all(&self.buffer)?;
+            self.buffer = Vec::new();
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => self.buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Extracts `layers` in order onto `rootfs`, while a second thread reads, decompresses and
+/// verifies the next layer into a spool, so decompression overlaps with writing to disk.
+pub(crate) fn extract_layers(
+    blobs: &BlobSource,
+    layers: &Layers,
+    staging: &Path,
+    rootfs: &Path,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+) -> Result<LayerReport> {
+    let Layers {
+        descriptors: layers,
+        diff_ids,
+        skipped,
+        windows_dir,
+        checkpoints,
+    } = *layers;
+    // Hands over each layer once the previous one is written, so that only the layer being
+    // written and the next one are spooled at a time
+    let (sender, receiver) = mpsc::sync_channel(0);
+
+    let result = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            for (index, (descriptor, diff_id)) in layers.iter().zip(diff_ids).enumerate() {
+                if skipped[index] {
+                    continue;
+                }
+                let started = Instant::now();
+                let mut timing = LayerTiming::new(index);
+                let mut compression = LayerCompression::new(index);
+                let spool = deadline
+                    .map_or(Ok(()), Deadline::check)
+                    .and_then(|()| {
+                        // A failed attempt's spool is dropped, so there's nothing to undo
+                        retry(index, options, deadline, |_| {
+                            read_layer(
+                                blobs,
+                                layers,
+                                index,
+                                diff_id,
+                                options,
+                                deadline,
+                                &mut timing,
+                                &mut compression,
+                                |reader, _| {
+                                    let mut spool = Spool::new(
+                                        staging.join(format!("prefetch-{index}")),
+                                        options.prefetch_spool_size,
+                                        size_hint(descriptor),
+                                    );
+                                    // Errors may come from either side, but reading is far more likely
+                                    // to fail
+                                    io::copy(reader, &mut spool).map_err(Error::Archive)?;
+                                    Ok(spool)
+                                },
+                            )
+                        })
+                    })
+                    .map(|spool| (spool, timing, compression, started, Instant::now()))
+                    .map_err(|e| e.in_layer(index, descriptor));
+                let failed = spool.is_err();
+                // A send error means extraction has stopped
+                if sender.send(spool).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        // Take ownership of the receiver, so the prefetch thread is unblocked if we stop early
+        let receiver = receiver;
+        let mut report = LayerReport::default();
+        for (index, descriptor) in layers.iter().enumerate() {
+            if skipped[index] {
+                continue;
+            }
+            let waiting = Instant::now();
+            // The prefetch thread only exits early after sending an error
+            let (spool, mut timing, compression, started, prefetched) =
+                receiver.recv().expect("prefetch thread exited")?;
+            let extracting = Instant::now();
+            timing.write_blocked = extracting - waiting;
+            timing.read_blocked = extracting.saturating_duration_since(prefetched);
+            if let Some(deadline) = deadline {
+                deadline.check()?;
+            }
+            let mut reader = spool.into_reader().with_path(staging)?;
+            let (mut layer_report, windows) =
+                windows::flatten(&mut reader, windows_dir, index, |reader| {
+                    extract_layer(
+                        &mut Archive::new(reader),
+                        rootfs,
+                        index,
+                        &options.for_layer(descriptor),
+                        &mut LayerChanges::new(false),
+                        None,
+                    )
+                })
+                .map_err(|e| e.in_layer(index, descriptor))?;
+            layer_report.windows.extend(windows);
+            timing.write += extracting.elapsed();
+            timing.duration = started.elapsed();
+            layer_applied(
+                index,
+                descriptor,
+                &mut layer_report,
+                &timing,
+                rootfs,
+                options,
+                deadline,
+                checkpoints,
+            )?;
+            layer_report.timings.push(timing);
+            layer_report.compression.push(compression);
+            report.append(layer_report);
+        }
+        Ok(report)
+    });
+
+    if let Err(e) = fs::remove_dir_all(staging) {
+        log::warn!("Failed to remove staging directory: {e}");
+    }
+    result
+}
@@ -0,0 +1,190 @@
+//! Paths that layers mustn't modify, for [`crate::UnpackOptions::protected_paths`].
+
+use crate::error::{Error, Result};
+use crate::options::UnpackOptions;
+use crate::report::Warnings;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+/// A pattern matching paths in the rootfs, relative to its root, with a leading `/` ignored.
+///
+/// Each component is matched separately: `*` matches any run of bytes within a component, `?`
+/// any single byte, and a component that is only `**` matches any number of components,
+/// including none. A pattern protects the paths it matches and everything beneath them, so
+/// `etc/audit` protects the directory and its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobPattern {
+    pattern: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    /// `**`
+    AnyComponents,
+    Component(Vec<u8>),
+}
+
+impl GlobPattern {
+    /// Parses `pattern`, failing with [`Error::InvalidGlobPattern`] if it has no components, or
+    /// has `.` or `..` components
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        for component in pattern.split('/').filter(|c| !c.is_empty()) {
+            parts.push(match component {
+                "." | ".." => return Err(Error::InvalidGlobPattern(pattern.to_string())),
+                "**" => Part::AnyComponents,
+                component => Part::Component(component.as_bytes().to_vec()),
+            });
+        }
+        if parts.is_empty() {
+            return Err(Error::InvalidGlobPattern(pattern.to_string()));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            parts,
+        })
+    }
+
+    /// Returns whether the pattern matches `path` or one of its ancestors
+    pub fn protects(&self, path: &Path) -> bool {
+        let components = components(path);
+        (1..=components.len()).any(|len| matches(&self.parts, &components[..len]))
+    }
+
+    /// Returns whether the pattern may match paths beneath `dir`, which removing `dir` or its
+    /// contents would remove
+    fn protects_beneath(&self, dir: &Path) -> bool {
+        matches_beneath(&self.parts, &components(dir))
+    }
+}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// How an entry modifies the rootfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Modification {
+    /// A directory entry, which only changes the directory's metadata
+    Dir,
+    /// A non-directory entry or whiteout, which replaces or removes whatever is at its path,
+    /// along with everything beneath it
+    Replace,
+    /// An opaque whiteout, which removes the contents of its directory
+    Opaque,
+}
+
+/// Checks whether the entry at `path`, which modifies the normalized `target`, modifies a
+/// protected path. If it does, that's a warning, or with [`crate::Strictness::Strict`], an
+/// [`Error::ProtectedPath`]. Entries that replace or remove `target`, and opaque whiteouts of it,
+/// are also checked against protected paths beneath it, which they may remove.
+pub(crate) fn check(
+    options: &UnpackOptions,
+    path: &Path,
+    target: &Path,
+    modification: Modification,
+    warnings: &mut Warnings,
+) -> Result<()> {
+    let matched = options.protected_paths.iter().find(|pattern| {
+        pattern.protects(target)
+            || (modification != Modification::Dir && pattern.protects_beneath(target))
+    });
+    match matched {
+        Some(pattern) => warnings.protected_path(path, pattern),
+        None => Ok(()),
+    }
+}
+
+/// Returns the normal components of `path` as bytes
+fn components(path: &Path) -> Vec<&[u8]> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns whether `parts` match all of `path`
+fn matches(parts: &[Part], path: &[&[u8]]) -> bool {
+    match parts.split_first() {
+        None => path.is_empty(),
+        Some((Part::AnyComponents, rest)) => {
+            matches(rest, path) || (!path.is_empty() && matches(parts, &path[1..]))
+        }
+        Some((Part::Component(pattern), rest)) => match path.split_first() {
+            Some((name, path)) => component_matches(pattern, name) && matches(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Returns whether `parts` may match a path strictly beneath `path`
+fn matches_beneath(parts: &[Part], path: &[&[u8]]) -> bool {
+    match (parts.split_first(), path.split_first()) {
+        (None, _) => false,
+        (Some((Part::AnyComponents, _)), _) | (Some(_), None) => true,
+        (Some((Part::Component(pattern), rest)), Some((name, path))) => {
+            component_matches(pattern, name) && matches_beneath(rest, path)
+        }
+    }
+}
+
+/// Returns whether the component `name` matches `pattern`, with `*` and `?` wildcards
+fn component_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| component_matches(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && component_matches(rest, &name[1..]),
+        Some((byte, rest)) => name.first() == Some(byte) && component_matches(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> GlobPattern {
+        GlobPattern::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_protects() {
+        let audit = pattern("/etc/audit/");
+        assert!(audit.protects(Path::new("etc/audit")));
+        assert!(audit.protects(Path::new("etc/audit/auditd.conf")));
+        assert!(!audit.protects(Path::new("etc")));
+        assert!(!audit.protects(Path::new("etc/auditd")));
+
+        let ima = pattern("usr/lib*/ima");
+        assert!(ima.protects(Path::new("usr/lib64/ima/policy")));
+        assert!(!ima.protects(Path::new("usr/share/ima")));
+
+        let any = pattern("**/secret?");
+        assert!(any.protects(Path::new("secret1")));
+        assert!(any.protects(Path::new("a/b/secret2/c")));
+        assert!(!any.protects(Path::new("a/secret")));
+    }
+
+    #[test]
+    fn test_protects_beneath() {
+        let audit = pattern("etc/audit");
+        assert!(audit.protects_beneath(Path::new("")));
+        assert!(audit.protects_beneath(Path::new("etc")));
+        assert!(!audit.protects_beneath(Path::new("etc/audit")));
+        assert!(!audit.protects_beneath(Path::new("usr")));
+        assert!(pattern("**/audit").protects_beneath(Path::new("usr")));
+        assert!(pattern("e*/audit").protects_beneath(Path::new("etc")));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for invalid in ["", "/", "etc/../audit", "./etc"] {
+            assert!(GlobPattern::new(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! [`ImageReference`], the human-readable name of an image, for
+//! [`crate::UnpackOptions::reference`] and [`crate::unpack_ref`].
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::layout_path;
+use ocidir::oci_spec::image::{Digest, ImageIndex};
+use ocidir::oci_spec::runtime::Spec;
+use ocidir::OciDir;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// The annotation naming an image: its tag in an OCI layout's `index.json`, and in a bundle's
+/// `config.json`, the reference it was unpacked by
+pub(crate) const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// The longest name a reference may have, not counting its tag and digest
+const MAX_NAME_LEN: usize = 255;
+
+/// The longest tag a reference may have
+const MAX_TAG_LEN: usize = 128;
+
+/// A reference to an image, `name[:tag][@digest]`, such as `docker.io/library/alpine:3.20` or
+/// `registry.example.com:5000/team/app@sha256:...`, as the distribution spec defines them.
+///
+/// The name is an optional domain, with an optional port, followed by `/`-separated path
+/// components of lowercase letters and digits, separated within a component by `.`, `_`, `__`
+/// or dashes. The first component is taken to be a domain if it's followed by others and
+/// contains a `.` or `:`, or is `localhost`.
+///
+/// ```
+/// use oci_bundle::ImageReference;
+///
+/// let reference: ImageReference = "alpine".parse().unwrap();
+/// assert_eq!(reference.tag(), None);
+/// assert_eq!(reference.normalized().to_string(), "docker.io/library/alpine:latest");
+/// assert!("Alpine:3.20".parse::<ImageReference>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageReference {
+    name: String,
+    tag: Option<String>,
+    digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Parses `reference`, failing with [`Error::InvalidReference`] saying what's wrong with it
+    pub fn parse(reference: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::InvalidReference {
+            reference: reference.to_string(),
+            reason,
+        };
+        if reference.is_empty() {
+            return Err(invalid("it's empty".to_string()));
+        }
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => {
+                if digest.parse::<Digest>().is_err() {
+                    return Err(invalid(format!("{digest:?} isn't a valid digest")));
+                }
+                (rest, Some(digest.to_string()))
+            }
+            None => (reference, None),
+        };
+        // A `:` after the last `/` starts the tag, while one before it is the domain's port
+        let tag_start = rest
+            .rfind(':')
+            .filter(|&colon| rest[colon..].find('/').is_none());
+        let (name, tag) = match tag_start {
+            Some(colon) => {
+                let tag = &rest[colon + 1..];
+                if !is_valid_tag(tag) {
+                    return Err(invalid(format!(
+                        "{tag:?} isn't a valid tag, of up to {MAX_TAG_LEN} letters, digits, \
+                         '_', '.' and '-', not starting with '.' or '-'"
+                    )));
+                }
+                (&rest[..colon], Some(tag.to_string()))
+            }
+            None => (rest, None),
+        };
+        if name.len() > MAX_NAME_LEN {
+            return Err(invalid(format!(
+                "its name is longer than {MAX_NAME_LEN} characters"
+            )));
+        }
+        let (domain, path) = split_domain(name);
+        if let Some(domain) = domain {
+            if !is_valid_domain(domain) {
+                return Err(invalid(format!("{domain:?} isn't a valid domain")));
+            }
+        }
+        for component in path.split('/') {
+            if component.is_empty() {
+                return Err(invalid("its name has an empty component".to_string()));
+            }
+            if !is_valid_component(component) {
+                return Err(invalid(format!(
+                    "{component:?} isn't a valid name component, of lowercase letters and \
+                     digits separated by '.', '_', '__' or dashes"
+                )));
+            }
+        }
+        Ok(Self {
+            name: name.to_string(),
+            tag,
+            digest,
+        })
+    }
+
+    /// The name, including the domain if there is one
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The domain of the registry, with its port, if the name has one
+    pub fn domain(&self) -> Option<&str> {
+        split_domain(&self.name).0
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// The digest of the image's manifest, e.g. `sha256:...`
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    /// Returns the reference as Docker completes it: names without a domain are on `docker.io`,
+    /// where those with a single path component are in `library/`, and references with neither
+    /// a tag nor a digest are tagged `latest`
+    pub fn normalized(&self) -> Self {
+        let (domain, path) = split_domain(&self.name);
+        let domain = match domain {
+            None | Some("index.docker.io") => "docker.io",
+            Some(domain) => domain,
+        };
+        let name = if domain == "docker.io" && !path.contains('/') {
+            format!("{domain}/library/{path}")
+        } else {
+            format!("{domain}/{path}")
+        };
+        let tag = match (&self.tag, &self.digest) {
+            (None, None) => Some("latest".to_string()),
+            (tag, _) => tag.clone(),
+        };
+        Self {
+            name,
+            tag,
+            digest: self.digest.clone(),
+        }
+    }
+
+    /// Returns whether the `org.opencontainers.image.ref.name` annotation of a manifest in an
+    /// OCI layout's index names this reference: the whole reference, or its tag
+    fn names(&self, ref_name: &str) -> bool {
+        ref_name == self.to_string() || self.tag.as_deref() == Some(ref_name)
+    }
+}
+
+impl FromStr for ImageReference {
+    type Err = Error;
+
+    fn from_str(reference: &str) -> Result<Self> {
+        Self::parse(reference)
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `name` into its domain, if it has one, and its path
+fn split_domain(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('/') {
+        Some((first, path))
+            if first.contains('.') || first.contains(':') || first == "localhost" =>
+        {
+            (Some(first), path)
+        }
+        _ => (None, name),
+    }
+}
+
+/// Returns whether `domain` is a host name or IPv4 address, with an optional port
+fn is_valid_domain(domain: &str) -> bool {
+    let (host, port) = match domain.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (domain, None),
+    };
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    let valid_port = |port: &str| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit());
+    host.split('.').all(valid_label) && port.into_iter().all(valid_port)
+}
+
+/// Returns whether `component` is runs of lowercase letters and digits, separated by `.`, `_`,
+/// `__` or any number of dashes
+fn is_valid_component(component: &str) -> bool {
+    let is_alphanumeric = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    let bytes = component.as_bytes();
+    let (Some(&first), Some(&last)) = (bytes.first(), bytes.last()) else {
+        return false;
+    };
+    if !is_alphanumeric(first) || !is_alphanumeric(last) {
+        return false;
+    }
+    let mut separator = String::new();
+    for &b in bytes {
+        if is_alphanumeric(b) {
+            let valid = matches!(separator.as_str(), "" | "." | "_" | "__")
+                || separator.bytes().all(|b| b == b'-');
+            if !valid {
+                return false;
+            }
+            separator.clear();
+        } else if matches!(b, b'.' | b'_' | b'-') {
+            separator.push(b as char);
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns whether `tag` is a valid tag
+fn is_valid_tag(tag: &str) -> bool {
+    let bytes = tag.as_bytes();
+    let is_word = |b: &u8| b.is_ascii_alphanumeric() || *b == b'_';
+    match bytes.split_first() {
+        Some((first, rest)) => {
+            bytes.len() <= MAX_TAG_LEN
+                && is_word(first)
+                && rest.iter().all(|b| is_word(b) || matches!(b, b'.' | b'-'))
+        }
+        None => false,
+    }
+}
+
+/// Returns the digest of the manifest that `reference` names in `oci_dir`: its digest if it has
+/// one, and otherwise that of the manifest in the layout's index whose
+/// [`REF_NAME_ANNOTATION`] names it
+pub(crate) fn resolve(oci_dir: &OciDir, reference: &ImageReference) -> Result<String> {
+    if let Some(digest) = reference.digest() {
+        return Ok(digest.to_string());
+    }
+    let not_found = || Error::ReferenceNotFound {
+        reference: reference.to_string(),
+    };
+    let bytes = match oci_dir.dir.read("index.json") {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+        Err(e) => return Err(e).with_path(layout_path(oci_dir).join("index.json")),
+    };
+    let index = ImageIndex::from_reader(bytes.as_slice())?;
+    index
+        .manifests()
+        .iter()
+        .find(|descriptor| {
+            descriptor
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+                .is_some_and(|ref_name| reference.names(ref_name))
+        })
+        .map(|descriptor| descriptor.digest().to_string())
+        .ok_or_else(not_found)
+}
+
+/// Records `reference` in the [`REF_NAME_ANNOTATION`] of `runtime_config`, if there is one
+pub(crate) fn annotate(mut runtime_config: Spec, reference: Option<&ImageReference>) -> Spec {
+    if let Some(reference) = reference {
+        let mut annotations = runtime_config.annotations().clone().unwrap_or_default();
+        annotations.insert(REF_NAME_ANNOTATION.to_string(), reference.to_string());
+        runtime_config.set_annotations(Some(annotations));
+    }
+    runtime_config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:4bcff63911fcb4448bd4fdacec207030997caf25e9bea4045fa6c8c44de311d1";
+
+    #[test]
+    fn test_parse() {
+        let parse = |reference: &str| {
+            let parsed = ImageReference::parse(reference).unwrap();
+            assert_eq!(parsed.to_string(), reference);
+            (
+                parsed.domain().map(str::to_string),
+                parsed.name().to_string(),
+                parsed.tag().map(str::to_string),
+                parsed.digest().map(str::to_string),
+            )
+        };
+        let owned = |s: &str| Some(s.to_string());
+        assert_eq!(parse("alpine"), (None, "alpine".to_string(), None, None));
+        assert_eq!(
+            parse("localhost:5000/team/my-app:v1.2_rc"),
+            (
+                owned("localhost:5000"),
+                "localhost:5000/team/my-app".to_string(),
+                owned("v1.2_rc"),
+                None
+            )
+        );
+        assert_eq!(
+            parse(&format!("quay.io/a__b/c.d@{DIGEST}")),
+            (
+                owned("quay.io"),
+                "quay.io/a__b/c.d".to_string(),
+                None,
+                owned(DIGEST)
+            )
+        );
+        assert_eq!(
+            parse(&format!("library/alpine:3.20@{DIGEST}")),
+            (
+                None,
+                "library/alpine".to_string(),
+                owned("3.20"),
+                owned(DIGEST)
+            )
+        );
+        assert_eq!(parse("a---b").1, "a---b");
+
+        let long_tag = format!("a:{}", "t".repeat(129));
+        let long_name = "a".repeat(256);
+        for invalid in [
+            "",
+            "Alpine",
+            "alpine:",
+            "alpine:-x",
+            "/alpine",
+            "team//app",
+            "a..b",
+            "a___b",
+            "-alpine",
+            "alpine@sha256:abc",
+            "exa_mple.com/app",
+            "example.com:port/app",
+            &long_tag,
+            &long_name,
+        ] {
+            let err = ImageReference::parse(invalid).unwrap_err();
+            assert!(
+                matches!(&err, Error::InvalidReference { reference, .. } if reference == invalid),
+                "{invalid}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalized() {
+        let normalized = |reference: &str| {
+            ImageReference::parse(reference)
+                .unwrap()
+                .normalized()
+                .to_string()
+        };
+        assert_eq!(normalized("alpine"), "docker.io/library/alpine:latest");
+        assert_eq!(normalized("user/app:1"), "docker.io/user/app:1");
+        assert_eq!(
+            normalized("index.docker.io/alpine:3.20"),
+            "docker.io/library/alpine:3.20"
+        );
+        assert_eq!(normalized("ghcr.io/org/app"), "ghcr.io/org/app:latest");
+        assert_eq!(
+            normalized(&format!("alpine@{DIGEST}")),
+            format!("docker.io/library/alpine@{DIGEST}")
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let reference = ImageReference::parse("example.com/app:1.0").unwrap();
+        assert!(reference.names("1.0"));
+        assert!(reference.names("example.com/app:1.0"));
+        assert!(!reference.names("example.com/app"));
+        assert!(!ImageReference::parse("app").unwrap().names("latest"));
+    }
+}
@@ -0,0 +1,426 @@
+//! Rewriting the ownership of a bundle's rootfs after it was unpacked, for
+//! [`crate::remap_bundle_ownership`].
+//!
+//! The rootfs is walked depth first in name order, so that every walk of an unchanged rootfs
+//! visits the same paths in the same order. Each directory's entries are remapped as a batch,
+//! and before a batch is started, the position it starts at and the ownership its entries had are
+//! saved in the bundle. A remap that's interrupted resumes from the batch it was in, remapping
+//! only those of its entries that still have their original ownership, so no path is remapped
+//! twice.
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::or_dot;
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::{Dir, MetadataExt, Permissions};
+use ocidir::oci_spec::runtime::{LinuxIdMapping, LinuxIdMappingBuilder, Spec};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The file in a bundle recording the progress of a remap, until it finishes
+pub(crate) const REMAP_PROGRESS: &str = ".remap-progress.json";
+
+/// A range of IDs in the container and the host IDs they map to, as in the `uidMappings` and
+/// `gidMappings` of a runtime config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct IdMapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+impl IdMapping {
+    pub fn new(container_id: u32, host_id: u32, size: u32) -> Self {
+        Self {
+            container_id,
+            host_id,
+            size,
+        }
+    }
+
+    fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.container_id)?;
+        (offset < self.size).then(|| self.host_id + offset)
+    }
+}
+
+impl From<&LinuxIdMapping> for IdMapping {
+    fn from(mapping: &LinuxIdMapping) -> Self {
+        Self::new(mapping.container_id(), mapping.host_id(), mapping.size())
+    }
+}
+
+/// What [`crate::remap_bundle_ownership`] changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RemapReport {
+    /// The number of paths whose owner or group was changed
+    pub remapped: u64,
+    /// The number of paths that are further hard links to a file already remapped, which were
+    /// skipped
+    pub skipped_hardlinks: u64,
+    /// Paths, relative to the rootfs, whose owner or group isn't in any mapping, so was left
+    /// unchanged
+    pub unmapped: Vec<PathBuf>,
+    /// Files, relative to the rootfs, whose setuid or setgid bits were cleared by the change of
+    /// owner, and have been set again
+    pub restored_setid: Vec<PathBuf>,
+    /// Whether an interrupted remap of the bundle was resumed
+    pub resumed: bool,
+}
+
+/// The progress of a remap, saved before each batch of entries is remapped
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    mappings: Vec<IdMapping>,
+    /// The position, in the walk of the rootfs, of the first entry of the batch
+    done: u64,
+    /// The owner and group of each entry of the batch before it was remapped
+    pending: Vec<(u32, u32)>,
+}
+
+pub(crate) fn remap(bundle: &Path, mappings: &[IdMapping]) -> Result<RemapReport> {
+    check(mappings)?;
+    let config_path = bundle.join("config.json");
+    let mut spec = Spec::load(&config_path)?;
+    let progress_path = bundle.join(REMAP_PROGRESS);
+    let mut report = RemapReport::default();
+    let progress = match fs::read(&progress_path) {
+        Ok(bytes) => {
+            let progress: Progress = serde_json::from_slice(&bytes)
+                .map_err(io::Error::from)
+                .with_path(&progress_path)?;
+            if progress.mappings != mappings {
+                return Err(Error::InterruptedRemap(bundle.to_path_buf()));
+            }
+            log::info!(
+                "Resuming remap of {} at {}",
+                bundle.display(),
+                progress.done
+            );
+            report.resumed = true;
+            progress
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if recorded(&spec).is_some_and(|recorded| recorded == mappings) {
+                log::info!("Bundle {} is already remapped", bundle.display());
+                return Ok(report);
+            }
+            Progress {
+                mappings: mappings.to_vec(),
+                done: 0,
+                pending: Vec::new(),
+            }
+        }
+        Err(e) => return Err(e).with_path(&progress_path),
+    };
+
+    let bundle_dir = Dir::open_ambient_dir(bundle, ambient_authority()).with_path(bundle)?;
+    let mut remapper = Remapper {
+        rootfs: bundle.join("rootfs"),
+        progress_path,
+        progress,
+        position: 0,
+        visited: HashSet::new(),
+        report,
+    };
+    remapper.batch(&bundle_dir, Path::new(""), vec!["rootfs".into()], true)?;
+
+    let mappings: Vec<_> = mappings
+        .iter()
+        .map(|mapping| {
+            LinuxIdMappingBuilder::default()
+                .container_id(mapping.container_id)
+                .host_id(mapping.host_id)
+                .size(mapping.size)
+                .build()
+        })
+        .collect::<std::result::Result<_, _>>()?;
+    let linux = spec.linux_mut().get_or_insert_with(Default::default);
+    linux.set_uid_mappings(Some(mappings.clone()));
+    linux.set_gid_mappings(Some(mappings));
+    spec.save(&config_path)?;
+    fs::remove_file(&remapper.progress_path).with_path(&remapper.progress_path)?;
+    let report = remapper.report;
+    log::info!(
+        "Remapped {} paths of {}, skipping {} hard links, with {} unmapped",
+        report.remapped,
+        bundle.display(),
+        report.skipped_hardlinks,
+        report.unmapped.len()
+    );
+    Ok(report)
+}
+
+/// Checks that each mapping is non-empty and within the range of IDs, and that no container ID
+/// is in more than one
+fn check(mappings: &[IdMapping]) -> Result<()> {
+    if mappings.is_empty() {
+        return Err(Error::InvalidIdMappings("no mappings".to_string()));
+    }
+    let mut ranges = Vec::new();
+    for mapping in mappings {
+        let end = |start: u32| start.checked_add(mapping.size);
+        if mapping.size == 0
+            || end(mapping.container_id).is_none()
+            || end(mapping.host_id).is_none()
+        {
+            return Err(Error::InvalidIdMappings(format!(
+                "{mapping:?} is empty or out of range"
+            )));
+        }
+        ranges.push((mapping.container_id, mapping.container_id + mapping.size));
+    }
+    ranges.sort_unstable();
+    if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err(Error::InvalidIdMappings(
+            "container IDs are in more than one mapping".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The mappings recorded in a runtime config, if its UID and GID mappings are the same
+fn recorded(spec: &Spec) -> Option<Vec<IdMapping>> {
+    let linux = spec.linux().as_ref()?;
+    let convert = |mappings: &Vec<LinuxIdMapping>| mappings.iter().map(IdMapping::from).collect();
+    let uids: Vec<_> = linux.uid_mappings().as_ref().map(convert)?;
+    let gids: Vec<_> = linux.gid_mappings().as_ref().map(convert)?;
+    (uids == gids).then_some(uids)
+}
+
+struct Remapper {
+    rootfs: PathBuf,
+    progress_path: PathBuf,
+    progress: Progress,
+    /// The position of the next entry in the walk of the rootfs
+    position: u64,
+    /// The device and inode of each file with more than one link that's been remapped
+    visited: HashSet<(u64, u64)>,
+    report: RemapReport,
+}
+
+impl Remapper {
+    /// Remaps the entries `names` of `dir`, which is `path` in the rootfs, or the bundle
+    /// directory if `is_bundle`, and then those beneath them
+    fn batch(
+        &mut self,
+        dir: &Dir,
+        path: &Path,
+        names: Vec<OsString>,
+        is_bundle: bool,
+    ) -> Result<()> {
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let path = if is_bundle {
+                PathBuf::new()
+            } else {
+                path.join(&name)
+            };
+            let metadata = dir
+                .symlink_metadata(&name)
+                .with_path(self.rootfs.join(&path))?;
+            entries.push((name, path, metadata));
+        }
+        let start = self.position;
+        self.position += entries.len() as u64;
+
+        let done = self.progress.done;
+        let originals = if self.position <= done {
+            // Remapped before the remap was interrupted, but the hard links are still needed
+            for (_, _, metadata) in &entries {
+                if is_hardlinked(metadata) {
+                    self.visited.insert((metadata.dev(), metadata.ino()));
+                }
+            }
+            None
+        } else if start == done && !self.progress.pending.is_empty() {
+            if self.progress.pending.len() != entries.len() {
+                return Err(Error::InterruptedRemap(self.rootfs.clone()));
+            }
+            Some(std::mem::take(&mut self.progress.pending))
+        } else if start >= done {
+            Some(
+                entries
+                    .iter()
+                    .map(|(_, _, metadata)| (metadata.uid(), metadata.gid()))
+                    .collect(),
+            )
+        } else {
+            // The rootfs has changed since the remap was interrupted
+            return Err(Error::InterruptedRemap(self.rootfs.clone()));
+        };
+        if let Some(originals) = originals {
+            self.save(start, &originals)?;
+            for ((name, path, metadata), original) in entries.iter().zip(originals) {
+                self.remap(dir, name, path, metadata, original)?;
+            }
+        }
+
+        for (name, path, metadata) in entries {
+            if !metadata.is_dir() {
+                continue;
+            }
+            let full_path = self.rootfs.join(&path);
+            let subdir = dir.open_dir(&name).with_path(&full_path)?;
+            let mut names: Vec<OsString> = subdir
+                .entries()
+                .and_then(|entries| entries.map(|entry| Ok(entry?.file_name())).collect())
+                .with_path(&full_path)?;
+            names.sort_unstable();
+            self.batch(&subdir, &path, names, false)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the progress at the start of a batch at `position` of entries with `originals`
+    fn save(&mut self, position: u64, originals: &[(u32, u32)]) -> Result<()> {
+        self.progress.done = position;
+        self.progress.pending = originals.to_vec();
+        let json = serde_json::to_vec(&self.progress).expect("progress serializes");
+        let temp = self.progress_path.with_extension("tmp");
+        fs::write(&temp, json).with_path(&temp)?;
+        fs::rename(&temp, &self.progress_path).with_path(&self.progress_path)?;
+        self.progress.pending.clear();
+        Ok(())
+    }
+
+    /// Remaps the entry `name` of `dir`, `path` in the rootfs, unless it's already been remapped
+    fn remap(
+        &mut self,
+        dir: &Dir,
+        name: &OsStr,
+        path: &Path,
+        metadata: &ocidir::cap_std::fs::Metadata,
+        (uid, gid): (u32, u32),
+    ) -> Result<()> {
+        if is_hardlinked(metadata) && !self.visited.insert((metadata.dev(), metadata.ino())) {
+            self.report.skipped_hardlinks += 1;
+            return Ok(());
+        }
+        if (metadata.uid(), metadata.gid()) != (uid, gid) {
+            // Remapped before the remap was interrupted
+            return Ok(());
+        }
+        let map = |id| self.progress.mappings.iter().find_map(|m| m.map(id));
+        let (new_uid, new_gid) = (map(uid), map(gid));
+        if new_uid.is_none() || new_gid.is_none() {
+            self.report.unmapped.push(or_dot(path).to_path_buf());
+        }
+        let (new_uid, new_gid) = (new_uid.unwrap_or(uid), new_gid.unwrap_or(gid));
+        if (new_uid, new_gid) == (uid, gid) {
+            return Ok(());
+        }
+        let full_path = self.rootfs.join(path);
+        chown_at(dir, name, new_uid, new_gid).with_path(&full_path)?;
+        self.report.remapped += 1;
+
+        // Changing the owner of a file clears its setuid and setgid bits
+        let setid = metadata.mode() & (libc::S_ISUID | libc::S_ISGID);
+        if setid != 0 && metadata.is_file() {
+            let changed = dir.symlink_metadata(name).with_path(&full_path)?;
+            if changed.mode() & setid != setid {
+                let mode = fs::Permissions::from_mode(metadata.mode() & 0o7777);
+                dir.set_permissions(name, Permissions::from_std(mode))
+                    .with_path(&full_path)?;
+                self.report.restored_setid.push(path.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_hardlinked(metadata: &ocidir::cap_std::fs::Metadata) -> bool {
+    !metadata.is_dir() && metadata.nlink() > 1
+}
+
+/// Changes the owner and group of the entry `name` of `dir`, without following it if it's a
+/// symlink
+fn chown_at(dir: &Dir, name: &OsStr, uid: u32, gid: u32) -> io::Result<()> {
+    let name = CString::new(name.as_bytes())?;
+    let ret = unsafe {
+        libc::fchownat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            uid,
+            gid,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt as _;
+
+    #[test]
+    fn test_check() {
+        check(&[IdMapping::new(0, 100000, 65536)]).unwrap();
+        check(&[IdMapping::new(0, 1000, 1), IdMapping::new(1, 100000, 65535)]).unwrap();
+        for mappings in [
+            vec![],
+            vec![IdMapping::new(0, 100000, 0)],
+            vec![IdMapping::new(0, u32::MAX, 2)],
+            vec![IdMapping::new(0, 1000, 2), IdMapping::new(1, 100000, 10)],
+        ] {
+            check(&mappings).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let bundle = tempfile::tempdir().unwrap();
+        let bundle = bundle.path();
+        let rootfs = bundle.join("rootfs");
+        fs::create_dir(&rootfs).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(rootfs.join(name), name).unwrap();
+        }
+        Spec::default().save(bundle.join("config.json")).unwrap();
+        let owner = |name: &str| {
+            let metadata = fs::symlink_metadata(rootfs.join(name)).unwrap();
+            (metadata.uid(), metadata.gid())
+        };
+        let original = owner("a");
+
+        // Interrupted after remapping the root and the first of its entries
+        assert_eq!(original.0, original.1);
+        let mappings = vec![IdMapping::new(original.0, 1000, 1)];
+        let progress = Progress {
+            mappings: mappings.clone(),
+            done: 1,
+            pending: vec![original; 3],
+        };
+        fs::write(
+            bundle.join(REMAP_PROGRESS),
+            serde_json::to_vec(&progress).unwrap(),
+        )
+        .unwrap();
+        std::os::unix::fs::lchown(&rootfs, Some(1000), Some(1000)).unwrap();
+        std::os::unix::fs::lchown(rootfs.join("a"), Some(1000), Some(1000)).unwrap();
+
+        // Resuming with other mappings fails
+        let err = remap(bundle, &[IdMapping::new(original.0, 2000, 1)]).unwrap_err();
+        assert!(matches!(err, Error::InterruptedRemap(_)), "{err:?}");
+
+        let report = remap(bundle, &mappings).unwrap();
+        assert!(report.resumed);
+        assert_eq!(report.remapped, 2);
+        for name in ["", "a", "b", "c"] {
+            assert_eq!(owner(name), (1000, 1000), "{name}");
+        }
+        assert!(!bundle.join(REMAP_PROGRESS).exists());
+    }
+}
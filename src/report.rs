@@ -0,0 +1,710 @@
+use crate::bundle::Bundle;
+use crate::capabilities::FileCapability;
+use crate::copy::CopyStrategy;
+use crate::effective::EffectiveOptions;
+use crate::error::{Error, Result};
+use crate::hermetic::HermeticSubstitution;
+use crate::hygiene::HygieneFinding;
+use crate::options::Strictness;
+use crate::protected::GlobPattern;
+use crate::spec::SpecIssue;
+use crate::windows::WindowsLayer;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// Describes a successful unpack
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct UnpackReport {
+    /// Problems with the image that were tolerated, in the order they were found
+    pub warnings: Vec<Warning>,
+    /// The indices of layers skipped by [`crate::UnpackOptions::layer_decision`]
+    pub skipped_layers: Vec<usize>,
+    /// Entries whose mode bits were removed by [`crate::UnpackOptions::permission_policy`], in
+    /// the order they were extracted
+    pub stripped_permissions: Vec<StrippedPermissions>,
+    /// How long each extracted layer took, in order
+    pub layer_timings: Vec<LayerTiming>,
+    /// How each extracted layer was compressed, in order
+    pub layer_compression: Vec<LayerCompression>,
+    /// How long the whole unpack took
+    pub duration: Duration,
+    /// Whether the existing bundle was reused, as allowed by
+    /// [`crate::Overwrite::ReuseIfMatching`], without extracting any layers
+    pub reused: bool,
+    /// The bytes of files written by each layer, by index, that later layers overwrote or
+    /// removed, with [`crate::UnpackOptions::analyze_waste`]. Empty otherwise.
+    pub wasted_bytes: Vec<u64>,
+    /// The largest of the files counted in [`Self::wasted_bytes`], largest first, up to 10
+    pub largest_wasted_paths: Vec<WastedPath>,
+    /// Paths that differ from others only in their Unicode normalization form, in layer order,
+    /// with [`crate::UnicodePolicy::Warn`]. Empty otherwise.
+    pub unicode_collisions: Vec<UnicodeCollision>,
+    /// The issues [`crate::validate_spec`] found with the generated runtime config
+    pub spec_issues: Vec<SpecIssue>,
+    /// Whether the process's working directory was missing from the rootfs, so it was created,
+    /// as [`crate::RuntimeConfigOptions::create_working_dir`] allows
+    pub created_working_dir: bool,
+    /// The path in the container of the marker file written by
+    /// [`crate::RuntimeConfigOptions::container_env_marker`], if any
+    pub container_env_marker: Option<PathBuf>,
+    /// The paths each layer changed, by index, in the order of its entries, with
+    /// [`crate::UnpackOptions::record_changes`]. Empty otherwise, and for skipped layers.
+    pub changes: Vec<Vec<Change>>,
+    /// What was done with each extracted layer of a Windows image, in order, with
+    /// [`crate::UnpackOptions::windows_layers`]. Empty otherwise.
+    pub windows_layers: Vec<WindowsLayer>,
+    /// The image the rootfs was packed into, with [`crate::UnpackOptions::rootfs_image`]
+    #[cfg(feature = "rootfs-image")]
+    pub rootfs_image: Option<crate::RootfsImage>,
+    /// The entries and whiteouts in each extracted layer, in order
+    pub layer_counts: Vec<LayerCounts>,
+    /// How the content of files was written for each layer read from the
+    /// [`crate::UnpackOptions::decompressed_blob_cache`], in order, unless
+    /// [`crate::UnpackOptions::cache_copy_strategy`] is [`CopyStrategy::Copy`]
+    pub layer_copies: Vec<LayerCopy>,
+    /// How the extracted layers differed from [`crate::UnpackOptions::expected`], in layer order.
+    /// Each is also a [`WarningKind::PlanDeviation`] warning.
+    pub plan_deviations: Vec<crate::PlanDeviation>,
+    /// The layers that were already in the base bundle given to [`crate::unpack_derived`], which
+    /// are the first of the image's, so weren't extracted. 0 if every layer was.
+    pub base_layers: usize,
+    /// The files in the rootfs with a `security.capability` attribute, in the order they were
+    /// extracted, including those whose capabilities were dropped
+    pub file_capabilities: Vec<FileCapability>,
+    /// The base image the image was built on, as returned by [`crate::base_image_of`]. `None`
+    /// if the bundle was reused.
+    pub base_image: Option<crate::BaseImageRef>,
+    /// The entries matched by [`crate::UnpackOptions::hygiene_rules`], in the order they were
+    /// extracted, with [`crate::UnpackOptions::hygiene`]. Empty otherwise.
+    pub hygiene_findings: Vec<HygieneFinding>,
+    /// The reference the image was unpacked by, from [`crate::UnpackOptions::reference`]
+    pub reference: Option<crate::ImageReference>,
+    /// Whether the layers were applied in memory before being written, as
+    /// [`crate::UnpackOptions::in_memory_max_bytes`] allows
+    pub applied_in_memory: bool,
+    /// The layers that an interrupted unpack had already applied or skipped, as recorded by
+    /// [`crate::UnpackOptions::checkpoint_layers`], which [`crate::resume_unpack`] resumed after.
+    /// 0 if every layer was extracted.
+    pub resumed_layers: usize,
+    /// What [`crate::RuntimeConfigOptions::hermetic`] used instead of what would have been
+    /// derived from the host. Empty otherwise.
+    pub hermetic_substitutions: Vec<HermeticSubstitution>,
+    /// The options the unpack used, once defaults were applied
+    pub effective_options: EffectiveOptions,
+    /// The bundle, opened once it was complete. `None` only if a bundle reused by
+    /// [`crate::Overwrite::ReuseIfMatching`] without [`crate::UnpackOptions::reuse_sanity_check`]
+    /// couldn't be opened.
+    pub bundle: Option<Bundle>,
+}
+
+impl UnpackReport {
+    pub(crate) fn extend(&mut self, layers: LayerReport) {
+        self.warnings.extend(layers.warnings);
+        self.stripped_permissions
+            .extend(layers.stripped_permissions);
+        self.layer_timings.extend(layers.timings);
+        self.layer_compression.extend(layers.compression);
+        self.windows_layers.extend(layers.windows);
+        self.layer_counts.extend(layers.counts);
+        self.layer_copies.extend(layers.copies);
+        self.hygiene_findings.extend(layers.hygiene);
+    }
+}
+
+/// The entries in a layer's archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerCounts {
+    pub layer_index: usize,
+    /// Every entry in the archive, including directories, whiteouts and those that were skipped
+    pub entries: u64,
+    /// The whiteouts and opaque whiteouts in the archive, not including invalid ones
+    pub whiteouts: u64,
+}
+
+/// How the content of a layer's files was written from its archive in the decompressed blob
+/// cache, as [`crate::UnpackOptions::cache_copy_strategy`] allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerCopy {
+    pub layer_index: usize,
+    /// [`CopyStrategy::Reflink`] if any file's content was cloned, otherwise
+    /// [`CopyStrategy::Copy`], or [`CopyStrategy::Auto`] if the layer has no file content
+    pub strategy: CopyStrategy,
+    /// The files whose content was cloned
+    pub reflinked_files: u64,
+    /// The files whose content was copied, as it couldn't be cloned
+    pub copied_files: u64,
+}
+
+/// How long a layer took to unpack, and where the time went.
+///
+/// With prefetched or parallel extraction, layers overlap, so their durations add up to more than
+/// the unpack's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerTiming {
+    pub layer_index: usize,
+    /// The size of the layer's blob
+    pub compressed_size: u64,
+    /// The size of the layer's archive, as far as it was extracted
+    pub uncompressed_size: u64,
+    /// The wall time from starting to read the layer until it was applied to the rootfs, including
+    /// any retries
+    pub duration: Duration,
+    /// Time spent reading, decompressing and hashing the blob, and verifying its digests
+    pub read: Duration,
+    /// Time spent writing the layer's entries, whether to the rootfs, a staging directory or a
+    /// prefetch spool, and applying its whiteouts
+    pub write: Duration,
+    /// Time the layer waited to be applied once it was read: for the previous layer to be
+    /// extracted when prefetching, or for lower layers to be merged when extracting in parallel
+    pub read_blocked: Duration,
+    /// Time spent waiting for the layer to be read before it could be applied, when prefetching
+    /// or extracting in parallel
+    pub write_blocked: Duration,
+}
+
+impl LayerTiming {
+    pub(crate) fn new(layer_index: usize) -> Self {
+        Self {
+            layer_index,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            duration: Duration::ZERO,
+            read: Duration::ZERO,
+            write: Duration::ZERO,
+            read_blocked: Duration::ZERO,
+            write_blocked: Duration::ZERO,
+        }
+    }
+
+    /// The uncompressed bytes unpacked per second of [`Self::duration`]
+    pub fn throughput(&self) -> f64 {
+        crate::timing::throughput(self.uncompressed_size, self.duration)
+    }
+}
+
+/// How a layer's blob was compressed.
+///
+/// The byte counts are exact, as the whole blob and archive are read, even when
+/// [`crate::UnpackOptions::verify_digests`] isn't set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LayerCompression {
+    pub layer_index: usize,
+    /// The media type of the layer's descriptor
+    pub media_type: String,
+    /// The size of the layer's blob
+    pub compressed_bytes: u64,
+    /// The size of the layer's archive
+    pub uncompressed_bytes: u64,
+    /// Whether the blob has several gzip members, as eStargz layers do, as far as it was read,
+    /// or `None` if it wasn't opened, as the archive was read from the
+    /// [`crate::UnpackOptions::decompressed_blob_cache`]. Every member is decompressed either
+    /// way, and the diff ID is the digest of them all.
+    pub multi_member: Option<bool>,
+    /// The digests of the blob and archive that were verified, in the order they were checked.
+    /// Empty unless [`crate::UnpackOptions::verify_digests`] is set or the archive was read from
+    /// the decompressed blob cache.
+    pub verified_digests: Vec<crate::VerifiedDigest>,
+    /// The size of the archive given by the [`crate::UNCOMPRESSED_SIZE_ANNOTATION`] on the
+    /// layer's descriptor, if it has one that can be parsed
+    pub uncompressed_size_hint: Option<u64>,
+}
+
+impl LayerCompression {
+    pub(crate) fn new(layer_index: usize) -> Self {
+        Self {
+            layer_index,
+            media_type: String::new(),
+            compressed_bytes: 0,
+            uncompressed_bytes: 0,
+            multi_member: None,
+            verified_digests: Vec::new(),
+            uncompressed_size_hint: None,
+        }
+    }
+
+    /// The uncompressed size divided by the compressed size, or 0 for an empty blob
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 0.0;
+        }
+        self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+/// A file written by one layer that a later layer overwrote or removed, so that its content is
+/// in the image but not the rootfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WastedPath {
+    /// The index of the layer that wrote the file
+    pub layer_index: usize,
+    /// The path of the file, relative to the root
+    pub path: PathBuf,
+    /// The file's size
+    pub size: u64,
+    /// The index of the layer that overwrote or removed the file
+    pub removed_by: usize,
+}
+
+/// A path written or whited out by one layer that differs only in its Unicode normalization form
+/// from a path in the rootfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnicodeCollision {
+    pub layer_index: usize,
+    /// The path of the entry, or of the whiteout's target, relative to the root
+    pub path: PathBuf,
+    /// The index of the layer that wrote the path it collides with
+    pub existing_layer_index: usize,
+    /// The path it collides with, relative to the root
+    pub existing_path: PathBuf,
+}
+
+impl fmt::Display for UnicodeCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in layer {} differs only in Unicode normalization from {} in layer {}",
+            self.path.display(),
+            self.layer_index,
+            self.existing_path.display(),
+            self.existing_layer_index
+        )
+    }
+}
+
+/// An entry unpacked without some of the mode bits recorded in its layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrippedPermissions {
+    pub layer_index: usize,
+    /// The path of the entry in the layer, relative to the root
+    pub path: PathBuf,
+    /// The mode recorded in the layer, including the setuid, setgid and sticky bits
+    pub mode: u32,
+    /// The bits that were removed from `mode`
+    pub stripped: u32,
+}
+
+impl fmt::Display for StrippedPermissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Stripped {:04o} from the mode {:04o} of {} in layer {}",
+            self.stripped,
+            self.mode,
+            self.path.display(),
+            self.layer_index
+        )
+    }
+}
+
+/// What was found while applying one or more layers
+#[derive(Debug, Default)]
+pub(crate) struct LayerReport {
+    pub(crate) warnings: Vec<Warning>,
+    pub(crate) stripped_permissions: Vec<StrippedPermissions>,
+    pub(crate) timings: Vec<LayerTiming>,
+    pub(crate) compression: Vec<LayerCompression>,
+    /// What each layer changed, when analyzing waste
+    pub(crate) changes: Vec<LayerLog>,
+    pub(crate) windows: Vec<WindowsLayer>,
+    pub(crate) counts: Vec<LayerCounts>,
+    pub(crate) copies: Vec<LayerCopy>,
+    /// The capabilities of the files each layer wrote, including those later layers replaced
+    pub(crate) capabilities: Vec<FileCapability>,
+    pub(crate) hygiene: Vec<HygieneFinding>,
+}
+
+impl LayerReport {
+    pub(crate) fn append(&mut self, mut other: LayerReport) {
+        self.warnings.append(&mut other.warnings);
+        self.stripped_permissions
+            .append(&mut other.stripped_permissions);
+        self.timings.append(&mut other.timings);
+        self.compression.append(&mut other.compression);
+        self.windows.append(&mut other.windows);
+        self.changes.append(&mut other.changes);
+        self.counts.append(&mut other.counts);
+        self.copies.append(&mut other.copies);
+        self.capabilities.append(&mut other.capabilities);
+        self.hygiene.append(&mut other.hygiene);
+    }
+}
+
+/// A path a layer changed, as listed in [`UnpackReport::changes`]. Paths are relative to the root.
+///
+/// Only the paths of the layer's entries are listed: not the parent directories created for
+/// them, nor the paths beneath a removed directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Change {
+    /// An entry was written at a path that didn't exist
+    Added(PathBuf),
+    /// An entry was written at a path that existed, whether from a lower layer or an earlier
+    /// entry in the same layer
+    Modified(PathBuf),
+    /// A whiteout removed the path, or an opaque whiteout removed the contents of the directory,
+    /// as written by lower layers
+    Removed(PathBuf, RemovalKind),
+}
+
+/// How a [`Change::Removed`] path was removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemovalKind {
+    /// A `.wh.` whiteout removed the path
+    Whiteout,
+    /// An opaque whiteout removed the directory's contents, leaving the directory
+    Opaque,
+}
+
+/// A change an entry makes to the rootfs, recorded to analyze the layers once all are applied
+#[derive(Debug)]
+pub(crate) enum EntryChange {
+    /// A non-directory entry of `size` bytes, which replaces whatever is at its path
+    Write { path: PathBuf, size: u64 },
+    /// A directory with the mtime in its header, which replaces a non-directory at its path
+    Dir { path: PathBuf, mtime: u64 },
+    /// An entry for the root of the layer, such as `./`, with the mtime in its header
+    Root { mtime: u64 },
+    /// A whiteout of a path from lower layers
+    Whiteout(PathBuf),
+    /// An opaque whiteout of a directory's contents from lower layers
+    Opaque(PathBuf),
+}
+
+/// The changes made by a layer, in the order of its entries
+#[derive(Debug)]
+pub(crate) struct LayerLog {
+    pub(crate) layer_index: usize,
+    pub(crate) changes: Vec<EntryChange>,
+}
+
+/// A problem with an entry in a layer that was tolerated rather than treated as an error.
+///
+/// With [`Strictness::Strict`], these are returned as [`Error::Warning`] instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub layer_index: usize,
+    /// The path of the entry in the layer, relative to the root
+    pub path: PathBuf,
+    pub kind: WarningKind,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if matches!(
+            self.kind,
+            WarningKind::MissingCreatedTime
+                | WarningKind::BaseImageMismatch
+                | WarningKind::BeforeConfigHookFailed
+        ) {
+            // Warnings about the whole image have no layer
+            return write!(f, "{}", self.kind);
+        }
+        if self.path.as_os_str().is_empty() {
+            // Warnings about whole layers have no path
+            return write!(f, "{} in layer {}", self.kind, self.layer_index);
+        }
+        write!(
+            f,
+            "{} {} in layer {}",
+            self.kind,
+            self.path.display(),
+            self.layer_index
+        )
+    }
+}
+
+/// The kind of problem a [`Warning`] describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The entry's path contains `..`, so it was skipped
+    UnsafePath,
+    /// The whiteout's target doesn't exist, so there was nothing to remove
+    DanglingWhiteout,
+    /// The entry is named `.wh.`, a whiteout with no target, so it was skipped
+    InvalidWhiteout,
+    /// The [`crate::UnpackOptions::content_inspector`] failed on the file, which was extracted
+    /// regardless
+    InspectionFailed,
+    /// The history entry that created the layer is older than the one that created the layer
+    /// below it, found by [`crate::UnpackOptions::check_layer_order`]. The path is empty.
+    HistoryOutOfOrder,
+    /// An annotation on the layer's descriptor gives a digest with an algorithm that isn't
+    /// supported, so it wasn't verified. The path is empty.
+    UnsupportedDigestAlgorithm,
+    /// The image config has no `created` time that can be parsed, so
+    /// [`crate::TimestampSource::ImageCreated`] used the epoch. The warning is about the whole
+    /// image, so its layer index is 0 and its path is empty.
+    MissingCreatedTime,
+    /// The layer's entries, whiteouts or uncompressed bytes differ from those in
+    /// [`crate::UnpackOptions::expected`], as described by [`UnpackReport::plan_deviations`].
+    /// The path is empty.
+    PlanDeviation,
+    /// The link's target contains a NUL byte, so it was skipped
+    InvalidLinkTarget,
+    /// The link's target is longer than [`crate::UnpackOptions::max_link_target_len`], so it was
+    /// skipped
+    LinkTargetTooLong,
+    /// The file's `security.capability` attribute couldn't be set, as the unpack isn't
+    /// privileged to or the filesystem doesn't support it, so it will run without the
+    /// capabilities it was given
+    CapabilityDropped,
+    /// The entry modifies a path matched by [`crate::UnpackOptions::protected_paths`], and was
+    /// applied regardless
+    ProtectedPath,
+    /// The file's POSIX ACLs, in its `system.posix_acl_access` or `system.posix_acl_default`
+    /// attribute, couldn't be set, as the unpack isn't privileged to or the filesystem doesn't
+    /// support them, nor applied as mode bits with [`crate::UnpackOptions::translate_acls`], so
+    /// its effective permissions differ from those the layer gave it
+    AclDropped,
+    /// The manifest's annotations and the config's labels name different base images, so the
+    /// manifest's were used, as described for [`crate::base_image_of`]. The warning is about the
+    /// whole image, so its layer index is 0 and its path is empty.
+    BaseImageMismatch,
+    /// The [`crate::UnpackOptions::after_layer`] hook failed after the layer was applied, and the
+    /// unpack continued regardless. The path is empty.
+    AfterLayerHookFailed,
+    /// The [`crate::UnpackOptions::before_config`] hook failed, and the unpack continued
+    /// regardless. The warning is about the whole bundle, so its layer index is 0 and its path is
+    /// empty.
+    BeforeConfigHookFailed,
+    /// An annotation on the layer's descriptor gives a digest of its archive that differs from
+    /// its diff ID in the image config, which is what's verified, so the annotation has been
+    /// tampered with or is stale. The path is empty.
+    AnnotatedDiffIdMismatch,
+    /// The size of the layer's archive differs from that given by the
+    /// [`crate::UNCOMPRESSED_SIZE_ANNOTATION`] on its descriptor. The path is empty.
+    UncompressedSizeMismatch,
+    /// An extended attribute of the entry, other than its capabilities or ACLs, couldn't be set,
+    /// as the unpack isn't privileged to or the filesystem doesn't support it, so it was skipped
+    XattrDropped,
+    /// The entry is a device node, which the unpack isn't privileged to create, so it was
+    /// skipped. [`crate::OwnershipMode::Emulate`] writes device nodes without privilege.
+    DeviceNodeSkipped,
+    /// The layer's media type isn't that of a layer, as for attestations and other artifacts
+    /// listed among an image's layers, so it was skipped along with its diff ID. Layers in formats
+    /// that can't be unpacked fail with [`crate::Error::UnsupportedMediaType`] regardless. The
+    /// path is empty.
+    UnsupportedMediaType,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WarningKind::UnsafePath => "Skipped unsafe path",
+            WarningKind::DanglingWhiteout => "Nothing to remove for whiteout",
+            WarningKind::InvalidWhiteout => "Skipped whiteout with no target",
+            WarningKind::InspectionFailed => "Failed to inspect",
+            WarningKind::HistoryOutOfOrder => "History out of order",
+            WarningKind::UnsupportedDigestAlgorithm => "Skipped digest with unsupported algorithm",
+            WarningKind::MissingCreatedTime => "No image creation time",
+            WarningKind::PlanDeviation => "Extraction differs from the plan",
+            WarningKind::InvalidLinkTarget => "Skipped link with a NUL in its target",
+            WarningKind::LinkTargetTooLong => "Skipped link with too long a target",
+            WarningKind::CapabilityDropped => "Dropped the capabilities of",
+            WarningKind::ProtectedPath => "Modified protected path",
+            WarningKind::AclDropped => "Dropped the ACLs of",
+            WarningKind::BaseImageMismatch => "Manifest and config disagree about the base image",
+            WarningKind::AfterLayerHookFailed => "The after_layer hook failed",
+            WarningKind::BeforeConfigHookFailed => "The before_config hook failed",
+            WarningKind::AnnotatedDiffIdMismatch => "Annotated digest differs from the diff ID",
+            WarningKind::UncompressedSizeMismatch => "Archive size differs from the annotated size",
+            WarningKind::XattrDropped => "Dropped extended attributes of",
+            WarningKind::DeviceNodeSkipped => "Skipped device node",
+            WarningKind::UnsupportedMediaType => "Skipped layer with unsupported media type",
+        })
+    }
+}
+
+/// Collects the warnings raised while unpacking a layer, or turns them into errors, along with
+/// the permissions stripped from its entries
+pub(crate) struct Warnings {
+    strictness: Strictness,
+    layer_index: usize,
+    warnings: Vec<Warning>,
+    stripped_permissions: Vec<StrippedPermissions>,
+    changes: Option<Vec<EntryChange>>,
+    counts: LayerCounts,
+    copy: Option<LayerCopy>,
+    capabilities: Vec<FileCapability>,
+    hygiene: Vec<HygieneFinding>,
+}
+
+impl Warnings {
+    pub(crate) fn new(strictness: Strictness, layer_index: usize) -> Self {
+        Self {
+            strictness,
+            layer_index,
+            warnings: Vec::new(),
+            stripped_permissions: Vec::new(),
+            changes: None,
+            counts: LayerCounts {
+                layer_index,
+                ..LayerCounts::default()
+            },
+            copy: None,
+            capabilities: Vec::new(),
+            hygiene: Vec::new(),
+        }
+    }
+
+    /// Counts an entry of the layer's archive
+    pub(crate) fn entry(&mut self) {
+        self.counts.entries += 1;
+    }
+
+    /// Counts a whiteout in the layer's archive
+    pub(crate) fn whiteout(&mut self) {
+        self.counts.whiteouts += 1;
+    }
+
+    /// Records how the content of the layer's files was written from its cached archive
+    pub(crate) fn copied(&mut self, copy: LayerCopy) {
+        self.copy = Some(copy);
+    }
+
+    /// Records the changes made by the layer's entries, for [`crate::UnpackOptions::analyze_waste`]
+    pub(crate) fn record_changes(mut self, record: bool) -> Self {
+        self.changes = record.then(Vec::new);
+        self
+    }
+
+    /// Records that an entry made `change`, if changes are recorded
+    pub(crate) fn change(&mut self, change: impl FnOnce() -> EntryChange) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(change());
+        }
+    }
+
+    pub(crate) fn warn(&mut self, path: &Path, kind: WarningKind) -> Result<()> {
+        let warning = Warning {
+            layer_index: self.layer_index,
+            path: report_path(path),
+            kind,
+        };
+        match self.strictness {
+            Strictness::Strict => Err(Error::Warning(warning)),
+            Strictness::Permissive => {
+                log::warn!("{warning}");
+                self.warnings.push(warning);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records that the file at `path` was given the capabilities in `value`, warning if they
+    /// were `dropped`, or with [`Strictness::Strict`], failing
+    pub(crate) fn capability(&mut self, path: &Path, value: &[u8], dropped: bool) -> Result<()> {
+        if dropped {
+            self.warn(path, WarningKind::CapabilityDropped)?;
+        }
+        let path = report_path(path);
+        match FileCapability::decode(self.layer_index, path.clone(), value, dropped) {
+            Some(capability) => self.capabilities.push(capability),
+            None => log::warn!("Can't decode the capabilities of {}", path.display()),
+        }
+        Ok(())
+    }
+
+    /// Records that the content inspector failed on `path` with `error`, returning it as an
+    /// error if strict
+    pub(crate) fn inspection_failed(&mut self, path: &Path, error: Error) -> Result<()> {
+        match self.strictness {
+            Strictness::Strict => Err(Error::ContentInspection {
+                path: report_path(path),
+                source: Box::new(error),
+            }),
+            Strictness::Permissive => {
+                log::warn!("Failed to inspect {}: {error}", path.display());
+                self.warn(path, WarningKind::InspectionFailed)
+            }
+        }
+    }
+
+    /// Records that the entry at `path` modifies a path protected by `pattern`, returning an
+    /// error if strict
+    pub(crate) fn protected_path(&mut self, path: &Path, pattern: &GlobPattern) -> Result<()> {
+        match self.strictness {
+            Strictness::Strict => Err(Error::ProtectedPath {
+                path: report_path(path),
+                pattern: pattern.to_string(),
+            }),
+            Strictness::Permissive => {
+                log::warn!("{} is protected by {pattern}", path.display());
+                self.warn(path, WarningKind::ProtectedPath)
+            }
+        }
+    }
+
+    /// Records that `stripped` was removed from the `mode` of the entry at `path`
+    pub(crate) fn strip(&mut self, path: &Path, mode: u32, stripped: u32) {
+        let stripped = StrippedPermissions {
+            layer_index: self.layer_index,
+            path: report_path(path),
+            mode,
+            stripped,
+        };
+        log::info!("{stripped}");
+        self.stripped_permissions.push(stripped);
+    }
+
+    /// Records that the entry at `path` was matched by the hygiene rule named `rule`, and whether
+    /// it was `stripped`
+    pub(crate) fn hygiene(&mut self, path: &Path, rule: &str, stripped: bool) {
+        let finding = HygieneFinding {
+            layer_index: self.layer_index,
+            path: report_path(path),
+            rule: rule.to_string(),
+            stripped,
+        };
+        log::warn!("{finding}");
+        self.hygiene.push(finding);
+    }
+
+    /// Returns the name of the rule that stripped the normalized `path` earlier in the layer, if
+    /// one did
+    pub(crate) fn hygiene_stripped(&self, path: &Path) -> Option<&str> {
+        self.hygiene
+            .iter()
+            .rev()
+            .find(|finding| finding.stripped && finding.path == path)
+            .map(|finding| finding.rule.as_str())
+    }
+
+    pub(crate) fn finish(self) -> LayerReport {
+        LayerReport {
+            warnings: self.warnings,
+            stripped_permissions: self.stripped_permissions,
+            timings: Vec::new(),
+            compression: Vec::new(),
+            windows: Vec::new(),
+            counts: vec![self.counts],
+            copies: self.copy.into_iter().collect(),
+            capabilities: self.capabilities,
+            hygiene: self.hygiene,
+            changes: self
+                .changes
+                .map(|changes| LayerLog {
+                    layer_index: self.layer_index,
+                    changes,
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// Strips root and `.` components from an archive path, keeping `..` so unsafe paths are reported
+/// as they are
+fn report_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
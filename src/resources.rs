@@ -0,0 +1,211 @@
+//! Resource limits that legacy Docker image configs record in `Memory`, `MemorySwap` and
+//! `CpuShares`, for [`crate::RuntimeConfigOptions::legacy_resource_fields`].
+
+use crate::error::{Error, Result};
+use crate::options::{CgroupVersion, RuntimeConfigOptions};
+use ocidir::oci_spec::runtime::{LinuxMemory, LinuxMemoryBuilder, Spec};
+use ocidir::oci_spec::OciSpecError;
+use serde_json::Value;
+
+/// The fields of `config` that are converted to `linux.resources`
+pub(crate) const FIELDS: [&str; 3] = ["Memory", "MemorySwap", "CpuShares"];
+
+/// The least and most CPU shares cgroup v1 accepts
+const MIN_SHARES: u64 = 2;
+const MAX_SHARES: u64 = 262_144;
+
+/// The limits from the legacy fields of an image config
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Limits {
+    /// Bytes of memory
+    memory: Option<i64>,
+    /// Bytes of memory and swap together, or -1 for unlimited swap
+    memory_swap: Option<i64>,
+    cpu_shares: Option<u64>,
+}
+
+impl Limits {
+    /// Reads the limits from the raw image config's `config`. Zero, which Docker records for
+    /// fields that weren't set, sets no limit.
+    fn read(raw_config: &[u8]) -> Result<Self> {
+        let value: Value =
+            serde_json::from_slice(raw_config).map_err(|e| Error::Spec(OciSpecError::SerDe(e)))?;
+        let Some(config) = value.get("config") else {
+            return Ok(Self::default());
+        };
+        let field = |name: &str| config.get(name).and_then(Value::as_i64);
+        Ok(Self {
+            memory: field("Memory").filter(|&bytes| bytes > 0),
+            memory_swap: field("MemorySwap").filter(|&bytes| bytes > 0 || bytes == -1),
+            cpu_shares: field("CpuShares")
+                .filter(|&shares| shares > 0)
+                .map(|shares| shares as u64),
+        })
+    }
+}
+
+/// Returns the cgroup v2 `cpu.weight` equivalent to cgroup v1 CPU `shares`, as runc and crun
+/// convert them, mapping the range of shares onto the range of weights, 1 to 10000
+fn cpu_weight(shares: u64) -> u64 {
+    let shares = shares.clamp(MIN_SHARES, MAX_SHARES);
+    1 + ((shares - MIN_SHARES) * 9999) / (MAX_SHARES - MIN_SHARES)
+}
+
+/// Sets `linux.resources` in `runtime_config` from the legacy fields of the raw image config,
+/// if [`RuntimeConfigOptions::legacy_resource_fields`] is set.
+///
+/// Memory limits are in bytes, as in both. CPU shares are set as `cpu.shares` for cgroup v1, and
+/// for cgroup v2, as the equivalent `cpu.weight` in `unified`.
+pub(crate) fn apply(
+    runtime_config: &mut Spec,
+    raw_config: &[u8],
+    options: &RuntimeConfigOptions,
+) -> Result<()> {
+    if !options.legacy_resource_fields {
+        return Ok(());
+    }
+    let limits = Limits::read(raw_config)?;
+    if limits == Limits::default() {
+        return Ok(());
+    }
+    let mut linux = runtime_config.linux().clone().unwrap_or_default();
+    let mut resources = linux.resources().clone().unwrap_or_default();
+    if limits.memory.is_some() || limits.memory_swap.is_some() {
+        let memory = (*resources.memory()).unwrap_or_default();
+        let memory = memory_limits(memory, limits.memory, limits.memory_swap)?;
+        resources.set_memory(Some(memory));
+    }
+    if let Some(shares) = limits.cpu_shares {
+        match options.cgroup_version {
+            CgroupVersion::V1 => {
+                let mut cpu = resources.cpu().clone().unwrap_or_default();
+                cpu.set_shares(Some(shares));
+                resources.set_cpu(Some(cpu));
+            }
+            CgroupVersion::V2 => {
+                let mut unified = resources.unified().clone().unwrap_or_default();
+                unified.insert("cpu.weight".to_string(), cpu_weight(shares).to_string());
+                resources.set_unified(Some(unified));
+            }
+        }
+    }
+    linux.set_resources(Some(resources));
+    runtime_config.set_linux(Some(linux));
+    Ok(())
+}
+
+/// Returns `memory` with its limit and swap limit replaced by those given. Its fields can only be
+/// set by building it again, so the rest are carried over to the builder.
+fn memory_limits(
+    memory: LinuxMemory,
+    limit: Option<i64>,
+    swap: Option<i64>,
+) -> Result<LinuxMemory> {
+    let mut builder = LinuxMemoryBuilder::default();
+    if let Some(limit) = limit.or(memory.limit()) {
+        builder = builder.limit(limit);
+    }
+    if let Some(reservation) = memory.reservation() {
+        builder = builder.reservation(reservation);
+    }
+    if let Some(swap) = swap.or(memory.swap()) {
+        builder = builder.swap(swap);
+    }
+    if let Some(kernel) = memory.kernel() {
+        builder = builder.kernel(kernel);
+    }
+    if let Some(kernel_tcp) = memory.kernel_tcp() {
+        builder = builder.kernel_tcp(kernel_tcp);
+    }
+    if let Some(swappiness) = memory.swappiness() {
+        builder = builder.swappiness(swappiness);
+    }
+    if let Some(disable) = memory.disable_oom_killer() {
+        builder = builder.disable_oom_killer(disable);
+    }
+    if let Some(use_hierarchy) = memory.use_hierarchy() {
+        builder = builder.use_hierarchy(use_hierarchy);
+    }
+    if let Some(check) = memory.check_before_update() {
+        builder = builder.check_before_update(check);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::runtime::SpecBuilder;
+
+    const CONFIG: &[u8] = br#"{
+        "architecture": "amd64",
+        "os": "linux",
+        "config": {
+            "Cmd": ["sh"],
+            "Memory": 536870912,
+            "MemorySwap": -1,
+            "CpuShares": 512
+        },
+        "rootfs": {"type": "layers", "diff_ids": []}
+    }"#;
+
+    #[test]
+    fn test_cpu_weight() {
+        // Docker's default of 1024 shares maps to 39, well below cgroup v2's default weight of
+        // 100, as runc and crun map the ranges linearly rather than the defaults onto each other
+        assert_eq!(cpu_weight(1024), 39);
+        assert_eq!(cpu_weight(2), 1);
+        assert_eq!(cpu_weight(MAX_SHARES), 10000);
+        // Shares outside of cgroup v1's range are clamped to it
+        assert_eq!(cpu_weight(1), 1);
+        assert_eq!(cpu_weight(1 << 20), 10000);
+    }
+
+    #[test]
+    fn test_read() {
+        assert_eq!(
+            Limits::read(CONFIG).unwrap(),
+            Limits {
+                memory: Some(512 * 1024 * 1024),
+                memory_swap: Some(-1),
+                cpu_shares: Some(512),
+            }
+        );
+        let unset = br#"{"config": {"Memory": 0, "MemorySwap": 0, "CpuShares": 0}}"#;
+        assert_eq!(Limits::read(unset).unwrap(), Limits::default());
+        assert_eq!(Limits::read(b"{}").unwrap(), Limits::default());
+    }
+
+    #[test]
+    fn test_apply() {
+        let apply_with = |options: RuntimeConfigOptions| {
+            let mut spec = SpecBuilder::default().build().unwrap();
+            spec.set_linux(None);
+            apply(&mut spec, CONFIG, &options).unwrap();
+            spec.linux()
+                .as_ref()
+                .and_then(|linux| linux.resources().clone())
+        };
+        assert_eq!(apply_with(RuntimeConfigOptions::new()), None);
+
+        let v1 = apply_with(RuntimeConfigOptions::new().legacy_resource_fields(true)).unwrap();
+        let memory = v1.memory().unwrap();
+        assert_eq!(memory.limit(), Some(512 * 1024 * 1024));
+        assert_eq!(memory.swap(), Some(-1));
+        assert_eq!(v1.cpu().as_ref().unwrap().shares(), Some(512));
+        assert_eq!(v1.unified(), &None);
+
+        let v2 = apply_with(
+            RuntimeConfigOptions::new()
+                .legacy_resource_fields(true)
+                .cgroup_version(CgroupVersion::V2),
+        )
+        .unwrap();
+        assert_eq!(v2.memory().unwrap().limit(), Some(512 * 1024 * 1024));
+        assert_eq!(v2.cpu().as_ref().and_then(|cpu| cpu.shares()), None);
+        assert_eq!(
+            v2.unified().as_ref().unwrap()["cpu.weight"],
+            cpu_weight(512).to_string()
+        );
+    }
+}
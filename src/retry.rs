@@ -0,0 +1,73 @@
+use crate::deadline::Deadline;
+use crate::error::{BlobError, Error, Result};
+use crate::UnpackOptions;
+use std::io;
+
+/// Calls `f` until it succeeds, retrying failures that may be transient as configured by
+/// [`UnpackOptions::retries`]. `f` is passed the number of the attempt, starting from 0, and is
+/// responsible for undoing the effects of the previous, failed attempt.
+pub(crate) fn retry<T>(
+    index: usize,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+    mut f: impl FnMut(u32) -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 0;
+    let mut retried_corruption = false;
+    loop {
+        let e = match f(attempt) {
+            Ok(output) => return Ok(output),
+            Err(e) => e,
+        };
+        let retry = match classify(&e) {
+            Failure::Transient => true,
+            // A mismatch may be a torn read, but is far more likely to be corruption that
+            // won't go away, so only give it a second chance
+            Failure::Corruption => !std::mem::replace(&mut retried_corruption, true),
+            Failure::Permanent => false,
+        };
+        if !retry || attempt >= options.retry_attempts {
+            return Err(e);
+        }
+
+        let backoff = options.retry_backoff * 2u32.saturating_pow(attempt);
+        log::warn!("Retrying layer {index} in {backoff:?} after error: {e}");
+        std::thread::sleep(backoff);
+        if let Some(deadline) = deadline {
+            deadline.check()?;
+        }
+        attempt += 1;
+    }
+}
+
+enum Failure {
+    Transient,
+    Corruption,
+    Permanent,
+}
+
+fn classify(e: &Error) -> Failure {
+    match e {
+        Error::DigestMismatch { .. } | Error::SizeMismatch { .. } | Error::TocMismatch { .. } => {
+            Failure::Corruption
+        }
+        Error::Archive(e) | Error::Io { source: e, .. } if is_transient(e) => Failure::Transient,
+        Error::Blob {
+            source: BlobError::Other(ocidir::Error::Io(e)),
+            ..
+        } if is_transient(e) => Failure::Transient,
+        _ => Failure::Permanent,
+    }
+}
+
+/// Whether an I/O error is one that reading the same data again may not hit, such as a network
+/// filesystem or connection hiccup
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::Interrupted
+    ) || e.raw_os_error() == Some(libc::EIO)
+}
@@ -0,0 +1,173 @@
+//! Packing the unpacked rootfs into a read-only filesystem image, for runtimes such as microVMs
+//! that mount an image rather than sharing a directory.
+
+use crate::error::{Error, IoResultExt, Result};
+use ocidir::oci_spec::runtime::Spec;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The name of the filesystem image in a bundle
+pub(crate) const ROOTFS_IMAGE: &str = "rootfs.img";
+
+/// The filesystem of a rootfs image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RootfsImageFormat {
+    /// Built with `mksquashfs` from squashfs-tools
+    Squashfs,
+    /// Built with `mkfs.erofs` from erofs-utils
+    Erofs,
+}
+
+impl RootfsImageFormat {
+    /// The filesystem type to mount the image with
+    pub fn fs_type(&self) -> &'static str {
+        match self {
+            RootfsImageFormat::Squashfs => "squashfs",
+            RootfsImageFormat::Erofs => "erofs",
+        }
+    }
+
+    fn default_tool(&self) -> &'static str {
+        match self {
+            RootfsImageFormat::Squashfs => "mksquashfs",
+            RootfsImageFormat::Erofs => "mkfs.erofs",
+        }
+    }
+}
+
+/// Options controlling how [`crate::UnpackOptions::rootfs_image`] packs the rootfs into an image
+#[derive(Debug, Clone)]
+pub struct RootfsImageOptions {
+    pub(crate) format: RootfsImageFormat,
+    pub(crate) keep_rootfs_dir: bool,
+    pub(crate) build_time: u64,
+    pub(crate) tool: Option<PathBuf>,
+}
+
+impl RootfsImageOptions {
+    /// Creates the default options for an image in `format`
+    pub fn new(format: RootfsImageFormat) -> Self {
+        Self {
+            format,
+            keep_rootfs_dir: false,
+            build_time: 0,
+            tool: None,
+        }
+    }
+
+    /// Whether to keep the rootfs directory once the image is built. Otherwise it's left empty,
+    /// as a mount point for the image, and the runtime config's root is made read-only. Defaults
+    /// to false.
+    pub fn keep_rootfs_dir(mut self, keep: bool) -> Self {
+        self.keep_rootfs_dir = keep;
+        self
+    }
+
+    /// The time, in seconds since the epoch, recorded as the image's build time, so that
+    /// unpacking the same image always builds the same bytes. Files keep their own mtimes.
+    /// Defaults to 0.
+    pub fn build_time(mut self, seconds: u64) -> Self {
+        self.build_time = seconds;
+        self
+    }
+
+    /// The program that builds the image, taking the same arguments as `mksquashfs` or
+    /// `mkfs.erofs`. Defaults to that program, found in `PATH`.
+    pub fn tool(mut self, tool: impl Into<PathBuf>) -> Self {
+        self.tool = Some(tool.into());
+        self
+    }
+}
+
+/// A rootfs image built by [`crate::UnpackOptions::rootfs_image`], to be mounted read-only at the
+/// runtime config's root
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RootfsImage {
+    /// The image, in the bundle
+    pub path: PathBuf,
+    pub format: RootfsImageFormat,
+    /// The size of the image in bytes
+    pub size: u64,
+    /// Whether the rootfs directory was kept alongside the image
+    pub rootfs_dir_kept: bool,
+}
+
+/// Makes the runtime config's root read-only if the rootfs directory will only be a mount point
+/// for the image
+pub(crate) fn mount_read_only(
+    mut runtime_config: Spec,
+    options: Option<&RootfsImageOptions>,
+) -> Spec {
+    if options.is_some_and(|options| !options.keep_rootfs_dir) {
+        if let Some(root) = runtime_config.root_mut() {
+            root.set_readonly(Some(true));
+        }
+    }
+    runtime_config
+}
+
+/// Packs `rootfs` into an image in `bundle`, then empties `rootfs` unless it's kept
+pub(crate) fn build(
+    bundle: &Path,
+    rootfs: &Path,
+    options: &RootfsImageOptions,
+) -> Result<RootfsImage> {
+    let path = bundle.join(ROOTFS_IMAGE);
+    let tool = options
+        .tool
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(options.format.default_tool()));
+    let mut command = Command::new(&tool);
+    match options.format {
+        RootfsImageFormat::Squashfs => {
+            command
+                .arg(rootfs)
+                .arg(&path)
+                .args(["-noappend", "-no-progress", "-quiet", "-mkfs-time"])
+                .arg(options.build_time.to_string());
+        }
+        RootfsImageFormat::Erofs => {
+            command
+                .arg(format!("-T{}", options.build_time))
+                .arg("-U00000000-0000-0000-0000-000000000000")
+                .arg("--quiet")
+                .arg(&path)
+                .arg(rootfs);
+        }
+    }
+    log::debug!("Building rootfs image: {command:?}");
+    let output = command.output().map_err(|e| Error::RootfsImage {
+        tool: tool.clone(),
+        message: e.to_string(),
+    })?;
+    if !output.status.success() {
+        return Err(Error::RootfsImage {
+            tool,
+            message: format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    let size = fs::metadata(&path).with_path(&path)?.len();
+
+    if !options.keep_rootfs_dir {
+        fs::remove_dir_all(rootfs).with_path(rootfs)?;
+        fs::create_dir(rootfs).with_path(rootfs)?;
+    }
+    log::info!(
+        "Built {} rootfs image {} of {size} bytes",
+        options.format.fs_type(),
+        path.display()
+    );
+    Ok(RootfsImage {
+        path,
+        format: options.format,
+        size,
+        rootfs_dir_kept: options.keep_rootfs_dir,
+    })
+}
@@ -0,0 +1,151 @@
+//! Recording the owners of unpacked files in extended attributes rather than applying them, for
+//! [`crate::OwnershipMode::Emulate`].
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::OwnershipMode;
+use crate::write;
+use ocidir::cap_std::fs::{Dir, OpenOptions, OpenOptionsExt};
+use ocidir::oci_spec::runtime::Spec;
+use std::fs::{File, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tar::{Entry, EntryType};
+use xattr::FileExt;
+
+/// The extended attribute holding the owner of a file unpacked with
+/// [`crate::OwnershipMode::Emulate`]. Its value is a `Resource` protobuf message from
+/// <https://github.com/rootless-containers/proto>, with the uid and gid in fields 1 and 2.
+pub const ROOTLESS_XATTR: &str = "user.rootlesscontainers";
+
+/// The extended attribute of the regular file standing for a device node unpacked with
+/// [`crate::OwnershipMode::Emulate`]. Its value is the device's type and number as `mknod` takes
+/// them, such as `c 1 3` or `b 8 0`.
+pub const EMULATED_DEVICE_XATTR: &str = "user.oci-bundle.device";
+
+/// The annotation of the runtime config of a bundle unpacked with
+/// [`crate::OwnershipMode::Emulate`], whose value is the [`ROOTLESS_XATTR`] the owners of its
+/// files are kept in
+pub const EMULATED_OWNERSHIP_ANNOTATION: &str = "oci-bundle.emulated-ownership";
+
+/// Returns the value of the [`ROOTLESS_XATTR`] for `uid` and `gid`. As in proto3, fields with
+/// the default value of 0 are left out, so root's is empty.
+pub(crate) fn encode(uid: u32, gid: u32) -> Vec<u8> {
+    let mut message = Vec::new();
+    for (tag, value) in [(0x08, uid), (0x10, gid)] {
+        if value == 0 {
+            continue;
+        }
+        message.push(tag);
+        let mut value = value;
+        while value >= 0x80 {
+            message.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        message.push(value as u8);
+    }
+    message
+}
+
+/// Records `uid` and `gid` as the owner of `file`
+pub(crate) fn record(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    file.set_xattr(ROOTLESS_XATTR, &encode(uid, gid))
+}
+
+/// Copies the recorded owner of the directory at `src` to that at `dst`, for directories merged
+/// into ones already in the rootfs
+pub(crate) fn copy(src: &Path, dst: &Path) -> Result<()> {
+    if let Some(value) = xattr::get(src, ROOTLESS_XATTR).with_path(src)? {
+        xattr::set(dst, ROOTLESS_XATTR, &value).with_path(dst)?;
+    }
+    Ok(())
+}
+
+/// Adds the [`EMULATED_OWNERSHIP_ANNOTATION`] to `runtime_config` if ownership is emulated
+pub(crate) fn annotate(mut runtime_config: Spec, ownership: OwnershipMode) -> Spec {
+    if ownership == OwnershipMode::Emulate {
+        let mut annotations = runtime_config.annotations().clone().unwrap_or_default();
+        annotations.insert(
+            EMULATED_OWNERSHIP_ANNOTATION.to_string(),
+            ROOTLESS_XATTR.to_string(),
+        );
+        runtime_config.set_annotations(Some(annotations));
+    }
+    runtime_config
+}
+
+/// Returns whether entries of `entry_type` are written by [`write_device`]
+pub(crate) fn is_device(entry_type: EntryType) -> bool {
+    entry_type.is_character_special() || entry_type.is_block_special()
+}
+
+/// Writes the device node `entry` at `path`, which is normalized, as an empty regular file
+/// recording the device and its owner, without the mode bits in `mask`
+pub(crate) fn write_device<R: Read>(
+    root_dir: &Dir,
+    entry: &Entry<R>,
+    path: &Path,
+    mask: u32,
+) -> Result<()> {
+    let header = entry.header();
+    let kind = if header.entry_type().is_block_special() {
+        'b'
+    } else {
+        'c'
+    };
+    let number = |number: io::Result<Option<u32>>| {
+        number
+            .map(Option::unwrap_or_default)
+            .map_err(Error::Archive)
+    };
+    let device = format!(
+        "{kind} {} {}",
+        number(header.device_major())?,
+        number(header.device_minor())?
+    );
+    let id = |id: io::Result<u64>| {
+        id.and_then(|id| u32::try_from(id).map_err(io::Error::other))
+            .map_err(Error::Archive)
+    };
+    let (uid, gid) = (id(header.uid())?, id(header.gid())?);
+    let mode = header.mode().map_err(Error::Archive)? & 0o7777 & !mask;
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        root_dir.create_dir_all(parent).with_path(parent)?;
+    }
+    match root_dir.remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).with_path(path),
+        _ => {}
+    }
+    let file = root_dir
+        .open_with(
+            path,
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(mode & 0o777),
+        )
+        .with_path(path)?
+        .into_std();
+    file.set_permissions(Permissions::from_mode(mode))
+        .with_path(path)?;
+    record(&file, uid, gid).with_path(path)?;
+    file.set_xattr(EMULATED_DEVICE_XATTR, device.as_bytes())
+        .with_path(path)?;
+    let mtime = write::file_time(header.mtime().map_err(Error::Archive)?);
+    filetime::set_file_handle_times(&file, Some(mtime), Some(mtime)).with_path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(0, 0), b"");
+        assert_eq!(encode(1, 0), [0x08, 0x01]);
+        assert_eq!(encode(0, 5), [0x10, 0x05]);
+        assert_eq!(encode(1000, 1000), [0x08, 0xe8, 0x07, 0x10, 0xe8, 0x07]);
+        assert_eq!(encode(u32::MAX, 0), [0x08, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+    }
+}
@@ -0,0 +1,367 @@
+//! Checks of a runtime config against the rules of the runtime spec, and those common runtimes
+//! enforce beyond it.
+
+use crate::annotations;
+use crate::error::{Error, Result};
+use crate::options::{RuntimeConfigOptions, Strictness};
+use ocidir::oci_spec::runtime::{Capabilities, LinuxIdMapping, Spec};
+use std::collections::HashSet;
+use std::fmt;
+
+/// A problem with a runtime config found by [`validate_spec`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SpecIssue {
+    pub code: SpecIssueCode,
+    /// The field with the problem, as a JSON path in the runtime config, e.g.
+    /// `mounts[2].destination`
+    pub field: String,
+    pub message: String,
+}
+
+impl SpecIssue {
+    pub(crate) fn new(
+        code: SpecIssueCode,
+        field: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            code,
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SpecIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} [{}]", self.field, self.message, self.code)
+    }
+}
+
+/// The kind of problem a [`SpecIssue`] describes, which can be allowed with
+/// [`RuntimeConfigOptions::allow_spec_issue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SpecIssueCode {
+    /// `process.args` is missing or empty, so there's nothing to run
+    EmptyArgs,
+    /// `process.cwd` isn't an absolute path
+    RelativeCwd,
+    /// `root` or `root.path` is missing or empty
+    MissingRoot,
+    /// `root.path` is absolute, so it doesn't move with the bundle
+    AbsoluteRoot,
+    /// A mount's destination isn't an absolute path
+    RelativeMountDestination,
+    /// More than one mount has the same destination
+    DuplicateMountDestination,
+    /// A UID or GID mapping maps no IDs, or its range overflows
+    InvalidIdMapping,
+    /// UID or GID mappings overlap, inside or outside the container
+    OverlappingIdMappings,
+    /// An effective capability isn't permitted, or an ambient one isn't both permitted and
+    /// inheritable, which the kernel rejects
+    UnheldCapability,
+    /// An annotation key doesn't use reverse domain notation, such as `com.example.key`
+    InvalidAnnotationKey,
+    /// An annotation value has control characters, such as newlines, which
+    /// [`RuntimeConfigOptions::annotation_control_characters`] can strip or escape
+    AnnotationControlCharacter,
+    /// A key under `org.opencontainers.` differed from another only in case, so was dropped
+    DuplicateAnnotationKey,
+    /// An annotation value was truncated to [`RuntimeConfigOptions::max_annotation_value_len`]
+    AnnotationTooLong,
+    /// An annotation was dropped to keep the annotations within
+    /// [`RuntimeConfigOptions::max_annotations_size`]
+    AnnotationsTooLarge,
+}
+
+impl SpecIssueCode {
+    /// A stable name for the code, e.g. for allowlists in configuration files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpecIssueCode::EmptyArgs => "empty-args",
+            SpecIssueCode::RelativeCwd => "relative-cwd",
+            SpecIssueCode::MissingRoot => "missing-root",
+            SpecIssueCode::AbsoluteRoot => "absolute-root",
+            SpecIssueCode::RelativeMountDestination => "relative-mount-destination",
+            SpecIssueCode::DuplicateMountDestination => "duplicate-mount-destination",
+            SpecIssueCode::InvalidIdMapping => "invalid-id-mapping",
+            SpecIssueCode::OverlappingIdMappings => "overlapping-id-mappings",
+            SpecIssueCode::UnheldCapability => "unheld-capability",
+            SpecIssueCode::InvalidAnnotationKey => "invalid-annotation-key",
+            SpecIssueCode::AnnotationControlCharacter => "annotation-control-character",
+            SpecIssueCode::DuplicateAnnotationKey => "duplicate-annotation-key",
+            SpecIssueCode::AnnotationTooLong => "annotation-too-long",
+            SpecIssueCode::AnnotationsTooLarge => "annotations-too-large",
+        }
+    }
+}
+
+impl fmt::Display for SpecIssueCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Checks `spec` against the rules of the runtime spec that a runtime needs to start a container
+/// from it, and those that common runtimes such as runc and crun enforce beyond it, returning
+/// the problems found, in the order of the fields.
+///
+/// Capability names aren't checked, as [`Spec`] can't hold unknown ones.
+pub fn validate_spec(spec: &Spec) -> Result<Vec<SpecIssue>> {
+    let mut issues = Vec::new();
+
+    match spec.process() {
+        Some(process) => {
+            if process.args().as_ref().is_none_or(|args| args.is_empty()) {
+                issues.push(SpecIssue::new(
+                    SpecIssueCode::EmptyArgs,
+                    "process.args",
+                    "No command to run",
+                ));
+            }
+            if !process.cwd().is_absolute() {
+                issues.push(SpecIssue::new(
+                    SpecIssueCode::RelativeCwd,
+                    "process.cwd",
+                    format!("{} isn't absolute", process.cwd().display()),
+                ));
+            }
+            if let Some(capabilities) = process.capabilities() {
+                let permitted = capabilities.permitted().clone().unwrap_or_default();
+                let inheritable = capabilities.inheritable().clone().unwrap_or_default();
+                check_held(
+                    &mut issues,
+                    "process.capabilities.effective",
+                    capabilities.effective(),
+                    |capability| permitted.contains(capability),
+                    "permitted",
+                );
+                check_held(
+                    &mut issues,
+                    "process.capabilities.ambient",
+                    capabilities.ambient(),
+                    |capability| permitted.contains(capability) && inheritable.contains(capability),
+                    "permitted and inheritable",
+                );
+            }
+        }
+        None => issues.push(SpecIssue::new(
+            SpecIssueCode::EmptyArgs,
+            "process",
+            "No process to run",
+        )),
+    }
+
+    match spec.root() {
+        Some(root) if !root.path().as_os_str().is_empty() => {
+            if root.path().is_absolute() {
+                issues.push(SpecIssue::new(
+                    SpecIssueCode::AbsoluteRoot,
+                    "root.path",
+                    format!("{} isn't relative to the bundle", root.path().display()),
+                ));
+            }
+        }
+        _ => issues.push(SpecIssue::new(
+            SpecIssueCode::MissingRoot,
+            "root.path",
+            "No root filesystem",
+        )),
+    }
+
+    let mut destinations = HashSet::new();
+    for (i, mount) in spec.mounts().iter().flatten().enumerate() {
+        let destination = mount.destination();
+        if !destination.is_absolute() {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::RelativeMountDestination,
+                format!("mounts[{i}].destination"),
+                format!("{} isn't absolute", destination.display()),
+            ));
+        }
+        if !destinations.insert(destination) {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::DuplicateMountDestination,
+                format!("mounts[{i}].destination"),
+                format!("{} is mounted more than once", destination.display()),
+            ));
+        }
+    }
+
+    let mut annotations: Vec<_> = spec.annotations().iter().flatten().collect();
+    annotations.sort_unstable();
+    for (key, value) in annotations {
+        let field = format!("annotations[{key:?}]");
+        if !annotations::is_reverse_domain(key) {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::InvalidAnnotationKey,
+                &field,
+                "Key doesn't use reverse domain notation",
+            ));
+        }
+        if value.contains(char::is_control) {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::AnnotationControlCharacter,
+                field,
+                "Value has control characters",
+            ));
+        }
+    }
+
+    if let Some(linux) = spec.linux() {
+        check_id_mappings(&mut issues, "linux.uidMappings", linux.uid_mappings());
+        check_id_mappings(&mut issues, "linux.gidMappings", linux.gid_mappings());
+    }
+
+    Ok(issues)
+}
+
+/// Adds an issue for each capability in `set` that `held` is false for
+fn check_held(
+    issues: &mut Vec<SpecIssue>,
+    field: &str,
+    set: &Option<Capabilities>,
+    held: impl Fn(&ocidir::oci_spec::runtime::Capability) -> bool,
+    required: &str,
+) {
+    let mut unheld: Vec<_> = set
+        .iter()
+        .flatten()
+        .filter(|capability| !held(capability))
+        .map(|capability| capability.to_string())
+        .collect();
+    // Sets are unordered, but the issues shouldn't be
+    unheld.sort();
+    for capability in unheld {
+        issues.push(SpecIssue::new(
+            SpecIssueCode::UnheldCapability,
+            field,
+            format!("{capability} isn't {required}"),
+        ));
+    }
+}
+
+/// Adds issues for mappings that are empty or overflow, and for those overlapping an earlier one
+fn check_id_mappings(
+    issues: &mut Vec<SpecIssue>,
+    field: &str,
+    mappings: &Option<Vec<LinuxIdMapping>>,
+) {
+    // The valid mappings so far, as their container and host starts and size
+    let mut ranges: Vec<(u64, u64, u64)> = Vec::new();
+    for (i, mapping) in mappings.iter().flatten().enumerate() {
+        let size = u64::from(mapping.size());
+        let container = u64::from(mapping.container_id());
+        let host = u64::from(mapping.host_id());
+        if size == 0 || container + size > 1 << 32 || host + size > 1 << 32 {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::InvalidIdMapping,
+                format!("{field}[{i}]"),
+                format!(
+                    "Maps {size} IDs from {container} to {host}, which isn't a valid range of IDs"
+                ),
+            ));
+            continue;
+        }
+        let overlaps = |start: u64, other: u64, other_size: u64| {
+            start < other + other_size && other < start + size
+        };
+        if ranges
+            .iter()
+            .any(|&(other_container, other_host, other_size)| {
+                overlaps(container, other_container, other_size)
+                    || overlaps(host, other_host, other_size)
+            })
+        {
+            issues.push(SpecIssue::new(
+                SpecIssueCode::OverlappingIdMappings,
+                format!("{field}[{i}]"),
+                "Overlaps an earlier mapping",
+            ));
+            continue;
+        }
+        ranges.push((container, host, size));
+    }
+}
+
+/// Validates the runtime config generated for an image, returning the issues to report, after
+/// the `normalized` ones describing changes made while generating it, or failing with
+/// [`Error::InvalidSpec`] if any that aren't allowed are found with [`Strictness::Strict`]
+pub(crate) fn check(
+    spec: &Spec,
+    options: &RuntimeConfigOptions,
+    strictness: Strictness,
+    normalized: Vec<SpecIssue>,
+) -> Result<Vec<SpecIssue>> {
+    let mut issues = normalized;
+    issues.extend(validate_spec(spec)?);
+    if strictness == Strictness::Strict
+        && issues
+            .iter()
+            .any(|issue| !options.allowed_spec_issues.contains(&issue.code))
+    {
+        return Err(Error::InvalidSpec(issues));
+    }
+    for issue in &issues {
+        log::warn!("Generated runtime config has an issue: {issue}");
+    }
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ocidir::oci_spec::runtime::{LinuxBuilder, LinuxIdMappingBuilder, SpecBuilder};
+
+    fn mapping(container_id: u32, host_id: u32, size: u32) -> LinuxIdMapping {
+        LinuxIdMappingBuilder::default()
+            .container_id(container_id)
+            .host_id(host_id)
+            .size(size)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_id_mappings() {
+        let mut spec = SpecBuilder::default().build().unwrap();
+        assert_eq!(validate_spec(&spec).unwrap(), vec![]);
+
+        let linux = LinuxBuilder::default()
+            .uid_mappings(vec![
+                mapping(0, 100000, 65536),
+                mapping(65536, 100000, 1),
+                mapping(70000, 0, 0),
+                mapping(u32::MAX, 1, 2),
+                mapping(65536, 0, 1),
+            ])
+            .build()
+            .unwrap();
+        spec.set_linux(Some(linux));
+        let codes: Vec<_> = validate_spec(&spec)
+            .unwrap()
+            .into_iter()
+            .map(|issue| (issue.field, issue.code))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (
+                    "linux.uidMappings[1]".to_string(),
+                    SpecIssueCode::OverlappingIdMappings
+                ),
+                (
+                    "linux.uidMappings[2]".to_string(),
+                    SpecIssueCode::InvalidIdMapping
+                ),
+                (
+                    "linux.uidMappings[3]".to_string(),
+                    SpecIssueCode::InvalidIdMapping
+                ),
+            ]
+        );
+    }
+}
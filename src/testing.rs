@@ -0,0 +1,582 @@
+//! Builders for OCI image layouts, for testing code that unpacks images.
+//!
+//! Layers are described entry by entry in memory, so that images with device nodes, whiteouts,
+//! unusual ownership or xattrs can be built without fixtures on disk:
+//!
+//! ```
+//! use oci_bundle::testing::{EntrySpec, ImageBuilder, LayerBuilder};
+//!
+//! # fn main() -> oci_bundle::Result<()> {
+//! # let temp = std::env::temp_dir().join(format!("oci-bundle-doctest-{}", std::process::id()));
+//! let (oci_dir, manifest) = ImageBuilder::new()
+//!     .layer(
+//!         LayerBuilder::new()
+//!             .entry(EntrySpec::dir("etc"))
+//!             .entry(EntrySpec::file("etc/motd", "hello").mode(0o600)),
+//!     )
+//!     .layer(LayerBuilder::new().entry(EntrySpec::whiteout("etc/motd")))
+//!     .build(&temp.join("oci"))?;
+//! oci_bundle::unpack(&manifest, &oci_dir, &temp.join("bundle"))?;
+//! assert!(!temp.join("bundle/rootfs/etc/motd").exists());
+//! # std::fs::remove_dir_all(&temp).unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::bundle_archive::pax_record;
+use crate::error::{Error, IoResultExt, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::Dir;
+use ocidir::oci_spec::image::{
+    ImageConfiguration, ImageConfigurationBuilder, ImageManifest, MediaType, Platform,
+};
+use ocidir::{Layer, OciDir};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tar::{EntryType, Header};
+
+/// An entry in a layer's archive
+#[derive(Debug, Clone)]
+pub struct EntrySpec {
+    path: PathBuf,
+    entry_type: EntryType,
+    content: Vec<u8>,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    link_target: Option<PathBuf>,
+    device: Option<(u32, u32)>,
+    xattrs: Vec<(String, Vec<u8>)>,
+    #[cfg(feature = "estargz")]
+    toc_digest: Option<String>,
+}
+
+impl EntrySpec {
+    fn new(path: impl Into<PathBuf>, entry_type: EntryType, mode: u32) -> Self {
+        Self {
+            path: path.into(),
+            entry_type,
+            content: Vec::new(),
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            link_target: None,
+            device: None,
+            xattrs: Vec::new(),
+            #[cfg(feature = "estargz")]
+            toc_digest: None,
+        }
+    }
+
+    /// A regular file with mode `0o644`
+    pub fn file(path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) -> Self {
+        Self {
+            content: content.into(),
+            ..Self::new(path, EntryType::Regular, 0o644)
+        }
+    }
+
+    /// A directory with mode `0o755`
+    pub fn dir(path: impl Into<PathBuf>) -> Self {
+        Self::new(path, EntryType::Directory, 0o755)
+    }
+
+    /// A symbolic link to `target`
+    pub fn symlink(path: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Self {
+            link_target: Some(target.into()),
+            ..Self::new(path, EntryType::Symlink, 0o777)
+        }
+    }
+
+    /// A hard link to `target`, which is a path in the archive
+    pub fn hardlink(path: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Self {
+            link_target: Some(target.into()),
+            ..Self::new(path, EntryType::Link, 0o644)
+        }
+    }
+
+    /// A character device node
+    pub fn char_device(path: impl Into<PathBuf>, major: u32, minor: u32) -> Self {
+        Self {
+            device: Some((major, minor)),
+            ..Self::new(path, EntryType::Char, 0o666)
+        }
+    }
+
+    /// A block device node
+    pub fn block_device(path: impl Into<PathBuf>, major: u32, minor: u32) -> Self {
+        Self {
+            device: Some((major, minor)),
+            ..Self::new(path, EntryType::Block, 0o660)
+        }
+    }
+
+    /// A named pipe
+    pub fn fifo(path: impl Into<PathBuf>) -> Self {
+        Self::new(path, EntryType::Fifo, 0o644)
+    }
+
+    /// A whiteout, removing `path` from lower layers
+    pub fn whiteout(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let mut name = std::ffi::OsString::from(".wh.");
+        name.push(path.file_name().expect("whiteouts name a file"));
+        Self::new(path.with_file_name(name), EntryType::Regular, 0o644)
+    }
+
+    /// An opaque whiteout, hiding the contents of `dir` in lower layers
+    pub fn opaque_whiteout(dir: impl AsRef<Path>) -> Self {
+        Self::new(dir.as_ref().join(".wh..wh..opq"), EntryType::Regular, 0o644)
+    }
+
+    /// Sets the permission bits, including setuid, setgid and sticky
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the owning user and group
+    pub fn owner(mut self, uid: u64, gid: u64) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Sets the modification time, in seconds since the epoch. Defaults to 0.
+    pub fn mtime(mut self, mtime: u64) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Adds an extended attribute, recorded as a `SCHILY.xattr.` PAX header
+    pub fn xattr(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.xattrs.push((name.into(), value.into()));
+        self
+    }
+
+    /// Records `digest` as the digest of the entry's content in the table of contents of an
+    /// eStargz layer, rather than the actual digest, for testing verification of the contents
+    #[cfg(feature = "estargz")]
+    pub fn toc_digest(mut self, digest: impl Into<String>) -> Self {
+        self.toc_digest = Some(digest.into());
+        self
+    }
+
+    fn append<W: Write>(&self, tar: &mut tar::Builder<W>) -> std::io::Result<()> {
+        if !self.xattrs.is_empty() {
+            let mut records = Vec::new();
+            for (name, value) in &self.xattrs {
+                pax_record(&mut records, &format!("SCHILY.xattr.{name}"), value);
+            }
+            let mut header = Header::new_ustar();
+            header.set_entry_type(EntryType::XHeader);
+            header.set_size(records.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, "PaxHeader", records.as_slice())?;
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(self.entry_type);
+        header.set_mode(self.mode);
+        header.set_uid(self.uid);
+        header.set_gid(self.gid);
+        header.set_mtime(self.mtime);
+        header.set_size(self.content.len() as u64);
+        if let Some((major, minor)) = self.device {
+            header.set_device_major(major)?;
+            header.set_device_minor(minor)?;
+        }
+        match &self.link_target {
+            Some(target) => tar.append_link(&mut header, &self.path, target),
+            None => tar.append_data(&mut header, &self.path, self.content.as_slice()),
+        }
+    }
+}
+
+/// A layer, built from entries and directories on disk, and how its blob is compressed
+#[derive(Debug, Clone, Default)]
+pub struct LayerBuilder {
+    contents: Vec<LayerContent>,
+    raw: Option<Vec<u8>>,
+    media_type: Option<MediaType>,
+    annotations: HashMap<String, String>,
+    gzip_members: usize,
+    trailing: Vec<u8>,
+    #[cfg(feature = "estargz")]
+    estargz: bool,
+}
+
+#[derive(Debug, Clone)]
+enum LayerContent {
+    Entry(EntrySpec),
+    Dir(PathBuf),
+}
+
+impl LayerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A layer whose uncompressed content is exactly `archive`, which needn't be a valid tar
+    pub fn raw(archive: impl Into<Vec<u8>>) -> Self {
+        Self {
+            raw: Some(archive.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Appends an entry
+    pub fn entry(mut self, entry: EntrySpec) -> Self {
+        self.contents.push(LayerContent::Entry(entry));
+        self
+    }
+
+    /// Appends the contents of the directory at `path`, recursively, at the root of the layer
+    pub fn dir_all(mut self, path: impl Into<PathBuf>) -> Self {
+        self.contents.push(LayerContent::Dir(path.into()));
+        self
+    }
+
+    /// Sets the media type in the layer's descriptor. The blob is always a gzip compressed tar,
+    /// so this is for testing handling of other media types. Defaults to
+    /// [`MediaType::ImageLayerGzip`].
+    pub fn media_type(mut self, media_type: MediaType) -> Self {
+        self.media_type = Some(media_type);
+        self
+    }
+
+    /// Adds an annotation to the layer's descriptor
+    pub fn annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    /// Compresses the archive as `members` concatenated gzip members of roughly equal size,
+    /// rather than one. The diff ID is that of the whole archive.
+    pub fn gzip_members(mut self, members: usize) -> Self {
+        self.gzip_members = members;
+        self
+    }
+
+    /// Appends `trailing` to the blob after the gzip stream
+    pub fn trailing_bytes(mut self, trailing: impl Into<Vec<u8>>) -> Self {
+        self.trailing = trailing.into();
+        self
+    }
+
+    /// Writes the layer in the eStargz format, with a gzip member for each entry's content, a
+    /// table of contents and the [`crate::TOC_DIGEST_ANNOTATION`] annotation, unless that's set
+    /// explicitly. Files aren't split into chunks.
+    #[cfg(feature = "estargz")]
+    pub fn estargz(mut self) -> Self {
+        self.estargz = true;
+        self
+    }
+
+    /// Returns the layer's uncompressed archive
+    pub fn archive(&self) -> Result<Vec<u8>> {
+        if let Some(raw) = &self.raw {
+            return Ok(raw.clone());
+        }
+        let mut tar = tar::Builder::new(Vec::new());
+        for content in &self.contents {
+            match content {
+                LayerContent::Entry(entry) => entry.append(&mut tar),
+                LayerContent::Dir(dir) => tar.append_dir_all(".", dir),
+            }
+            .map_err(Error::Archive)?;
+        }
+        tar.into_inner().map_err(Error::Archive)
+    }
+
+    /// Writes the layer's blob to `oci_dir`, returning it with the annotations of its descriptor
+    fn write(&self, oci_dir: &OciDir) -> Result<(Layer, HashMap<String, String>)> {
+        let archive = self.archive()?;
+
+        #[cfg(feature = "estargz")]
+        if self.estargz {
+            return self.write_estargz(oci_dir, &archive);
+        }
+
+        let mut blob = oci_dir.create_blob()?;
+        let members = self.gzip_members.max(1);
+        let member_len = archive.len().div_ceil(members).max(1);
+        let mut chunks: Vec<_> = archive.chunks(member_len).collect();
+        chunks.resize(members, &[]);
+        for chunk in chunks {
+            let mut encoder = GzEncoder::new(&mut blob, Compression::default());
+            encoder.write_all(chunk).map_err(Error::Archive)?;
+            encoder.finish().map_err(Error::Archive)?;
+        }
+        blob.write_all(&self.trailing).map_err(Error::Archive)?;
+        let layer = Layer {
+            blob: blob.complete()?,
+            uncompressed_sha256: hex::encode(openssl::sha::sha256(&archive)).parse()?,
+        };
+        Ok((layer, self.annotations.clone()))
+    }
+
+    /// Writes `archive` as an eStargz blob: each entry's header and content in separate gzip
+    /// members, then a member with the TOC, and finally the footer pointing to it
+    #[cfg(feature = "estargz")]
+    fn write_estargz(
+        &self,
+        oci_dir: &OciDir,
+        archive: &[u8],
+    ) -> Result<(Layer, HashMap<String, String>)> {
+        let toc_digests: HashMap<_, _> = self
+            .contents
+            .iter()
+            .filter_map(|content| match content {
+                LayerContent::Entry(entry) => Some((entry.path.clone(), entry.toc_digest.clone()?)),
+                LayerContent::Dir(_) => None,
+            })
+            .collect();
+
+        let mut compressed = Vec::new();
+        let mut uncompressed = openssl::sha::Sha256::new();
+        let mut member = |compressed: &mut Vec<u8>, data: &[u8]| -> std::io::Result<()> {
+            uncompressed.update(data);
+            let mut encoder = GzEncoder::new(compressed, Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish().map(|_| ())
+        };
+        let mut toc_entries = Vec::new();
+        let mut end = 0;
+        let mut entries = tar::Archive::new(archive);
+        for entry in entries.entries().map_err(Error::Archive)? {
+            let entry = entry.map_err(Error::Archive)?;
+            let header = entry.header();
+            let path = entry.path().map_err(Error::Archive)?.into_owned();
+            let content_start = entry.raw_file_position() as usize;
+            let content_end = content_start + (entry.size() as usize).div_ceil(512) * 512;
+            member(&mut compressed, &archive[end..content_start]).map_err(Error::Archive)?;
+            let offset = compressed.len();
+            member(&mut compressed, &archive[content_start..content_end])
+                .map_err(Error::Archive)?;
+            end = content_end;
+
+            let entry_type = match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => "reg",
+                EntryType::Directory => "dir",
+                EntryType::Symlink => "symlink",
+                EntryType::Link => "hardlink",
+                EntryType::Char => "char",
+                EntryType::Block => "block",
+                EntryType::Fifo => "fifo",
+                _ => continue,
+            };
+            let name = path.to_string_lossy().trim_start_matches("./").to_string();
+            let mut toc_entry = serde_json::json!({
+                "name": name,
+                "type": entry_type,
+                "mode": header.mode().map_err(Error::Archive)?,
+                "uid": header.uid().map_err(Error::Archive)?,
+                "gid": header.gid().map_err(Error::Archive)?,
+            });
+            if let Some(target) = entry.link_name().map_err(Error::Archive)? {
+                toc_entry["linkName"] = target.to_string_lossy().into();
+            }
+            if entry_type == "reg" {
+                let content = &archive[content_start..content_start + entry.size() as usize];
+                let digest = match toc_digests.get(&path) {
+                    Some(digest) => digest.clone(),
+                    None => format!("sha256:{}", hex::encode(openssl::sha::sha256(content))),
+                };
+                toc_entry["size"] = entry.size().into();
+                toc_entry["offset"] = offset.into();
+                toc_entry["digest"] = digest.into();
+            }
+            toc_entries.push(toc_entry);
+        }
+
+        let toc = serde_json::to_vec(&serde_json::json!({
+            "version": 1,
+            "entries": toc_entries,
+        }))
+        .map_err(|e| Error::Archive(e.into()))?;
+        let mut toc_archive = tar::Builder::new(Vec::new());
+        let mut header = Header::new_ustar();
+        header.set_size(toc.len() as u64);
+        header.set_mode(0o444);
+        toc_archive
+            .append_data(&mut header, crate::estargz::TOC_NAME, toc.as_slice())
+            .map_err(Error::Archive)?;
+        let toc_offset = compressed.len();
+        member(
+            &mut compressed,
+            &toc_archive.into_inner().map_err(Error::Archive)?,
+        )
+        .map_err(Error::Archive)?;
+
+        // The footer's extra field is a single "SG" subfield holding the TOC's offset
+        let payload = format!("{toc_offset:016x}STARGZ");
+        let mut extra = b"SG".to_vec();
+        extra.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        extra.extend_from_slice(payload.as_bytes());
+        flate2::GzBuilder::new()
+            .extra(extra)
+            .write(&mut compressed, Compression::none())
+            .finish()
+            .map_err(Error::Archive)?;
+
+        let mut blob = oci_dir.create_blob()?;
+        blob.write_all(&compressed).map_err(Error::Archive)?;
+        let layer = Layer {
+            blob: blob.complete()?,
+            uncompressed_sha256: hex::encode(uncompressed.finish()).parse()?,
+        };
+        let mut annotations = self.annotations.clone();
+        annotations
+            .entry(crate::TOC_DIGEST_ANNOTATION.to_string())
+            .or_insert_with(|| format!("sha256:{}", hex::encode(openssl::sha::sha256(&toc))));
+        Ok((layer, annotations))
+    }
+}
+
+type ConfigCustomizer = Box<dyn FnOnce(&mut ImageConfiguration)>;
+
+/// An image, with its layers, config and platform, to be written to an OCI image layout
+pub struct ImageBuilder {
+    layers: Vec<LayerBuilder>,
+    config: ImageConfiguration,
+    customize: Option<ConfigCustomizer>,
+    raw_fields: Option<serde_json::Value>,
+    platform: Platform,
+    tag: Option<String>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self {
+            layers: Vec::new(),
+            config: ImageConfigurationBuilder::default()
+                .build()
+                .expect("the default config is valid"),
+            customize: None,
+            raw_fields: None,
+            platform: Platform::default(),
+            tag: None,
+        }
+    }
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer on top of those already added
+    pub fn layer(mut self, layer: LayerBuilder) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Sets the image config. Its diff IDs are replaced by those of the layers.
+    pub fn config(mut self, config: ImageConfiguration) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Modifies the image config after the layers' diff IDs have been added to it
+    pub fn customize_config(
+        mut self,
+        customize: impl FnOnce(&mut ImageConfiguration) + 'static,
+    ) -> Self {
+        self.customize = Some(Box::new(customize));
+        self
+    }
+
+    /// Merges `fields`, a JSON object, into the serialized image config, for fields that
+    /// [`ImageConfiguration`] doesn't know, such as those of legacy Docker configs. Nested objects
+    /// are merged, and other values replace those in the config.
+    pub fn raw_config_fields(mut self, fields: serde_json::Value) -> Self {
+        self.raw_fields = Some(fields);
+        self
+    }
+
+    /// Sets the platform of the manifest's entry in the layout's index
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Tags the manifest in the layout's index
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Writes the image to a new OCI image layout at `path`, returning the layout and manifest
+    pub fn build(self, path: &Path) -> Result<(OciDir, ImageManifest)> {
+        fs::create_dir_all(path).with_path(path)?;
+        let dir = Dir::open_ambient_dir(path, ambient_authority()).with_path(path)?;
+        let oci_dir = OciDir::ensure(&dir)?;
+
+        let mut manifest = ocidir::new_empty_manifest().build()?;
+        let mut config = self.config;
+        config.rootfs_mut().diff_ids_mut().clear();
+        for (index, layer) in self.layers.iter().enumerate() {
+            let (blob, annotations) = layer.write(&oci_dir)?;
+            oci_dir.push_layer(
+                &mut manifest,
+                &mut config,
+                blob,
+                &format!("layer {index}"),
+                Some(annotations).filter(|a| !a.is_empty()),
+            );
+            if let Some(media_type) = &layer.media_type {
+                manifest.layers_mut()[index].set_media_type(media_type.clone());
+            }
+        }
+        if let Some(customize) = self.customize {
+            customize(&mut config);
+        }
+
+        let descriptor = match self.raw_fields {
+            Some(fields) => {
+                let mut value = serde_json::to_value(&config)
+                    .map_err(|e| Error::Spec(ocidir::oci_spec::OciSpecError::SerDe(e)))?;
+                merge_json(&mut value, fields);
+                let config = oci_dir
+                    .write_json_blob(&value, MediaType::ImageConfig)?
+                    .build()?;
+                manifest.set_config(config);
+                oci_dir.insert_manifest(manifest, self.tag.as_deref(), self.platform)?
+            }
+            None => oci_dir.insert_manifest_and_config(
+                manifest,
+                config,
+                self.tag.as_deref(),
+                self.platform,
+            )?,
+        };
+        let manifest = ImageManifest::from_reader(oci_dir.read_blob(&descriptor)?)?;
+        Ok((oci_dir, manifest))
+    }
+}
+
+/// Merges `fields` into `value`, recursing into objects present in both
+fn merge_json(value: &mut serde_json::Value, fields: serde_json::Value) {
+    match (value, fields) {
+        (serde_json::Value::Object(value), serde_json::Value::Object(fields)) => {
+            for (key, field) in fields {
+                match value.get_mut(&key) {
+                    Some(existing) => merge_json(existing, field),
+                    None => {
+                        value.insert(key, field);
+                    }
+                }
+            }
+        }
+        (value, fields) => *value = fields,
+    }
+}
@@ -0,0 +1,53 @@
+//! The timestamps of the files this crate writes into a bundle, for
+//! [`crate::UnpackOptions::timestamp_source`].
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::history;
+use crate::options::{Strictness, TimestampSource};
+use crate::report::{Warning, WarningKind};
+use filetime::FileTime;
+use ocidir::oci_spec::image::ImageConfiguration;
+use std::path::PathBuf;
+
+/// Returns the time to give the files this crate writes, or `None` to leave them with the time
+/// they were written, along with a warning if the image has no creation time to use. Fails
+/// instead of warning with [`Strictness::Strict`].
+pub(crate) fn resolve(
+    source: TimestampSource,
+    image_config: &ImageConfiguration,
+    strictness: Strictness,
+) -> Result<(Option<FileTime>, Option<Warning>)> {
+    match source {
+        TimestampSource::Clock => Ok((None, None)),
+        TimestampSource::Fixed(time) => Ok((Some(FileTime::from_system_time(time)), None)),
+        TimestampSource::ImageCreated => {
+            let created = image_config.created().as_deref();
+            if let Some(seconds) = created.and_then(history::unix_time) {
+                return Ok((Some(FileTime::from_unix_time(seconds, 0)), None));
+            }
+            let warning = Warning {
+                layer_index: 0,
+                path: PathBuf::new(),
+                kind: WarningKind::MissingCreatedTime,
+            };
+            if strictness == Strictness::Strict {
+                return Err(Error::Warning(warning));
+            }
+            match created {
+                Some(created) => log::warn!("{warning}: can't parse {created}, using the epoch"),
+                None => log::warn!("{warning}: using the epoch"),
+            }
+            Ok((Some(FileTime::zero()), Some(warning)))
+        }
+    }
+}
+
+/// Sets the access and modification times of each of `paths` that exists to `time`
+pub(crate) fn stamp(paths: &[PathBuf], time: FileTime) -> Result<()> {
+    for path in paths {
+        if path.exists() {
+            filetime::set_file_times(path, time, time).with_path(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use crate::report::{LayerTiming, UnpackReport};
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+/// Wraps a reader and measures the time spent in, and the bytes returned by, its reads
+pub(crate) struct TimedReader<R> {
+    inner: R,
+    elapsed: Duration,
+    bytes_read: u64,
+}
+
+impl<R: Read> TimedReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            elapsed: Duration::ZERO,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let result = self.inner.read(buf);
+        self.elapsed += started.elapsed();
+        if let Ok(len) = result {
+            self.bytes_read += len as u64;
+        }
+        result
+    }
+}
+
+/// Calls `f` with `reader`, adding the time `f` spends other than reading it, which is the time
+/// spent writing the archive's entries, to `timing`, along with the bytes it read
+pub(crate) fn time_writes<T>(
+    timing: &mut LayerTiming,
+    reader: &mut dyn Read,
+    f: impl FnOnce(&mut dyn Read) -> T,
+) -> T {
+    let mut reader = TimedReader::new(reader);
+    let started = Instant::now();
+    let output = f(&mut reader);
+    timing.write += started.elapsed().saturating_sub(reader.elapsed);
+    timing.uncompressed_size = reader.bytes_read;
+    output
+}
+
+/// Logs a single line summarizing how long the unpack took, for following throughput without
+/// trace logging
+pub(crate) fn log_summary(report: &UnpackReport) {
+    let bytes: u64 = report
+        .layer_timings
+        .iter()
+        .map(|timing| timing.uncompressed_size)
+        .sum();
+    let slowest = report
+        .layer_timings
+        .iter()
+        .max_by_key(|timing| timing.duration);
+    log::info!(
+        "Unpack finished: layers={} bytes={bytes} duration={:?} throughput={:.1}MiB/s \
+         slowest_layer={} slowest_duration={:?}",
+        report.layer_timings.len(),
+        report.duration,
+        throughput(bytes, report.duration) / (1024.0 * 1024.0),
+        slowest.map_or("none".to_string(), |timing| timing.layer_index.to_string()),
+        slowest.map_or(Duration::ZERO, |timing| timing.duration),
+    );
+}
+
+/// Bytes per second
+pub(crate) fn throughput(bytes: u64, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 0.0;
+    }
+    bytes as f64 / duration.as_secs_f64()
+}
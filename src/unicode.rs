@@ -0,0 +1,235 @@
+//! Paths that differ only in their Unicode normalization form, such as `café` with a precomposed
+//! `é` (NFC) and with an `e` followed by a combining accent (NFD).
+//!
+//! [`UnicodePolicy::NormalizeNfc`] rewrites each path as it's extracted. The other policies compare
+//! the NFC form of each path once all layers are applied, replaying the changes each layer recorded
+//! in layer order, as the waste analysis does.
+
+use crate::error::{Error, Result};
+use crate::options::UnicodePolicy;
+use crate::report::{EntryChange, LayerLog, UnicodeCollision};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+impl UnicodePolicy {
+    /// Returns whether paths are rewritten in NFC as they're extracted
+    pub(crate) fn normalizes(self) -> bool {
+        #[cfg(feature = "unicode")]
+        return self == UnicodePolicy::NormalizeNfc;
+        #[cfg(not(feature = "unicode"))]
+        false
+    }
+
+    /// Returns whether paths that collide fail the unpack
+    fn rejects(self) -> bool {
+        #[cfg(feature = "unicode")]
+        return self == UnicodePolicy::Reject;
+        #[cfg(not(feature = "unicode"))]
+        false
+    }
+
+    /// Returns whether paths are compared once all layers are applied
+    pub(crate) fn compares(self) -> bool {
+        self != UnicodePolicy::Allow && !self.normalizes()
+    }
+
+    /// Returns where an entry at `path` is written
+    pub(crate) fn path(self, path: &Path) -> Cow<'_, Path> {
+        if self.normalizes() {
+            nfc(path)
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+}
+
+/// Returns `path` with each component that is UTF-8 in NFC
+#[cfg(feature = "unicode")]
+fn nfc(path: &Path) -> Cow<'_, Path> {
+    use unicode_normalization::{is_nfc, UnicodeNormalization};
+    if path.to_str().is_some_and(is_nfc) {
+        return Cow::Borrowed(path);
+    }
+    Cow::Owned(
+        path.iter()
+            .map(|c| match c.to_str() {
+                Some(c) => c.nfc().collect::<String>().into(),
+                None => c.to_owned(),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(not(feature = "unicode"))]
+fn nfc(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// The paths in the rootfs as the changes are replayed, with the layer that wrote each, and the
+/// paths with each NFC form
+#[derive(Default)]
+struct Replay {
+    paths: BTreeMap<PathBuf, usize>,
+    forms: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl Replay {
+    /// Returns the collision of `path`, from the layer at `layer_index`, with a path already in
+    /// the rootfs
+    fn collision(&self, path: &Path, layer_index: usize) -> Option<UnicodeCollision> {
+        let existing = self
+            .forms
+            .get(nfc(path).as_ref())?
+            .iter()
+            .find(|existing| *existing != path)?;
+        Some(UnicodeCollision {
+            layer_index,
+            path: path.to_path_buf(),
+            existing_layer_index: self.paths[existing],
+            existing_path: existing.clone(),
+        })
+    }
+
+    fn add(&mut self, path: &Path, layer_index: usize) {
+        if self.paths.insert(path.to_path_buf(), layer_index).is_none() {
+            self.forms
+                .entry(nfc(path).into_owned())
+                .or_default()
+                .push(path.to_path_buf());
+        }
+    }
+
+    /// Removes the paths beneath `dir` written by layers before `layer_index`, and `dir` itself
+    /// if `inclusive` is set
+    fn remove(&mut self, dir: &Path, layer_index: usize, inclusive: bool) {
+        let removed: Vec<_> = self
+            .paths
+            .range(dir.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(dir))
+            .filter(|(path, layer)| **layer < layer_index && (inclusive || *path != dir))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in removed {
+            self.paths.remove(&path);
+            let key = nfc(&path).into_owned();
+            let forms = self.forms.get_mut(&key).expect("paths have forms");
+            forms.retain(|form| *form != path);
+            if forms.is_empty() {
+                self.forms.remove(&key);
+            }
+        }
+    }
+}
+
+/// Replays the changes of each layer in order, returning the paths written that collide with a
+/// path in the rootfs, and whiteouts that don't remove a path because it's in another form. Fails
+/// on the first with [`UnicodePolicy::Reject`].
+pub(crate) fn check(logs: &[LayerLog], policy: UnicodePolicy) -> Result<Vec<UnicodeCollision>> {
+    let mut logs: Vec<_> = logs.iter().collect();
+    logs.sort_by_key(|log| log.layer_index);
+    let mut replay = Replay::default();
+    let mut collisions = Vec::new();
+    for log in logs {
+        let index = log.layer_index;
+        for change in &log.changes {
+            let collision = match change {
+                EntryChange::Write { path, .. } | EntryChange::Dir { path, .. } => {
+                    let collision = replay.collision(path, index);
+                    replay.add(path, index);
+                    collision
+                }
+                EntryChange::Whiteout(path) => {
+                    let collision = if replay.paths.contains_key(path) {
+                        None
+                    } else {
+                        replay.collision(path, index)
+                    };
+                    replay.remove(path, index, true);
+                    collision
+                }
+                EntryChange::Opaque(dir) => {
+                    replay.remove(dir, index, false);
+                    None
+                }
+                EntryChange::Root { .. } => None,
+            };
+            if let Some(collision) = collision {
+                log::warn!("{collision}");
+                if policy.rejects() {
+                    return Err(Error::UnicodeCollision(collision));
+                }
+                collisions.push(collision);
+            }
+        }
+    }
+    Ok(collisions)
+}
+
+#[cfg(all(test, feature = "unicode"))]
+mod tests {
+    use super::*;
+
+    fn write(path: &str) -> EntryChange {
+        EntryChange::Write {
+            path: path.into(),
+            size: 1,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let logs = vec![
+            LayerLog {
+                layer_index: 1,
+                changes: vec![
+                    write("caf\u{e9}"),
+                    EntryChange::Whiteout("d\u{e9}j\u{e0}".into()),
+                    EntryChange::Whiteout("gone".into()),
+                ],
+            },
+            LayerLog {
+                layer_index: 0,
+                changes: vec![
+                    write("cafe\u{301}"),
+                    EntryChange::Dir {
+                        path: "de\u{301}ja\u{300}".into(),
+                        mtime: 0,
+                    },
+                    write("gone/\u{e9}"),
+                ],
+            },
+            LayerLog {
+                layer_index: 2,
+                changes: vec![write("gone/e\u{301}")],
+            },
+        ];
+        let collisions = check(&logs, UnicodePolicy::Warn).unwrap();
+        assert_eq!(
+            collisions,
+            vec![
+                UnicodeCollision {
+                    layer_index: 1,
+                    path: "caf\u{e9}".into(),
+                    existing_layer_index: 0,
+                    existing_path: "cafe\u{301}".into(),
+                },
+                UnicodeCollision {
+                    layer_index: 1,
+                    path: "d\u{e9}j\u{e0}".into(),
+                    existing_layer_index: 0,
+                    existing_path: "de\u{301}ja\u{300}".into(),
+                },
+            ]
+        );
+
+        let error = check(&logs, UnicodePolicy::Reject).unwrap_err();
+        assert!(matches!(error, Error::UnicodeCollision(c) if c == collisions[0]));
+    }
+
+    #[test]
+    fn test_nfc() {
+        assert_eq!(nfc(Path::new("a/cafe\u{301}")), Path::new("a/caf\u{e9}"));
+        assert!(matches!(nfc(Path::new("a/caf\u{e9}")), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,270 @@
+//! [`Unpacker`], which checks a set of options and sets up what they need once, to unpack any
+//! number of images with them.
+
+use crate::checkpoint;
+use crate::deadline::Deadline;
+use crate::error::{Error, IoResultExt, Result};
+use crate::events::{self, Event};
+use crate::options::UnpackOptions;
+use crate::report::UnpackReport;
+use crate::update::{self, Base, UpdateReport};
+use crate::{mounts, timing, unpack_bundle};
+use ocidir::oci_spec::image::{ImageManifest, Platform};
+use ocidir::OciDir;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Unpacks images with one set of [`UnpackOptions`], which are checked when it's created, along
+/// with anything they need that can be shared between unpacks: the platform images must match
+/// is resolved, and the decompressed blob cache and staging directories are created.
+///
+/// There's no thread pool shared between unpacks: the threads of the parallel and prefetch modes
+/// are still started by each unpack, and end with it, as they borrow its layout, bundle and
+/// report, which a pool's threads would outlive. Starting them costs little next to reading a
+/// layer.
+///
+/// An `Unpacker` may be shared between threads, each unpacking into a different bundle, as
+/// described for [`crate::unpack`].
+///
+/// ```
+/// use oci_bundle::{UnpackOptions, Unpacker};
+/// use ocidir::oci_spec::image::ImageManifest;
+/// use ocidir::OciDir;
+/// use std::path::PathBuf;
+///
+/// fn unpack_all(oci_dir: &OciDir, images: &[(ImageManifest, PathBuf)]) -> oci_bundle::Result<()> {
+///     let unpacker = Unpacker::new(UnpackOptions::new().parallel_layers(4))?;
+///     std::thread::scope(|scope| {
+///         let threads: Vec<_> = images
+///             .iter()
+///             .map(|(manifest, bundle)| scope.spawn(|| unpacker.unpack(manifest, oci_dir, bundle)))
+///             .collect();
+///         threads
+///             .into_iter()
+///             .try_for_each(|thread| thread.join().unwrap().map(drop))
+///     })
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Unpacker {
+    options: UnpackOptions,
+    platform: Platform,
+}
+
+impl Unpacker {
+    /// Checks `options`, failing if they're invalid or the directories they name can't be
+    /// created
+    pub fn new(options: UnpackOptions) -> Result<Self> {
+        mounts::validate(&options.runtime_config)?;
+        // The cache is only written to when layers are verified
+        let cache = options
+            .decompressed_blob_cache
+            .as_ref()
+            .filter(|_| options.verify_digests);
+        for dir in [cache, options.staging_dir.as_ref()].into_iter().flatten() {
+            fs::create_dir_all(dir).with_path(dir)?;
+        }
+        let platform = options.platform();
+        Ok(Self { options, platform })
+    }
+
+    pub fn options(&self) -> &UnpackOptions {
+        &self.options
+    }
+
+    /// Unpacks the layers of an image into `bundle`, as [`crate::unpack_with_options`] does
+    pub fn unpack(
+        &self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        bundle: &Path,
+    ) -> Result<UnpackReport> {
+        self.run(manifest, bundle, |bundle, deadline| {
+            self.unpack_bundle(manifest, oci_dir, bundle, Base::Empty, deadline)
+                .map(|report| (report, ()))
+        })
+        .map(|(report, ())| report)
+    }
+
+    /// Unpacks an image into `bundle` over a copy of `base_bundle`, as [`crate::unpack_derived`]
+    /// does
+    pub fn unpack_derived(
+        &self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        base_bundle: &Path,
+        bundle: &Path,
+    ) -> Result<UnpackReport> {
+        self.run(manifest, bundle, |bundle, deadline| {
+            let base = Base::Bundle(base_bundle);
+            self.unpack_bundle(manifest, oci_dir, bundle, base, deadline)
+                .map(|report| (report, ()))
+        })
+        .map(|(report, ())| report)
+    }
+
+    /// Updates `bundle` to the image with `manifest`, as [`crate::update_bundle`] does
+    pub fn update_bundle(
+        &self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        bundle: &Path,
+    ) -> Result<UpdateReport> {
+        let (unpack, reverted) = self.run(manifest, bundle, |bundle, deadline| {
+            let reverted = update::revert(bundle, manifest, oci_dir, &self.options, deadline)?;
+            let base = match &reverted {
+                Some(reverted) => Base::Reverted {
+                    layers: reverted.kept_layers,
+                    changes: &reverted.changes,
+                },
+                None => Base::Empty,
+            };
+            self.unpack_bundle(manifest, oci_dir, bundle, base, deadline)
+                .map(|report| (report, reverted))
+        })?;
+        Ok(match reverted {
+            Some(reverted) => UpdateReport {
+                kept_layers: reverted.kept_layers,
+                reverted_layers: reverted.reverted_layers,
+                reread_layers: reverted.reread_layers,
+                unpacked_every_layer: false,
+                unpack,
+            },
+            None => UpdateReport {
+                unpacked_every_layer: true,
+                unpack,
+                ..UpdateReport::default()
+            },
+        })
+    }
+
+    /// Continues an interrupted unpack of `bundle`, as [`crate::resume_unpack`] does
+    pub fn resume_unpack(
+        &self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        bundle: &Path,
+    ) -> Result<UnpackReport> {
+        self.run(manifest, bundle, |bundle, deadline| {
+            let checkpoint = checkpoint::load(bundle, manifest, &self.options)?;
+            let base = match &checkpoint {
+                Some(checkpoint) => Base::Resumed(checkpoint),
+                None => Base::Empty,
+            };
+            self.unpack_bundle(manifest, oci_dir, bundle, base, deadline)
+                .map(|report| (report, ()))
+        })
+        .map(|(report, ())| report)
+    }
+
+    fn unpack_bundle(
+        &self,
+        manifest: &ImageManifest,
+        oci_dir: &OciDir,
+        bundle: &Path,
+        base: Base,
+        deadline: Option<&Deadline>,
+    ) -> Result<UnpackReport> {
+        unpack_bundle(
+            manifest,
+            oci_dir,
+            bundle,
+            &self.options,
+            &self.platform,
+            base,
+            deadline,
+        )
+    }
+
+    /// Resolves `bundle` and runs `unpack` on it, which returns the report of the unpack and
+    /// anything else the caller needs, then reports how it went
+    fn run<T>(
+        &self,
+        manifest: &ImageManifest,
+        bundle: &Path,
+        unpack: impl FnOnce(&Path, Option<&Deadline>) -> Result<(UnpackReport, T)>,
+    ) -> Result<(UnpackReport, T)> {
+        let options = &self.options;
+        let started = Instant::now();
+        let deadline = options
+            .timeout
+            .map(|timeout| Deadline::new(timeout, manifest.layers().len()));
+        let bundle = &match resolve_bundle(bundle, options.follow_bundle_symlink) {
+            Ok(bundle) => bundle,
+            Err(e) => {
+                options.emit(&Event::Finished {
+                    layers: manifest.layers().len(),
+                    warnings: 0,
+                    error: Some(events::describe_error(&e)),
+                    duration_seconds: started.elapsed().as_secs_f64(),
+                });
+                return Err(e);
+            }
+        };
+        // A bundle that was there before, such as one being updated, is left for the caller
+        let existed = bundle.symlink_metadata().is_ok();
+        let result = unpack(bundle, deadline.as_ref()).map(|(report, extra)| {
+            let report = UnpackReport {
+                duration: started.elapsed(),
+                ..report
+            };
+            (report, extra)
+        });
+        let result = match (result, &deadline) {
+            (Err(e), Some(deadline)) => {
+                let e = deadline.convert(e);
+                if !existed && matches!(e.without_layer(), Error::TimedOut { .. }) {
+                    if let Err(remove_err) = fs::remove_dir_all(bundle) {
+                        log::warn!(
+                            "Failed to remove timed out bundle {}: {remove_err}",
+                            bundle.display()
+                        );
+                    }
+                }
+                Err(e)
+            }
+            (result, _) => result,
+        };
+        if let Ok((report, _)) = &result {
+            timing::log_summary(report);
+        }
+        options.emit(&Event::Finished {
+            layers: manifest.layers().len(),
+            warnings: result
+                .as_ref()
+                .map_or(0, |(report, _)| report.warnings.len()),
+            error: result.as_ref().err().map(events::describe_error),
+            duration_seconds: started.elapsed().as_secs_f64(),
+        });
+        result
+    }
+}
+
+/// Returns the path to unpack `bundle` into: the path itself, or if it's a symlink and `follow`
+/// is set, its target. Fails with [`Error::SymlinkBundle`] for a symlink otherwise.
+fn resolve_bundle(bundle: &Path, follow: bool) -> Result<PathBuf> {
+    match fs::symlink_metadata(bundle) {
+        Ok(metadata) if metadata.is_symlink() => {}
+        _ => return Ok(bundle.to_path_buf()),
+    }
+    if !follow {
+        return Err(Error::SymlinkBundle(bundle.to_path_buf()));
+    }
+    let target = match fs::canonicalize(bundle) {
+        Ok(target) => target,
+        // A dangling symlink leads to where the bundle will be created
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let link = fs::read_link(bundle).with_path(bundle)?;
+            bundle.parent().unwrap_or(Path::new("")).join(link)
+        }
+        Err(e) => return Err(e).with_path(bundle),
+    };
+    log::info!(
+        "Following bundle symlink {} to {}",
+        bundle.display(),
+        target.display()
+    );
+    Ok(target)
+}
@@ -0,0 +1,618 @@
+//! Updating a bundle in place to a rebuild of the image it was unpacked from, for
+//! [`crate::update_bundle`].
+//!
+//! With [`crate::UnpackOptions::record_changes`], a bundle keeps the paths each of its layers
+//! changed. An update keeps the layers the bundle shares with the new image, and reverts the
+//! rest: every path they changed is removed, and whatever the kept layers had there is
+//! extracted again from them. The new image's remaining layers are then applied over the kept
+//! ones, as [`crate::unpack_derived`] applies them over a base bundle.
+
+use crate::blobs::BlobSource;
+use crate::checkpoint::Checkpoint;
+use crate::deadline::Deadline;
+use crate::error::{IoResultExt, Result};
+use crate::options::{OwnershipMode, UnpackOptions};
+use crate::report::{EntryChange, LayerCompression, LayerLog, LayerTiming, UnpackReport};
+use crate::write::current_umask;
+use crate::{extract_layer, metadata, read_config, read_layer, LayerChanges};
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::{Dir, OpenOptions, OpenOptionsExt};
+use ocidir::oci_spec::image::{ImageManifest, Os};
+use ocidir::OciDir;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, Permissions};
+use std::io;
+use std::ops::Bound;
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tar::Archive;
+
+/// The name of the file in a bundle that records the paths each of its layers changed
+pub(crate) const LAYER_CHANGES: &str = "oci-bundle-changes.json";
+
+const LAYER_CHANGES_VERSION: u32 = 1;
+
+/// Describes a successful [`crate::update_bundle`]
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct UpdateReport {
+    /// The number of leading layers the bundle shared with the image, which were kept rather
+    /// than extracted again. Zero if every layer was unpacked.
+    pub kept_layers: usize,
+    /// The number of layers the bundle had been unpacked from that the image doesn't share,
+    /// whose changes were reverted
+    pub reverted_layers: usize,
+    /// The indices of the kept layers that were read again, to restore the paths the reverted
+    /// layers changed
+    pub reread_layers: Vec<usize>,
+    /// Whether the bundle was unpacked from scratch, as it couldn't be updated: it wasn't
+    /// complete, didn't record its layers' changes, or shared no layers with the image
+    pub unpacked_every_layer: bool,
+    /// The report of applying the image's remaining layers, whose
+    /// [`UnpackReport::base_layers`] are the kept layers
+    pub unpack: UnpackReport,
+}
+
+/// The layers of the bundle on disk, all applied, whose changes were recorded
+#[derive(Serialize, Deserialize)]
+struct RecordedLayers {
+    version: u32,
+    diff_ids: Vec<String>,
+    layers: Vec<Vec<RecordedChange>>,
+}
+
+/// A path a layer changed, as an [`EntryChange`] without the details only reports need
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RecordedChange {
+    Write(PathBuf),
+    Dir(PathBuf),
+    Root,
+    Whiteout(PathBuf),
+    Opaque(PathBuf),
+}
+
+impl From<&EntryChange> for RecordedChange {
+    fn from(change: &EntryChange) -> Self {
+        match change {
+            EntryChange::Write { path, .. } => Self::Write(path.clone()),
+            EntryChange::Dir { path, .. } => Self::Dir(path.clone()),
+            EntryChange::Root { .. } => Self::Root,
+            EntryChange::Whiteout(path) => Self::Whiteout(path.clone()),
+            EntryChange::Opaque(dir) => Self::Opaque(dir.clone()),
+        }
+    }
+}
+
+/// Where the rootfs of a bundle being unpacked starts from
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Base<'a> {
+    /// An empty directory
+    Empty,
+    /// A copy of the rootfs of another bundle, for [`crate::unpack_derived`]
+    Bundle(&'a Path),
+    /// The bundle's own rootfs, reverted by [`revert`] to its first `layers` layers, which made
+    /// `changes`
+    Reverted {
+        layers: usize,
+        changes: &'a [Vec<RecordedChange>],
+    },
+    /// The bundle's own rootfs, to which an interrupted unpack had applied the layers recorded
+    /// in its checkpoint, for [`crate::resume_unpack`]
+    Resumed(&'a Checkpoint),
+}
+
+/// The result of [`revert`]
+pub(crate) struct Reverted {
+    pub(crate) kept_layers: usize,
+    /// The changes of the kept layers
+    pub(crate) changes: Vec<Vec<RecordedChange>>,
+    pub(crate) reverted_layers: usize,
+    pub(crate) reread_layers: Vec<usize>,
+}
+
+/// Records the paths each of the image's layers changed in `bundle`, for updating it later: those
+/// of the `base_layers` taken from `base`, and those `applied`. If the base's changes aren't
+/// known, nothing is recorded, and nor is it for paths that aren't UTF-8, which JSON can't hold.
+/// The bundle can still be updated, by unpacking every layer.
+pub(crate) fn record(
+    bundle: &Path,
+    base: Base,
+    base_layers: usize,
+    diff_ids: &[String],
+    applied: &[LayerLog],
+) -> Result<()> {
+    let mut layers = match base {
+        _ if base_layers == 0 => Vec::new(),
+        Base::Empty => Vec::new(),
+        Base::Reverted { changes, .. } => changes.to_vec(),
+        // Unpacks that record changes aren't checkpointed, so there are none to resume after
+        Base::Resumed(_) => return Ok(()),
+        Base::Bundle(base) => match read(base)? {
+            Some(recorded) if recorded.diff_ids == diff_ids[..base_layers] => recorded.layers,
+            _ => {
+                log::info!(
+                    "Base bundle {} didn't record its layers' changes, so neither does {}",
+                    base.display(),
+                    bundle.display()
+                );
+                return Ok(());
+            }
+        },
+    };
+    layers.resize(diff_ids.len(), Vec::new());
+    for log in applied {
+        layers[log.layer_index].extend(log.changes.iter().map(RecordedChange::from));
+    }
+    let recorded = RecordedLayers {
+        version: LAYER_CHANGES_VERSION,
+        diff_ids: diff_ids.to_vec(),
+        layers,
+    };
+    let path = bundle.join(LAYER_CHANGES);
+    match serde_json::to_vec(&recorded) {
+        Ok(json) => fs::write(&path, json).with_path(&path),
+        Err(e) => {
+            log::info!("Not recording the changes of each layer: {e}");
+            Ok(())
+        }
+    }
+}
+
+/// Reads the changes recorded in `bundle`, if it has any this version understands
+fn read(bundle: &Path) -> Result<Option<RecordedLayers>> {
+    let path = bundle.join(LAYER_CHANGES);
+    let json = match fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_path(&path),
+    };
+    match serde_json::from_slice::<RecordedLayers>(&json) {
+        Ok(recorded) if recorded.version == LAYER_CHANGES_VERSION => Ok(Some(recorded)),
+        Ok(recorded) => {
+            log::debug!(
+                "Ignoring layer changes version {} in {}",
+                recorded.version,
+                path.display()
+            );
+            Ok(None)
+        }
+        Err(e) => {
+            log::debug!("Ignoring unreadable layer changes {}: {e}", path.display());
+            Ok(None)
+        }
+    }
+}
+
+/// Removes everything in `bundle` but its rootfs, which is being updated in place
+pub(crate) fn clear_bundle(bundle: &Path) -> Result<()> {
+    for entry in fs::read_dir(bundle).with_path(bundle)? {
+        let entry = entry.with_path(bundle)?;
+        let path = entry.path();
+        if entry.file_name() == "rootfs" {
+            continue;
+        }
+        if entry.file_type().with_path(&path)?.is_dir() {
+            fs::remove_dir_all(&path).with_path(&path)?;
+        } else {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reverts the rootfs of `bundle` to the layers it shares with the image with `manifest`,
+/// removing the paths that later layers changed and extracting them again from the shared
+/// layers. Returns `None`, having changed nothing, if the bundle can't be reverted, so every
+/// layer must be unpacked.
+///
+/// Hard links whose targets a later shared layer changed can't be restored without extracting
+/// that layer again, so they also return `None`, though having changed the bundle.
+pub(crate) fn revert(
+    bundle: &Path,
+    manifest: &ImageManifest,
+    oci_dir: &OciDir,
+    options: &UnpackOptions,
+    deadline: Option<&Deadline>,
+) -> Result<Option<Reverted>> {
+    let Some(bundle_diff_ids) = metadata::complete_diff_ids(bundle)? else {
+        log::info!(
+            "Bundle {} wasn't completely unpacked, unpacking every layer",
+            bundle.display()
+        );
+        return Ok(None);
+    };
+    let rootfs = bundle.join("rootfs");
+    let recorded = read(bundle)?.filter(|recorded| recorded.diff_ids == bundle_diff_ids);
+    let Some(mut recorded) = recorded.filter(|_| rootfs.is_dir()) else {
+        log::info!(
+            "Bundle {} didn't record its layers' changes, unpacking every layer",
+            bundle.display()
+        );
+        return Ok(None);
+    };
+    let blobs = BlobSource::layout(oci_dir);
+    let (image_config, _) = read_config(&blobs, manifest.config(), options)?;
+    if *image_config.os() == Os::Windows {
+        log::info!(
+            "Not updating bundle {} for a Windows image",
+            bundle.display()
+        );
+        return Ok(None);
+    }
+    let diff_ids = image_config.rootfs().diff_ids();
+    let kept_layers = diff_ids
+        .iter()
+        .zip(&recorded.diff_ids)
+        .take_while(|(new, old)| new == old)
+        .count();
+    if kept_layers == 0 {
+        log::info!(
+            "Image shares no layers with bundle {}, unpacking every layer",
+            bundle.display()
+        );
+        return Ok(None);
+    }
+    let reverted = recorded.layers.split_off(kept_layers);
+    let kept = recorded.layers;
+    let Some(scope) = Scope::new(&kept, &reverted) else {
+        log::info!(
+            "Reverting bundle {} would reset its root, unpacking every layer",
+            bundle.display()
+        );
+        return Ok(None);
+    };
+    log::info!(
+        "Keeping {kept_layers} layers of bundle {}, reverting {} and reading {} again",
+        bundle.display(),
+        reverted.len(),
+        scope.reread.len()
+    );
+    if !reverted.is_empty() {
+        metadata::mark_incomplete(bundle)?;
+        let root_dir = Dir::open_ambient_dir(&rootfs, ambient_authority()).with_path(&rootfs)?;
+        for path in &scope.reset {
+            remove(&root_dir, path)?;
+        }
+        scope.restore_implicit_dirs(&root_dir, options.ownership)?;
+        let layers = manifest.layers();
+        for &index in &scope.reread {
+            if let Some(deadline) = deadline {
+                deadline.check()?;
+            }
+            let mut timing = LayerTiming::new(index);
+            let mut compression = LayerCompression::new(index);
+            let mut changes = LayerChanges::new(false).restoring(&scope);
+            read_layer(
+                &blobs,
+                layers,
+                index,
+                &diff_ids[index],
+                options,
+                deadline,
+                &mut timing,
+                &mut compression,
+                |reader, cloner| {
+                    extract_layer(
+                        &mut Archive::new(reader),
+                        &rootfs,
+                        index,
+                        &options.for_layer(&layers[index]),
+                        &mut changes,
+                        cloner,
+                    )
+                },
+            )
+            .map_err(|e| e.in_layer(index, &layers[index]))?;
+            if changes.found_stale_link {
+                log::info!(
+                    "A hard link in layer {index} of bundle {} can't be restored, unpacking \
+                     every layer",
+                    bundle.display()
+                );
+                return Ok(None);
+            }
+        }
+    }
+    let reread_layers = scope.reread;
+    Ok(Some(Reverted {
+        kept_layers,
+        changes: kept,
+        reverted_layers: reverted.len(),
+        reread_layers,
+    }))
+}
+
+/// Removes whatever is at `path` in the rootfs, if anything
+fn remove(root_dir: &Dir, path: &Path) -> Result<()> {
+    let result = match root_dir.symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => root_dir.remove_dir_all(path),
+        Ok(_) => root_dir.remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.with_path(path)
+}
+
+/// The paths of a rootfs that reverting layers restores from the layers that are kept
+pub(crate) struct Scope<'a> {
+    /// The paths the reverted layers changed, which are removed and extracted again in full.
+    /// None is beneath another.
+    reset: BTreeSet<PathBuf>,
+    /// The directories the reverted layers only gave new metadata, which kept layers' entries
+    /// for them restore
+    dirs: BTreeSet<PathBuf>,
+    /// Those of `dirs` that no kept layer has an entry for, as they were created as parents
+    implicit_dirs: Vec<PathBuf>,
+    /// The changes of the kept layers
+    kept: &'a [Vec<RecordedChange>],
+    /// The kept layers with entries in scope, in order
+    reread: Vec<usize>,
+}
+
+impl<'a> Scope<'a> {
+    /// Works out what to restore to revert the layers that made `reverted` over those that made
+    /// `kept`. Returns `None` if the root itself would have to be reset.
+    fn new(kept: &'a [Vec<RecordedChange>], reverted: &[Vec<RecordedChange>]) -> Option<Self> {
+        let state = KeptState::replay(kept);
+        let mut reset = BTreeSet::new();
+        let mut dirs = BTreeSet::new();
+        for change in reverted.iter().flatten() {
+            let path = match change {
+                RecordedChange::Dir(path) if state.is_dir(path) => {
+                    dirs.insert(path.clone());
+                    continue;
+                }
+                RecordedChange::Root => {
+                    dirs.insert(PathBuf::new());
+                    continue;
+                }
+                RecordedChange::Write(path)
+                | RecordedChange::Dir(path)
+                | RecordedChange::Whiteout(path)
+                | RecordedChange::Opaque(path) => path,
+            };
+            // Parents created for the path are reset along with it
+            let top = path
+                .ancestors()
+                .take_while(|ancestor| !state.exists(ancestor))
+                .last()
+                .unwrap_or(path);
+            if top.as_os_str().is_empty() {
+                return None;
+            }
+            reset.insert(top.to_path_buf());
+        }
+        let mut outermost = BTreeSet::new();
+        for path in reset {
+            if !outermost
+                .iter()
+                .any(|kept: &PathBuf| path.starts_with(kept))
+            {
+                outermost.insert(path);
+            }
+        }
+        let mut scope = Self {
+            reset: outermost,
+            dirs: BTreeSet::new(),
+            implicit_dirs: Vec::new(),
+            kept,
+            reread: Vec::new(),
+        };
+        scope.dirs = dirs.into_iter().filter(|dir| !scope.covers(dir)).collect();
+        scope.implicit_dirs = scope
+            .dirs
+            .iter()
+            .filter(|dir| !state.is_explicit(dir))
+            .cloned()
+            .collect();
+        scope.reread = (0..kept.len())
+            .filter(|&index| kept[index].iter().any(|change| scope.is_relevant(change)))
+            .collect();
+        Some(scope)
+    }
+
+    /// Returns whether `path` is reset, being at or beneath a reset path
+    pub(crate) fn covers(&self, path: &Path) -> bool {
+        path.ancestors()
+            .any(|ancestor| self.reset.contains(ancestor))
+    }
+
+    /// Returns whether the metadata of the directory at `path` is restored
+    pub(crate) fn restores_dir(&self, path: &Path) -> bool {
+        self.covers(path) || self.dirs.contains(path)
+    }
+
+    /// Returns the reset paths strictly beneath `path`
+    fn reset_beneath<'s>(&'s self, path: &'s Path) -> impl Iterator<Item = &'s PathBuf> {
+        self.reset
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(move |reset| reset.starts_with(path))
+            .filter(move |reset| reset.as_path() != path)
+    }
+
+    /// Removes the reset paths beneath `path`, for an entry outside the scope that would have
+    /// removed them by replacing or emptying `path`
+    pub(crate) fn remove_beneath(&self, root_dir: &Dir, path: &Path) -> Result<()> {
+        for reset in self.reset_beneath(path) {
+            remove(root_dir, reset)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a kept layer after the one at `index` changed what's at `path`, which
+    /// decides whether hard links in that layer can be restored
+    pub(crate) fn changed_later(&self, path: &Path, index: usize) -> bool {
+        self.kept[index + 1..]
+            .iter()
+            .flatten()
+            .any(|change| match change {
+                RecordedChange::Write(changed) | RecordedChange::Whiteout(changed) => {
+                    path.starts_with(changed)
+                }
+                RecordedChange::Dir(changed) => path == changed,
+                RecordedChange::Opaque(dir) => path.starts_with(dir) && path != dir,
+                RecordedChange::Root => false,
+            })
+    }
+
+    /// Returns whether a kept layer's `change` touches the scope, so its layer is read again
+    fn is_relevant(&self, change: &RecordedChange) -> bool {
+        match change {
+            RecordedChange::Write(path)
+            | RecordedChange::Whiteout(path)
+            | RecordedChange::Opaque(path) => {
+                self.covers(path) || self.reset_beneath(path).next().is_some()
+            }
+            RecordedChange::Dir(path) => self.restores_dir(path),
+            RecordedChange::Root => self.dirs.contains(Path::new("")),
+        }
+    }
+
+    /// Gives the directories no kept layer has an entry for the ownership and mode of the
+    /// parents created while extracting
+    fn restore_implicit_dirs(&self, root_dir: &Dir, ownership: OwnershipMode) -> Result<()> {
+        let mode = 0o777 & !current_umask().unwrap_or(0o022);
+        for dir in &self.implicit_dirs {
+            let path = crate::or_dot(dir);
+            let dir = root_dir
+                .open_with(
+                    path,
+                    OpenOptions::new()
+                        .read(true)
+                        .custom_flags(libc::O_DIRECTORY),
+                )
+                .with_path(path)?
+                .into_std();
+            if ownership == OwnershipMode::Preserve {
+                // SAFETY: geteuid and getegid can't fail
+                let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+                fchown(&dir, Some(uid), Some(gid)).with_path(path)?;
+            }
+            dir.set_permissions(Permissions::from_mode(mode))
+                .with_path(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entries the kept layers leave in the rootfs, as their changes are replayed
+#[derive(Default)]
+struct KeptState<'a> {
+    /// Whether each entry a layer wrote is a directory, and the layer that last wrote it
+    entries: BTreeMap<&'a Path, (bool, usize)>,
+    /// Whether any layer has an entry for the root
+    root: bool,
+}
+
+impl<'a> KeptState<'a> {
+    fn replay(kept: &'a [Vec<RecordedChange>]) -> Self {
+        let mut state = Self::default();
+        for (index, changes) in kept.iter().enumerate() {
+            for change in changes {
+                match change {
+                    RecordedChange::Write(path) => {
+                        state.remove(path, true, |_| true);
+                        state.entries.insert(path, (false, index));
+                    }
+                    RecordedChange::Dir(path) => {
+                        state.entries.insert(path, (true, index));
+                    }
+                    RecordedChange::Root => state.root = true,
+                    RecordedChange::Whiteout(path) => state.remove(path, true, |l| l < index),
+                    RecordedChange::Opaque(dir) => state.remove(dir, false, |l| l < index),
+                }
+            }
+        }
+        state
+    }
+
+    /// Removes the entries beneath `path`, and at it if `inclusive`, written by layers that
+    /// `filter` accepts
+    fn remove(&mut self, path: &Path, inclusive: bool, filter: impl Fn(usize) -> bool) {
+        let removed: Vec<_> = self
+            .entries
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(|(entry, _)| entry.starts_with(path))
+            .filter(|(entry, (_, layer))| (inclusive || **entry != path) && filter(*layer))
+            .map(|(entry, _)| *entry)
+            .collect();
+        for entry in removed {
+            self.entries.remove(entry);
+        }
+    }
+
+    /// Returns whether anything is at `path`, including a directory created as the parent of
+    /// entries beneath it
+    fn exists(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty()
+            || self
+                .entries
+                .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+                .next()
+                .is_some_and(|(entry, _)| entry.starts_with(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        match self.entries.get(path) {
+            Some((dir, _)) => *dir,
+            None => self.exists(path),
+        }
+    }
+
+    /// Returns whether a layer has an entry for the directory at `path`
+    fn is_explicit(&self, path: &Path) -> bool {
+        if path.as_os_str().is_empty() {
+            self.root
+        } else {
+            self.entries.contains_key(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &str) -> RecordedChange {
+        RecordedChange::Write(PathBuf::from(path))
+    }
+
+    fn dir(path: &str) -> RecordedChange {
+        RecordedChange::Dir(PathBuf::from(path))
+    }
+
+    #[test]
+    fn test_scope() {
+        let kept = vec![
+            vec![dir("etc"), write("etc/hosts"), write("usr/bin/sh")],
+            vec![write("etc/passwd"), write("opt/app/bin")],
+        ];
+        let reverted = vec![
+            vec![
+                write("etc/passwd"),
+                dir("etc"),
+                write("var/cache/apt/lock"),
+                RecordedChange::Whiteout(PathBuf::from("opt/app")),
+            ],
+            vec![dir("usr/bin"), write("opt/app/bin/tool")],
+        ];
+        let scope = Scope::new(&kept, &reverted).unwrap();
+        let reset: Vec<_> = scope.reset.iter().map(|p| p.to_str().unwrap()).collect();
+        // var didn't exist, and opt/app/bin/tool is beneath the whiteout of opt/app
+        assert_eq!(reset, ["etc/passwd", "opt/app", "var"]);
+        let dirs: Vec<_> = scope.dirs.iter().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(dirs, ["etc", "usr/bin"]);
+        assert_eq!(scope.implicit_dirs, [PathBuf::from("usr/bin")]);
+        // Layer 0 has the etc directory, and layer 1 the reset paths
+        assert_eq!(scope.reread, [0, 1]);
+
+        assert!(scope.covers(Path::new("opt/app/bin")));
+        assert!(!scope.covers(Path::new("opt")));
+        assert!(scope.changed_later(Path::new("etc/passwd"), 0));
+        assert!(!scope.changed_later(Path::new("etc/hosts"), 0));
+
+        let opaque_root = vec![vec![RecordedChange::Opaque(PathBuf::new())]];
+        assert!(Scope::new(&kept, &opaque_root).is_none());
+    }
+}
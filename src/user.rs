@@ -0,0 +1,352 @@
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::{AdditionalGids, DatabaseSource, UserDatabase, UserResolution};
+use crate::write;
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::Dir;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use users::{
+    get_group_by_gid, get_group_by_name, get_user_by_name, get_user_by_uid, get_user_groups,
+};
+
+/// The annotation recording `Config.User` when it's left unresolved by [`UserResolution::Skip`]
+pub const UNRESOLVED_USER_ANNOTATION: &str = "oci-bundle.unresolved-user";
+
+/// The most symlinks followed resolving a path in the rootfs, as on Linux
+pub(crate) const MAX_SYMLINKS: usize = 40;
+
+/// The user and groups a process runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResolvedUser {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) additional_gids: Vec<u32>,
+}
+
+/// Resolves `Config.User`, which is `user`, `uid`, `user:group`, `uid:gid`, `uid:group` or
+/// `user:gid`, returning `None` if it's left unresolved
+pub(crate) fn resolve(
+    spec: &str,
+    rootfs: &Path,
+    resolution: &UserResolution,
+    supplied: &UserDatabase,
+    additional_gids: &AdditionalGids,
+) -> Result<Option<ResolvedUser>> {
+    let resolved = resolve_user(spec, rootfs, resolution, supplied, additional_gids)?;
+    Ok(resolved.map(|user| match additional_gids {
+        AdditionalGids::Resolve => user,
+        AdditionalGids::Skip => ResolvedUser {
+            additional_gids: Vec::new(),
+            ..user
+        },
+        AdditionalGids::Explicit(gids) => ResolvedUser {
+            additional_gids: gids.clone(),
+            ..user
+        },
+    }))
+}
+
+/// Resolves `Config.User` with its additional groups as `resolution` finds them. Only the host's
+/// are left out unless they're resolved, as looking them up may be slow.
+fn resolve_user(
+    spec: &str,
+    rootfs: &Path,
+    resolution: &UserResolution,
+    supplied: &UserDatabase,
+    additional_gids: &AdditionalGids,
+) -> Result<Option<ResolvedUser>> {
+    let (user, group) = match spec.split(':').collect::<Vec<_>>().as_slice() {
+        [user] => (*user, None),
+        [user, group] => (*user, Some(*group)),
+        _ => {
+            return Err(Error::UserResolution(
+                "Invalid user format in Config.User".to_string(),
+            ))
+        }
+    };
+    match resolution {
+        UserResolution::Rootfs => {
+            let root_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+            // The rootfs's entries come first, so are found first
+            let mut database = Database {
+                passwd: read_database(&root_dir, "etc/passwd")?,
+                group: read_database(&root_dir, "etc/group")?,
+            };
+            database.passwd.extend(read_supplied(&supplied.passwd)?);
+            database.group.extend(read_supplied(&supplied.group)?);
+            database.resolve(user, group).map(Some)
+        }
+        UserResolution::Host => {
+            let resolve_groups = *additional_gids == AdditionalGids::Resolve;
+            resolve_on_host(user, group, resolve_groups).map(Some)
+        }
+        UserResolution::Skip => {
+            // Numeric IDs need no lookup, so are used as they are
+            match (user.parse(), group.map(str::parse).transpose()) {
+                (Ok(uid), Ok(gid)) => Ok(Some(ResolvedUser {
+                    uid,
+                    gid: gid.unwrap_or(0),
+                    additional_gids: Vec::new(),
+                })),
+                _ => Ok(None),
+            }
+        }
+        UserResolution::Custom(resolve) => {
+            let (uid, gid, additional_gids) = resolve(spec)?;
+            Ok(Some(ResolvedUser {
+                uid,
+                gid,
+                additional_gids,
+            }))
+        }
+    }
+}
+
+/// Reads a colon-separated database file from the rootfs, which is empty if it doesn't exist.
+/// Symlinks are followed within the rootfs, as they would be in the container.
+fn read_database(root_dir: &Dir, path: &str) -> Result<Vec<Vec<String>>> {
+    let path = resolve_in_root(root_dir, Path::new(path))?;
+    let content = match write::open_file(root_dir, &path) {
+        Ok(mut file) => {
+            let mut content = String::new();
+            file.read_to_string(&mut content).with_path(&path)?;
+            content
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_path(path),
+    };
+    Ok(parse_database(&content))
+}
+
+/// Reads the entries of a [`UserDatabase`], reading its files from the host
+fn read_supplied(sources: &[DatabaseSource]) -> Result<Vec<Vec<String>>> {
+    let mut entries = Vec::new();
+    for source in sources {
+        match source {
+            DatabaseSource::Content(content) => entries.extend(parse_database(content)),
+            DatabaseSource::File(path) => {
+                let content = fs::read_to_string(path).with_path(path)?;
+                entries.extend(parse_database(&content));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Splits the entries of a colon-separated database into their fields, skipping blank lines and
+/// comments
+fn parse_database(content: &str) -> Vec<Vec<String>> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(str::to_string).collect())
+        .collect()
+}
+
+/// Resolves the symlinks in `path` as if the rootfs were the root, so that absolute targets and
+/// `..` components stop at the rootfs rather than leading out of it, which cap-std would refuse.
+/// Components that don't exist are left as they are.
+fn resolve_in_root(root_dir: &Dir, path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    // The components still to resolve, last first. A normal component can't be "..", so that
+    // stands for the parent.
+    let mut pending: Vec<OsString> = Vec::new();
+    let push = |pending: &mut Vec<OsString>, path: &Path| {
+        let components = path.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        });
+        let start = pending.len();
+        pending.extend(components);
+        pending[start..].reverse();
+    };
+    push(&mut pending, path);
+    let mut followed = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        match root_dir.symlink_metadata(&candidate) {
+            Ok(metadata) if metadata.is_symlink() => {
+                followed += 1;
+                if followed > MAX_SYMLINKS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP)).with_path(path);
+                }
+                let target = root_dir
+                    .read_link_contents(&candidate)
+                    .with_path(&candidate)?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push(&mut pending, &target);
+            }
+            _ => resolved = candidate,
+        }
+    }
+    Ok(resolved)
+}
+
+/// The contents of a rootfs's `/etc/passwd` and `/etc/group`, followed by those of a
+/// [`UserDatabase`]
+struct Database {
+    passwd: Vec<Vec<String>>,
+    group: Vec<Vec<String>>,
+}
+
+impl Database {
+    /// Resolves a user as runc does: a numeric user or group that isn't in the database is used as
+    /// is, with a primary group of 0 for an unknown user, but an unknown name is an error
+    fn resolve(&self, user: &str, group: Option<&str>) -> Result<ResolvedUser> {
+        let entry = match user.parse::<u32>() {
+            Ok(uid) => self
+                .passwd
+                .iter()
+                .find(|entry| entry.get(2).and_then(|id| id.parse().ok()) == Some(uid)),
+            Err(_) => Some(
+                self.passwd
+                    .iter()
+                    .find(|entry| entry[0] == user)
+                    .ok_or_else(|| {
+                        Error::UserResolution(format!("User {user} not found in /etc/passwd"))
+                    })?,
+            ),
+        };
+        let field = |entry: &Vec<String>, index: usize| {
+            entry
+                .get(index)
+                .and_then(|id| id.parse::<u32>().ok())
+                .ok_or_else(|| {
+                    Error::UserResolution(format!("Invalid entry for {} in /etc/passwd", entry[0]))
+                })
+        };
+        let (uid, primary_gid) = match entry {
+            Some(entry) => (field(entry, 2)?, field(entry, 3)?),
+            None => (user.parse().expect("only numeric users may be missing"), 0),
+        };
+
+        match group {
+            Some(group) => {
+                let gid = match group.parse() {
+                    Ok(gid) => gid,
+                    Err(_) => self
+                        .group
+                        .iter()
+                        .find(|entry| entry[0] == group)
+                        .and_then(|entry| entry.get(2)?.parse().ok())
+                        .ok_or_else(|| {
+                            Error::UserResolution(format!("Group {group} not found in /etc/group"))
+                        })?,
+                };
+                Ok(ResolvedUser {
+                    uid,
+                    gid,
+                    additional_gids: Vec::new(),
+                })
+            }
+            None => {
+                let mut additional_gids = vec![primary_gid];
+                if let Some(name) = entry.map(|entry| &entry[0]) {
+                    for group in &self.group {
+                        let is_member = group
+                            .get(3)
+                            .is_some_and(|members| members.split(',').any(|m| m == name));
+                        if let Some(gid) = group.get(2).and_then(|gid| gid.parse().ok()) {
+                            if is_member && !additional_gids.contains(&gid) {
+                                additional_gids.push(gid);
+                            }
+                        }
+                    }
+                }
+                Ok(ResolvedUser {
+                    uid,
+                    gid: primary_gid,
+                    additional_gids,
+                })
+            }
+        }
+    }
+}
+
+/// Resolves a user in the host's database, with their additional groups if `resolve_groups`
+fn resolve_on_host(user: &str, group: Option<&str>, resolve_groups: bool) -> Result<ResolvedUser> {
+    let (uid, primary_gid) = resolve_host_user(user)?;
+    match group {
+        Some(group) => Ok(ResolvedUser {
+            uid,
+            gid: resolve_host_group(group)?,
+            additional_gids: Vec::new(),
+        }),
+        None if resolve_groups => Ok(ResolvedUser {
+            uid,
+            gid: primary_gid,
+            additional_gids: resolve_host_additional_gids(uid)?,
+        }),
+        None => Ok(ResolvedUser {
+            uid,
+            gid: primary_gid,
+            additional_gids: Vec::new(),
+        }),
+    }
+}
+
+fn resolve_host_user(user: &str) -> Result<(u32, u32)> {
+    if let Ok(uid) = user.parse::<u32>() {
+        let user = get_user_by_uid(uid)
+            .ok_or_else(|| Error::UserResolution(format!("User ID {} not found", uid)))?;
+        Ok((user.uid(), user.primary_group_id()))
+    } else {
+        let user = get_user_by_name(user)
+            .ok_or_else(|| Error::UserResolution(format!("User {} not found", user)))?;
+        Ok((user.uid(), user.primary_group_id()))
+    }
+}
+
+fn resolve_host_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        get_group_by_gid(gid)
+            .ok_or_else(|| Error::UserResolution(format!("Group ID {} not found", gid)))?;
+        Ok(gid)
+    } else {
+        let group = get_group_by_name(group)
+            .ok_or_else(|| Error::UserResolution(format!("Group {} not found", group)))?;
+        Ok(group.gid())
+    }
+}
+
+/// Returns the groups of the user with `uid`, including their primary group, from a single
+/// `getgrouplist(3)` call, rather than by enumerating every group
+fn resolve_host_additional_gids(uid: u32) -> Result<Vec<u32>> {
+    let user = get_user_by_uid(uid)
+        .ok_or_else(|| Error::UserResolution(format!("User ID {} not found", uid)))?;
+    let groups = get_user_groups(user.name(), user.primary_group_id()).ok_or_else(|| {
+        Error::UserResolution(format!("Failed to resolve groups of user ID {}", uid))
+    })?;
+    Ok(groups.iter().map(|g| g.gid()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_root_on_host() {
+        let root = resolve_on_host("root", None, true).unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.additional_gids.contains(&0), "{root:?}");
+
+        let root = resolve_on_host("0", None, false).unwrap();
+        assert_eq!(
+            root,
+            ResolvedUser {
+                uid: 0,
+                gid: 0,
+                additional_gids: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::digest_reader::DigestReader;
+use crate::error::{Error, IoResultExt, Result};
+use crate::write_bits::WRITE_BITS;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// The name of the file in a bundle that records its rootfs, for [`crate::verify_bundle`]
+pub(crate) const FILE_MANIFEST: &str = "file-manifest.jsonl";
+
+const FILE_MANIFEST_VERSION: u32 = 1;
+
+/// The first line of a file manifest
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    /// Whether the write bits were removed from the rootfs after it was recorded, so that the
+    /// recorded modes are those it had before
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    write_bits_stripped: bool,
+}
+
+/// A path in the rootfs, as recorded in the file manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Record {
+    path: RecordedPath,
+    /// The full `st_mode`, including the file type
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<RecordedPath>,
+}
+
+/// A path, recorded as a string where it's valid UTF-8 and as bytes otherwise
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RecordedPath {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for RecordedPath {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(path) => RecordedPath::Utf8(path.to_string()),
+            None => RecordedPath::Bytes(path.as_os_str().as_bytes().to_vec()),
+        }
+    }
+}
+
+impl From<&RecordedPath> for PathBuf {
+    fn from(path: &RecordedPath) -> Self {
+        match path {
+            RecordedPath::Utf8(path) => PathBuf::from(path),
+            RecordedPath::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes.clone())),
+        }
+    }
+}
+
+/// Options controlling how [`crate::verify_bundle_with_options`] checks a bundle
+#[derive(Debug, Clone, Default)]
+pub struct VerifyBundleOptions {
+    metadata_only: bool,
+}
+
+impl VerifyBundleOptions {
+    /// Creates the default options, which match the behavior of [`crate::verify_bundle`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compare only file types, sizes, modes, ownership and symlink targets, skipping the hashing
+    /// of file contents. Defaults to `false`.
+    pub fn metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+}
+
+/// The result of comparing a bundle's rootfs with the file manifest recorded when it was unpacked
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VerifyBundleReport {
+    /// The number of paths in the file manifest
+    pub checked: usize,
+    /// Paths in the rootfs that aren't in the manifest
+    pub added: Vec<PathBuf>,
+    /// Paths in the manifest that aren't in the rootfs
+    pub removed: Vec<PathBuf>,
+    /// Paths in both that differ
+    pub modified: Vec<ModifiedPath>,
+}
+
+impl VerifyBundleReport {
+    /// Whether the rootfs matches the manifest
+    pub fn is_unchanged(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyBundleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed and {} modified of {} paths",
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len(),
+            self.checked
+        )
+    }
+}
+
+/// A path whose metadata or content differs from the file manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModifiedPath {
+    pub path: PathBuf,
+    pub differences: Vec<Difference>,
+}
+
+/// What differs about a [`ModifiedPath`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Difference {
+    /// The file type, e.g. a file replaced by a symlink. Nothing else is compared.
+    Type,
+    /// The permission bits, including setuid, setgid and sticky
+    Mode,
+    /// The owning user or group
+    Ownership,
+    Size,
+    /// The content of a regular file
+    Content,
+    /// The target of a symlink
+    Target,
+}
+
+/// Records every path in `rootfs` in the file manifest in `bundle`, noting whether its write bits
+/// are about to be stripped
+pub(crate) fn write_file_manifest(
+    bundle: &Path,
+    rootfs: &Path,
+    write_bits_stripped: bool,
+) -> Result<()> {
+    let manifest_path = bundle.join(FILE_MANIFEST);
+    let mut manifest = BufWriter::new(File::create(&manifest_path).with_path(&manifest_path)?);
+    let header = Header {
+        version: FILE_MANIFEST_VERSION,
+        write_bits_stripped,
+    };
+    write_line(&mut manifest, &header).with_path(&manifest_path)?;
+    for record in walk(rootfs, true) {
+        write_line(&mut manifest, &record?).with_path(&manifest_path)?;
+    }
+    manifest.flush().with_path(&manifest_path)
+}
+
+fn write_line(mut w: impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut w, value)?;
+    w.write_all(b"\n")
+}
+
+/// Describes each path under `rootfs`, excluding the root itself, in a stable order
+fn walk(rootfs: &Path, hash: bool) -> impl Iterator<Item = Result<Record>> + '_ {
+    walkdir::WalkDir::new(rootfs)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .map(move |entry| {
+            let entry = entry.map_err(|e| Error::Io {
+                path: e.path().unwrap_or(rootfs).to_path_buf(),
+                source: e.into(),
+            })?;
+            let metadata = entry.metadata().map_err(|e| Error::Io {
+                path: entry.path().to_path_buf(),
+                source: e.into(),
+            })?;
+            describe(rootfs, entry.path(), &metadata, hash)
+        })
+}
+
+/// Describes `path`, under `rootfs`, which has `metadata`, hashing its content if it's a file
+/// and `hash` is set
+fn describe(rootfs: &Path, path: &Path, metadata: &fs::Metadata, hash: bool) -> Result<Record> {
+    let file_type = metadata.file_type();
+    let (size, sha256) = if file_type.is_file() {
+        let sha256 = if hash {
+            Some(hash_file(path).with_path(path)?)
+        } else {
+            None
+        };
+        (Some(metadata.len()), sha256)
+    } else {
+        (None, None)
+    };
+    let target = if file_type.is_symlink() {
+        Some(RecordedPath::from(
+            fs::read_link(path).with_path(path)?.as_path(),
+        ))
+    } else {
+        None
+    };
+    Ok(Record {
+        path: RecordedPath::from(
+            path.strip_prefix(rootfs)
+                .expect("described paths are under the rootfs"),
+        ),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        size,
+        sha256,
+        target,
+    })
+}
+
+/// Describes the first `count` paths under `rootfs`, in the order the file manifest lists them,
+/// without hashing the content of files
+pub(crate) fn sample(rootfs: &Path, count: usize) -> Result<Vec<Record>> {
+    walk(rootfs, false).take(count).collect()
+}
+
+/// Returns whether the paths of a [`sample`] of `rootfs` are still as they were described, but
+/// for the content of files
+pub(crate) fn sample_matches(rootfs: &Path, sample: &[Record]) -> Result<bool> {
+    let options = VerifyBundleOptions::new().metadata_only(true);
+    for expected in sample {
+        let path = rootfs.join(PathBuf::from(&expected.path));
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_path(&path),
+        };
+        let actual = describe(rootfs, &path, &metadata, false)?;
+        if !compare(expected, &actual, &options).is_empty() {
+            log::debug!("{} differs from the sample", path.display());
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Hashes the regular file at `path`, which is opened without blocking, in case it's been
+/// replaced with a FIFO since it was found
+fn hash_file(path: &Path) -> io::Result<String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file",
+        ));
+    }
+    let mut reader = DigestReader::new(file);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish().0.sha256().to_string())
+}
+
+/// Compares the rootfs of `bundle` with its file manifest
+pub(crate) fn verify_bundle(
+    bundle: &Path,
+    options: &VerifyBundleOptions,
+) -> Result<VerifyBundleReport> {
+    if crate::metadata::is_incomplete(bundle) {
+        return Err(Error::IncompleteBundle(bundle.to_path_buf()));
+    }
+    compare_with_manifest(bundle, options)
+}
+
+/// Compares the rootfs of `bundle` with its file manifest, whether or not the bundle is complete
+pub(crate) fn compare_with_manifest(
+    bundle: &Path,
+    options: &VerifyBundleOptions,
+) -> Result<VerifyBundleReport> {
+    let manifest_path = bundle.join(FILE_MANIFEST);
+    let mut lines = BufReader::new(File::open(&manifest_path).with_path(&manifest_path)?).lines();
+    let parse_error = |e: serde_json::Error| Error::Io {
+        path: manifest_path.clone(),
+        source: e.into(),
+    };
+    let header: Header = match lines.next() {
+        Some(line) => {
+            serde_json::from_str(&line.with_path(&manifest_path)?).map_err(parse_error)?
+        }
+        None => {
+            return Err(Error::Io {
+                path: manifest_path.clone(),
+                source: io::Error::new(io::ErrorKind::InvalidData, "Empty file manifest"),
+            })
+        }
+    };
+    if header.version != FILE_MANIFEST_VERSION {
+        return Err(Error::Io {
+            path: manifest_path.clone(),
+            source: io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported file manifest version {}", header.version),
+            ),
+        });
+    }
+    let mut expected = BTreeMap::new();
+    for line in lines {
+        let mut record: Record =
+            serde_json::from_str(&line.with_path(&manifest_path)?).map_err(parse_error)?;
+        if header.write_bits_stripped && record.mode & libc::S_IFMT != libc::S_IFLNK {
+            record.mode &= !WRITE_BITS;
+        }
+        expected.insert(PathBuf::from(&record.path), record);
+    }
+
+    let mut report = VerifyBundleReport {
+        checked: expected.len(),
+        ..Default::default()
+    };
+    for actual in walk(&bundle.join("rootfs"), !options.metadata_only) {
+        let actual = actual?;
+        let path = PathBuf::from(&actual.path);
+        let Some(expected) = expected.remove(&path) else {
+            report.added.push(path);
+            continue;
+        };
+        let differences = compare(&expected, &actual, options);
+        if !differences.is_empty() {
+            report.modified.push(ModifiedPath { path, differences });
+        }
+    }
+    report.removed = expected.into_keys().collect();
+
+    if report.is_unchanged() {
+        Ok(report)
+    } else {
+        Err(Error::BundleModified(Box::new(report)))
+    }
+}
+
+fn compare(expected: &Record, actual: &Record, options: &VerifyBundleOptions) -> Vec<Difference> {
+    let file_type = |mode: u32| mode & libc::S_IFMT;
+    if file_type(expected.mode) != file_type(actual.mode) {
+        return vec![Difference::Type];
+    }
+    let mut differences = Vec::new();
+    if expected.mode != actual.mode {
+        differences.push(Difference::Mode);
+    }
+    if (expected.uid, expected.gid) != (actual.uid, actual.gid) {
+        differences.push(Difference::Ownership);
+    }
+    if expected.size != actual.size {
+        differences.push(Difference::Size);
+    } else if !options.metadata_only && expected.sha256 != actual.sha256 {
+        differences.push(Difference::Content);
+    }
+    if expected.target != actual.target {
+        differences.push(Difference::Target);
+    }
+    differences
+}
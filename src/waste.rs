@@ -0,0 +1,149 @@
+//! Accounting for the bytes each layer writes that later layers overwrite or remove, which are
+//! stored in the image but never seen in the rootfs.
+//!
+//! Layers record what their entries change as they're extracted, in whatever order the unpack
+//! mode extracts them, and the records are replayed in layer order once all are applied.
+
+use crate::report::{EntryChange, LayerLog, WastedPath};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The number of wasted paths kept in [`crate::UnpackReport::largest_wasted_paths`]
+pub(crate) const LARGEST_WASTED_PATHS: usize = 10;
+
+/// The layer and size of each file in the rootfs, as the changes are replayed
+#[derive(Default)]
+struct Replay {
+    files: BTreeMap<PathBuf, (usize, u64)>,
+    wasted_bytes: Vec<u64>,
+    wasted_paths: Vec<WastedPath>,
+}
+
+impl Replay {
+    /// Removes the entries at and beneath `path` written by layers that `filter` accepts,
+    /// attributing their bytes as wasted by `layer_index`
+    fn remove(&mut self, path: &Path, layer_index: usize, filter: impl Fn(usize) -> bool) {
+        let removed: Vec<_> = self
+            .files
+            .range(path.to_path_buf()..)
+            .take_while(|(entry, _)| entry.starts_with(path))
+            .filter(|(_, (layer, _))| filter(*layer))
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        for entry in removed {
+            let (layer, size) = self.files.remove(&entry).expect("entry was found");
+            if size == 0 {
+                continue;
+            }
+            self.wasted_bytes[layer] += size;
+            self.wasted_paths.push(WastedPath {
+                layer_index: layer,
+                path: entry,
+                size,
+                removed_by: layer_index,
+            });
+        }
+    }
+}
+
+/// Replays the changes of each layer in order, returning the bytes wasted in each of `layers`
+/// layers, and the largest wasted paths
+pub(crate) fn analyze(mut logs: Vec<LayerLog>, layers: usize) -> (Vec<u64>, Vec<WastedPath>) {
+    logs.sort_by_key(|log| log.layer_index);
+    let mut replay = Replay {
+        wasted_bytes: vec![0; layers],
+        ..Replay::default()
+    };
+    for log in logs {
+        let index = log.layer_index;
+        for change in log.changes {
+            match change {
+                EntryChange::Write { path, size } => {
+                    replay.remove(&path, index, |_| true);
+                    replay.files.insert(path, (index, size));
+                }
+                EntryChange::Dir { path, .. } => {
+                    if replay.files.contains_key(&path) {
+                        replay.remove(&path, index, |_| true);
+                    }
+                }
+                EntryChange::Whiteout(path) | EntryChange::Opaque(path) => {
+                    replay.remove(&path, index, |layer| layer < index);
+                }
+                EntryChange::Root { .. } => {}
+            }
+        }
+    }
+    let mut wasted_paths = replay.wasted_paths;
+    wasted_paths.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    wasted_paths.truncate(LARGEST_WASTED_PATHS);
+    (replay.wasted_bytes, wasted_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &str, size: u64) -> EntryChange {
+        EntryChange::Write {
+            path: path.into(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let logs = vec![
+            LayerLog {
+                layer_index: 2,
+                changes: vec![
+                    EntryChange::Opaque("opt".into()),
+                    write("etc/motd", 5),
+                    EntryChange::Whiteout("var/cache".into()),
+                    EntryChange::Dir {
+                        path: "bin/tool".into(),
+                        mtime: 0,
+                    },
+                ],
+            },
+            LayerLog {
+                layer_index: 0,
+                changes: vec![
+                    write("etc/motd", 10),
+                    write("var/cache/a", 100),
+                    write("var/cache/b", 200),
+                    write("opt/app", 1000),
+                    write("bin/tool", 50),
+                ],
+            },
+            LayerLog {
+                layer_index: 1,
+                changes: vec![write("etc/motd", 20), write("opt/new/app", 2000)],
+            },
+        ];
+        let (wasted_bytes, wasted_paths) = analyze(logs, 4);
+        assert_eq!(wasted_bytes, [10 + 100 + 200 + 1000 + 50, 20 + 2000, 0, 0]);
+        let paths: Vec<_> = wasted_paths
+            .iter()
+            .map(|wasted| {
+                (
+                    wasted.path.to_str().unwrap(),
+                    wasted.layer_index,
+                    wasted.removed_by,
+                )
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                ("opt/new/app", 1, 2),
+                ("opt/app", 0, 2),
+                ("var/cache/b", 0, 2),
+                ("var/cache/a", 0, 2),
+                ("bin/tool", 0, 2),
+                ("etc/motd", 1, 2),
+                ("etc/motd", 0, 1),
+            ]
+        );
+    }
+}
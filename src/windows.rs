@@ -0,0 +1,322 @@
+//! Flattening Windows layers, for [`crate::UnpackOptions::windows_layers`]. Their files are under
+//! `Files/`, registry hives under `Hives/`, and Windows metadata such as security descriptors in
+//! `MSWINDOWS.*` PAX records. Each layer's archive is rewritten as it's read, with the `Files/`
+//! prefix stripped, so that it's extracted like any other, while its hives and records are saved
+//! beside the rootfs.
+
+use crate::error::{Error, IoResultExt, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entries, Entry, EntryType, Header};
+
+/// The directory in a bundle that Windows layers' hives and records are saved in, by layer index
+pub(crate) const WINDOWS_LAYERS: &str = "windows-layers";
+
+/// The file, in a layer's directory, recording the `MSWINDOWS.*` PAX records of its entries
+const RECORDS: &str = "records.json";
+
+/// The prefix of the PAX records holding Windows metadata
+const RECORD_PREFIX: &str = "MSWINDOWS.";
+
+/// `FILE_ATTRIBUTE_REPARSE_POINT`
+const REPARSE_POINT: u32 = 0x400;
+
+/// What [`crate::UnpackOptions::windows_layers`] did with a Windows layer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WindowsLayer {
+    pub layer_index: usize,
+    /// The directory in the bundle the layer's hives and records were saved in
+    pub dir: PathBuf,
+    /// The files under `Hives/` in the layer, saved in the `hives` directory of [`Self::dir`],
+    /// relative to it
+    pub hives: Vec<PathBuf>,
+    /// The number of entries whose `MSWINDOWS.*` PAX records, such as their security
+    /// descriptors, were saved in `records.json` in [`Self::dir`], by path in the rootfs
+    pub records: usize,
+    /// Reparse points that aren't symlinks, such as junctions, which were skipped as they can't
+    /// be represented, relative to the root
+    pub skipped_reparse_points: Vec<PathBuf>,
+    /// Entries outside `Files/` and `Hives/`, such as a utility VM's, which were skipped, and
+    /// those under `Hives/` that aren't files or directories, as they are in the layer
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Calls `extract` with the archive `reader` reads, flattened if `dir`, the bundle's
+/// [`WINDOWS_LAYERS`] directory, is given, and the report of what was flattened
+pub(crate) fn flatten<T>(
+    reader: &mut dyn Read,
+    dir: Option<&Path>,
+    index: usize,
+    extract: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<(T, Option<WindowsLayer>)> {
+    let Some(dir) = dir else {
+        return Ok((extract(reader)?, None));
+    };
+    let mut archive = Archive::new(reader);
+    let mut flattener = Flattener {
+        entries: archive.entries().map_err(Error::Archive)?,
+        current: None,
+        buffer: Vec::new(),
+        position: 0,
+        finished: false,
+        records: BTreeMap::new(),
+        layer: WindowsLayer {
+            layer_index: index,
+            dir: dir.join(index.to_string()),
+            ..WindowsLayer::default()
+        },
+    };
+    let output = extract(&mut flattener)?;
+    Ok((output, Some(flattener.finish()?)))
+}
+
+/// Reads a Windows layer's archive as one with its `Files/` prefix stripped
+struct Flattener<'a, R: Read> {
+    entries: Entries<'a, R>,
+    /// The entry whose data is being copied, and the bytes of it still to copy
+    current: Option<(Entry<'a, R>, u64)>,
+    /// Headers and padding to be read before anything else
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+    records: BTreeMap<String, BTreeMap<String, String>>,
+    layer: WindowsLayer,
+}
+
+impl<R: Read> Read for Flattener<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.buffer.len() {
+                let n = buf.len().min(self.buffer.len() - self.position);
+                buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+                self.position += n;
+                return Ok(n);
+            }
+            if let Some((entry, remaining)) = &mut self.current {
+                if *remaining > 0 {
+                    let limit = buf
+                        .len()
+                        .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    let n = entry.read(&mut buf[..limit])?;
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *remaining -= n as u64;
+                    return Ok(n);
+                }
+                let padding = (512 - entry.size() % 512) % 512;
+                self.set_buffer(vec![0; padding as usize]);
+                self.current = None;
+                continue;
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            match self.entries.next() {
+                Some(entry) => self.start(entry?)?,
+                None => {
+                    // The end of archive marker
+                    self.set_buffer(vec![0; 1024]);
+                    self.finished = true;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, R: Read> Flattener<'a, R> {
+    fn set_buffer(&mut self, buffer: Vec<u8>) {
+        self.buffer = buffer;
+        self.position = 0;
+    }
+
+    /// Queues the headers of `entry`, if it's kept, ready to copy its data, or saves or skips it
+    fn start(&mut self, mut entry: Entry<'a, R>) -> io::Result<()> {
+        let entry_type = entry.header().entry_type();
+        if matches!(entry_type, EntryType::XGlobalHeader) {
+            return Ok(());
+        }
+        let path = crate::entry_path(&entry).map_err(io::Error::other)?;
+        let mut components = path.components();
+        let prefix = match components.next() {
+            Some(Component::Normal(prefix)) => prefix.to_ascii_lowercase(),
+            _ => Default::default(),
+        };
+        let rest = components.as_path().to_path_buf();
+        if prefix == "hives" {
+            return self.save_hive(&mut entry, &path, rest);
+        }
+        if prefix != "files" {
+            self.layer.skipped.push(path);
+            return Ok(());
+        }
+        if rest.as_os_str().is_empty() {
+            // `Files/` itself holds the Windows metadata of the root, which isn't applied
+            return Ok(());
+        }
+
+        let mut kept = Vec::new();
+        let mut windows = BTreeMap::new();
+        for extension in entry.pax_extensions()?.into_iter().flatten() {
+            let extension = extension?;
+            let key = extension.key_bytes();
+            if let Some(name) = extension
+                .key()
+                .ok()
+                .filter(|key| key.starts_with(RECORD_PREFIX))
+            {
+                windows.insert(
+                    name.to_string(),
+                    String::from_utf8_lossy(extension.value_bytes()).into_owned(),
+                );
+            }
+            if key != b"path" && key != b"linkpath" {
+                kept.push((key.to_vec(), extension.value_bytes().to_vec()));
+            }
+        }
+        let attributes = windows
+            .get("MSWINDOWS.fileattr")
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(0);
+        if windows.contains_key("MSWINDOWS.mountpoint")
+            || (attributes & REPARSE_POINT != 0 && !entry_type.is_symlink())
+        {
+            self.layer.skipped_reparse_points.push(rest);
+            return Ok(());
+        }
+        if !windows.is_empty() {
+            self.layer.records += 1;
+            self.records
+                .insert(rest.to_string_lossy().into_owned(), windows);
+        }
+
+        kept.push((b"path".to_vec(), path_bytes(&rest)));
+        if let Some(target) = entry.link_name()? {
+            // Hard links name other entries in the layer, which have moved
+            let target = if entry_type.is_hard_link() {
+                crate::normalize(&target)
+                    .strip_prefix("Files")
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|_| target.into_owned())
+            } else {
+                target.into_owned()
+            };
+            kept.push((b"linkpath".to_vec(), path_bytes(&target)));
+        }
+        let mut buffer = pax_header(&kept)?;
+        buffer.extend_from_slice(entry.header().as_bytes());
+        self.set_buffer(buffer);
+        let size = entry.size();
+        self.current = Some((entry, size));
+        Ok(())
+    }
+
+    /// Saves the file or directory at `path` in the layer, `rest` beneath `Hives/`
+    fn save_hive(
+        &mut self,
+        entry: &mut Entry<'a, R>,
+        path: &Path,
+        rest: PathBuf,
+    ) -> io::Result<()> {
+        if rest.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let entry_type = entry.header().entry_type();
+        let is_safe = rest.components().all(|c| matches!(c, Component::Normal(_)));
+        if !is_safe || !(entry_type.is_file() || entry_type.is_dir()) {
+            self.layer.skipped.push(path.to_path_buf());
+            return Ok(());
+        }
+        let destination = self.layer.dir.join("hives").join(&rest);
+        if entry_type.is_dir() {
+            return fs::create_dir_all(&destination);
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&destination)?;
+        self.layer.hives.push(rest);
+        Ok(())
+    }
+
+    /// Saves the layer's records, returning what was done with it
+    fn finish(self) -> Result<WindowsLayer> {
+        let layer = self.layer;
+        if !self.records.is_empty() {
+            fs::create_dir_all(&layer.dir).with_path(&layer.dir)?;
+            let path = layer.dir.join(RECORDS);
+            let json = serde_json::to_vec_pretty(&self.records).expect("records serialize");
+            fs::write(&path, json).with_path(&path)?;
+        }
+        log::info!(
+            "Flattened Windows layer {}: saved {} hives and the records of {} entries, skipped {} \
+             reparse points and {} other entries",
+            layer.layer_index,
+            layer.hives.len(),
+            layer.records,
+            layer.skipped_reparse_points.len(),
+            layer.skipped.len()
+        );
+        Ok(layer)
+    }
+}
+
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// Returns a PAX extended header holding `records`, with its padding
+fn pax_header(records: &[(Vec<u8>, Vec<u8>)]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // The length of a record includes the digits of the length itself
+        let rest = key.len() + value.len() + 3;
+        let mut length = rest + rest.to_string().len();
+        if length.to_string().len() > rest.to_string().len() {
+            length += 1;
+        }
+        data.extend_from_slice(format!("{length} ").as_bytes());
+        data.extend_from_slice(key);
+        data.push(b'=');
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+    let mut header = Header::new_ustar();
+    header.set_path("PaxHeader")?;
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    let mut buffer = header.as_bytes().to_vec();
+    buffer.append(&mut data);
+    buffer.resize(buffer.len().div_ceil(512) * 512, 0);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_header() {
+        for length in [1, 90, 94, 95, 96, 990, 995, 996, 1000] {
+            let value = vec![b'a'; length];
+            let header = pax_header(&[(b"path".to_vec(), value.clone())]).unwrap();
+            let mut archive = Vec::new();
+            archive.extend_from_slice(&header);
+            let mut file = Header::new_ustar();
+            file.set_path("short").unwrap();
+            file.set_size(0);
+            file.set_cksum();
+            archive.extend_from_slice(file.as_bytes());
+            archive.extend_from_slice(&[0; 1024]);
+            let mut archive = Archive::new(archive.as_slice());
+            let entry = archive.entries().unwrap().next().unwrap().unwrap();
+            assert_eq!(path_bytes(&entry.path().unwrap()), value, "{length}");
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::error::{IoResultExt, Result};
+use crate::options::OwnershipMode;
+use crate::rootless;
+use ocidir::cap_std::ambient_authority;
+use ocidir::cap_std::fs::{Dir, OpenOptions, OpenOptionsExt};
+use ocidir::oci_spec::runtime::Spec;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Creates the process's working directory in `rootfs`, along with any missing parents, if no
+/// layer did, so that the runtime can change to it. Directories are created with mode 0755 and,
+/// unless `ownership` is [`OwnershipMode::Ignore`], owned by the process's user, or recorded as
+/// such.
+///
+/// Returns whether anything was created. The directory is looked up through a handle on the
+/// rootfs, so neither `..` nor symlinks can lead outside of it.
+pub(crate) fn create(
+    rootfs: &Path,
+    runtime_config: &Spec,
+    ownership: OwnershipMode,
+) -> Result<bool> {
+    let Some(process) = runtime_config.process() else {
+        return Ok(false);
+    };
+    let cwd = process.cwd();
+    if cwd.components().any(|c| c == Component::ParentDir) {
+        log::warn!(
+            "Not creating working directory {}, as it contains `..`",
+            cwd.display()
+        );
+        return Ok(false);
+    }
+    let path: PathBuf = cwd
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let root_dir = Dir::open_ambient_dir(rootfs, ambient_authority()).with_path(rootfs)?;
+
+    let mut created = false;
+    let mut dir = PathBuf::new();
+    for component in path.components() {
+        dir.push(component);
+        match root_dir.create_dir(&dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_path(&dir),
+        }
+        created = true;
+        let handle = root_dir
+            .open_with(
+                &dir,
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECTORY),
+            )
+            .with_path(&dir)?
+            .into_std();
+        let user = process.user();
+        match ownership {
+            OwnershipMode::Preserve => {
+                fchown(&handle, Some(user.uid()), Some(user.gid())).with_path(&dir)?
+            }
+            OwnershipMode::Emulate => {
+                rootless::record(&handle, user.uid(), user.gid()).with_path(&dir)?
+            }
+            OwnershipMode::Ignore => {}
+        }
+        // The mode is set regardless of the umask
+        handle
+            .set_permissions(Permissions::from_mode(0o755))
+            .with_path(&dir)?;
+    }
+    if created {
+        log::info!("Created working directory {}", cwd.display());
+    }
+    Ok(created)
+}
@@ -0,0 +1,773 @@
+use crate::acl;
+use crate::capabilities;
+use crate::copy::ArchiveCloner;
+use crate::error::{Error, IoResultExt, Result};
+use crate::options::{ContentInspector, OwnershipMode};
+use crate::report::{LayerCopy, WarningKind};
+use crate::rootless;
+use filetime::FileTime;
+use ocidir::cap_std::fs::{Dir, MetadataExt, OpenOptions, OpenOptionsExt};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, Permissions};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{fchown, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use tar::{Entry, EntryType};
+use xattr::FileExt;
+
+/// Returns whether an entry is a regular file that [`FileWriter`] can write
+pub(crate) fn is_regular_file(entry_type: EntryType) -> bool {
+    matches!(entry_type, EntryType::Regular | EntryType::Continuous)
+}
+
+/// Writes regular file entries into a directory, applying the same metadata as tar-rs's
+/// `unpack_in` would while skipping the syscalls that wouldn't change anything.
+///
+/// Files are created with their final mode, so `fchmod` is only needed for special bits or when
+/// the umask masks part of the mode. `fchown` is skipped when the file already has the owner a new
+/// file gets, which depends on whether its parent directory is setgid, so that is looked up once
+/// per directory. With [`OwnershipMode::Emulate`], the owner is recorded rather than set.
+pub(crate) struct FileWriter<'a> {
+    root_dir: &'a Dir,
+    buffer_size: usize,
+    ownership: OwnershipMode,
+    uid: u32,
+    gid: u32,
+    umask: Option<u32>,
+    /// The group new files get in each parent directory known to exist
+    parent_gids: HashMap<PathBuf, u32>,
+    inspector: Option<&'a Mutex<ContentInspector>>,
+    /// Whether the last file written had a `security.capability` attribute that couldn't be set
+    capability_dropped: bool,
+    /// Whether ACLs that can't be set are applied as mode bits where they can be
+    translate_acls: bool,
+    /// Whether the last file written had an ACL that couldn't be set
+    acl_dropped: bool,
+    /// Whether the last file written had another extended attribute that couldn't be set
+    xattr_dropped: bool,
+    /// The largest files whose content is kept, if any are
+    sniff_limit: Option<u64>,
+    /// The content of the last file written, if it was kept
+    sniffed: Option<Vec<u8>>,
+    /// The smallest files whose space is allocated before they're written, if any are
+    preallocate_min: Option<u64>,
+    /// Clones files' content from the archive they're read from, if it's in the cache
+    cloner: Option<ArchiveCloner>,
+}
+
+impl<'a> FileWriter<'a> {
+    pub(crate) fn new(root_dir: &'a Dir, buffer_size: usize, ownership: OwnershipMode) -> Self {
+        // SAFETY: these calls have no preconditions and can't fail
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Self {
+            root_dir,
+            buffer_size,
+            ownership,
+            uid,
+            gid,
+            umask: current_umask(),
+            parent_gids: HashMap::new(),
+            inspector: None,
+            capability_dropped: false,
+            translate_acls: false,
+            acl_dropped: false,
+            xattr_dropped: false,
+            sniff_limit: None,
+            sniffed: None,
+            preallocate_min: None,
+            cloner: None,
+        }
+    }
+
+    /// Passes the content of each file to `inspector` as it's written
+    pub(crate) fn inspector(mut self, inspector: Option<&'a Mutex<ContentInspector>>) -> Self {
+        self.inspector = inspector;
+        self
+    }
+
+    /// Applies ACLs that can't be set as mode bits, where they can be, as with
+    /// [`crate::UnpackOptions::translate_acls`]
+    pub(crate) fn translate_acls(mut self, translate: bool) -> Self {
+        self.translate_acls = translate;
+        self
+    }
+
+    /// Keeps the content of each file no larger than `limit` bytes as it's written, for
+    /// [`Self::take_sniffed`]
+    pub(crate) fn sniff(mut self, limit: Option<u64>) -> Self {
+        self.sniff_limit = limit;
+        self
+    }
+
+    /// Allocates the space of each file of at least `min_size` bytes before writing it, as with
+    /// [`crate::UnpackOptions::preallocate_files`]
+    pub(crate) fn preallocate(mut self, min_size: Option<u64>) -> Self {
+        self.preallocate_min = min_size;
+        self
+    }
+
+    /// Clones the content of files from the archive they're read from with `cloner`, rather than
+    /// writing what's read, as with [`crate::UnpackOptions::cache_copy_strategy`]
+    pub(crate) fn clone_from(mut self, cloner: Option<ArchiveCloner>) -> Self {
+        self.cloner = cloner;
+        self
+    }
+
+    /// Returns how the content of the files written was written, if any could be cloned
+    pub(crate) fn finish_cloning(&mut self, layer_index: usize) -> Option<LayerCopy> {
+        self.cloner.take().map(|cloner| cloner.finish(layer_index))
+    }
+
+    /// Writes a regular file entry to `path`, through a buffer of the writer's buffer size, without
+    /// the mode bits in `mask`.
+    ///
+    /// Returns the inspector's error if it fails, in which case the file is still written in full.
+    /// A `security.capability` attribute that can't be set is skipped, as reported by
+    /// [`Self::capability_dropped`], as are ACLs, as reported by [`Self::acl_dropped`], and other
+    /// extended attributes, as reported by [`Self::xattr_dropped`].
+    pub(crate) fn write<R: Read>(
+        &mut self,
+        entry: &mut Entry<R>,
+        path: &Path,
+        mask: u32,
+    ) -> Result<Option<Error>> {
+        self.capability_dropped = false;
+        self.acl_dropped = false;
+        self.xattr_dropped = false;
+        self.sniffed = None;
+        let path: PathBuf = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mode = entry.header().mode().map_err(Error::Archive)? & 0o7777 & !mask;
+
+        // Write a new file rather than overwriting one in place, as tar-rs does
+        match self.root_dir.remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).with_path(&path),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true).mode(mode & 0o777);
+        let file = match self.root_dir.open_with(&path, &options) {
+            // The parent may not exist yet, or have been removed by a whiteout since it was cached
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.parent_gids.remove(&parent);
+                self.create_parent(&parent)?;
+                self.root_dir.open_with(&path, &options)
+            }
+            result => result,
+        }
+        .with_path(&path)?
+        .into_std();
+        let new_file_gid = self.new_file_gid(&parent)?;
+        let sniff = self.sniff_limit.is_some_and(|limit| entry.size() <= limit);
+        let cloned = match &mut self.cloner {
+            // Content that's inspected or kept has to be read
+            Some(cloner) if entry.size() > 0 && self.inspector.is_none() && !sniff => cloner
+                .clone_range(entry.raw_file_position(), entry.size(), &file)
+                .with_path(&path)?,
+            _ => false,
+        };
+        if cloned {
+            // What's left unread of the entry is still read, and hashed, by the next one
+            self.sniffed = None;
+            self.apply_metadata(entry, &file, (mode, mask), new_file_gid)
+                .with_path(&path)?;
+            return Ok(None);
+        }
+        if self.preallocate_min.is_some_and(|min| entry.size() >= min) {
+            preallocate(&file, entry.size(), &path);
+        }
+
+        let mut writer = SniffingWriter {
+            writer: BufWriter::with_capacity(self.buffer_size, file),
+            content: sniff.then(Vec::new),
+        };
+        let inspection = match self.inspector {
+            Some(inspector) => inspect(inspector, entry, &mut writer, &path)?,
+            None => {
+                io::copy(entry, &mut writer).with_path(&path)?;
+                None
+            }
+        };
+        self.sniffed = writer.content;
+        let file = writer
+            .writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(&path)?;
+        self.apply_metadata(entry, &file, (mode, mask), new_file_gid)
+            .with_path(&path)?;
+        Ok(inspection)
+    }
+
+    /// Whether the last file written had a `security.capability` attribute that couldn't be set
+    pub(crate) fn capability_dropped(&self) -> bool {
+        self.capability_dropped
+    }
+
+    /// Whether the last file written had an ACL that couldn't be set, nor applied as mode bits
+    pub(crate) fn acl_dropped(&self) -> bool {
+        self.acl_dropped
+    }
+
+    /// Whether the last file written had another extended attribute that couldn't be set
+    pub(crate) fn xattr_dropped(&self) -> bool {
+        self.xattr_dropped
+    }
+
+    /// Takes the content of the last file written, if it was kept, as set by [`Self::sniff`]
+    pub(crate) fn take_sniffed(&mut self) -> Option<Vec<u8>> {
+        self.sniffed.take()
+    }
+
+    fn create_parent(&self, parent: &Path) -> Result<()> {
+        if !parent.as_os_str().is_empty() {
+            self.root_dir.create_dir_all(parent).with_path(parent)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the group a file newly created in `parent` belongs to
+    fn new_file_gid(&mut self, parent: &Path) -> Result<u32> {
+        if let Some(gid) = self.parent_gids.get(parent) {
+            return Ok(*gid);
+        }
+        let metadata = if parent.as_os_str().is_empty() {
+            self.root_dir.dir_metadata()
+        } else {
+            self.root_dir.metadata(parent)
+        }
+        .with_path(parent)?;
+        let gid = if metadata.mode() & libc::S_ISGID != 0 {
+            metadata.gid()
+        } else {
+            self.gid
+        };
+        self.parent_gids.insert(parent.to_path_buf(), gid);
+        Ok(gid)
+    }
+
+    fn apply_metadata<R: Read>(
+        &mut self,
+        entry: &mut Entry<R>,
+        file: &File,
+        (mode, mask): (u32, u32),
+        new_file_gid: u32,
+    ) -> io::Result<()> {
+        let header = entry.header();
+        let mtime = file_time(header.mtime()?);
+        filetime::set_file_handle_times(file, Some(mtime), Some(mtime))?;
+
+        let mut chowned = false;
+        if self.ownership != OwnershipMode::Ignore {
+            let uid: u32 = header.uid()?.try_into().map_err(io::Error::other)?;
+            let gid: u32 = header.gid()?.try_into().map_err(io::Error::other)?;
+            if self.ownership == OwnershipMode::Emulate {
+                rootless::record(file, uid, gid)?;
+            } else if (uid, gid) != (self.uid, new_file_gid) {
+                // Ownership is set first, as changing it clears setuid and setgid bits
+                fchown(file, Some(uid), Some(gid))?;
+                chowned = true;
+            }
+        }
+        let created_mode = self.umask.map(|umask| mode & 0o777 & !umask);
+        if chowned || created_mode != Some(mode) {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if let Some(name) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                    let name = OsStr::from_bytes(name);
+                    let value = extension.value_bytes();
+                    if acl::is_acl(name) {
+                        let translate = self.translate_acls;
+                        self.acl_dropped |= acl::set(file, name, value, (mode, mask), translate)?;
+                        continue;
+                    }
+                    match file.set_xattr(name, value) {
+                        Err(e) if capabilities::is_dropped(name, &e) => {
+                            self.capability_dropped = true;
+                        }
+                        Err(e) if xattr_dropped(&e) => self.xattr_dropped = true,
+                        result => result?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory entry, kept until the rest of its layer is written with only the metadata tar-rs's
+/// `unpack_in` applies to directories, rather than as a whole entry with its header buffers
+pub(crate) struct DeferredDir {
+    pub(crate) path: PathBuf,
+    /// The mode recorded in the layer
+    pub(crate) mode: u32,
+    /// The bits the permission policy removes from the mode
+    pub(crate) mask: u32,
+    uid: u32,
+    gid: u32,
+    /// The mtime recorded in the layer, which is only applied to the root here, as later entries
+    /// would change it; see [`crate::UnpackOptions::restore_dir_mtimes`]
+    pub(crate) mtime: u64,
+    /// The ACLs recorded in the layer, by extended attribute name
+    acls: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The other extended attributes of an entry for the root, which are applied to the rootfs
+    /// directory itself
+    root: Option<RootMetadata>,
+}
+
+struct RootMetadata {
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl DeferredDir {
+    pub(crate) fn new<R: Read>(entry: &mut Entry<R>, path: PathBuf, mask: u32) -> Result<Self> {
+        let mut acls = Vec::new();
+        if let Some(extensions) = entry.pax_extensions().map_err(Error::Archive)? {
+            for extension in extensions {
+                let extension = extension.map_err(Error::Archive)?;
+                if let Some(name) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                    if acl::is_acl(OsStr::from_bytes(name)) {
+                        acls.push((name.to_vec(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+        let header = entry.header();
+        let id = |id: io::Result<u64>| {
+            id.and_then(|id| u32::try_from(id).map_err(io::Error::other))
+                .map_err(Error::Archive)
+        };
+        Ok(Self {
+            path,
+            mode: header.mode().map_err(Error::Archive)?,
+            mask,
+            uid: id(header.uid())?,
+            gid: id(header.gid())?,
+            mtime: header.mtime().map_err(Error::Archive)?,
+            acls,
+            root: None,
+        })
+    }
+
+    /// Keeps the entry for the root of a layer, such as `./`, along with its extended attributes
+    pub(crate) fn root<R: Read>(entry: &mut Entry<R>, mask: u32) -> Result<Self> {
+        let mut dir = Self::new(entry, PathBuf::new(), mask)?;
+        let mut xattrs = Vec::new();
+        if let Some(extensions) = entry.pax_extensions().map_err(Error::Archive)? {
+            for extension in extensions {
+                let extension = extension.map_err(Error::Archive)?;
+                if let Some(name) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                    if !acl::is_acl(OsStr::from_bytes(name)) {
+                        xattrs.push((name.to_vec(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+        dir.root = Some(RootMetadata { xattrs });
+        Ok(dir)
+    }
+
+    /// Creates the directory and its parents in `root_dir`, if they don't exist, and sets its
+    /// ownership, or records it, mode, ACLs and, for the root, extended attributes. Returns
+    /// [`WarningKind::AclDropped`] if an ACL couldn't be set, nor, with `translate_acls`, applied
+    /// as mode bits, and [`WarningKind::XattrDropped`] if another attribute couldn't be set.
+    pub(crate) fn create(
+        &self,
+        root_dir: &Dir,
+        ownership: OwnershipMode,
+        translate_acls: bool,
+    ) -> Result<Vec<WarningKind>> {
+        let path = crate::or_dot(&self.path);
+        root_dir.create_dir_all(path).with_path(path)?;
+        let dir = root_dir
+            .open_with(
+                path,
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECTORY),
+            )
+            .with_path(path)?
+            .into_std();
+        match ownership {
+            // Ownership is set first, as changing it clears setuid and setgid bits
+            OwnershipMode::Preserve => {
+                fchown(&dir, Some(self.uid), Some(self.gid)).with_path(path)?
+            }
+            OwnershipMode::Emulate => rootless::record(&dir, self.uid, self.gid).with_path(path)?,
+            OwnershipMode::Ignore => {}
+        }
+        dir.set_permissions(Permissions::from_mode(self.mode & !self.mask))
+            .with_path(path)?;
+        let mut acl_dropped = false;
+        for (name, value) in &self.acls {
+            let mode = (self.mode & !self.mask, self.mask);
+            acl_dropped |= acl::set(&dir, OsStr::from_bytes(name), value, mode, translate_acls)
+                .with_path(path)?;
+        }
+        let mut dropped = Vec::new();
+        if acl_dropped {
+            dropped.push(WarningKind::AclDropped);
+        }
+        if let Some(root) = &self.root {
+            let mut skipped = false;
+            for (name, value) in &root.xattrs {
+                match dir.set_xattr(OsStr::from_bytes(name), value) {
+                    Err(e) if xattr_dropped(&e) => skipped = true,
+                    result => result.with_path(path)?,
+                }
+            }
+            if skipped {
+                dropped.push(WarningKind::XattrDropped);
+            }
+            let mtime = file_time(self.mtime);
+            filetime::set_file_handle_times(&dir, Some(mtime), Some(mtime)).with_path(path)?;
+        }
+        Ok(dropped)
+    }
+}
+
+/// Returns whether failing to set an extended attribute with `error` means it's dropped, rather
+/// than the layer failing, which is so for attributes the unpack isn't privileged to set, such as
+/// those in the `trusted.` namespace, or the filesystem doesn't support
+pub(crate) fn xattr_dropped(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EPERM | libc::ENOTSUP | libc::EACCES)
+    )
+}
+
+/// Converts an mtime from a tar header. As in tar-rs, zero mtimes are avoided, as some tools don't
+/// handle them well.
+pub(crate) fn file_time(mtime: u64) -> FileTime {
+    FileTime::from_unix_time(i64::try_from(mtime.max(1)).unwrap_or(i64::MAX), 0)
+}
+
+/// Unpacks `entry` beneath `root` at `path`, which is the entry's own path unless the Unicode policy
+/// rewrote it. Rewritten paths are unpacked as `unpack_in` would, creating their parent in
+/// `root_dir` and checking it's beneath `root` first. Hard links aren't rewritten here, as their
+/// targets would need to be too.
+pub(crate) fn unpack_in<R: Read>(
+    entry: &mut Entry<R>,
+    root: &Path,
+    root_dir: &Dir,
+    path: &Path,
+) -> Result<()> {
+    if crate::normalize(&entry.path().map_err(Error::Archive)?) == crate::normalize(path) {
+        entry.unpack_in(root).with_path(path)?;
+        return Ok(());
+    }
+    let path = crate::normalize(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        root_dir.create_dir_all(parent).with_path(parent)?;
+        let canonical = root.join(parent).canonicalize().with_path(parent)?;
+        if !canonical.starts_with(root.canonicalize().with_path(root)?) {
+            return Err(Error::Archive(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is outside the root", path.display()),
+            )));
+        }
+    }
+    entry.unpack(root.join(&path)).with_path(path)?;
+    Ok(())
+}
+
+/// Returns whether an entry is a FIFO or device node, which [`write_special`] creates
+pub(crate) fn is_special_file(entry_type: EntryType) -> bool {
+    entry_type.is_fifo() || entry_type.is_character_special() || entry_type.is_block_special()
+}
+
+/// Creates the FIFO or device node `entry` at `path`, which is normalized, without the mode bits
+/// in `mask`, replacing whatever that isn't a directory is there.
+///
+/// It's never opened, as opening a FIFO blocks until its other end is opened, and opening a
+/// device does whatever its driver does. It's created with `mknodat`, and its owner, mode,
+/// extended attributes and mtime are applied by name, relative to its parent, which is opened
+/// through `root_dir` so that it can't be outside it. As with tar-rs's `unpack_in`, owners are
+/// only set with [`OwnershipMode::Preserve`]; FIFOs can't have the `user.` attribute that
+/// [`OwnershipMode::Emulate`] records them in.
+///
+/// Returns [`WarningKind::DeviceNodeSkipped`] if the unpack isn't privileged to create a device
+/// node, in which case nothing is at `path`, or [`WarningKind::XattrDropped`] if an extended
+/// attribute couldn't be set.
+pub(crate) fn write_special<R: Read>(
+    root_dir: &Dir,
+    entry: &mut Entry<R>,
+    path: &Path,
+    mask: u32,
+    ownership: OwnershipMode,
+) -> Result<Option<WarningKind>> {
+    let header = entry.header();
+    let entry_type = header.entry_type();
+    let kind = if entry_type.is_fifo() {
+        libc::S_IFIFO
+    } else if entry_type.is_block_special() {
+        libc::S_IFBLK
+    } else {
+        libc::S_IFCHR
+    };
+    let number = |number: io::Result<Option<u32>>| {
+        number
+            .map(Option::unwrap_or_default)
+            .map_err(Error::Archive)
+    };
+    let device = if entry_type.is_fifo() {
+        0
+    } else {
+        libc::makedev(
+            number(header.device_major())?,
+            number(header.device_minor())?,
+        )
+    };
+    let id = |id: io::Result<u64>| {
+        id.and_then(|id| u32::try_from(id).map_err(io::Error::other))
+            .map_err(Error::Archive)
+    };
+    let (uid, gid) = (id(header.uid())?, id(header.gid())?);
+    let mode = header.mode().map_err(Error::Archive)? & 0o7777 & !mask;
+    let mtime = file_time(header.mtime().map_err(Error::Archive)?);
+
+    let parent = path.parent().unwrap_or(Path::new(""));
+    if !parent.as_os_str().is_empty() {
+        root_dir.create_dir_all(parent).with_path(parent)?;
+    }
+    let parent_dir = root_dir.open_dir(crate::or_dot(parent)).with_path(parent)?;
+    let name = path.file_name().expect("special files have a name");
+    match parent_dir.remove_file(name) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).with_path(path),
+        _ => {}
+    }
+    let c_name = CString::new(name.as_bytes())
+        .map_err(io::Error::from)
+        .with_path(path)?;
+    let (fd, name_ptr) = (parent_dir.as_raw_fd(), c_name.as_ptr());
+    let check = |ret: libc::c_int| -> Result<()> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error()).with_path(path)
+        }
+    };
+    // SAFETY: `fd` is an open directory, and `name_ptr` points to a NUL-terminated string, for
+    // the duration of each call
+    if unsafe { libc::mknodat(fd, name_ptr, kind | (mode & 0o777), device) } != 0 {
+        let e = io::Error::last_os_error();
+        // Creating devices needs privilege, which creating FIFOs doesn't
+        if kind != libc::S_IFIFO && e.raw_os_error() == Some(libc::EPERM) {
+            return Ok(Some(WarningKind::DeviceNodeSkipped));
+        }
+        return Err(e).with_path(path);
+    }
+    if ownership == OwnershipMode::Preserve {
+        // Ownership is set first, as changing it clears setuid and setgid bits
+        check(unsafe { libc::fchownat(fd, name_ptr, uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+    }
+    // The mode given to mknodat is masked by the umask, and can't have special bits
+    check(unsafe { libc::fchmodat(fd, name_ptr, mode, 0) })?;
+
+    let mut dropped = None;
+    if let Some(extensions) = entry.pax_extensions().map_err(Error::Archive)? {
+        // Setting extended attributes takes a path or an open file, so the file is named through
+        // the parent's descriptor
+        let named = Path::new("/proc/self/fd").join(fd.to_string()).join(name);
+        for extension in extensions {
+            let extension = extension.map_err(Error::Archive)?;
+            if let Some(key) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                match xattr::set(&named, OsStr::from_bytes(key), extension.value_bytes()) {
+                    Err(e) if xattr_dropped(&e) => dropped = Some(WarningKind::XattrDropped),
+                    result => result.with_path(path)?,
+                }
+            }
+        }
+    }
+    let time = libc::timespec {
+        tv_sec: mtime.unix_seconds() as libc::time_t,
+        tv_nsec: mtime.nanoseconds() as _,
+    };
+    let times = [time, time];
+    check(unsafe { libc::utimensat(fd, name_ptr, times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(dropped)
+}
+
+/// Opens the regular file at `path` in `root_dir` for reading, failing if there's anything else
+/// there rather than following a symlink, opening a device, or blocking on a FIFO. It's opened
+/// without following symlinks or blocking, in case it's replaced once it's been checked.
+pub(crate) fn open_file(root_dir: &Dir, path: &Path) -> io::Result<File> {
+    let not_a_file = || io::Error::new(io::ErrorKind::InvalidInput, "not a regular file");
+    if !root_dir.symlink_metadata(path)?.is_file() {
+        return Err(not_a_file());
+    }
+    let file = root_dir
+        .open_with(
+            path,
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW),
+        )?
+        .into_std();
+    if !file.metadata()?.is_file() {
+        return Err(not_a_file());
+    }
+    Ok(file)
+}
+
+/// Creates `path` as a copy of `target`, for a hard link that's written as a copy. Regular files
+/// are copied with their mode, ownership, mtime and extended attributes, which include any
+/// recorded owner, and symlinks are recreated.
+pub(crate) fn copy_link_target(
+    root_dir: &Dir,
+    target: &Path,
+    path: &Path,
+    ownership: OwnershipMode,
+) -> Result<()> {
+    let metadata = root_dir.symlink_metadata(target).with_path(target)?;
+    match root_dir.symlink_metadata(path) {
+        Ok(existing) if existing.is_dir() => root_dir.remove_dir_all(path).with_path(path)?,
+        Ok(_) => root_dir.remove_file(path).with_path(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_path(path),
+    }
+    if metadata.is_symlink() {
+        let link = root_dir.read_link_contents(target).with_path(target)?;
+        return root_dir.symlink_contents(link, path).with_path(path);
+    }
+    if !metadata.is_file() {
+        return Err(Error::Io {
+            path: path.to_path_buf(),
+            source: io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't copy hard link target {}", target.display()),
+            ),
+        });
+    }
+
+    let mut source = open_file(root_dir, target).with_path(target)?;
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .create_new(true)
+        .mode(metadata.mode() & 0o777);
+    let mut file = root_dir
+        .open_with(path, &options)
+        .with_path(path)?
+        .into_std();
+    io::copy(&mut source, &mut file).with_path(path)?;
+    for name in source.list_xattr().with_path(target)? {
+        if let Some(value) = source.get_xattr(&name).with_path(target)? {
+            file.set_xattr(&name, &value).with_path(path)?;
+        }
+    }
+    if ownership == OwnershipMode::Preserve {
+        // Ownership is set first, as changing it clears setuid and setgid bits
+        fchown(&file, Some(metadata.uid()), Some(metadata.gid())).with_path(path)?;
+    }
+    file.set_permissions(Permissions::from_mode(metadata.mode() & 0o7777))
+        .with_path(path)?;
+    let mtime = FileTime::from_unix_time(metadata.mtime(), metadata.mtime_nsec() as u32);
+    filetime::set_file_handle_times(&file, Some(mtime), Some(mtime)).with_path(path)
+}
+
+/// Writes the content of `entry` to `writer` as `inspector` reads it, then writes whatever it
+/// didn't read. Returns the inspector's error, if any.
+fn inspect(
+    inspector: &Mutex<ContentInspector>,
+    entry: &mut impl Read,
+    writer: &mut impl Write,
+    path: &Path,
+) -> Result<Option<Error>> {
+    let mut tee = TeeReader {
+        reader: entry,
+        writer,
+        error: None,
+    };
+    let result = {
+        // An inspector that panicked has no state this could corrupt
+        let mut inspector = inspector.lock().unwrap_or_else(PoisonError::into_inner);
+        inspector(path, &mut tee)
+    };
+    let drained = io::copy(&mut tee, &mut io::sink());
+    // Failures to read or write the file take precedence, as the inspector only saw their effect
+    if let Some(e) = tee.error.take() {
+        return Err(e).with_path(path);
+    }
+    drained.with_path(path)?;
+    Ok(result.err())
+}
+
+/// Writes to `writer`, keeping a copy of what's written in `content` if there is one
+struct SniffingWriter<W> {
+    writer: W,
+    content: Option<Vec<u8>>,
+}
+
+impl<W: Write> Write for SniffingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if let Some(content) = &mut self.content {
+            content.extend_from_slice(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes everything read from `reader` to `writer`, recording the first error from either
+struct TeeReader<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = &self.error {
+            return Err(io::Error::new(e.kind(), e.to_string()));
+        }
+        let result = self
+            .reader
+            .read(buf)
+            .and_then(|read| self.writer.write_all(&buf[..read]).map(|()| read));
+        result.map_err(|e| {
+            let copy = io::Error::new(e.kind(), e.to_string());
+            self.error = Some(e);
+            copy
+        })
+    }
+}
+
+/// Allocates `size` bytes for the empty `file`, so that it's written to contiguous space where
+/// the filesystem can arrange it. This is only a hint, so failures, such as from filesystems that
+/// don't support it, are ignored.
+fn preallocate(file: &File, size: u64, path: &Path) {
+    let Ok(len) = libc::off_t::try_from(size) else {
+        return;
+    };
+    // SAFETY: the file descriptor is open for the duration of the call
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } != 0 {
+        log::debug!(
+            "Failed to preallocate {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Returns the process's umask, if it can be read without changing it
+pub(crate) fn current_umask() -> Option<u32> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let umask = status.lines().find_map(|l| l.strip_prefix("Umask:"))?;
+    u32::from_str_radix(umask.trim(), 8).ok()
+}
@@ -0,0 +1,41 @@
+use crate::error::{Error, IoResultExt, Result};
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The write bits of a mode, for the owner, group and others
+pub(crate) const WRITE_BITS: u32 = 0o222;
+
+/// Removes the write bits from the mode of `rootfs` and every file and directory beneath it.
+/// Symlinks are skipped, as changing their mode would change their target's.
+///
+/// Directories are changed before their entries, which only needs them to be readable and
+/// searchable, so the rootfs can be walked whoever owns it.
+pub(crate) fn strip(rootfs: &Path) -> Result<()> {
+    let mut stripped = 0u64;
+    for entry in walkdir::WalkDir::new(rootfs) {
+        let entry = entry.map_err(|e| Error::Io {
+            path: e.path().unwrap_or(rootfs).to_path_buf(),
+            source: e.into(),
+        })?;
+        if entry.file_type().is_symlink() {
+            continue;
+        }
+        let path = entry.path();
+        let metadata = entry.metadata().map_err(|e| Error::Io {
+            path: path.to_path_buf(),
+            source: e.into(),
+        })?;
+        let mode = metadata.permissions().mode();
+        if mode & WRITE_BITS != 0 {
+            fs::set_permissions(path, Permissions::from_mode(mode & !WRITE_BITS))
+                .with_path(path)?;
+            stripped += 1;
+        }
+    }
+    log::debug!(
+        "Removed write bits from {stripped} paths in {}",
+        rootfs.display()
+    );
+    Ok(())
+}